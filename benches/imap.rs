@@ -0,0 +1,141 @@
+// cargo bench --features internals
+// The grammar and the stream's buffering are on the path of every byte downloaded.
+
+use sin::internals::{Stream, parser, utf7_to_utf8};
+use std::{cmp, hint, io, mem};
+
+fn message(size: usize) -> Vec<u8> {
+  let mut message = b"From: test\r\nMessage-ID: test\r\n\r\n".to_vec();
+  let line = b"Lorem ipsum dolor sit amet, consectetur adipiscing elit, sed do eiusmod tempor.\r\n";
+  while message.len() < size {
+    message.extend_from_slice(&line[..cmp::min(line.len(), size - message.len())]);
+  }
+  message
+}
+
+fn fetch_body(size: usize) -> Vec<u8> {
+  [
+    format!("1 FETCH (UID 10 BODY[] {{{size}}}\r\n").as_bytes(),
+    &message(size),
+    b")\r\n",
+  ]
+  .concat()
+}
+
+fn select_data(criterion: &mut criterion::Criterion) {
+  // What a SELECT (QRESYNC) returns for a large mailbox, minus the "* " parsed beforehand.
+  let lines: Vec<Vec<u8>> = (1..=10000)
+    .map(|uid| {
+      format!(
+        "{uid} FETCH (UID {uid} FLAGS (\\Seen $Junk) MODSEQ ({}))\r\n",
+        uid * 2
+      )
+      .into_bytes()
+    })
+    .collect();
+  criterion.bench_function("select_data", |bencher| {
+    bencher.iter(|| {
+      for line in &lines {
+        hint::black_box(parser::select_data(hint::black_box(line)).unwrap());
+      }
+    })
+  });
+}
+
+fn fetch_body_data(criterion: &mut criterion::Criterion) {
+  let mut group = criterion.benchmark_group("fetch_body_data");
+  for size in [64 * 1024, 4 * 1024 * 1024, 32 * 1024 * 1024] {
+    let fetch = fetch_body(size);
+    group.throughput(criterion::Throughput::Bytes(fetch.len() as u64));
+    group.bench_with_input(
+      criterion::BenchmarkId::from_parameter(size),
+      &fetch,
+      |bencher, fetch| {
+        bencher.iter(|| hint::black_box(parser::fetch_body_data(hint::black_box(fetch)).unwrap()))
+      },
+    );
+  }
+  group.finish();
+}
+
+// Answers each command with the response, and the NOOP used for chunking with its completion. Reads
+// are limited like they'd be over TLS (one record at a time).
+struct Server {
+  response: Vec<u8>,
+  output: Vec<u8>,
+  input: Vec<u8>,
+  position: usize,
+}
+
+impl sin::ReadWrite for Server {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    let length = cmp::min(
+      cmp::min(buf.len(), 16 * 1024),
+      self.input.len() - self.position,
+    );
+    buf[..length].copy_from_slice(&self.input[self.position..self.position + length]);
+    self.position += length;
+    Ok(length)
+  }
+
+  fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+    self.output.extend_from_slice(buf);
+    if !self.output.ends_with(b"\r\n") {
+      return Ok(());
+    }
+    self.input.drain(..self.position);
+    self.position = 0;
+    let command = mem::take(&mut self.output);
+    let tag = command.split(|byte| *byte == b' ').next().unwrap();
+    if !command.ends_with(b" NOOP\r\n") {
+      self.input.extend_from_slice(&self.response);
+    }
+    self
+      .input
+      .extend_from_slice(&[tag, b" OK completed.\r\n"].concat());
+    Ok(())
+  }
+}
+
+fn chunk(criterion: &mut criterion::Criterion) {
+  let mut group = criterion.benchmark_group("chunk");
+  for size in [64 * 1024, 4 * 1024 * 1024, 32 * 1024 * 1024] {
+    let response = [b"* ", &fetch_body(size)[..]].concat();
+    group.throughput(criterion::Throughput::Bytes(response.len() as u64));
+    let mut stream = Stream::new(Server {
+      response,
+      output: Vec::new(),
+      input: Vec::new(),
+      position: 0,
+    });
+    stream.set_capabilities(&[b"LITERAL+".to_vec()]);
+    group.bench_function(criterion::BenchmarkId::from_parameter(size), |bencher| {
+      bencher.iter(|| {
+        let command: &[&[u8]] = &[b"fetch UID FETCH 10 BODY.PEEK[]\r\n"];
+        stream.input(command, command.len()).unwrap();
+        stream.expect(parser::start).unwrap();
+        hint::black_box(stream.expect(parser::fetch_body_data).unwrap());
+        stream.expect(parser::start).unwrap();
+        stream.expect(parser::ok).unwrap();
+      })
+    });
+  }
+  group.finish();
+}
+
+fn utf7(criterion: &mut criterion::Criterion) {
+  let mut group = criterion.benchmark_group("utf7_to_utf8");
+  for (name, mailbox) in [
+    ("ascii", &b"Archives/2023/Mailing lists/notmuch"[..]),
+    // https://www.rfc-editor.org/rfc/rfc3501#section-5.1.3
+    ("mixed", b"~peter/mail/&U,BTFw-/&ZeVnLIqe-"),
+  ] {
+    group.bench_with_input(name, mailbox, |bencher, mailbox| {
+      bencher.iter(|| hint::black_box(utf7_to_utf8(hint::black_box(mailbox)).unwrap()))
+    });
+  }
+  group.finish();
+}
+
+criterion::criterion_group!(benches, select_data, fetch_body_data, chunk, utf7);
+criterion::criterion_main!(benches);
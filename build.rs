@@ -1,6 +1,8 @@
+#[cfg(feature = "notmuch")]
 use std::{env, path};
 
 // https://rust-lang.github.io/rust-bindgen/tutorial-3.html
+#[cfg(feature = "notmuch")]
 fn main() {
   let out = path::PathBuf::from(env::var("OUT_DIR").unwrap());
   let header = "source/notmuch/bindings.h";
@@ -13,3 +15,7 @@ fn main() {
     .unwrap();
   bindings.write_to_file(out.join("notmuch.rs")).unwrap();
 }
+
+// Nothing to bind without the notmuch backend.
+#[cfg(not(feature = "notmuch"))]
+fn main() {}
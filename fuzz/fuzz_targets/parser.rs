@@ -0,0 +1,36 @@
+#![no_main]
+
+use sin::internals::parser;
+
+// Every public rule, they're all fed what follows the tag of a response.
+libfuzzer_sys::fuzz_target!(|data: &[u8]| {
+  let _ = parser::start(data);
+  let _ = parser::continue_req(data);
+  let _ = parser::skip(data);
+  let _ = parser::untagged(data);
+  let _ = parser::bad(data);
+  let _ = parser::ok(data);
+  let _ = parser::available_capabilities(data);
+  let _ = parser::greeting(data);
+  let _ = parser::capability_response(data);
+  let _ = parser::enabled_capabilities(data);
+  let _ = parser::id_response(data);
+  let _ = parser::namespace_response(data);
+  let _ = parser::list_mailbox(data);
+  let _ = parser::lsub_mailbox(data);
+  let _ = parser::select_data(data);
+  let _ = parser::fetch_size_data(data);
+  let _ = parser::fetch_gmail_message_id_data(data);
+  let _ = parser::fetch_flags_data(data);
+  let _ = parser::fetch_body_data(data);
+  let _ = parser::append(data);
+  let _ = parser::trycreate(data);
+  let _ = parser::append_data(data);
+  let _ = parser::store(data);
+  let _ = parser::store_data(data);
+  let _ = parser::move_(data);
+  let _ = parser::move_data(data);
+  let _ = parser::copy(data);
+  let _ = parser::update(data);
+  let _ = parser::esearch(data);
+});
@@ -0,0 +1,6 @@
+#![no_main]
+
+// Mailbox names come from the server's LIST responses.
+libfuzzer_sys::fuzz_target!(|data: &[u8]| {
+  let _ = sin::internals::utf7_to_utf8(data);
+});
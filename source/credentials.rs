@@ -0,0 +1,214 @@
+use crate::{Keyring, imap};
+use anyhow::Context as _;
+use std::{env, process, str, sync, thread, time};
+use zeroize::Zeroize as _;
+
+// Where the password (or token) comes from.
+#[derive(Clone, Debug)]
+pub enum Secret {
+  // Only suitable for preauthenticated connections or AUTH=EXTERNAL (sync::authenticate will
+  // complain otherwise).
+  None,
+  // The first line of stdout.
+  Command(Vec<String>),
+  Keyring(Keyring),
+  Environment(String),
+  Prompt,
+}
+
+pub struct Credentials {
+  pub user: String,
+  secret: Secret,
+  // Picked from the advertised ones when not forced.
+  pub mechanism: Option<imap::Mechanism>,
+  // When the command or the keyring fails (e.g.: a locked GPG agent, an unreachable KMS): how many
+  // more times to try and whether to prompt as a last resort.
+  retries: usize,
+  ask: bool,
+  // Resolved when the server first asks for it, shared by all the connections.
+  password: sync::Mutex<Option<zeroize::Zeroizing<String>>>,
+}
+
+impl Credentials {
+  pub fn new(user: &str, secret: Secret, mechanism: Option<imap::Mechanism>) -> Self {
+    Self {
+      user: user.to_string(),
+      secret,
+      mechanism,
+      retries: 0,
+      ask: false,
+      password: sync::Mutex::new(None),
+    }
+  }
+
+  pub fn set_retries(&mut self, retries: usize) {
+    self.retries = retries;
+  }
+
+  pub fn set_ask(&mut self, ask: bool) {
+    self.ask = ask;
+  }
+
+  pub fn has_password(&self) -> bool {
+    !matches!(self.secret, Secret::None)
+  }
+
+  pub fn password(&self) -> anyhow::Result<zeroize::Zeroizing<String>> {
+    // Hold the lock while resolving: concurrent connections shouldn't prompt (or spawn) twice.
+    let mut password = self.password.lock().unwrap();
+    if password.is_none() {
+      *password = Some(zeroize::Zeroizing::new(self.resolve()?));
+    }
+    Ok(password.clone().unwrap())
+  }
+
+  fn resolve(&self) -> anyhow::Result<String> {
+    let mut attempt = 0;
+    loop {
+      let password = match &self.secret {
+        Secret::None => anyhow::bail!("no password was given"),
+        Secret::Command(command) => command_password(command),
+        Secret::Keyring(keyring) => keyring_password(keyring),
+        Secret::Environment(variable) => {
+          log::info!("getting password from ${variable}");
+          env::var(variable).with_context(|| format!("couldn't get password from ${variable}"))
+        }
+        Secret::Prompt => return self.prompt(),
+      };
+      let error = match password {
+        Ok(password) => return Ok(password),
+        Err(error) => error,
+      };
+      // The environment won't change in the meantime.
+      if attempt < self.retries && !matches!(self.secret, Secret::Environment(_)) {
+        attempt += 1;
+        log::warn!("{error:#}, retrying ({attempt}/{})", self.retries);
+        // Give the agent (or whatever is behind it) some time to recover.
+        thread::sleep(time::Duration::from_secs(attempt as u64));
+        continue;
+      }
+      if self.ask {
+        log::warn!("{error:#}, prompting instead");
+        return self.prompt();
+      }
+      return Err(error);
+    }
+  }
+
+  fn prompt(&self) -> anyhow::Result<String> {
+    rpassword::prompt_password(format!("Password for {}: ", self.user))
+      .context("couldn't prompt for the password")
+  }
+
+  // Forget the password so the next connection resolves it again (e.g.: an expired OAuth token
+  // will be refreshed by the password command).
+  pub fn invalidate(&self) {
+    *self.password.lock().unwrap() = None;
+  }
+}
+
+fn command_password(password_command: &[String]) -> anyhow::Result<String> {
+  let mut program = process::Command::new(&password_command[0]);
+  let command = program.args(&password_command[1..]);
+  log::info!("getting password from {command:?}");
+  let output = command
+    .output()
+    .with_context(|| format!("couldn't run {command:?}"))?;
+  let mut stdout = output.stdout;
+  if !output.status.success() {
+    stdout.zeroize();
+    // What went wrong (e.g.: gpg: decryption failed: No secret key).
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    anyhow::bail!(
+      "couldn't get password: {command:?} failed with {}{}",
+      output.status,
+      match stderr.trim() {
+        "" => String::new(),
+        stderr => format!(": {stderr}"),
+      }
+    );
+  }
+  let password = str::from_utf8(
+    stdout
+      .split(|byte| *byte == b'\n')
+      .next()
+      .with_context(|| format!("{command:?} didn't output anything"))?,
+  )
+  .with_context(|| format!("{command:?} didn't output UTF-8"))?;
+  let password = password.to_string();
+  stdout.zeroize();
+  Ok(password)
+}
+
+fn keyring_password(keyring: &Keyring) -> anyhow::Result<String> {
+  log::info!(
+    "getting password from the keyring ({}/{})",
+    keyring.service,
+    keyring.account
+  );
+  keyring::Entry::new(&keyring.service, &keyring.account)
+    .and_then(|entry| entry.get_password())
+    .with_context(|| {
+      format!(
+        "couldn't get password from the keyring ({}/{})",
+        keyring.service, keyring.account
+      )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn lazy() {
+    let directory = tempfile::tempdir().unwrap();
+    let counter = directory.path().join("counter");
+    let credentials = Credentials::new(
+      "user",
+      Secret::Command(vec![
+        "sh".to_string(),
+        "-c".to_string(),
+        format!("echo >> {0}; wc -l < {0}", counter.display()),
+      ]),
+      None,
+    );
+    assert!(credentials.has_password());
+    // Nothing is resolved until needed.
+    assert!(!counter.exists());
+    assert_eq!("1", credentials.password().unwrap().as_str());
+    assert_eq!("1", credentials.password().unwrap().as_str());
+    credentials.invalidate();
+    assert_eq!("2", credentials.password().unwrap().as_str());
+
+    let credentials = Credentials::new("user", Secret::None, None);
+    assert!(!credentials.has_password());
+    assert!(credentials.password().is_err());
+  }
+
+  #[test]
+  fn retries() {
+    let directory = tempfile::tempdir().unwrap();
+    let counter = directory.path().join("counter");
+    // Fails the first time.
+    let secret = Secret::Command(vec![
+      "sh".to_string(),
+      "-c".to_string(),
+      format!(
+        "echo >> {0}; test $(wc -l < {0}) -gt 1 || {{ echo locked >&2; exit 1; }}; echo password",
+        counter.display()
+      ),
+    ]);
+    let credentials = Credentials::new("user", secret.clone(), None);
+    let error = credentials.password().unwrap_err().to_string();
+    assert!(
+      error.ends_with("failed with exit status: 1: locked"),
+      "{error}"
+    );
+
+    std::fs::remove_file(&counter).unwrap();
+    let mut credentials = Credentials::new("user", secret, None);
+    credentials.set_retries(1);
+    assert_eq!("password", credentials.password().unwrap().as_str());
+  }
+}
@@ -0,0 +1,94 @@
+// https://www.rfc-editor.org/rfc/rfc6186 - Use of SRV Records for Locating Email Submission/Access
+// https://www.rfc-editor.org/rfc/rfc8314 - Cleartext Considered Obsolete
+
+use anyhow::Context as _;
+use hickory_resolver::proto::rr::rdata;
+use std::cmp;
+
+// Only implicit TLS is supported (no STARTTLS) so only look for _imaps.
+pub fn srv(user: &str) -> anyhow::Result<(String, u16)> {
+  let (_, domain) = user
+    .rsplit_once('@')
+    .with_context(|| format!("{user} isn't an email address"))?;
+  // https://www.rfc-editor.org/rfc/rfc6186#section-3.2
+  // _imaps._tcp.example.com. SRV 0 1 993 imap.example.com.
+  let name = format!("_imaps._tcp.{domain}.");
+  log::info!("looking up {name}");
+  let resolver = hickory_resolver::Resolver::from_system_conf()?;
+  let lookup = match resolver.srv_lookup(name.as_str()) {
+    Ok(lookup) => lookup,
+    Err(error) => match error.kind() {
+      hickory_resolver::error::ResolveErrorKind::NoRecordsFound { .. } => {
+        anyhow::bail!("no SRV record found for {name}, use --address and --port")
+      }
+      _ => Err(error).with_context(|| format!("couldn't look up {name}"))?,
+    },
+  };
+  let records: Vec<rdata::SRV> = lookup.iter().cloned().collect();
+  let (target, port) = select(&name, &records)?;
+  // Nothing vouches for the answer but the server's certificate (for the target, not the domain).
+  let (target_, domain_) = (target.to_ascii_lowercase(), domain.to_ascii_lowercase());
+  if target_ != domain_ && !target_.ends_with(&format!(".{domain_}")) {
+    log::warn!("{name} points outside of {domain}, to {target}: make sure it's expected");
+  }
+  log::info!("discovered {target}:{port}");
+  Ok((target, port))
+}
+
+// The record to use among the ones found for name.
+fn select(name: &str, records: &[rdata::SRV]) -> anyhow::Result<(String, u16)> {
+  // https://www.rfc-editor.org/rfc/rfc2782
+  // A client MUST attempt to contact the target host with the lowest-numbered priority it can
+  // reach [...] Larger weights SHOULD be given a proportionately higher probability of being
+  // selected.
+  // Selecting the highest weight is good enough here since the result is saved for later runs.
+  let record = records
+    .iter()
+    .min_by_key(|record| (record.priority(), cmp::Reverse(record.weight())))
+    .with_context(|| format!("no SRV record found for {name}"))?;
+  // https://www.rfc-editor.org/rfc/rfc6186#section-4
+  // [...] a target of "." indicates that the service is decidedly not available.
+  anyhow::ensure!(
+    !record.target().is_root(),
+    "{name} indicates IMAP over TLS isn't available"
+  );
+  let target = record.target().to_ascii();
+  Ok((target.trim_end_matches('.').to_string(), record.port()))
+}
+
+#[cfg(test)]
+mod tests {
+  use hickory_resolver::{Name, proto::rr::rdata};
+
+  #[test]
+  fn select() -> anyhow::Result<()> {
+    let record = |priority, weight, target| -> anyhow::Result<_> {
+      Ok(rdata::SRV::new(
+        priority,
+        weight,
+        993,
+        Name::from_ascii(target)?,
+      ))
+    };
+    let name = "_imaps._tcp.example.com.";
+    assert_eq!(
+      ("b.example.com".to_string(), 993),
+      super::select(
+        name,
+        &[
+          record(1, 100, "a.example.com.")?,
+          record(0, 1, "c.example.com.")?,
+          record(0, 10, "b.example.com.")?,
+        ]
+      )?
+    );
+    assert_eq!(
+      "_imaps._tcp.example.com. indicates IMAP over TLS isn't available",
+      super::select(name, &[record(0, 0, ".")?])
+        .unwrap_err()
+        .to_string()
+    );
+    assert!(super::select(name, &[]).is_err());
+    Ok(())
+  }
+}
@@ -0,0 +1,259 @@
+// Fault injection, so the recovery paths can be tested deterministically: interruptions at fixed
+// points (see tests/interruptions.rs) and, with the faults feature, failures at named sites (see
+// Kind). Faults are per thread since the tests run concurrently, the threads spawned along the way
+// adopt their parent's (see current and adopt).
+
+use anyhow::Context as _;
+use std::{collections, error, fmt, io, str, sync, thread};
+
+#[derive(Copy, Clone, Debug, PartialEq, clap::ValueEnum)]
+pub enum Interruption {
+  AppendIsNotTransactional,
+  MoveOutOfTmpPostRename,
+  StoredFlags,
+  SuccessfulMovePreCommit,
+}
+
+impl fmt::Display for Interruption {
+  fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+    write!(formatter, "{self:?}")
+  }
+}
+
+impl error::Error for Interruption {}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Kind {
+  // Writing the command (e.g.: FETCH, UID is skipped) fails as if the connection was reset.
+  Command,
+  // The response to the command is cut short and the connection ends.
+  ShortRead,
+  // The Notmuch operation (add, remove or commit) fails.
+  Notmuch,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Fault {
+  pub kind: Kind,
+  pub site: String,
+  // Only this occurrence fails (counting from 1), once.
+  pub occurrence: usize,
+}
+
+impl Fault {
+  // kind:site[@occurrence], e.g.: command:FETCH@2, short-read:SELECT or notmuch:commit.
+  pub fn parse(argument: &str) -> anyhow::Result<Self> {
+    let (kind, site) = argument
+      .split_once(':')
+      .filter(|(_, site)| !site.is_empty())
+      .with_context(|| format!("{argument} isn't kind:site[@occurrence]"))?;
+    let kind = match kind {
+      "command" => Kind::Command,
+      "short-read" => Kind::ShortRead,
+      "notmuch" => Kind::Notmuch,
+      _ => anyhow::bail!("unknown kind {kind} (command, short-read or notmuch)"),
+    };
+    let (site, occurrence) = match site.rsplit_once('@') {
+      Some((site, occurrence)) => (
+        site,
+        occurrence
+          .parse()
+          .ok()
+          .filter(|occurrence| *occurrence > 0)
+          .with_context(|| format!("{occurrence} isn't a positive number"))?,
+      ),
+      None => (site, 1),
+    };
+    Ok(Self {
+      kind,
+      site: site.to_string(),
+      occurrence,
+    })
+  }
+}
+
+#[derive(Debug, Default)]
+struct State {
+  interruption: Option<Interruption>,
+  // With the occurrences left until they fail, removed once they did.
+  faults: Vec<(Fault, usize)>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Faults(sync::Arc<sync::Mutex<State>>);
+
+static FAULTS: once_cell::sync::Lazy<sync::Mutex<collections::HashMap<thread::ThreadId, Faults>>> =
+  once_cell::sync::Lazy::new(|| sync::Mutex::new(collections::HashMap::new()));
+
+// Replaces the current thread's.
+pub fn set(interruption: Option<Interruption>, faults: &[Fault]) -> anyhow::Result<()> {
+  anyhow::ensure!(
+    faults.is_empty() || cfg!(feature = "faults"),
+    "injecting faults requires the faults feature"
+  );
+  adopt((interruption.is_some() || !faults.is_empty()).then(|| {
+    Faults(sync::Arc::new(sync::Mutex::new(State {
+      interruption,
+      faults: faults
+        .iter()
+        .map(|fault| (fault.clone(), fault.occurrence))
+        .collect(),
+    })))
+  }));
+  Ok(())
+}
+
+// For the threads spawned by the current one (see adopt).
+pub fn current() -> Option<Faults> {
+  FAULTS.lock().unwrap().get(&thread::current().id()).cloned()
+}
+
+// Share the faults of another thread (an occurrence is counted once for all of them), None when
+// done.
+pub fn adopt(faults: Option<Faults>) {
+  let mut threads = FAULTS.lock().unwrap();
+  match faults {
+    Some(faults) => threads.insert(thread::current().id(), faults),
+    None => threads.remove(&thread::current().id()),
+  };
+}
+
+pub fn interrupt(interruption: Interruption) -> Result<(), Interruption> {
+  match current().and_then(|faults| faults.0.lock().unwrap().interruption) {
+    Some(interruption_) if interruption_ == interruption => Err(interruption),
+    _ => Ok(()),
+  }
+}
+
+// Whether a fault is due at the site (it's then spent).
+fn due(kind: Kind, site: &str) -> bool {
+  if !cfg!(feature = "faults") {
+    return false;
+  }
+  let Some(faults) = current() else {
+    return false;
+  };
+  let mut state = faults.0.lock().unwrap();
+  let mut due = false;
+  state.faults.retain_mut(|(fault, left)| {
+    if fault.kind != kind || !fault.site.eq_ignore_ascii_case(site) {
+      return true;
+    }
+    *left -= 1;
+    due |= *left == 0;
+    *left > 0
+  });
+  if due {
+    log::warn!("injecting a fault ({kind:?}) at {site}");
+  }
+  due
+}
+
+// A command (tag [UID] name ...) is about to be written: whether it fails or whether its response
+// is to be cut short.
+pub fn command(command: &[u8]) -> io::Result<bool> {
+  if !cfg!(feature = "faults") {
+    return Ok(false);
+  }
+  let mut words = command
+    .split(|byte| matches!(byte, b' ' | b'\r'))
+    .skip(1)
+    .filter(|word| !word.is_empty());
+  let name = match words.next() {
+    Some(word) if word.eq_ignore_ascii_case(b"UID") => words.next(),
+    word => word,
+  }
+  .and_then(|name| str::from_utf8(name).ok())
+  .unwrap_or_default();
+  if due(Kind::Command, name) {
+    return Err(io::Error::new(
+      io::ErrorKind::ConnectionReset,
+      format!("injected fault: {name} failed"),
+    ));
+  }
+  Ok(due(Kind::ShortRead, name))
+}
+
+pub fn notmuch(site: &str) -> anyhow::Result<()> {
+  anyhow::ensure!(
+    !due(Kind::Notmuch, site),
+    "injected fault: notmuch {site} failed"
+  );
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parse() -> anyhow::Result<()> {
+    assert_eq!(
+      Fault {
+        kind: Kind::Command,
+        site: "FETCH".to_string(),
+        occurrence: 2,
+      },
+      Fault::parse("command:FETCH@2")?
+    );
+    assert_eq!(
+      Fault {
+        kind: Kind::Notmuch,
+        site: "commit".to_string(),
+        occurrence: 1,
+      },
+      Fault::parse("notmuch:commit")?
+    );
+    assert!(Fault::parse("command:").is_err());
+    assert!(Fault::parse("command:FETCH@0").is_err());
+    assert!(Fault::parse("other:FETCH").is_err());
+    Ok(())
+  }
+
+  #[test]
+  fn interrupt() -> anyhow::Result<()> {
+    set(Some(Interruption::StoredFlags), &[])?;
+    assert_eq!(
+      Ok(()),
+      super::interrupt(Interruption::AppendIsNotTransactional)
+    );
+    let faults = current();
+    thread::spawn(move || {
+      adopt(faults);
+      assert_eq!(
+        Err(Interruption::StoredFlags),
+        super::interrupt(Interruption::StoredFlags)
+      );
+      adopt(None);
+    })
+    .join()
+    .unwrap();
+    set(None, &[])?;
+    assert_eq!(Ok(()), super::interrupt(Interruption::StoredFlags));
+    Ok(())
+  }
+
+  #[cfg(feature = "faults")]
+  #[test]
+  fn command() -> anyhow::Result<()> {
+    set(
+      None,
+      &[
+        Fault::parse("command:FETCH@2")?,
+        Fault::parse("short-read:select")?,
+      ],
+    )?;
+    assert!(!super::command(b"fetch UID FETCH 1:* (FLAGS)\r\n")?);
+    assert_eq!(
+      io::ErrorKind::ConnectionReset,
+      super::command(b"fetch UID FETCH 1:* (FLAGS)\r\n")
+        .unwrap_err()
+        .kind()
+    );
+    // Spent.
+    assert!(!super::command(b"fetch UID FETCH 1:* (FLAGS)\r\n")?);
+    assert!(super::command(b"select SELECT INBOX\r\n")?);
+    set(None, &[])?;
+    Ok(())
+  }
+}
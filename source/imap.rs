@@ -4,7 +4,7 @@
 
 use anyhow::Context as _;
 use base64::Engine as _;
-use std::{borrow, cell, cmp, io, str};
+use std::{any, borrow, cell, cmp, error, fmt, io, mem, ops, str, time};
 
 // Inclusive.
 #[derive(Debug, PartialEq)]
@@ -23,15 +23,88 @@ pub struct SelectFetch<'input> {
   pub modseq: u64,
 }
 
+// The attributes of a FETCH response (solicited or not), whatever their order.
+#[derive(Debug, Default, PartialEq)]
+pub struct MessageAttributes<'input> {
+  pub uid: Option<u64>,
+  pub flags: Option<Vec<&'input [u8]>>,
+  pub modseq: Option<u64>,
+  pub size: Option<u64>,
+  pub body: Option<Option<borrow::Cow<'input, [u8]>>>,
+  pub gmail_message_id: Option<u64>,
+}
+
+enum MessageAttribute<'input> {
+  Uid(u64),
+  Flags(Vec<&'input [u8]>),
+  ModSeq(u64),
+  Size(u64),
+  Body(Option<borrow::Cow<'input, [u8]>>),
+  GmailMessageId(u64),
+  Unknown,
+}
+
+impl<'input> MessageAttributes<'input> {
+  fn new(attributes: Vec<MessageAttribute<'input>>) -> Self {
+    let mut attributes_ = Self::default();
+    for attribute in attributes {
+      match attribute {
+        MessageAttribute::Uid(uid) => attributes_.uid = Some(uid),
+        MessageAttribute::Flags(flags) => attributes_.flags = Some(flags),
+        MessageAttribute::ModSeq(modseq) => attributes_.modseq = Some(modseq),
+        MessageAttribute::Size(size) => attributes_.size = Some(size),
+        MessageAttribute::Body(body) => attributes_.body = Some(body),
+        MessageAttribute::GmailMessageId(id) => attributes_.gmail_message_id = Some(id),
+        MessageAttribute::Unknown => (),
+      }
+    }
+    attributes_
+  }
+}
+
 #[derive(Debug, PartialEq)]
 pub enum Select<'input> {
   Flags(Vec<&'input [u8]>),
   UIDValidity(u64),
+  UIDNext(u64),
   HighestModSeq(u64),
   Vanished(Vec<Range>),
   Fetch(SelectFetch<'input>),
 }
 
+#[derive(Debug, PartialEq)]
+pub struct Greeting<'input> {
+  pub preauthenticated: bool,
+  // Optional, a CAPABILITY command is needed otherwise.
+  pub capabilities: Option<Vec<&'input [u8]>>,
+  // Usually names the server.
+  pub text: &'input [u8],
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Untagged<'input> {
+  Exists(u64),
+  Recent(u64),
+  // The message sequence number, not the UID.
+  Expunge(u64),
+  Flags(Vec<&'input [u8]>),
+  Other,
+}
+
+// What another client changed in the selected mailbox, reported when the server sees fit (e.g.: in
+// response to a NOOP).
+#[derive(Debug, PartialEq)]
+pub enum Update {
+  Vanished(Vec<Range>),
+  Fetch {
+    uid: u64,
+    flags: Vec<String>,
+    modseq: u64,
+  },
+  // The message sequence number, not the UID.
+  Expunge(u64),
+}
+
 #[derive(Debug, PartialEq)]
 pub struct Append {
   pub uidvalidity: u64,
@@ -51,10 +124,26 @@ pub struct Move {
   pub to: Vec<Range>,
 }
 
-fn parse_number(n: &[u8]) -> u64 {
-  // One unwrap could be eliminiated since it's guaranteed by the BNF but it's either that or
-  // unsafe...
-  str::from_utf8(n).unwrap().parse().unwrap()
+#[derive(Debug, PartialEq)]
+pub struct Namespace<'input> {
+  pub prefix: borrow::Cow<'input, [u8]>,
+  pub separator: Option<u8>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Namespaces<'input> {
+  pub personal: Vec<Namespace<'input>>,
+  pub other: Vec<Namespace<'input>>,
+  pub shared: Vec<Namespace<'input>>,
+}
+
+fn parse_number(n: &[u8]) -> Result<u64, &'static str> {
+  // The unwrap could be eliminiated since it's guaranteed by the BNF but it's either that or
+  // unsafe... The BNF doesn't guarantee the number fits, though.
+  str::from_utf8(n)
+    .unwrap()
+    .parse()
+    .or(Err("a 64-bit number"))
 }
 
 peg::parser! {
@@ -70,8 +159,6 @@ peg::parser! {
     // CHAR = %x01-7F
     rule CHAR() -> u8
       = [b'\x01'..=b'\x7f']
-    // CHAR8 = %x01-ff
-    rule CHAR8() = [b'\x01'..=b'\xff']
     // CTL = %x00-1F / %x7F
     rule CTL() = [b'\x00'..=b'\x1f'] / "\x7f"
     // DQUOTE = %x22
@@ -93,11 +180,11 @@ peg::parser! {
     // number = 1*DIGIT
     rule number() -> u64
       = n:$(DIGIT()+)
-      { parse_number(n) }
+      {? parse_number(n) }
     // nz-number = digit-nz *DIGIT
     rule nz_number() -> u64
       = n:$(digit_nz() DIGIT()*)
-      { parse_number(n) }
+      {? parse_number(n) }
     // uniqueid = nz-number
     rule uniqueid() -> u64 = nz_number()
     // text = 1*TEXT-CHAR
@@ -131,11 +218,20 @@ peg::parser! {
       { q }
     // literal = "{" number "}" CRLF *CHAR8
     rule literal() -> &'input [u8]
-      // Note that with a sufficiently high optimization level, * will not result in a Vec being
-      // allocated (https://github.com/kevinmehall/rust-peg/pull/292) and there's no need to skip
-      // ahead (https://github.com/kevinmehall/rust-peg/issues/284).
-      = "{" n:number() "}" CRLF() l:$(CHAR8()*<{usize::try_from(n).unwrap() /* not much we can do */}>)
+      = "{" n:literal_length() "}" CRLF() l:(literal_data(n) / expected!("as many octets as announced"))
       { l }
+    rule literal_length() -> usize
+      = n:number()
+      {? usize::try_from(n).or(Err("a literal length")) }
+    // Skip ahead instead of matching octet per octet
+    // (https://github.com/kevinmehall/rust-peg/issues/284): a length larger than the (complete)
+    // response fails right away, a server can't make the parser loop on it.
+    rule literal_data(length: usize) -> &'input [u8]
+      = #{|input, pos| match pos.checked_add(length).and_then(|end| input.get(pos..end)) {
+          // CHAR8 = %x01-ff
+          Some(data) if memchr::memchr(0, data).is_none() => peg::RuleResult::Matched(pos + length, data),
+          _ => peg::RuleResult::Failed,
+        }}
     // string = quoted / literal
     rule string() -> borrow::Cow<'input, [u8]>
       = q:quoted() { borrow::Cow::Owned(q) } / l:literal() { borrow::Cow::Borrowed(l) }
@@ -187,11 +283,15 @@ peg::parser! {
     // flag-fetch = flag / "\Recent"
     // This rule is equivalent (because flag allows any system flag).
     rule flag_fetch() -> &'input [u8] = flag()
+    // flag-list = "(" [flag *(SP flag)] ")"
+    rule flag_list() -> Vec<&'input [u8]>
+      = "(" fs:(flag() ** SP()) ")"
+      { fs }
 
     // mod-sequence-value = 1*DIGIT
     rule mod_sequence_value() -> u64
       = n:$(DIGIT()+)
-      { parse_number(n) }
+      {? parse_number(n) }
     // https://www.rfc-editor.org/rfc/rfc7162#section-7
     // permsg-modsequence = mod-sequence-value
     rule permsg_modsequence() -> u64 = mod_sequence_value()
@@ -210,6 +310,45 @@ peg::parser! {
     rule fetch_mod_resp() -> u64
       = "MODSEQ" SP() "(" m:permsg_modsequence() ")"
       { m }
+    // msg-att-static = ... / "RFC822.SIZE" SP number / ...
+    rule msg_att_static_size() -> u64
+      = "RFC822.SIZE" SP() n:number()
+      { n }
+    // section = "[" [section-spec] "]"
+    // msg-att-static = ... / "BODY" section ["<" number ">"] SP nstring / ...
+    //
+    // Only the whole message is ever requested.
+    rule msg_att_static_body() -> Option<borrow::Cow<'input, [u8]>>
+      = "BODY[]" SP() s:nstring()
+      { s }
+    // https://developers.google.com/gmail/imap/imap-extensions#access_to_the_gmail_unique_message_id_x-gm-msgid
+    // The message ID is a 64-bit unsigned integer
+    rule msg_att_gmail_message_id() -> u64
+      = "X-GM-MSGID" SP() n:number()
+      { n }
+    // Anything else that could be sent (ENVELOPE, BODYSTRUCTURE, INTERNALDATE, X-GM-LABELS, ...),
+    // loosely: a name (with its section and partial) and a value made of lists, strings, atoms and
+    // flags. Literals are properly consumed.
+    rule msg_att_unknown_value()
+      = ("(" (msg_att_unknown_value() ** SP()) ")") / (nstring() {}) / ("\\"? ("*" / atom()))
+    rule msg_att_unknown()
+      = (!"[" ATOM_CHAR())+ ("[" (!"]" TEXT_CHAR())* "]")? ("<" number() ">")? SP() msg_att_unknown_value()
+    // msg-att = "(" (msg-att-dynamic / msg-att-static) *(SP (msg-att-dynamic / msg-att-static)) ")"
+    rule msg_att() -> MessageAttributes<'input>
+      = "(" atts:((
+          u:msg_att_static_uid() { MessageAttribute::Uid(u) }
+        / fs:msg_att_dynamic_flags() { MessageAttribute::Flags(fs) }
+        / m:fetch_mod_resp() { MessageAttribute::ModSeq(m) }
+        / n:msg_att_static_size() { MessageAttribute::Size(n) }
+        / b:msg_att_static_body() { MessageAttribute::Body(b) }
+        / i:msg_att_gmail_message_id() { MessageAttribute::GmailMessageId(i) }
+        / msg_att_unknown() { MessageAttribute::Unknown }
+        ) ** SP()) ")"
+      { MessageAttributes::new(atts) }
+    // message-data = nz-number SP ("EXPUNGE" / ("FETCH" SP msg-att))
+    rule message_data_fetch() -> MessageAttributes<'input>
+      = nz_number() SP() "FETCH" SP() a:msg_att()
+      { a }
 
     // seq-number = nz-number / "*"
     rule seq_number() -> Range = n:nz_number() { Range(n, n) } / "*" { Range(0, u64::max_value()) }
@@ -259,6 +398,10 @@ peg::parser! {
     rule resp_code_uidvalidity() -> u64
       = "UIDVALIDITY" SP() n:nz_number()
       { n }
+    // resp-text-code = ... / "UIDNEXT" SP nz-number / ...
+    rule resp_code_uidnext() -> u64
+      = "UIDNEXT" SP() n:nz_number()
+      { n }
     // https://www.rfc-editor.org/rfc/rfc4551#section-3.6
     // resp-text-code =/ "HIGHESTMODSEQ" SP mod-sequence-value / ...
     rule resp_code_highestmodseq() -> u64
@@ -295,12 +438,40 @@ peg::parser! {
       = s:($("*") / tag()) SP() p:position!()
       { (p, s) }
 
-    // TODO? replace text with CHAR8? search for literals?
+    // https://www.rfc-editor.org/rfc/rfc4422#section-3.1
+    // continue-req = "+" SP (resp-text / base64) CRLF
+    //
+    // Some servers omit the space when there's nothing to send.
+    #[no_eof]
+    pub rule continue_req() -> (usize, &'input [u8])
+      = "+" SP()? t:$(TEXT_CHAR()*) CRLF() p:position!()
+      { (p, t) }
+
+    // Anything up to the end of the response, literals included (e.g.: a LIST response with a
+    // mailbox name sent as a literal).
     #[no_eof]
     pub rule skip() -> (usize, ())
-      = text() CRLF() p:position!()
+      = (literal() {} / (!CRLF() [_] {}))* CRLF() p:position!()
       { (p, ()) }
 
+    // mailbox-data = "FLAGS" SP flag-list / ... / number SP "EXISTS" / number SP "RECENT"
+    // message-data = nz-number SP ("EXPUNGE" / ("FETCH" SP msg-att))
+    // response-data = "*" SP (resp-cond-state / resp-cond-bye / mailbox-data / message-data / capability-data) CRLF
+    //
+    // The responses a server may send at any time (e.g.: another client changed the mailbox), the
+    // rest is skipped.
+    #[no_eof]
+    pub rule untagged() -> (usize, Untagged<'input>)
+      = u:(
+          n:number() SP() "EXISTS" { Untagged::Exists(n) }
+        / n:number() SP() "RECENT" { Untagged::Recent(n) }
+        / n:nz_number() SP() "EXPUNGE" { Untagged::Expunge(n) }
+        / "FLAGS" SP() fs:flag_list() { Untagged::Flags(fs) }
+        ) CRLF() p:position!()
+      { (p, u) }
+      / s:skip()
+      { (s.0, Untagged::Other) }
+
     // resp-text = ["[" resp-text-code "]" SP] text
     // resp-cond-auth = ("OK" / "PREAUTH") SP resp-text
     // resp-cond-state = ("OK" / "NO" / "BAD") SP resp-text
@@ -328,11 +499,24 @@ peg::parser! {
     // thus no LOGIN command is needed.
     //
     // We're only concerned about the capabilities in the greetings so inline that and discard the
-    // rest. PREAUTH isn't supported.
+    // rest.
     #[no_eof]
     pub rule available_capabilities() -> (usize, Vec<&'input [u8]>)
       = "OK" SP() "[" cs:capability_data() "]" SP() text() CRLF() p:position!()
       { (p, cs) }
+    //
+    // The capabilities are optional in the greetings, in which case a CAPABILITY command is needed.
+    #[no_eof]
+    pub rule greeting() -> (usize, Greeting<'input>)
+      = a:("OK" { false } / "PREAUTH" { true }) SP() cs:("[" cs:capability_data() "]" SP() { cs })?
+        t:$(text()) CRLF() p:position!()
+      { (p, Greeting { preauthenticated: a, capabilities: cs, text: t }) }
+
+    // response-data = "*" SP (... / capability-data / ...) CRLF
+    #[no_eof]
+    pub rule capability_response() -> (usize, Vec<&'input [u8]>)
+      = cs:capability_data() CRLF() p:position!()
+      { (p, cs) }
 
     // https://www.rfc-editor.org/rfc/rfc5161
     // enable-data = "ENABLED" *(SP capability)
@@ -342,6 +526,34 @@ peg::parser! {
       = "ENABLED" cs:((SP() c:capability() { c })*) CRLF() p:position!()
       { (p, cs) }
 
+    // https://www.rfc-editor.org/rfc/rfc2971#section-4
+    // id_response ::= "ID" SPACE id_params_list
+    // id_params_list ::= "(" #(string SPACE nstring) ")" / nil
+    // response-data =/ "*" SP id_response CRLF
+    #[no_eof]
+    pub rule id_response() -> (usize, Vec<(borrow::Cow<'input, [u8]>, Option<borrow::Cow<'input, [u8]>>)>)
+      = "ID" SP() ps:("(" ps:((k:string() SP() v:nstring() { (k, v) }) ** SP()) ")" { ps } / nil() { Vec::new() })
+        CRLF() p:position!()
+      { (p, ps) }
+
+    // https://www.rfc-editor.org/rfc/rfc2342#section-6
+    // Namespace_Response_Extension = SP string SP "(" string *(SP string) ")"
+    rule namespace_response_extension() = SP() string() SP() "(" (string() ++ SP()) ")"
+    // Namespace = nil / "(" 1*( "(" string SP  (<"> QUOTED_CHAR <"> / nil)
+    //             *(Namespace_Response_Extension) ")" ) ")"
+    rule namespace() -> Vec<Namespace<'input>>
+      = nil() { Vec::new() }
+      / "(" ns:("(" p:string() SP() s:(DQUOTE() c:QUOTED_CHAR() DQUOTE() { Some(c) } / nil() { None })
+          namespace_response_extension()* ")" { Namespace { prefix: p, separator: s } })+ ")"
+      { ns }
+    // Namespace_Response = "*" SP "NAMESPACE" SP Namespace SP Namespace SP Namespace
+    // The personal namespaces come first, followed by the other users' and the shared ones.
+    #[no_eof]
+    pub rule namespace_response() -> (usize, Namespaces<'input>)
+      = "NAMESPACE" SP() personal:namespace() SP() other:namespace() SP() shared:namespace() CRLF()
+        p:position!()
+      { (p, Namespaces { personal, other, shared }) }
+
     // mailbox-data = ... / "LIST" SP mailbox-list / ...
     // response-data = "*" SP (... / mailbox-data / ...) CRLF
     #[no_eof]
@@ -349,6 +561,12 @@ peg::parser! {
       = "LIST" SP() l:mailbox_list() CRLF() p:position!()
       { (p, l) }
 
+    // mailbox-data =/ "LSUB" SP mailbox-list
+    #[no_eof]
+    pub rule lsub_mailbox() -> (usize, (Vec<&'input [u8]>, Option<u8>, Mailbox<'input>))
+      = "LSUB" SP() l:mailbox_list() CRLF() p:position!()
+      { (p, l) }
+
     // resp-text = ["[" resp-text-code "]" SP] text
     // resp-cond-state = ("OK" / "NO" / "BAD") SP resp-text
     // response-tagged = tag SP resp-cond-state CRLF
@@ -382,40 +600,59 @@ peg::parser! {
       = s:(("OK" SP() "[" s:(
               p:resp_code_permanentflags() { Select::Flags(p) }
             / u:resp_code_uidvalidity() { Select::UIDValidity(u) }
+            / u:resp_code_uidnext() { Select::UIDNext(u) }
             / h:resp_code_highestmodseq() { Select::HighestModSeq(h) }
             ) "]" SP() text() { s }) /
            ("VANISHED" SP() "(EARLIER)" SP() us:known_uids() { Select::Vanished(us) }) /
-           (nz_number() SP() "FETCH" SP() "(" sf:(
-              // All possible permutations... I hope I won't have to extend it.
-              (u:msg_att_static_uid() SP() fs:msg_att_dynamic_flags() SP() m:fetch_mod_resp() { (u, fs, m) })
-            / (u:msg_att_static_uid() SP() m:fetch_mod_resp() SP() fs:msg_att_dynamic_flags() { (u, fs, m) })
-            / (fs:msg_att_dynamic_flags() SP() u:msg_att_static_uid() SP() m:fetch_mod_resp() { (u, fs, m) })
-            / (fs:msg_att_dynamic_flags() SP() m:fetch_mod_resp() SP() u:msg_att_static_uid() { (u, fs, m) })
-            / (m:fetch_mod_resp() SP() u:msg_att_static_uid() SP() fs:msg_att_dynamic_flags() { (u, fs, m) })
-            / (m:fetch_mod_resp() SP() fs:msg_att_dynamic_flags() SP() u:msg_att_static_uid() { (u, fs, m) })
-           ) ")" { Select::Fetch(SelectFetch { uid: sf.0, flags: sf.1, modseq: sf.2 }) })) CRLF() p:position!()
+           (a:message_data_fetch() {?
+              match a {
+                MessageAttributes { uid: Some(uid), flags: Some(flags), modseq: Some(modseq), .. } =>
+                  Ok(Select::Fetch(SelectFetch { uid, flags, modseq })),
+                _ => Err("UID, FLAGS and MODSEQ"),
+              }
+            })) CRLF() p:position!()
       { (p, s) }
 
-    // section = "[" [section-spec] "]"
-    // msg-att-static = ... / "RFC822.SIZE" SP number / "BODY" section ["<" number ">"] SP nstring / "UID" SP uniqueid /...
-    // msg-att = "(" (msg-att-dynamic / msg-att-static) *(SP (msg-att-dynamic / msg-att-static)) ")"
     // message-data = nz-number SP ("EXPUNGE" / ("FETCH" SP msg-att))
     // response-data = "*" SP (... / message-data / ...) CRLF
     //
-    // We're only concerned about single body FETCHes so inline that and discard the rest.
+    // We're only concerned about single attribute FETCHes (along the UID).
     #[no_eof]
     pub rule fetch_size_data() -> (usize, (u64, u64))
-      = nz_number() SP() "FETCH" SP() "(" f:(
-          (u:msg_att_static_uid() SP() "RFC822.SIZE" SP() n:number() { (u, n) })
-        / ("RFC822.SIZE" SP() n:number() SP() u:msg_att_static_uid() { (u, n) })
-        ) ")" CRLF() p:position!()
+      = f:(a:message_data_fetch() {?
+          match a {
+            MessageAttributes { uid: Some(uid), size: Some(size), .. } => Ok((uid, size)),
+            _ => Err("UID and RFC822.SIZE"),
+          }
+        }) CRLF() p:position!()
+      { (p, f) }
+    #[no_eof]
+    pub rule fetch_gmail_message_id_data() -> (usize, (u64, u64))
+      = f:(a:message_data_fetch() {?
+          match a {
+            MessageAttributes { uid: Some(uid), gmail_message_id: Some(id), .. } => Ok((uid, id)),
+            _ => Err("UID and X-GM-MSGID"),
+          }
+        }) CRLF() p:position!()
+      { (p, f) }
+    #[no_eof]
+    pub rule fetch_flags_data() -> (usize, (u64, (Vec<&'input [u8]>, u64)))
+      = f:(a:message_data_fetch() {?
+          match a {
+            MessageAttributes { uid: Some(uid), flags: Some(flags), modseq: Some(modseq), .. } =>
+              Ok((uid, (flags, modseq))),
+            _ => Err("UID, FLAGS and MODSEQ"),
+          }
+        }) CRLF() p:position!()
       { (p, f) }
     #[no_eof]
     pub rule fetch_body_data() -> (usize, (u64, Option<borrow::Cow<'input, [u8]>>))
-      = nz_number() SP() "FETCH" SP() "(" f:(
-          (u:msg_att_static_uid() SP() "BODY[]" SP() s:nstring() { (u, s) })
-        / ("BODY[]" SP() s:nstring() SP() u:msg_att_static_uid() { (u, s) })
-        ) ")" CRLF() p:position!()
+      = f:(a:message_data_fetch() {?
+          match a {
+            MessageAttributes { uid: Some(uid), body: Some(body), .. } => Ok((uid, body)),
+            _ => Err("UID and BODY[]"),
+          }
+        }) CRLF() p:position!()
       { (p, f) }
 
     // resp-text = ["[" resp-text-code "]" SP] text
@@ -429,6 +666,21 @@ peg::parser! {
       = "OK" SP() "[" a:resp_code_apnd() "]" SP() text() CRLF() p:position!()
       { (p, a) }
 
+    // resp-text-code = ... / "TRYCREATE" / ...
+    // resp-text = ["[" resp-text-code "]" SP] text
+    // resp-cond-state = ("OK" / "NO" / "BAD") SP resp-text
+    // response-tagged = tag SP resp-cond-state CRLF
+    //
+    // https://www.rfc-editor.org/rfc/rfc3501#section-6.3.11
+    // If the destination mailbox does not exist, a server MUST return an error, and MUST NOT
+    // automatically create the mailbox. Unless it is certain that the destination mailbox can not
+    // be created, the server MUST send the response code "[TRYCREATE]" as the prefix of the text of
+    // the tagged NO response.
+    #[no_eof]
+    pub rule trycreate() -> (usize, ())
+      = "NO" SP() "[TRYCREATE]" SP() text() CRLF() p:position!()
+      { (p, ()) }
+
     // I'm not sure which RFC describes this.
     #[no_eof]
     pub rule append_data() -> (usize, u64)
@@ -452,12 +704,13 @@ peg::parser! {
     // response MUST include the MODSEQ message data item.
     #[no_eof]
     pub rule store_data() -> (usize, Store)
-      = nz_number() SP() "FETCH" SP() "(" sf:(
-        // All possible permutations... I hope I won't have to extend it.
-          (u:msg_att_static_uid() SP() m:fetch_mod_resp() { (u, m) })
-        / (m:fetch_mod_resp() SP() u:msg_att_static_uid() { (u, m) })
-      ) ")" CRLF() p:position!()
-      { (p, Store { uid: sf.0, modseq: sf.1 }) }
+      = s:(a:message_data_fetch() {?
+          match a {
+            MessageAttributes { uid: Some(uid), modseq: Some(modseq), .. } => Ok(Store { uid, modseq }),
+            _ => Err("UID and MODSEQ"),
+          }
+        }) CRLF() p:position!()
+      { (p, s) }
 
     // https://www.rfc-editor.org/rfc/rfc6851#section-3.3
     #[no_eof]
@@ -478,17 +731,347 @@ peg::parser! {
     pub rule move_data() -> (usize, Move)
       = "OK" SP() "[" c:resp_code_copy() "]" SP() text() CRLF() p:position!()
       { (p, c) }
+
+    // https://www.rfc-editor.org/rfc/rfc4315#section-3
+    // COPYUID, followed by the UIDVALIDITY of the destination mailbox, a UID set containing the
+    // UIDs of the message(s) in the source mailbox that were copied, and a UID set containing the
+    // UIDs assigned to the copied message(s) in the destination mailbox, in the same order.
+    //
+    // Unlike MOVE, in the tagged response.
+    #[no_eof]
+    pub rule copy() -> (usize, Option<Move>)
+      = "OK" SP() c:("[" c:resp_code_copy() "]" SP() { c })? text() CRLF() p:position!()
+      { (p, c) }
+
+    // https://www.rfc-editor.org/rfc/rfc7162#section-7
+    // expunged-resp = "VANISHED" [SP "(EARLIER)"] SP known-uids
+    // message-data = nz-number SP ("EXPUNGE" / ("FETCH" SP msg-att))
+    //
+    // https://www.rfc-editor.org/rfc/rfc7162#section-3.2.10
+    // The VANISHED response without the EARLIER tag is used for announcement of message removals
+    // within an already selected mailbox.
+    //
+    // The FETCH responses are only useful with their UID (always sent once QRESYNC is enabled).
+    #[no_eof]
+    pub rule update() -> (usize, Update)
+      = u:(("VANISHED" SP() us:known_uids() { Update::Vanished(us) }) /
+           (n:nz_number() SP() "EXPUNGE" { Update::Expunge(n) }) /
+           (a:message_data_fetch() {?
+              match a {
+                MessageAttributes { uid: Some(uid), flags: Some(flags), modseq: Some(modseq), .. } =>
+                  Ok(Update::Fetch {
+                    uid,
+                    flags: flags
+                      .iter()
+                      .map(|flag| str::from_utf8(flag).unwrap().to_string()) // Guaranteed by the BNF.
+                      .collect(),
+                    modseq,
+                  }),
+                _ => Err("UID, FLAGS and MODSEQ"),
+              }
+            })) CRLF() p:position!()
+      { (p, u) }
+
+    // https://www.rfc-editor.org/rfc/rfc4731#section-5
+    // esearch-response = "ESEARCH" [search-correlator] [SP "UID"] *(SP search-return-data)
+    // search-correlator = SP "(" "TAG" SP tag-string ")"
+    // search-return-data = ... / "ALL" SP sequence-set / ...
+    //
+    // https://www.rfc-editor.org/rfc/rfc7162#section-7
+    // search-return-data =/ "MODSEQ" SP mod-sequence-value
+    //
+    // We're only concerned about UID SEARCH RETURN (ALL), ALL is missing when nothing matched.
+    #[no_eof]
+    pub rule esearch() -> (usize, Vec<Range>)
+      = "ESEARCH" (SP() "(" "TAG" SP() string() ")")? SP() "UID"
+        us:(SP() "ALL" SP() us:sequence_set() { us })? (SP() "MODSEQ" SP() mod_sequence_value())?
+        CRLF() p:position!()
+      { (p, us.unwrap_or_default()) }
   }
 }
 
-pub fn plain(user: &str, password: &str) -> String {
-  let engine = base64::engine::GeneralPurpose::new(
+// https://www.rfc-editor.org/rfc/rfc4422 - Simple Authentication and Security Layer (SASL)
+//
+// Ordered from the weakest to the strongest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+pub enum Mechanism {
+  // https://www.rfc-editor.org/rfc/rfc4422#appendix-A
+  External,
+  // https://datatracker.ietf.org/doc/html/draft-murchison-sasl-login
+  Login,
+  // https://www.rfc-editor.org/rfc/rfc4616
+  Plain,
+  // https://www.rfc-editor.org/rfc/rfc2195
+  CramMd5,
+  // https://www.rfc-editor.org/rfc/rfc7677
+  #[value(name = "scram-sha-256")]
+  ScramSha256,
+}
+
+impl Mechanism {
+  pub fn name(&self) -> &'static str {
+    match self {
+      Self::External => "EXTERNAL",
+      Self::Login => "LOGIN",
+      Self::Plain => "PLAIN",
+      Self::CramMd5 => "CRAM-MD5",
+      Self::ScramSha256 => "SCRAM-SHA-256",
+    }
+  }
+}
+
+// The mailbox a session has selected, as reported by the SELECT.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Selected {
+  pub mailbox: Vec<u8>,
+  pub uidvalidity: u64,
+  pub highestmodseq: u64,
+  // PERMANENTFLAGS contains \* (or the quirks allow keywords anyway).
+  pub keywords: bool,
+}
+
+// Known server behaviors that are worth accounting for.
+#[derive(Clone, Copy, Debug, Default, PartialEq, clap::ValueEnum)]
+pub enum Quirks {
+  #[default]
+  Standard,
+  // PERMANENTFLAGS only lists \* while the mailbox has room for another keyword (MAX_USER_FLAGS,
+  // see index_listflags in Cyrus' imap/index.c): the keywords are still left out but the user is
+  // told why.
+  Cyrus,
+}
+
+impl Quirks {
+  // From the greeting or ID's name.
+  pub fn detect(name: &[u8]) -> Option<Self> {
+    let name = String::from_utf8_lossy(name).to_lowercase();
+    name.contains("cyrus").then_some(Self::Cyrus)
+  }
+}
+
+enum State {
+  Start,
+  // LOGIN: the user has been sent.
+  User,
+  // SCRAM: the client-first-message has been sent.
+  ClientFirst { bare: String },
+  // SCRAM: the client-final-message has been sent.
+  ClientFinal { server_signature: Vec<u8> },
+  Done,
+}
+
+// Challenges and responses are base64 encoded, as they appear on the wire.
+pub struct Sasl<'a> {
+  mechanism: Mechanism,
+  user: &'a str,
+  password: &'a str,
+  nonce: String,
+  state: State,
+}
+
+fn base64_engine() -> base64::engine::GeneralPurpose {
+  base64::engine::GeneralPurpose::new(
     &base64::alphabet::STANDARD,
     base64::engine::general_purpose::PAD,
-  );
-  // https://www.rfc-editor.org/rfc/rfc2595#section-6
-  // Non-US-ASCII characters are permitted as long as they are represented in UTF-8.
-  engine.encode(format!("\0{user}\0{password}"))
+  )
+}
+
+fn hmac<D>(key: &[u8], data: &[u8]) -> Vec<u8>
+where
+  D: hmac::digest::Digest + hmac::digest::core_api::BlockSizeUser,
+{
+  use hmac::Mac as _;
+  let mut mac = hmac::SimpleHmac::<D>::new_from_slice(key).expect("HMAC accepts keys of any size");
+  mac.update(data);
+  mac.finalize().into_bytes().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+  bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+impl<'a> Sasl<'a> {
+  pub fn new(mechanism: Mechanism, user: &'a str, password: &'a str) -> Self {
+    // https://www.rfc-editor.org/rfc/rfc5802#section-5.1
+    // r: This attribute specifies a sequence of random printable ASCII characters excluding ','
+    Self::with_nonce(
+      mechanism,
+      user,
+      password,
+      &uuid::Uuid::new_v4().simple().to_string(),
+    )
+  }
+
+  fn with_nonce(mechanism: Mechanism, user: &'a str, password: &'a str, nonce: &str) -> Self {
+    Self {
+      mechanism,
+      user,
+      password,
+      nonce: nonce.to_string(),
+      state: State::Start,
+    }
+  }
+
+  // The response to send along the command (or to the first empty challenge, without SASL-IR).
+  pub fn initial(&mut self) -> Option<zeroize::Zeroizing<String>> {
+    let response = match self.mechanism {
+      // https://www.rfc-editor.org/rfc/rfc4422#appendix-A
+      // When the authorization identity string is empty, the client is requesting to act as the
+      // identity the server has associated with the client's credentials.
+      Mechanism::External => String::new(),
+      // https://www.rfc-editor.org/rfc/rfc4616#section-2
+      // message = [authzid] UTF8NUL authcid UTF8NUL passwd
+      Mechanism::Plain => format!("\0{}\0{}", self.user, self.password),
+      Mechanism::Login | Mechanism::CramMd5 => return None,
+      Mechanism::ScramSha256 => {
+        // https://www.rfc-editor.org/rfc/rfc5802#section-5.1
+        // The characters ',' or '=' in usernames are sent as '=2C' and '=3D' respectively.
+        let user = self.user.replace('=', "=3D").replace(',', "=2C");
+        let bare = format!("n={user},r={}", self.nonce);
+        // https://www.rfc-editor.org/rfc/rfc5802#section-7
+        // gs2-header = gs2-cbind-flag "," [ authzid ] ","
+        // Channel binding isn't supported ("n").
+        let response = format!("n,,{bare}");
+        self.state = State::ClientFirst { bare };
+        response
+      }
+    };
+    if matches!(self.state, State::Start) {
+      self.state = State::Done;
+    }
+    Some(zeroize::Zeroizing::new(base64_engine().encode(response)))
+  }
+
+  pub fn step(&mut self, challenge: &[u8]) -> anyhow::Result<zeroize::Zeroizing<String>> {
+    let engine = base64_engine();
+    let response = match (self.mechanism, &self.state) {
+      // The challenges ("Username:" and "Password:") are informational.
+      (Mechanism::Login, State::Start) => {
+        self.state = State::User;
+        self.user.as_bytes().to_vec()
+      }
+      (Mechanism::Login, State::User) => {
+        self.state = State::Done;
+        self.password.as_bytes().to_vec()
+      }
+      (Mechanism::CramMd5, State::Start) => {
+        // https://www.rfc-editor.org/rfc/rfc2195#section-2
+        // The data encoded in the first ready response contains an presumptively arbitrary string
+        // of random digits, a timestamp, and the fully-qualified primary host name of the server.
+        // [...] The response [...] consists of the user name, a space, and a digest.
+        let challenge = engine.decode(challenge)?;
+        self.state = State::Done;
+        let digest = hmac::<md5::Md5>(self.password.as_bytes(), &challenge);
+        format!("{} {}", self.user, hex(&digest)).into_bytes()
+      }
+      (Mechanism::ScramSha256, State::ClientFirst { bare }) => {
+        let server_first = String::from_utf8(engine.decode(challenge)?)?;
+        let (response, server_signature) = self.client_final(bare, &server_first)?;
+        self.state = State::ClientFinal { server_signature };
+        response.into_bytes()
+      }
+      (Mechanism::ScramSha256, State::ClientFinal { server_signature }) => {
+        // https://www.rfc-editor.org/rfc/rfc5802#section-7
+        // server-final-message = (server-error / verifier) ["," extensions]
+        let server_final = String::from_utf8(engine.decode(challenge)?)?;
+        let mut attributes = server_final.split(',');
+        match attributes
+          .next()
+          .and_then(|attribute| attribute.split_once('='))
+        {
+          Some(("e", error)) => anyhow::bail!("authentication failed: {error}"),
+          Some(("v", verifier)) => anyhow::ensure!(
+            engine.decode(verifier)? == *server_signature,
+            "the server's signature doesn't match"
+          ),
+          _ => anyhow::bail!("unexpected server-final-message {server_final:?}"),
+        }
+        self.state = State::Done;
+        Vec::new()
+      }
+      _ => anyhow::bail!("unexpected challenge for {}", self.mechanism.name()),
+    };
+    Ok(zeroize::Zeroizing::new(engine.encode(response)))
+  }
+
+  // https://www.rfc-editor.org/rfc/rfc5802#section-3
+  fn client_final(&self, bare: &str, server_first: &str) -> anyhow::Result<(String, Vec<u8>)> {
+    // server-first-message = [reserved-mext ","] nonce "," salt "," iteration-count ["," extensions]
+    let (mut nonce, mut salt, mut iterations) = (None, None, None);
+    for attribute in server_first.split(',') {
+      match attribute.split_once('=') {
+        // https://www.rfc-editor.org/rfc/rfc5802#section-5.1
+        // m: This attribute is reserved for future extensibility. [...] the client MUST cause
+        // authentication failure when the attribute is parsed and understood.
+        Some(("m", _)) => anyhow::bail!("mandatory SCRAM extensions aren't supported"),
+        Some(("r", value)) => nonce = Some(value),
+        Some(("s", value)) => salt = Some(base64_engine().decode(value)?),
+        Some(("i", value)) => iterations = Some(value.parse::<u32>()?),
+        _ => (),
+      }
+    }
+    let (nonce, salt, iterations) = match (nonce, salt, iterations) {
+      (Some(nonce), Some(salt), Some(iterations)) if iterations > 0 => (nonce, salt, iterations),
+      _ => anyhow::bail!("unexpected server-first-message {server_first:?}"),
+    };
+    // https://www.rfc-editor.org/rfc/rfc5802#section-5.1
+    // The client MUST verify that the initial part of the nonce used in subsequent messages is the
+    // same as the nonce it initially specified.
+    anyhow::ensure!(
+      nonce.starts_with(&self.nonce) && nonce.len() > self.nonce.len(),
+      "the server's nonce doesn't extend the client's"
+    );
+
+    // SaltedPassword := Hi(Normalize(password), salt, i)
+    // The password isn't normalized with SASLprep, which is only a concern for non-ASCII passwords.
+    let mut u = hmac::<sha2::Sha256>(
+      self.password.as_bytes(),
+      &[&salt[..], &[0, 0, 0, 1]].concat(),
+    );
+    let mut salted_password = zeroize::Zeroizing::new(u.clone());
+    for _ in 1..iterations {
+      u = hmac::<sha2::Sha256>(self.password.as_bytes(), &u);
+      for (byte, u) in salted_password.iter_mut().zip(&u) {
+        *byte ^= u;
+      }
+    }
+    // ClientKey := HMAC(SaltedPassword, "Client Key")
+    // StoredKey := H(ClientKey)
+    // AuthMessage := client-first-message-bare + "," + server-first-message + "," +
+    //                client-final-message-without-proof
+    // ClientSignature := HMAC(StoredKey, AuthMessage)
+    // ClientProof := ClientKey XOR ClientSignature
+    // ServerKey := HMAC(SaltedPassword, "Server Key")
+    // ServerSignature := HMAC(ServerKey, AuthMessage)
+    use sha2::Digest as _;
+    let client_key = hmac::<sha2::Sha256>(&salted_password, b"Client Key");
+    let stored_key = sha2::Sha256::digest(&client_key);
+    // c=biws is the base64 encoded gs2-header (n,,).
+    let without_proof = format!("c=biws,r={nonce}");
+    let auth_message = format!("{bare},{server_first},{without_proof}");
+    let client_signature = hmac::<sha2::Sha256>(&stored_key, auth_message.as_bytes());
+    let client_proof: Vec<_> = client_key
+      .iter()
+      .zip(&client_signature)
+      .map(|(key, signature)| key ^ signature)
+      .collect();
+    let server_key = hmac::<sha2::Sha256>(&salted_password, b"Server Key");
+    let server_signature = hmac::<sha2::Sha256>(&server_key, auth_message.as_bytes());
+    Ok((
+      format!("{without_proof},p={}", base64_engine().encode(client_proof)),
+      server_signature,
+    ))
+  }
+
+  // Make sure the server proved its identity, when the mechanism allows it.
+  pub fn finish(&self) -> anyhow::Result<()> {
+    anyhow::ensure!(
+      matches!(self.state, State::Done),
+      "{} authentication didn't complete",
+      self.mechanism.name()
+    );
+    Ok(())
+  }
 }
 
 pub fn utf7_to_utf8(input: &[u8]) -> Option<String> {
@@ -562,6 +1145,71 @@ fn escape(bytes: &[u8]) -> String {
   string
 }
 
+// Part of a command given to Stream::exchange, secrets (credentials) are never logged, recorded nor
+// kept for the error messages.
+pub enum Segment<'a> {
+  Public(&'a [u8]),
+  Secret(&'a [u8]),
+}
+
+impl Segment<'_> {
+  fn bytes(&self) -> &[u8] {
+    match self {
+      Self::Public(bytes) | Self::Secret(bytes) => bytes,
+    }
+  }
+}
+
+fn redacted(segments: &[Segment]) -> String {
+  segments
+    .iter()
+    .map(|segment| match segment {
+      Segment::Public(bytes) => escape(bytes),
+      Segment::Secret(_) => "<redacted>".to_string(),
+    })
+    .collect()
+}
+
+fn loggable(buffers: &[&[u8]], log: usize) -> String {
+  format!(
+    "{}{}",
+    escape(&buffers[..log].concat()),
+    if log < buffers.len() {
+      "...omitted..."
+    } else {
+      ""
+    }
+  )
+}
+
+// Enough context to make sense of a response that couldn't be parsed (the PEG error, with what
+// was expected, is the source).
+#[derive(Debug)]
+pub struct ParseError {
+  // The response line where the parse failed (escaped).
+  pub line: String,
+  // Where in this line.
+  pub offset: usize,
+  pub rule: &'static str,
+  // The last command sent (escaped, minus the omitted parts), none for the greeting.
+  pub command: Option<String>,
+}
+
+impl fmt::Display for ParseError {
+  fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+    write!(
+      formatter,
+      "{} (while parsing {} at byte {}, in response to {})",
+      self.line,
+      self.rule,
+      self.offset,
+      self.command.as_deref().unwrap_or("the greeting")
+    )
+  }
+}
+
+impl error::Error for ParseError {}
+
 fn summarize(bytes: &[u8]) -> String {
   let stop = bytes
     .windows(2)
@@ -578,6 +1226,11 @@ fn summarize(bytes: &[u8]) -> String {
 pub trait ReadWrite {
   fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>;
   fn write_all(&mut self, buf: &[u8]) -> io::Result<()>;
+  // The secret segments of the commands (see Segment), only the recording cares about the
+  // difference (see trace::Recording).
+  fn write_secret(&mut self, buf: &[u8]) -> io::Result<()> {
+    self.write_all(buf)
+  }
 }
 
 impl<Any: io::Read + io::Write> ReadWrite for Any {
@@ -589,13 +1242,101 @@ impl<Any: io::Read + io::Write> ReadWrite for Any {
   }
 }
 
+// What was received from the server and not consumed yet.
+// The storage stays initialized past the data so reads land directly in it and consumed responses
+// are skipped rather than moved: the data is only moved to the front when room is needed.
+#[derive(Default)]
+struct Buffer {
+  storage: Vec<u8>,
+  start: usize,
+  end: usize,
+}
+
+impl Buffer {
+  fn data(&self) -> &[u8] {
+    &self.storage[self.start..self.end]
+  }
+
+  fn len(&self) -> usize {
+    self.end - self.start
+  }
+
+  fn move_to_front(&mut self) {
+    self.storage.copy_within(self.start..self.end, 0);
+    self.end -= self.start;
+    self.start = 0;
+  }
+
+  // At least length bytes to read into, to be committed with filled.
+  fn spare(&mut self, length: usize) -> &mut [u8] {
+    if self.storage.len() - self.end < length {
+      self.move_to_front();
+      if self.storage.len() - self.end < length {
+        // Doubling keeps the zeroing of the new storage linear in the size of the responses.
+        let size = cmp::max(self.end + length, 2 * self.storage.len());
+        self.storage.resize(size, 0);
+      }
+    }
+    &mut self.storage[self.end..self.end + length]
+  }
+
+  fn filled(&mut self, length: usize) {
+    self.end += length;
+  }
+
+  fn consume(&mut self, length: usize) {
+    self.start += length;
+    if self.start == self.end {
+      (self.start, self.end) = (0, 0);
+    }
+  }
+
+  // Only for short lines in the middle of the data.
+  fn remove(&mut self, range: ops::Range<usize>) {
+    let length = range.len();
+    self
+      .storage
+      .copy_within(self.start + range.end..self.end, self.start + range.start);
+    self.end -= length;
+  }
+
+  fn shrink(&mut self, capacity: usize) {
+    if self.storage.len() > capacity {
+      self.move_to_front();
+      self.storage.truncate(cmp::max(self.end, capacity));
+      self.storage.shrink_to_fit();
+    }
+  }
+}
+
 pub struct Stream<RW> {
   rw: RW,
-  buffer: Vec<u8>,
+  buffer: Buffer,
   end: cell::Cell<usize>,
-  needle: Option<String>,
+  // And the mailbox selected when the NOOP was sent.
+  needle: Option<(String, Option<Vec<u8>>)>,
+  // How large a non-synchronizing literal can be (unbounded with LITERAL+).
+  non_synchronizing_limit: Option<usize>,
+  append_limit: Option<u64>,
+  catenate: bool,
+  qresync: bool,
+  // How large the buffer can grow (the parser needs whole responses, literals included).
+  buffer_limit: Option<usize>,
+  quirks: Quirks,
+  selected: Option<Selected>,
+  command: Option<String>,
+  // Per mailbox, see updates.
+  updates: Vec<(Vec<u8>, Update)>,
+  // When something was last sent, see keepalive.
+  last_input: time::Instant,
+  // The next read is cut short, see fault::command.
+  short_read: bool,
 }
 
+// The capacity kept between commands (room for a few reads, see chunk), larger responses release
+// their memory once consumed.
+const RETAINED_CAPACITY: usize = 4 * 1024 * 1024;
+
 impl<RW> Stream<RW>
 where
   RW: ReadWrite,
@@ -603,26 +1344,107 @@ where
   pub fn new(rw: RW) -> Self {
     Self {
       rw,
-      buffer: Vec::new(),
+      buffer: Buffer::default(),
       end: cell::Cell::new(0),
       needle: None,
+      non_synchronizing_limit: None,
+      append_limit: None,
+      catenate: false,
+      qresync: false,
+      buffer_limit: None,
+      quirks: Quirks::default(),
+      selected: None,
+      command: None,
+      updates: Vec::new(),
+      last_input: time::Instant::now(),
+      short_read: false,
     }
   }
 
+  // Commands are always written with non-synchronizing literals ({n+}), they're rewritten on the
+  // fly when the server doesn't support them.
+  pub fn set_capabilities(&mut self, capabilities: &[Vec<u8>]) {
+    let has = |capability: &[u8]| capabilities.iter().any(|c| c == capability);
+    self.non_synchronizing_limit = if has(b"LITERAL+") {
+      None
+    } else if has(b"LITERAL-") {
+      // https://www.rfc-editor.org/rfc/rfc7888#section-4
+      // LITERAL- only allows non-synchronizing literals up to 4096 octets.
+      Some(4096)
+    } else {
+      Some(0)
+    };
+    // https://www.rfc-editor.org/rfc/rfc7889
+    // APPENDLIMIT=<number> applies to all the mailboxes (a bare APPENDLIMIT means each mailbox has
+    // its own, only available via STATUS or LIST, which isn't supported).
+    self.append_limit = capabilities
+      .iter()
+      .find_map(|capability| capability.strip_prefix(b"APPENDLIMIT="))
+      .and_then(|limit| str::from_utf8(limit).ok()?.parse().ok());
+    self.catenate = has(b"CATENATE");
+    self.qresync = has(b"QRESYNC");
+  }
+
+  pub fn append_limit(&self) -> Option<u64> {
+    self.append_limit
+  }
+
+  pub fn catenate(&self) -> bool {
+    self.catenate
+  }
+
+  pub fn qresync(&self) -> bool {
+    self.qresync
+  }
+
+  pub fn set_buffer_limit(&mut self, limit: Option<usize>) {
+    self.buffer_limit = limit;
+  }
+
+  pub fn set_quirks(&mut self, quirks: Quirks) {
+    self.quirks = quirks;
+  }
+
+  pub fn quirks(&self) -> Quirks {
+    self.quirks
+  }
+
+  pub fn set_selected(&mut self, selected: Option<Selected>) {
+    // Tags the log lines (see main.rs), a connection is only used from one thread at a time.
+    match &selected {
+      Some(selected) => log_mdc::insert(
+        "mailbox",
+        utf7_to_utf8(&selected.mailbox).unwrap_or_else(|| escape(&selected.mailbox)),
+      ),
+      None => log_mdc::remove("mailbox"),
+    };
+    // What was reported before is superseded by the response to SELECT (or irrelevant without a
+    // mailbox selected), see updates.
+    self.updates.clear();
+    self.selected = selected;
+  }
+
+  pub fn selected(&self) -> Option<&Selected> {
+    self.selected.as_ref()
+  }
+
   fn inner_input(&mut self, buffers: &[&[u8]], log: usize) -> anyhow::Result<()> {
     if log::log_enabled!(log::Level::Debug) && log > 0 {
-      log::debug!(
-        "> {}{}",
-        escape(&buffers[..log].concat()),
-        if log < buffers.len() {
-          "...omitted..."
-        } else {
-          ""
-        }
-      );
+      log::debug!("> {}", loggable(buffers, log));
     } else {
       log::debug!("> ...omitted...");
     }
+    self.write(buffers)
+  }
+
+  fn write(&mut self, buffers: &[&[u8]]) -> anyhow::Result<()> {
+    self.last_input = time::Instant::now();
+    if let Some(command) = buffers.first() {
+      self.short_read |= crate::fault::command(command)?;
+    }
+    if let Some(limit) = self.non_synchronizing_limit {
+      return self.synchronizing_input(&buffers.concat(), limit);
+    }
     for buffer in buffers.iter() {
       // https://www.rfc-editor.org/rfc/rfc7162#section-4
       // [...] a client should limit the length of the command lines it generates to approximately
@@ -632,16 +1454,130 @@ where
     Ok(())
   }
 
-  pub fn read(&mut self, buffer: &mut [u8]) -> anyhow::Result<usize> {
-    match self.rw.read(buffer)? {
+  // https://www.rfc-editor.org/rfc/rfc3501#section-7.5
+  // The command continuation request response is indicated by a "+" token instead of a tag. This
+  // form of response indicates that the server is ready to accept the continuation of a command
+  // from the client.
+  //
+  // https://www.rfc-editor.org/rfc/rfc7888#section-8
+  // literal = "{" number64 ["+"] "}" CRLF *CHAR8
+  fn synchronizing_input(&mut self, command: &[u8], limit: usize) -> anyhow::Result<()> {
+    let (mut written, mut position, mut start) = (0, 0, 0);
+    while let Some(offset) = memchr::memmem::find(&command[position..], b"+}\r\n") {
+      let marker = position + offset;
+      let digits = command[..marker]
+        .iter()
+        .rev()
+        .take_while(|byte| byte.is_ascii_digit())
+        .count();
+      if digits == 0 || marker == digits || command[marker - digits - 1] != b'{' {
+        position = marker + 1;
+        continue;
+      }
+      // The command line might have ended (pipelined commands), find the tag of the current one.
+      if let Some(end) = memchr::memmem::rfind(&command[position..marker], b"\r\n") {
+        start = position + end + 2;
+      }
+      let tag = command[start..]
+        .split(|byte| *byte == b' ')
+        .next()
+        .unwrap() // Split always yields at least one item.
+        .to_vec();
+      let length: usize = str::from_utf8(&command[marker - digits..marker])
+        .unwrap() // Guaranteed by is_ascii_digit.
+        .parse()?;
+      if length > limit {
+        self.rw.write_all(&command[written..marker])?;
+        self.rw.write_all(b"}\r\n")?;
+        self.continuation(&tag)?;
+        written = marker + 4;
+      }
+      position = cmp::min(marker + 4 + length, command.len());
+    }
+    self.rw.write_all(&command[written..])?;
+    Ok(())
+  }
+
+  // Wait for the command continuation request and consume it, untagged responses and the
+  // completion of previously pipelined commands are left for the parser.
+  fn continuation(&mut self, tag: &[u8]) -> anyhow::Result<()> {
+    let tag = &[tag, b" "].concat();
+    // Complete lines are only looked at once.
+    let mut start = self.end.get();
+    loop {
+      for line in self.buffer.data()[start..].split_inclusive(|byte| *byte == b'\n') {
+        if !line.ends_with(b"\r\n") {
+          break;
+        }
+        if line.starts_with(b"+") {
+          log::debug!("< {}", escape(line));
+          self.buffer.remove(start..start + line.len());
+          return Ok(());
+        }
+        // https://www.rfc-editor.org/rfc/rfc3501#section-7.5
+        // If instead, the server detected an error in the command, it sends a BAD completion
+        // response with a tag matching the command [...] to reject the command and prevent the
+        // client from sending any more of the command.
+        anyhow::ensure!(
+          !line.starts_with(tag),
+          "the server refused the literal: {}",
+          escape(line)
+        );
+        start += line.len();
+      }
+      self.read(32 * 1024)?;
+    }
+  }
+
+  // Read up to length bytes, directly into the buffer.
+  pub fn read(&mut self, length: usize) -> anyhow::Result<usize> {
+    match self.rw.read(self.buffer.spare(length))? {
       0 => anyhow::bail!("end of stream"),
+      length if self.short_read => {
+        // Half of it made it before the connection ended.
+        self.buffer.filled(length / 2);
+        Err(io::Error::new(
+          io::ErrorKind::UnexpectedEof,
+          "injected fault: short read",
+        ))?
+      }
       length => {
-        self.buffer.extend_from_slice(&buffer[..length]);
+        self.buffer.filled(length);
+        if let Some(limit) = self.buffer_limit {
+          anyhow::ensure!(
+            self.buffer.len() <= limit,
+            "the response to {} exceeds the buffer limit of {limit} bytes",
+            self.command.as_deref().unwrap_or("the greeting")
+          );
+        }
         Ok(length)
       }
     }
   }
 
+  // Get rid of the previous chunk.
+  // Until the tagged response, the changes to the mailbox (if any) are kept for updates.
+  fn until(&mut self, tag: &[u8], mailbox: Option<Vec<u8>>) -> anyhow::Result<()> {
+    loop {
+      match self.expect(parser::start)? {
+        b"*" => match (self.parse(parser::update)?, &mailbox) {
+          (Some(update), Some(mailbox)) => self.updates.push((mailbox.clone(), update)),
+          (Some(_), None) => (),
+          (None, _) => self.expect(parser::skip)?,
+        },
+        tag_ if tag_ == tag => break self.expect(parser::ok),
+        tag => anyhow::bail!("unexpected tag {tag:?}"),
+      }
+    }
+  }
+
+  fn drain(&mut self) -> anyhow::Result<()> {
+    if let Some((needle, mailbox)) = self.needle.take() {
+      self.until(needle.as_bytes(), mailbox)?;
+    }
+    Ok(())
+  }
+
   fn chunk(&mut self) -> anyhow::Result<()> {
     // PEG doesn't return any information whatsoever that could tell us we're making progress but
     // still failing the parse (for example, when transferring large messages):
@@ -650,59 +1586,137 @@ where
     // responses without an exhaustive parser. Because I don't want to be in this business I'm
     // opting for something I'm gonna regret: introducing my own chunking protocol on top :)
 
-    // Get rid of the previous chunk.
-    if let Some(needle) = self.needle.take() {
-      loop {
-        match self.expect(parser::start)? {
-          b"*" => self.expect(parser::skip)?,
-          tag if tag == needle.as_bytes() => break self.expect(parser::ok)?,
-          tag => anyhow::bail!("unexpected tag {tag:?}"),
-        }
-      }
-    }
+    self.drain()?;
 
     // Start a new chunk.
     let needle = uuid::Uuid::new_v4().as_hyphenated().to_string();
     let command: &[&[u8]] = &[needle.as_bytes(), &b" NOOP\r\n"[..]];
     self.inner_input(command, command.len())?;
 
-    let mut buffer = [0; 1024 * 1024];
     // Yeah, I'm completely breaking the abstraction... Let's hope it's sufficiently unique.
     let needle_ = &[b"\r\n", needle.as_bytes(), b" OK "].concat();
-    let (mut start, mut next_start) = (0, 0);
+    let finder = memchr::memmem::FinderRev::new(needle_);
+    let mut start = 0;
     let position = loop {
-      // Starting from the end of the buffer (and limiting to the last data retrieved) with memchr
-      // makes a huge difference over the naive .windows().position(). While this sounds wasteful,
-      // CPU time appears to be dominated by Xapian.
-      if let Some(position) = memchr::memmem::rfind_iter(&self.buffer[start..], needle_).next() {
-        break position;
+      // Starting from the end of the buffer with memchr makes a huge difference over the naive
+      // .windows().position(). Each search only covers the data retrieved since the previous one
+      // (and enough of the previous data for a needle split across reads).
+      let data = self.buffer.data();
+      if let Some(position) = finder.rfind(&data[start..]) {
+        break start + position;
       }
-      start = cmp::max(next_start, needle_.len()) - needle_.len();
-      next_start += self.read(&mut buffer)?;
+      start = data.len().saturating_sub(needle_.len() - 1);
+      self.read(1024 * 1024)?;
     };
     // The needle was found but we might not have enough to read until the end of the response.
-    while parser::ok(&self.buffer[start + position + 2 + needle.len() + 1..]).is_err() {
-      self.read(&mut buffer)?;
+    while parser::ok(&self.buffer.data()[position + 2 + needle.len() + 1..]).is_err() {
+      self.read(1024 * 1024)?;
     }
 
-    self.needle = Some(needle);
+    let mailbox = self
+      .selected
+      .as_ref()
+      .map(|selected| selected.mailbox.clone());
+    self.needle = Some((needle, mailbox));
     Ok(())
   }
 
-  pub fn input(&mut self, buffers: &[&[u8]], log: usize) -> anyhow::Result<()> {
-    let end = self.end.get();
-    let rest = self.buffer.len() - end;
-    self.buffer.copy_within(end.., 0);
-    self.buffer.truncate(rest);
+  // The changes to the mailbox made by other clients that were reported so far, the response to the
+  // last NOOP included (servers usually report them there, see chunk).
+  pub fn updates(&mut self, mailbox: &[u8]) -> anyhow::Result<Vec<Update>> {
+    self.drain()?;
+    let (updates, others) = mem::take(&mut self.updates)
+      .into_iter()
+      .partition(|(mailbox_, _)| mailbox_ == mailbox);
+    self.updates = others;
+    Ok(updates.into_iter().map(|(_, update)| update).collect())
+  }
+
+  // For the connections whose updates aren't looked at (e.g.: once a pull is done with them, it
+  // goes through the changes of the mailboxes on its own), so they don't pile up.
+  pub fn discard_updates(&mut self) -> anyhow::Result<()> {
+    self.drain()?;
+    self.updates.clear();
+    Ok(())
+  }
+
+  // https://www.rfc-editor.org/rfc/rfc3501#section-6.1.2
+  // Since any command can return a status update as untagged data, the NOOP command can be used as
+  // a periodic poll for new messages or message status updates during a period of inactivity (this
+  // is the preferred method to do this). The NOOP command can also be used to reset any inactivity
+  // autologout timer on the server.
+  //
+  // For the connections left waiting while the database is busy (e.g.: indexing a large pull),
+  // nothing is sent if the connection was used recently enough.
+  pub fn keepalive(&mut self, interval: time::Duration) -> anyhow::Result<()> {
+    if self.last_input.elapsed() < interval {
+      return Ok(());
+    }
+    let command: &[&[u8]] = &[b"keepalive NOOP\r\n"];
+    self.input(command, command.len())?;
+    let mailbox = self
+      .selected
+      .as_ref()
+      .map(|selected| selected.mailbox.clone());
+    self.until(b"keepalive", mailbox)
+  }
+
+  fn compact(&mut self) {
+    self.buffer.consume(self.end.get());
     self.end.set(0);
+    // Large messages would otherwise keep the peak memory usage around for the whole session.
+    self.buffer.shrink(RETAINED_CAPACITY);
+  }
+
+  pub fn input(&mut self, buffers: &[&[u8]], log: usize) -> anyhow::Result<()> {
+    self.compact();
+    self.command = Some(loggable(buffers, log));
 
     self.inner_input(buffers, log)?;
     // IMAP allows for reordering pipelined commands, wait for some input first (I can't remember if
     // untagged responses can come any time besides the initial login).
-    self.read(&mut [0; 1])?;
+    self.read(1)?;
     self.chunk()
   }
 
+  // Commands expecting command continuation requests (like AUTHENTICATE) can't be chunked: the NOOP
+  // would be taken for the client's response. Read until either a continuation request or the
+  // tagged response is complete instead (these are expected to be short lines).
+  pub fn exchange(&mut self, segments: &[Segment], tag: &[u8]) -> anyhow::Result<()> {
+    self.drain()?;
+    self.compact();
+    let command = redacted(segments);
+    log::debug!("> {command}");
+    self.command = Some(command);
+
+    // These commands don't carry literals, each segment can be written as is.
+    self.last_input = time::Instant::now();
+    if let Some(segment) = segments.first() {
+      self.short_read |= crate::fault::command(segment.bytes())?;
+    }
+    for segment in segments {
+      match segment {
+        Segment::Public(bytes) => self.rw.write_all(bytes)?,
+        Segment::Secret(bytes) => self.rw.write_secret(bytes)?,
+      }
+    }
+    let tag = &[tag, b" "].concat();
+    // Complete lines are only looked at once.
+    let mut start = 0;
+    loop {
+      for line in self.buffer.data()[start..].split_inclusive(|byte| *byte == b'\n') {
+        if !line.ends_with(b"\r\n") {
+          break;
+        }
+        if line.starts_with(b"+") || line.starts_with(tag) {
+          return Ok(());
+        }
+        start += line.len();
+      }
+      self.read(32 * 1024)?;
+    }
+  }
+
   fn inner_parse<'a, P, R>(&'a self, parser: P) -> anyhow::Result<R>
   where
     P: Fn(
@@ -710,7 +1724,7 @@ where
     ) -> Result<(usize, R), peg::error::ParseError<<[u8] as ::peg::Parse>::PositionRepr>>,
   {
     let start = self.end.get();
-    let buffer = &self.buffer[start..];
+    let buffer = &self.buffer.data()[start..];
     match parser(buffer) {
       Ok((end, result)) => {
         log::debug!("< {}", summarize(&buffer[..end]));
@@ -719,7 +1733,22 @@ where
       }
       Err(error) => {
         log::trace!("<< {:?} {}", error, summarize(buffer));
-        Err(error).context(summarize(buffer))?
+        let location = start + error.location;
+        // From where the parse started (e.g.: after the tag), unless it failed on a later line.
+        let data = self.buffer.data();
+        let line_start = memchr::memmem::rfind(&data[start..location], b"\r\n")
+          .map(|position| start + position + 2)
+          .unwrap_or(start);
+        let line_end = memchr::memmem::find(&data[location..], b"\r\n")
+          .map(|position| location + position + 2)
+          .unwrap_or(data.len());
+        let context = ParseError {
+          line: escape(&data[line_start..line_end]),
+          offset: location - line_start,
+          rule: any::type_name::<P>().rsplit("::").next().unwrap(), // Always at least one item.
+          command: self.command.clone(),
+        };
+        Err(error).context(context)?
       }
     }
   }
@@ -794,12 +1823,196 @@ mod tests {
     assert_eq!(vec![&b"IMAP4rev1"[..], &b"AUTH=PLAIN"[..]], capabilities);
   }
 
+  #[test]
+  fn greeting() {
+    let (_, greeting) =
+      parser::greeting(b"OK [CAPABILITY IMAP4rev1 AUTH=PLAIN] Dovecot ready.\r\n").unwrap();
+    assert!(!greeting.preauthenticated);
+    assert_eq!(
+      Some(vec![&b"IMAP4rev1"[..], &b"AUTH=PLAIN"[..]]),
+      greeting.capabilities
+    );
+    assert_eq!(b"Dovecot ready.", greeting.text);
+    let (_, greeting) =
+      parser::greeting(b"PREAUTH [CAPABILITY IMAP4rev1 MOVE] Logged in as user\r\n").unwrap();
+    assert!(greeting.preauthenticated);
+    assert_eq!(
+      Some(vec![&b"IMAP4rev1"[..], &b"MOVE"[..]]),
+      greeting.capabilities
+    );
+    let (_, greeting) =
+      parser::greeting(b"PREAUTH IMAP4rev1 server logged in as user\r\n").unwrap();
+    assert!(greeting.preauthenticated);
+    assert_eq!(None, greeting.capabilities);
+  }
+
+  #[test]
+  fn quirks() {
+    assert_eq!(Some(Quirks::Cyrus), Quirks::detect(b"Cyrus IMAP"));
+    assert_eq!(None, Quirks::detect(b"Dovecot ready."));
+  }
+
+  #[test]
+  fn capability_response() {
+    let (_, capabilities) =
+      parser::capability_response(b"CAPABILITY IMAP4rev1 QRESYNC\r\n").unwrap();
+    assert_eq!(vec![&b"IMAP4rev1"[..], &b"QRESYNC"[..]], capabilities);
+  }
+
+  #[test]
+  fn continue_req() {
+    let (_, text) = parser::continue_req(b"+ VXNlcm5hbWU6\r\n").unwrap();
+    assert_eq!(b"VXNlcm5hbWU6", text);
+    let (_, text) = parser::continue_req(b"+\r\n").unwrap();
+    assert_eq!(b"", text);
+  }
+
+  #[test]
+  fn sasl() {
+    let engine = base64_engine();
+    let decode = |response: zeroize::Zeroizing<String>| engine.decode(response.as_str()).unwrap();
+
+    let mut sasl = Sasl::new(Mechanism::Plain, "user", "password");
+    assert_eq!(b"\0user\0password", &decode(sasl.initial().unwrap())[..]);
+    sasl.finish().unwrap();
+
+    let mut sasl = Sasl::new(Mechanism::Login, "user", "password");
+    assert!(sasl.initial().is_none());
+    assert_eq!(b"user", &decode(sasl.step(b"VXNlcm5hbWU6").unwrap())[..]);
+    assert!(sasl.finish().is_err());
+    assert_eq!(
+      b"password",
+      &decode(sasl.step(b"UGFzc3dvcmQ6").unwrap())[..]
+    );
+    sasl.finish().unwrap();
+
+    // https://www.rfc-editor.org/rfc/rfc2195#section-2
+    let mut sasl = Sasl::new(Mechanism::CramMd5, "tim", "tanstaaftanstaaf");
+    assert!(sasl.initial().is_none());
+    let challenge = engine.encode("<1896.697170952@postoffice.reston.mci.net>");
+    assert_eq!(
+      b"tim b913a602c7eda7a495b4e6e7334d3890",
+      &decode(sasl.step(challenge.as_bytes()).unwrap())[..]
+    );
+    sasl.finish().unwrap();
+
+    // https://www.rfc-editor.org/rfc/rfc7677#section-3
+    let mut sasl = Sasl::with_nonce(
+      Mechanism::ScramSha256,
+      "user",
+      "pencil",
+      "rOprNGfwEbeRWgbNEkqO",
+    );
+    assert_eq!(
+      b"n,,n=user,r=rOprNGfwEbeRWgbNEkqO",
+      &decode(sasl.initial().unwrap())[..]
+    );
+    let server_first = engine.encode(
+      "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096",
+    );
+    assert_eq!(
+      &b"c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ="[..],
+      &decode(sasl.step(server_first.as_bytes()).unwrap())[..]
+    );
+    assert!(sasl.finish().is_err());
+    let server_final = engine.encode("v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=");
+    assert_eq!(
+      b"",
+      &decode(sasl.step(server_final.as_bytes()).unwrap())[..]
+    );
+    sasl.finish().unwrap();
+
+    // The server must prove it knows the password too.
+    let mut sasl = Sasl::with_nonce(
+      Mechanism::ScramSha256,
+      "user",
+      "pencil",
+      "rOprNGfwEbeRWgbNEkqO",
+    );
+    sasl.initial().unwrap();
+    sasl.step(server_first.as_bytes()).unwrap();
+    let server_final = engine.encode("v=AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=");
+    assert!(sasl.step(server_final.as_bytes()).is_err());
+    assert!(sasl.finish().is_err());
+  }
+
   #[test]
   fn enabled_capabilities() {
     let (_, capabilities) = parser::enabled_capabilities(b"ENABLED CONDSTORE\r\n").unwrap();
     assert_eq!(vec![b"CONDSTORE"], capabilities);
   }
 
+  #[test]
+  fn id_response() {
+    let (_, parameters) =
+      parser::id_response(b"ID (\"name\" \"Dovecot\" \"version\" {3}\r\n2.3 \"os\" NIL)\r\n")
+        .unwrap();
+    assert_eq!(
+      vec![
+        (
+          borrow::Cow::Owned(b"name".to_vec()),
+          Some(borrow::Cow::Owned(b"Dovecot".to_vec()))
+        ),
+        (
+          borrow::Cow::Owned(b"version".to_vec()),
+          Some(borrow::Cow::Borrowed(&b"2.3"[..]))
+        ),
+        (borrow::Cow::Owned(b"os".to_vec()), None),
+      ],
+      parameters
+    );
+    let (_, parameters) = parser::id_response(b"ID NIL\r\n").unwrap();
+    assert!(parameters.is_empty());
+  }
+
+  #[test]
+  fn lsub_mailbox() {
+    let (_, (flags, separator, mailbox)) =
+      parser::lsub_mailbox(b"LSUB (\\Noselect) \"/\" folder\r\n").unwrap();
+    assert_eq!(vec![b"\\Noselect"], flags);
+    assert_eq!(Some(b'/'), separator);
+    assert_eq!(Mailbox::Other(borrow::Cow::Borrowed(b"folder")), mailbox);
+  }
+
+  #[test]
+  fn namespace_response() {
+    // https://www.rfc-editor.org/rfc/rfc2342#section-5
+    let (_, namespaces) = parser::namespace_response(
+      b"NAMESPACE ((\"\" \"/\")) ((\"~\" \"/\")) ((\"#shared/\" \"/\")(\"#public/\" \"/\")(\"#ftp/\" \"/\")(\"#news.\" \".\"))\r\n",
+    )
+    .unwrap();
+    assert_eq!(
+      vec![Namespace {
+        prefix: borrow::Cow::Owned(b"".to_vec()),
+        separator: Some(b'/')
+      }],
+      namespaces.personal
+    );
+    assert_eq!(1, namespaces.other.len());
+    assert_eq!(4, namespaces.shared.len());
+    assert_eq!(
+      Namespace {
+        prefix: borrow::Cow::Owned(b"#news.".to_vec()),
+        separator: Some(b'.')
+      },
+      namespaces.shared[3]
+    );
+
+    let (_, namespaces) = parser::namespace_response(
+      b"NAMESPACE ((\"INBOX.\" \".\" \"X-PARAM\" (\"FLAG1\" \"FLAG2\"))) NIL NIL\r\n",
+    )
+    .unwrap();
+    assert_eq!(
+      vec![Namespace {
+        prefix: borrow::Cow::Owned(b"INBOX.".to_vec()),
+        separator: Some(b'.')
+      }],
+      namespaces.personal
+    );
+    assert!(namespaces.other.is_empty());
+    assert!(namespaces.shared.is_empty());
+  }
+
   #[test]
   fn list_mailbox() {
     let (_, (flags, seperator, mailbox)) =
@@ -835,6 +2048,9 @@ mod tests {
     let (_, select) = parser::select_data(b"OK [UIDVALIDITY 1676645821] UIDs valid\r\n").unwrap();
     assert_eq!(Select::UIDValidity(1676645821), select);
 
+    let (_, select) = parser::select_data(b"OK [UIDNEXT 4392] Predicted next UID\r\n").unwrap();
+    assert_eq!(Select::UIDNext(4392), select);
+
     let (_, select) = parser::select_data(b"OK [HIGHESTMODSEQ 2] Highest\r\n").unwrap();
     assert_eq!(Select::HighestModSeq(2), select);
 
@@ -858,6 +2074,28 @@ mod tests {
     }
   }
 
+  #[test]
+  fn fetch_flags_data() {
+    let (_, fetch) =
+      parser::fetch_flags_data(b"1 FETCH (UID 10 MODSEQ (100) FLAGS (\\Seen foo))\r\n").unwrap();
+    assert_eq!((10, (vec![&b"\\Seen"[..], b"foo"], 100)), fetch);
+    assert!(parser::fetch_flags_data(b"1 FETCH (UID 10 FLAGS (\\Seen))\r\n").is_err());
+  }
+
+  #[test]
+  fn numbers() {
+    // Hostile or broken servers shouldn't be able to make the parser panic.
+    assert!(
+      parser::select_data(b"OK [UIDNEXT 18446744073709551616] Predicted next UID\r\n").is_err()
+    );
+    assert!(
+      parser::fetch_body_data(b"1 FETCH (UID 10 BODY[] {18446744073709551615}\r\n)\r\n").is_err()
+    );
+    let (_, select) =
+      parser::select_data(b"OK [UIDNEXT 18446744073709551615] Predicted next UID\r\n").unwrap();
+    assert_eq!(Select::UIDNext(u64::MAX), select);
+  }
+
   #[test]
   fn fetch_body_data() {
     let (_, fetch) = parser::fetch_body_data(b"1 FETCH (UID 10 BODY[] {0}\r\n)\r\n").unwrap();
@@ -865,6 +2103,68 @@ mod tests {
 
     let (_, fetch) = parser::fetch_body_data(b"1 FETCH (BODY[] \"\" UID 10)\r\n").unwrap();
     assert_eq!((10, Some(borrow::Cow::Owned(b"".to_vec()))), fetch);
+
+    // Unsolicited or unknown attributes are skipped, whatever their order.
+    let (_, fetch) = parser::fetch_body_data(
+      b"1 FETCH (INTERNALDATE \"17-Jul-1996 02:44:25 -0700\" X-GM-LABELS (\\Inbox \"a b\") \
+        BODY[HEADER.FIELDS (SUBJECT)]<0> {4}\r\n)\r\n) UID 10 FLAGS (\\Seen) BODY[] NIL MODSEQ (1) \
+        ENVELOPE (NIL \"subject\" ((NIL NIL \"user\" \"example.com\")) NIL))\r\n",
+    )
+    .unwrap();
+    assert_eq!((10, None), fetch);
+
+    assert!(parser::fetch_body_data(b"1 FETCH (UID 10 FLAGS (\\Seen))\r\n").is_err());
+
+    let (_, fetch) = parser::fetch_body_data(b"1 FETCH (UID 10 BODY[] {3}\r\na)b)\r\n").unwrap();
+    assert_eq!((10, Some(borrow::Cow::Borrowed(&b"a)b"[..]))), fetch);
+    // CHAR8 excludes NUL.
+    assert!(parser::fetch_body_data(b"1 FETCH (UID 10 BODY[] {3}\r\na\0b)\r\n").is_err());
+    // Longer than the response.
+    let error =
+      parser::fetch_body_data(b"1 FETCH (UID 10 BODY[] {1000000000}\r\nab)\r\n").unwrap_err();
+    assert!(
+      error
+        .expected
+        .tokens()
+        .any(|token| token == "as many octets as announced")
+    );
+  }
+
+  #[test]
+  fn fetch_gmail_message_id_data() {
+    let (_, fetch) =
+      parser::fetch_gmail_message_id_data(b"1 FETCH (X-GM-MSGID 1278455344230334865 UID 10)\r\n")
+        .unwrap();
+    assert_eq!((10, 1278455344230334865), fetch);
+
+    assert!(parser::fetch_gmail_message_id_data(b"1 FETCH (UID 10)\r\n").is_err());
+  }
+
+  #[test]
+  fn untagged() {
+    let (_, untagged) = parser::untagged(b"23 EXISTS\r\n").unwrap();
+    assert_eq!(Untagged::Exists(23), untagged);
+    let (_, untagged) = parser::untagged(b"0 RECENT\r\n").unwrap();
+    assert_eq!(Untagged::Recent(0), untagged);
+    let (_, untagged) = parser::untagged(b"3 EXPUNGE\r\n").unwrap();
+    assert_eq!(Untagged::Expunge(3), untagged);
+    let (_, untagged) = parser::untagged(b"FLAGS (\\Answered \\Seen $Forwarded)\r\n").unwrap();
+    assert_eq!(
+      Untagged::Flags(vec![b"\\Answered", b"\\Seen", b"$Forwarded"]),
+      untagged
+    );
+
+    // Literals are consumed, even when they contain what looks like the end of the response.
+    let response = b"LIST () \"/\" {6}\r\nA\r\nB\r\n\r\n* 1 EXISTS\r\n";
+    let (end, untagged) = parser::untagged(response).unwrap();
+    assert_eq!(Untagged::Other, untagged);
+    assert_eq!(b"* 1 EXISTS\r\n", &response[end..]);
+  }
+
+  #[test]
+  fn trycreate() {
+    parser::trycreate(b"NO [TRYCREATE] Mailbox doesn't exist: folder\r\n").unwrap();
+    assert!(parser::trycreate(b"NO [OVERQUOTA] Quota exceeded\r\n").is_err());
   }
 
   #[test]
@@ -898,6 +2198,9 @@ mod tests {
   fn store_data() {
     let (_, store) = parser::store_data(b"1 FETCH (UID 1 MODSEQ (3))\r\n").unwrap();
     assert_eq!(Store { uid: 1, modseq: 3 }, store);
+
+    let (_, store) = parser::store_data(b"1 FETCH (FLAGS (\\Seen) MODSEQ (3) UID 1)\r\n").unwrap();
+    assert_eq!(Store { uid: 1, modseq: 3 }, store);
   }
 
   #[test]
@@ -921,4 +2224,258 @@ mod tests {
       r#move
     );
   }
+
+  #[test]
+  fn copy() {
+    let (_, copy) = parser::copy(b"OK [COPYUID 1677882317 3 5] Copy completed.\r\n").unwrap();
+    assert_eq!(
+      Some(Move {
+        uidvalidity: 1677882317,
+        from: vec![Range(3, 3)],
+        to: vec![Range(5, 5)]
+      }),
+      copy
+    );
+
+    let (_, copy) = parser::copy(b"OK Copy completed.\r\n").unwrap();
+    assert_eq!(None, copy);
+  }
+
+  #[test]
+  fn update() {
+    let (_, update) = parser::update(b"VANISHED 1:3,5\r\n").unwrap();
+    assert_eq!(Update::Vanished(vec![Range(1, 3), Range(5, 5)]), update);
+    let (_, update) = parser::update(b"2 EXPUNGE\r\n").unwrap();
+    assert_eq!(Update::Expunge(2), update);
+    let (_, update) = parser::update(b"1 FETCH (UID 4 FLAGS (\\Seen) MODSEQ (12))\r\n").unwrap();
+    assert_eq!(
+      Update::Fetch {
+        uid: 4,
+        flags: vec!["\\Seen".to_string()],
+        modseq: 12
+      },
+      update
+    );
+    // Without the UID, it can't be used.
+    assert!(parser::update(b"1 FETCH (FLAGS (\\Seen) MODSEQ (12))\r\n").is_err());
+    // Only in response to a command.
+    assert!(parser::update(b"VANISHED (EARLIER) 1:3\r\n").is_err());
+  }
+
+  #[test]
+  fn esearch() {
+    let (_, uids) =
+      parser::esearch(b"ESEARCH (TAG \"search\") UID ALL 1:3,5 MODSEQ 1234\r\n").unwrap();
+    assert_eq!(vec![Range(1, 3), Range(5, 5)], uids);
+
+    let (_, uids) = parser::esearch(b"ESEARCH (TAG \"search\") UID\r\n").unwrap();
+    assert_eq!(Vec::<Range>::new(), uids);
+
+    // Not a UID SEARCH.
+    assert!(parser::esearch(b"ESEARCH ALL 1:3\r\n").is_err());
+  }
+
+  // Replays the server's side, one byte at a time to exercise partial lines.
+  struct Replay {
+    input: io::Cursor<Vec<u8>>,
+    output: Vec<u8>,
+  }
+
+  impl io::Read for Replay {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+      let length = cmp::min(buf.len(), 1);
+      io::Read::read(&mut self.input, &mut buf[..length])
+    }
+  }
+
+  impl io::Write for Replay {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+      io::Write::write(&mut self.output, buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+      Ok(())
+    }
+  }
+
+  fn buffer(data: &[u8]) -> Buffer {
+    Buffer {
+      storage: data.to_vec(),
+      start: 0,
+      end: data.len(),
+    }
+  }
+
+  fn replay(input: &[u8]) -> Stream<Replay> {
+    let mut stream = Stream::new(Replay {
+      input: io::Cursor::new(input.to_vec()),
+      output: Vec::new(),
+    });
+    stream.set_capabilities(&[]);
+    stream
+  }
+
+  #[test]
+  fn parse_error() {
+    let mut stream = replay(b"");
+    stream.command = Some("select SELECT INBOX\\r\\n".to_string());
+    stream.buffer = buffer(b"* 1 EXISTS\r\nselect NO [NONEXISTENT] Unknown\r\n");
+    stream.expect(parser::start).unwrap();
+    stream.expect(parser::skip).unwrap();
+    stream.expect(parser::start).unwrap();
+    let error = stream.expect(parser::ok).unwrap_err();
+    let context = error.downcast_ref::<ParseError>().unwrap();
+    assert_eq!("NO [NONEXISTENT] Unknown\\r\\n", context.line);
+    assert_eq!(0, context.offset);
+    assert_eq!("ok", context.rule);
+    assert_eq!(
+      "NO [NONEXISTENT] Unknown\\r\\n (while parsing ok at byte 0, in response to select SELECT \
+       INBOX\\r\\n)",
+      error.to_string()
+    );
+    assert_eq!(
+      "error at 0: expected \"OK\"",
+      error.root_cause().to_string()
+    );
+    // Still a parse error for the optional parses.
+    assert_eq!(None, stream.parse(parser::ok).unwrap());
+  }
+
+  #[test]
+  fn synchronizing_literals() {
+    let mut stream = replay(b"+ Ready\r\n* OK untagged\r\n+ Ready\r\n");
+    let command: &[&[u8]] = &[
+      b"select0 SELECT {5+}\r\n",
+      b"INBOX",
+      b"\r\nselect1 SELECT {6+}\r\n",
+      b"{1+}\r\n",
+      b"\r\n",
+    ];
+    stream.inner_input(command, command.len()).unwrap();
+    assert_eq!(
+      &b"select0 SELECT {5}\r\nINBOX\r\nselect1 SELECT {6}\r\n{1+}\r\n\r\n"[..],
+      stream.rw.output
+    );
+    // The continuation requests are consumed, the rest is left for the parser.
+    assert_eq!(&b"* OK untagged\r\n"[..], stream.buffer.data());
+
+    let mut stream = replay(b"append BAD Too long\r\n");
+    let command: &[&[u8]] = &[b"append APPEND INBOX {100+}\r\n", &[b'a'; 100], b"\r\n"];
+    assert!(stream.inner_input(command, command.len()).is_err());
+    assert_eq!(&b"append APPEND INBOX {100}\r\n"[..], stream.rw.output);
+
+    // Only the large literals are synchronizing with LITERAL-.
+    let mut stream = replay(b"+ Ready\r\n");
+    stream.set_capabilities(&[b"LITERAL-".to_vec(), b"APPENDLIMIT=10000".to_vec()]);
+    assert_eq!(Some(10000), stream.append_limit());
+    assert!(!stream.catenate());
+    let large = [b'a'; 5000];
+    let command: &[&[u8]] = &[
+      b"append APPEND {5+}\r\n",
+      b"INBOX",
+      b" {5000+}\r\n",
+      &large,
+      b"\r\n",
+    ];
+    stream.inner_input(command, command.len()).unwrap();
+    assert_eq!(
+      [
+        &b"append APPEND {5+}\r\nINBOX {5000}\r\n"[..],
+        &large,
+        b"\r\n"
+      ]
+      .concat(),
+      stream.rw.output
+    );
+  }
+
+  #[test]
+  fn buffer_limit() {
+    let mut stream = replay(b"* 1 FETCH (BODY[] {10}\r\n0123456789)\r\n");
+    stream.set_buffer_limit(Some(16));
+    stream.command = Some("fetch UID FETCH 1 BODY[]\\r\\n".to_string());
+    let error = loop {
+      if let Err(error) = stream.read(1) {
+        break error;
+      }
+    };
+    assert_eq!(
+      "the response to fetch UID FETCH 1 BODY[]\\r\\n exceeds the buffer limit of 16 bytes",
+      error.to_string()
+    );
+  }
+
+  #[test]
+  fn buffer_storage() {
+    let mut buffer = Buffer::default();
+    buffer.spare(4).copy_from_slice(b"* OK");
+    buffer.filled(4);
+    buffer.spare(8)[..4].copy_from_slice(b"\r\n+\r");
+    buffer.filled(4);
+    assert_eq!(b"* OK\r\n+\r", buffer.data());
+    buffer.remove(6..8);
+    assert_eq!(b"* OK\r\n", buffer.data());
+    buffer.consume(2);
+    assert_eq!(b"OK\r\n", buffer.data());
+    // Enough room, the data isn't moved.
+    let capacity = buffer.storage.len();
+    buffer.spare(capacity - 6);
+    assert_eq!((2, 6), (buffer.start, buffer.end));
+    // Moved to the front first.
+    buffer.spare(capacity - 4);
+    assert_eq!(
+      (0, 4, capacity),
+      (buffer.start, buffer.end, buffer.storage.len())
+    );
+    buffer.consume(4);
+    assert_eq!((0, 0, 0), (buffer.start, buffer.end, buffer.len()));
+  }
+
+  #[test]
+  fn updates() {
+    let mut stream = replay(b"");
+    stream.needle = Some(("needle".to_string(), Some(b"INBOX".to_vec())));
+    stream.buffer = buffer(
+      b"* 3 FETCH (UID 5 FLAGS (\\Seen) MODSEQ (10))\r\n* VANISHED 7:8\r\n* 4 EXISTS\r\nneedle OK \
+        NOOP completed.\r\n",
+    );
+    assert!(stream.updates(b"Archives").unwrap().is_empty());
+    assert_eq!(
+      vec![
+        Update::Fetch {
+          uid: 5,
+          flags: vec!["\\Seen".to_string()],
+          modseq: 10
+        },
+        Update::Vanished(vec![Range(7, 8)])
+      ],
+      stream.updates(b"INBOX").unwrap()
+    );
+    assert!(stream.updates(b"INBOX").unwrap().is_empty());
+
+    // Dropped once another mailbox is selected.
+    let mut stream = replay(b"");
+    stream.needle = Some(("needle".to_string(), Some(b"INBOX".to_vec())));
+    stream.buffer =
+      buffer(b"* 3 FETCH (UID 5 FLAGS () MODSEQ (11))\r\nneedle OK NOOP completed.\r\n");
+    stream.drain().unwrap();
+    stream.set_selected(None);
+    assert!(stream.updates(b"INBOX").unwrap().is_empty());
+  }
+
+  #[test]
+  fn keepalive() {
+    let mut stream = replay(b"");
+    stream.keepalive(time::Duration::from_secs(60)).unwrap();
+    assert!(stream.rw.output.is_empty());
+  }
+
+  #[test]
+  fn compact() {
+    let mut stream = replay(b"");
+    stream.buffer = buffer(&[&[b'a'; 4 * RETAINED_CAPACITY][..], b"* OK\r\n"].concat());
+    stream.end.set(4 * RETAINED_CAPACITY);
+    stream.compact();
+    assert_eq!(&b"* OK\r\n"[..], stream.buffer.data());
+    assert!(stream.buffer.storage.len() < 2 * RETAINED_CAPACITY);
+  }
 }
@@ -0,0 +1,491 @@
+// The state of other synchronization tools, so switching to Sin doesn't require downloading
+// everything again. The maildir layout must already be Sin's (Maildir++).
+// Or the messages themselves, from an mbox file or a maildir not synchronized with any server: they
+// are copied to a mailbox's maildir and uploaded by the next push.
+
+use anyhow::Context as _;
+use std::{collections, fs, io, path, str};
+
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+pub enum Tool {
+  // https://isync.sourceforge.io/mbsync.html
+  // With the default SyncState (*), AltMap (no) and the IMAP store as the far side.
+  Mbsync,
+  // https://www.offlineimap.org/
+  // The UIDs are in the file names, the UIDVALIDITY is in the FolderValidity directory of the
+  // remote repository (--import-state).
+  Offlineimap,
+  // https://github.com/gauteh/lieer
+  // Its files are named after the Gmail message IDs, they are linked to the mailboxes' maildirs
+  // (--import-state is the directory with .gmailieer.json).
+  Lieer,
+  // https://www.rfc-editor.org/rfc/rfc4155
+  // The messages of an mbox file (--import-source), e.g.: an archive or a local mail spool.
+  Mbox,
+  // The messages of a maildir (--import-source), e.g.: converted from an mbox with mb2md.
+  Maildir,
+}
+
+// What a tool knows about a mailbox.
+#[derive(Debug, Default, PartialEq)]
+pub struct Mailbox {
+  pub uidvalidity: u64,
+  pub uids: Vec<(path::PathBuf, u64 /* server UID */)>,
+}
+
+// The messages of a maildir, with the U= field of their file names.
+fn files(maildir: &path::Path) -> anyhow::Result<Vec<(path::PathBuf, u64)>> {
+  let mut files = Vec::new();
+  for directory in ["cur", "new"] {
+    for entry in fs::read_dir(maildir.join(directory))? {
+      let path = entry?.path();
+      if let Some(uid) = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(uid)
+      {
+        files.push((path, uid));
+      }
+    }
+  }
+  files.sort(); // Stable order.
+  Ok(files)
+}
+
+// https://cr.yp.to/proto/maildir.html
+// When you move a file from new to cur, you have to change its name from uniq to uniq:info.
+//
+// Both tools append ,U=<uid> to the unique part.
+fn uid(file_name: &str) -> Option<u64> {
+  let unique = file_name
+    .split_once(':')
+    .map_or(file_name, |(unique, _)| unique);
+  unique
+    .split(',')
+    .find_map(|field| field.strip_prefix("U="))
+    .and_then(|uid| uid.parse().ok())
+}
+
+// https://cr.yp.to/proto/maildir.html
+// "2,": Each character after the comma is an independent flag.
+pub fn flags(path: &path::Path) -> collections::HashSet<&'static str> {
+  let info = path
+    .file_name()
+    .and_then(|name| name.to_str())
+    .and_then(|name| name.split_once(":2,"))
+    .map_or("", |(_, info)| info);
+  info
+    .chars()
+    .filter_map(|flag| match flag {
+      'D' => Some("\\Draft"),
+      'F' => Some("\\Flagged"),
+      'R' => Some("\\Answered"),
+      'S' => Some("\\Seen"),
+      'T' => Some("\\Deleted"),
+      _ => None,
+    })
+    .collect()
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct MbsyncState {
+  far_uidvalidity: u64,
+  near_uidvalidity: u64,
+  // Near (maildir) UID to far (server) UID.
+  uids: collections::HashMap<u64, u64>,
+}
+
+fn parse_number(number: &str) -> anyhow::Result<u64> {
+  number
+    .parse()
+    .with_context(|| format!("{number:?} isn't a number"))
+}
+
+// The header is either made of "key value" lines followed by an empty line (isync 1.4, Master and
+// Slave were renamed to Far and Near) or of a single "farvalidity:maxuid nearvalidity:maxuid"
+// line (isync 1.3). The records are "far near flags" (the flags may be prefixed by the expiration
+// status).
+fn parse_mbsync_state(state: &str) -> anyhow::Result<MbsyncState> {
+  let mut result = MbsyncState::default();
+  let mut lines = state.lines();
+  match lines.next() {
+    Some(line) if line.contains(':') => {
+      let validities: Vec<&str> = line
+        .split(' ')
+        .map(|field| {
+          field
+            .split_once(':')
+            .map_or(field, |(validity, _)| validity)
+        })
+        .collect();
+      let [far, near] = validities[..] else {
+        anyhow::bail!("invalid header {line:?}");
+      };
+      (result.far_uidvalidity, result.near_uidvalidity) = (parse_number(far)?, parse_number(near)?);
+    }
+    Some(mut line) => loop {
+      let (key, value) = line
+        .split_once(' ')
+        .with_context(|| format!("invalid header {line:?}"))?;
+      match key {
+        "FarUidValidity" | "MasterUidValidity" => result.far_uidvalidity = parse_number(value)?,
+        "NearUidValidity" | "SlaveUidValidity" => result.near_uidvalidity = parse_number(value)?,
+        _ => (),
+      }
+      match lines.next() {
+        Some("") | None => break,
+        Some(next) => line = next,
+      }
+    },
+    None => anyhow::bail!("empty state"),
+  }
+  anyhow::ensure!(
+    result.far_uidvalidity != 0 && result.near_uidvalidity != 0,
+    "missing UIDVALIDITY"
+  );
+  for line in lines.filter(|line| !line.is_empty()) {
+    let mut fields = line.split(' ');
+    let (Some(far), Some(near)) = (fields.next(), fields.next()) else {
+      anyhow::bail!("invalid record {line:?}");
+    };
+    let (far, near) = (parse_number(far)?, parse_number(near)?);
+    // 0 when the message doesn't exist (yet) on one side.
+    if far != 0 && near != 0 {
+      result.uids.insert(near, far);
+    }
+  }
+  Ok(result)
+}
+
+pub fn mbsync(maildir: &path::Path) -> anyhow::Result<Option<Mailbox>> {
+  let state = match fs::read_to_string(maildir.join(".mbsyncstate")) {
+    Ok(state) => parse_mbsync_state(&state).with_context(|| format!("{maildir:?}"))?,
+    Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+    Err(error) => Err(error)?,
+  };
+  // The UIDs in the file names are only valid along with the maildir's UIDVALIDITY, the first line
+  // of .uidvalidity (the second one is the last UID).
+  let uidvalidity = fs::read_to_string(maildir.join(".uidvalidity"))
+    .with_context(|| format!("couldn't read {:?}", maildir.join(".uidvalidity")))?;
+  let uidvalidity = parse_number(uidvalidity.lines().next().unwrap_or_default())?;
+  anyhow::ensure!(
+    uidvalidity == state.near_uidvalidity,
+    "{maildir:?} changed UIDVALIDITY since the last mbsync run"
+  );
+  Ok(Some(Mailbox {
+    uidvalidity: state.far_uidvalidity,
+    uids: files(maildir)?
+      .into_iter()
+      .filter_map(|(path, uid)| state.uids.get(&uid).map(|uid| (path, *uid)))
+      .collect(),
+  }))
+}
+
+// offlineimap names the FolderValidity files after the remote folders, with / replaced by dots.
+pub fn offlineimap(
+  maildir: &path::Path,
+  folder_validity: &path::Path,
+  mailbox: &str,
+) -> anyhow::Result<Option<Mailbox>> {
+  let uidvalidity = match fs::read_to_string(folder_validity.join(mailbox.replace('/', "."))) {
+    Ok(uidvalidity) => parse_number(uidvalidity.trim())?,
+    Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+    Err(error) => Err(error)?,
+  };
+  Ok(Some(Mailbox {
+    uidvalidity,
+    uids: files(maildir)?,
+  }))
+}
+
+// https://developers.google.com/gmail/imap/imap-extensions#access_to_the_gmail_unique_message_id_x-gm-msgid
+// The same message ID can be retrieved through the Gmail API [...] in hex
+//
+// lieer names its files after them, in a single maildir (the labels are tags). The file names are
+// what lieer itself maps back to the message IDs (everything up to the ":2," info, see
+// https://github.com/gauteh/lieer/blob/master/lieer/local.py), .gmailieer.json only holds the
+// account and the synchronization's progress so it's only checked for.
+pub fn lieer(directory: &path::Path) -> anyhow::Result<collections::HashMap<u64, path::PathBuf>> {
+  anyhow::ensure!(
+    directory.join(".gmailieer.json").exists(),
+    "{directory:?} isn't managed by lieer (no .gmailieer.json)"
+  );
+  let mut files = collections::HashMap::new();
+  for subdirectory in ["cur", "new"] {
+    for entry in fs::read_dir(directory.join("mail").join(subdirectory))? {
+      let path = entry?.path();
+      if let Some(id) = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| u64::from_str_radix(name.split(':').next().unwrap(), 16).ok())
+      {
+        files.insert(id, path);
+      }
+    }
+  }
+  Ok(files)
+}
+
+// https://www.rfc-editor.org/rfc/rfc4155
+// Each message is preceded by a "From " line (dropped) and followed by an empty line. The lines of
+// the messages starting with "From " (and, with mboxrd, with any number of ">" before it) were
+// escaped with a ">", which is removed (mboxo can't be told apart from mboxrd).
+fn split_mbox(content: &[u8]) -> anyhow::Result<Vec<Vec<u8>>> {
+  let mut messages = Vec::new();
+  let mut message: Option<Vec<u8>> = None;
+  let mut finish = |message: Option<Vec<u8>>| {
+    if let Some(mut message) = message {
+      if message.ends_with(b"\r\n\r\n") {
+        message.truncate(message.len() - 2);
+      } else if message.ends_with(b"\n\n") {
+        message.pop();
+      }
+      messages.push(message);
+    }
+  };
+  for line in content.split_inclusive(|byte| *byte == b'\n') {
+    if line.starts_with(b"From ") {
+      finish(message.replace(Vec::new()));
+      continue;
+    }
+    let Some(message) = message.as_mut() else {
+      anyhow::ensure!(
+        line.iter().all(u8::is_ascii_whitespace),
+        "the first message doesn't start with a \"From \" line"
+      );
+      continue;
+    };
+    let unescaped = match line.iter().position(|byte| *byte != b'>') {
+      Some(start) if start > 0 && line[start..].starts_with(b"From ") => &line[1..],
+      _ => line,
+    };
+    message.extend_from_slice(unescaped);
+  }
+  finish(message);
+  Ok(messages)
+}
+
+pub fn mbox(path: &path::Path) -> anyhow::Result<Vec<Vec<u8>>> {
+  let content = fs::read(path).with_context(|| format!("couldn't read {path:?}"))?;
+  split_mbox(&content).with_context(|| format!("couldn't parse {path:?}"))
+}
+
+// The flags mail clients (e.g.: mutt) keep in the header of the messages of an mbox: R in Status
+// for \Seen, A, F and T in X-Status for \Answered, \Flagged and \Draft (not D for \Deleted: the
+// message is imported to be kept). Like mb2md, these header fields are dropped from the message.
+pub fn mbox_message(message: &[u8]) -> (Vec<u8>, collections::HashSet<&'static str>) {
+  let (mut stripped, mut flags) = (
+    Vec::with_capacity(message.len()),
+    collections::HashSet::new(),
+  );
+  let mut lines = message.split_inclusive(|byte| *byte == b'\n');
+  for line in lines.by_ref() {
+    let trimmed = line.strip_suffix(b"\n").unwrap_or(line);
+    let trimmed = trimmed.strip_suffix(b"\r").unwrap_or(trimmed);
+    if trimmed.is_empty() {
+      stripped.extend_from_slice(line);
+      break; // The end of the header.
+    }
+    let field = str::from_utf8(trimmed)
+      .ok()
+      .and_then(|line| line.split_once(':'));
+    let (letters, value): (&[(char, &str)], &str) = match field {
+      Some((name, value)) if name.eq_ignore_ascii_case("Status") => (&[('R', "\\Seen")], value),
+      Some((name, value)) if name.eq_ignore_ascii_case("X-Status") => (
+        &[('A', "\\Answered"), ('F', "\\Flagged"), ('T', "\\Draft")],
+        value,
+      ),
+      _ => {
+        stripped.extend_from_slice(line);
+        continue;
+      }
+    };
+    flags.extend(
+      letters
+        .iter()
+        .filter(|(letter, _)| value.contains(*letter))
+        .map(|(_, flag)| *flag),
+    );
+  }
+  for line in lines {
+    stripped.extend_from_slice(line);
+  }
+  (stripped, flags)
+}
+
+// The messages of a maildir, whatever their names (see flags).
+pub fn maildir(maildir: &path::Path) -> anyhow::Result<Vec<path::PathBuf>> {
+  let mut files = Vec::new();
+  for directory in ["cur", "new"] {
+    let directory = maildir.join(directory);
+    for entry in fs::read_dir(&directory).with_context(|| format!("couldn't read {directory:?}"))? {
+      let path = entry?.path();
+      if path.is_file() {
+        files.push(path);
+      }
+    }
+  }
+  files.sort(); // Stable order.
+  Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn uid() {
+    assert_eq!(Some(12), super::uid("1700000000.R1.host,U=12:2,S"));
+    assert_eq!(
+      Some(3),
+      super::uid("1700000000_0.1.host,U=3,FMD5=abcdef:2,")
+    );
+    assert_eq!(None, super::uid("1700000000.R1.host:2,S"));
+    assert_eq!(None, super::uid("1700000000.R1.host:2,U=1"));
+  }
+
+  #[test]
+  fn flags() {
+    assert_eq!(
+      collections::HashSet::from(["\\Flagged", "\\Seen"]),
+      super::flags(path::Path::new("/maildir/cur/test,U=1:2,FS"))
+    );
+    assert!(super::flags(path::Path::new("/maildir/new/test,U=1")).is_empty());
+  }
+
+  #[test]
+  fn mbsync_state() -> anyhow::Result<()> {
+    let state = parse_mbsync_state(
+      "FarUidValidity 1000\nMaxPulledUid 5\nNearUidValidity 2000\nMaxPushedUid 3\n\n1 1 S\n2 0 \n4 \
+       2 ~F\n0 3 \n",
+    )?;
+    assert_eq!(
+      MbsyncState {
+        far_uidvalidity: 1000,
+        near_uidvalidity: 2000,
+        uids: collections::HashMap::from([(1, 1), (2, 4)]),
+      },
+      state
+    );
+
+    let state = parse_mbsync_state("1000:5 2000:3\n1 1 S\n")?;
+    assert_eq!(
+      MbsyncState {
+        far_uidvalidity: 1000,
+        near_uidvalidity: 2000,
+        uids: collections::HashMap::from([(1, 1)]),
+      },
+      state
+    );
+
+    assert!(parse_mbsync_state("MaxPulledUid 5\n\n").is_err());
+    Ok(())
+  }
+
+  #[test]
+  fn mbsync() -> anyhow::Result<()> {
+    let directory = tempfile::tempdir()?;
+    let maildir = directory.path();
+    assert_eq!(None, super::mbsync(maildir)?);
+
+    for subdirectory in ["cur", "new", "tmp"] {
+      fs::create_dir(maildir.join(subdirectory))?;
+    }
+    fs::write(maildir.join("cur/a,U=1:2,S"), "")?;
+    fs::write(maildir.join("new/b,U=2"), "")?;
+    fs::write(maildir.join("new/c"), "")?;
+    fs::write(
+      maildir.join(".mbsyncstate"),
+      "FarUidValidity 1000\nNearUidValidity 2000\n\n10 1 S\n11 2 \n",
+    )?;
+    fs::write(maildir.join(".uidvalidity"), "2001\n2\n")?;
+    assert!(super::mbsync(maildir).is_err());
+
+    fs::write(maildir.join(".uidvalidity"), "2000\n2\n")?;
+    assert_eq!(
+      Some(Mailbox {
+        uidvalidity: 1000,
+        uids: vec![
+          (maildir.join("cur/a,U=1:2,S"), 10),
+          (maildir.join("new/b,U=2"), 11)
+        ],
+      }),
+      super::mbsync(maildir)?
+    );
+    Ok(())
+  }
+
+  #[test]
+  fn mbox() -> anyhow::Result<()> {
+    let messages = split_mbox(
+      b"\nFrom alice@example.com Mon Jan  1 00:00:00 2024\nStatus: RO\nX-Status: AF\n\n>From \
+        here\n>>From there\n\nFrom bob@example.com Tue Jan  2 00:00:00 2024\nSubject: \
+        test\n\ntext\n",
+    )?;
+    assert_eq!(
+      vec![
+        b"Status: RO\nX-Status: AF\n\nFrom here\n>From there\n".to_vec(),
+        b"Subject: test\n\ntext\n".to_vec()
+      ],
+      messages
+    );
+    assert_eq!(
+      (
+        b"\nFrom here\n>From there\n".to_vec(),
+        collections::HashSet::from(["\\Seen", "\\Answered", "\\Flagged"])
+      ),
+      mbox_message(&messages[0])
+    );
+    assert_eq!(
+      (
+        b"Subject: test\n\nStatus: R\n".to_vec(),
+        collections::HashSet::new()
+      ),
+      mbox_message(b"Subject: test\nX-Status: D\n\nStatus: R\n")
+    );
+    assert!(split_mbox(b"Subject: test\n\ntext\n").is_err());
+    assert!(split_mbox(b"")?.is_empty());
+    Ok(())
+  }
+
+  #[test]
+  fn maildir() -> anyhow::Result<()> {
+    let directory = tempfile::tempdir()?;
+    let maildir = directory.path();
+    assert!(super::maildir(maildir).is_err());
+
+    for subdirectory in ["cur", "new", "tmp"] {
+      fs::create_dir(maildir.join(subdirectory))?;
+    }
+    fs::write(maildir.join("cur/a:2,S"), "")?;
+    fs::write(maildir.join("new/b"), "")?;
+    fs::write(maildir.join("tmp/c"), "")?;
+    assert_eq!(
+      vec![maildir.join("cur/a:2,S"), maildir.join("new/b")],
+      super::maildir(maildir)?
+    );
+    Ok(())
+  }
+
+  #[test]
+  fn lieer() -> anyhow::Result<()> {
+    let directory = tempfile::tempdir()?;
+    let directory = directory.path();
+    assert!(super::lieer(directory).is_err());
+
+    fs::write(directory.join(".gmailieer.json"), "{}")?;
+    for subdirectory in ["cur", "new", "tmp"] {
+      fs::create_dir_all(directory.join("mail").join(subdirectory))?;
+    }
+    fs::write(directory.join("mail/cur/11bdc6a3e2c9a0d1:2,S"), "")?;
+    fs::write(directory.join("mail/new/not-an-id"), "")?;
+    assert_eq!(
+      collections::HashMap::from([(
+        1278396276429922513,
+        directory.join("mail/cur/11bdc6a3e2c9a0d1:2,S")
+      )]),
+      super::lieer(directory)?
+    );
+    Ok(())
+  }
+}
@@ -4,62 +4,586 @@
 // https://www.rfc-editor.org/rfc/rfc7162 - [...] Quick Mailbox Resynchronization (QRESYNC)
 
 #![allow(clippy::upper_case_acronyms)]
+// Without the notmuch backend, much of what's shared with it goes unused.
+#![cfg_attr(
+  not(feature = "notmuch"),
+  allow(dead_code, unused_imports, unused_variables)
+)]
 
 use anyhow::Context as _;
+use clap::ValueEnum as _;
+use sha2::Digest as _;
 use std::{
-  collections, error, fmt, io,
+  collections, fmt, fs, io,
   net::{self, ToSocketAddrs as _},
-  num, path, process, result, str, thread, time,
+  num, ops, path, process, str, time,
 };
-use zeroize::Zeroize as _;
 
+mod credentials;
+mod discovery;
+mod fault;
 mod imap;
+mod import;
 pub mod maildir;
+#[cfg(feature = "notmuch")]
 mod notmuch;
+mod sidecar;
+mod sieve;
+#[cfg(feature = "notmuch")]
+mod state;
+mod status;
 mod sync;
-use sync::Open as _;
+mod systemd;
+mod trace;
+mod watch;
+// Library users can bring their own transport (see run_with).
+pub use credentials::{Credentials, Secret};
+pub use fault::{Fault, Interruption};
+pub use imap::{Mechanism, Quirks, ReadWrite};
+pub use import::Tool;
+pub use maildir::{Layout, Naming};
+#[cfg(feature = "notmuch")]
+pub use notmuch::Decrypt;
+pub use sync::Open;
+// Not a stable interface, see the internals feature.
+#[cfg(feature = "internals")]
+#[doc(hidden)]
+pub mod internals {
+  pub use crate::imap::{Stream, parser, utf7_to_utf8};
+}
+
+// The IMAP layer, for other Notmuch tooling (see the protocol feature): Stream sends the commands
+// (literals, pipelining and logging taken care of) and buffers each response to completion, parser
+// is the grammar for the responses of RFC 3501 and the extensions sin relies on (see readme.org).
+// Unlike internals, this is a stable interface: removing or changing any of it is a breaking
+// change.
+#[cfg(feature = "protocol")]
+pub mod protocol {
+  pub use crate::imap::{
+    Append, Greeting, Mailbox, Mechanism, MessageAttributes, Move, Namespace, Namespaces,
+    ParseError, Quirks, Range, ReadWrite, Sasl, Segment, Select, SelectFetch, Selected, Store,
+    Stream, Untagged, Update, parser, utf7_to_utf8,
+  };
+}
 
 #[derive(Clone, Debug, PartialEq, clap::ValueEnum)]
 pub enum Mode {
   ConnectOnly,
   Pull,
   Push,
+  // Push on local changes, until interrupted.
+  Watch,
+  // Report the discrepancies between the server and the database, without changing anything.
+  Check,
+  // Rebuild the state from the server and the local files, without purging.
+  Repair,
+  // Seed the state from another tool (--import-from), for the mailboxes never synchronized. Or
+  // copy the messages of an mbox file or a maildir to a mailbox (--mailbox), for the next push.
+  Import,
+  // Append a message (--message or stdin) to a mailbox (--mailbox) and file it locally, e.g. as a
+  // mail client's FCC.
+  Append,
+  // Print the recorded state, without connecting.
+  State,
+  // Edit the recorded state (--property and --value), without connecting.
+  StateSet,
+  // Forget a mailbox (--mailbox) or another maildir's properties (--namespace-id), without
+  // connecting.
+  StateClear,
+  // Move the state recorded under another namespace (--migrate-from) to --namespace, without
+  // connecting.
+  MigrateNamespace,
+  // Synchronize the active Sieve script with <namespace>.sieve in the Notmuch directory (or
+  // --maildir-root with the maildir backend), over ManageSieve (--sieve-port) with the same
+  // credentials.
+  Sieve,
   // A full sync mode (pull+push) would need to invoke notmuch new --no-hooks because the pull
   // relies on notmuch new's detection of new messages.
 }
 
+impl Mode {
+  // Only work on the database.
+  fn offline(&self) -> bool {
+    matches!(
+      self,
+      Mode::State | Mode::StateSet | Mode::StateClear | Mode::MigrateNamespace
+    )
+  }
+}
+
+// Where the synchronization is recorded.
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+pub enum Backend {
+  // Notmuch properties, the messages are indexed along (requires the notmuch feature).
+  Notmuch,
+  // A file next to each mailbox's maildir, the flags are the ones of the file names (see
+  // sync::plain): only pull and push.
+  Maildir,
+}
+
+// What a push does when a message's flags changed on the server since the last pull.
+#[derive(Clone, Copy, Debug, Default, PartialEq, clap::ValueEnum)]
+pub enum FlagMerge {
+  // Ask for a pull.
+  #[default]
+  Bail,
+  // Apply the server's changes locally and store the local ones, with the cached tags as the base.
+  ThreeWay,
+}
+
+// https://www.rfc-editor.org/rfc/rfc2342#section-5
+// Namespaces besides the personal one.
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+pub enum NamespaceKind {
+  // Other Users' Namespace
+  Other,
+  // Shared Namespace
+  Shared,
+}
+
 fn parse_duration(argument: &str) -> Result<time::Duration, num::ParseIntError> {
   Ok(time::Duration::from_secs(argument.parse()?))
 }
 
+fn parse_fetch_batch(argument: &str) -> anyhow::Result<num::NonZeroUsize> {
+  let batch: num::NonZeroUsize = argument
+    .parse()
+    .with_context(|| format!("{argument} isn't a positive number"))?;
+  anyhow::ensure!(
+    batch.get() <= sync::MAX_FETCH_BATCH,
+    "{argument} is more than {} (the FETCH command would be too long)",
+    sync::MAX_FETCH_BATCH
+  );
+  Ok(batch)
+}
+
+// SHA-256 of the server's (DER) certificate.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Fingerprint(pub [u8; 32]);
+
+fn parse_fingerprint(argument: &str) -> anyhow::Result<Fingerprint> {
+  // As output by openssl x509 -fingerprint -sha256 (colons are optional).
+  let hex = argument.replace(':', "");
+  anyhow::ensure!(
+    hex.len() == 64 && hex.is_ascii(),
+    "{argument} isn't a SHA-256 fingerprint"
+  );
+  let mut fingerprint = [0; 32];
+  for (i, byte) in fingerprint.iter_mut().enumerate() {
+    *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16)
+      .with_context(|| format!("{argument} isn't a SHA-256 fingerprint"))?;
+  }
+  Ok(Fingerprint(fingerprint))
+}
+
+// Entry in the platform keyring (Secret Service, macOS Keychain).
+#[derive(Clone, Debug, PartialEq)]
+pub struct Keyring {
+  pub service: String,
+  pub account: String,
+}
+
+fn parse_keyring(argument: &str) -> anyhow::Result<Keyring> {
+  // The account is usually an email address, which can't contain a slash in its domain.
+  let (service, account) = argument
+    .split_once('/')
+    .filter(|(service, account)| !service.is_empty() && !account.is_empty())
+    .with_context(|| format!("{argument} isn't service/account"))?;
+  Ok(Keyring {
+    service: service.to_string(),
+    account: account.to_string(),
+  })
+}
+
+// Tag given to the messages of a mailbox, from a template like folder::{mailbox}.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MailboxTag {
+  pub prefix: String,
+  pub suffix: String,
+}
+
+impl MailboxTag {
+  pub fn tag(&self, mailbox: &str) -> String {
+    format!("{}{mailbox}{}", self.prefix, self.suffix)
+  }
+
+  pub fn matches(&self, tag: &str) -> bool {
+    tag.len() > self.prefix.len() + self.suffix.len()
+      && tag.starts_with(&self.prefix)
+      && tag.ends_with(&self.suffix)
+  }
+}
+
+impl fmt::Display for MailboxTag {
+  fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+    write!(formatter, "{}", self.tag("{mailbox}"))
+  }
+}
+
+fn parse_mailbox_tag(argument: &str) -> anyhow::Result<MailboxTag> {
+  let (prefix, suffix) = argument
+    .split_once("{mailbox}")
+    .filter(|(_, suffix)| !suffix.contains("{mailbox}"))
+    .with_context(|| format!("{argument} must contain {{mailbox}} once"))?;
+  Ok(MailboxTag {
+    prefix: prefix.to_string(),
+    suffix: suffix.to_string(),
+  })
+}
+
+// What a rule does, when pushing, to the messages tagged with its tag.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Action {
+  // Move them to the mailbox.
+  Move(String),
+  // Add the keyword, as a tag too (so it's cached like the others).
+  Keyword(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rule {
+  pub tag: String,
+  pub action: Action,
+}
+
+impl fmt::Display for Rule {
+  fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+    match &self.action {
+      Action::Move(mailbox) => write!(formatter, "{}:move={mailbox}", self.tag),
+      Action::Keyword(keyword) => write!(formatter, "{}:keyword={keyword}", self.tag),
+    }
+  }
+}
+
+// tag=mailbox, the mailbox may contain anything, not the tag.
+fn parse_move_rule(argument: &str) -> anyhow::Result<Rule> {
+  let (tag, mailbox) = argument
+    .split_once('=')
+    .filter(|(tag, mailbox)| !tag.is_empty() && !mailbox.is_empty())
+    .with_context(|| format!("{argument} isn't tag=mailbox"))?;
+  Ok(Rule {
+    tag: tag.to_string(),
+    action: Action::Move(mailbox.to_string()),
+  })
+}
+
+// tag:action=argument, the tag may contain colons (e.g.: folder::INBOX).
+fn parse_rule(argument: &str) -> anyhow::Result<Rule> {
+  let (tag, action, value) = argument
+    .split_once('=')
+    .and_then(|(rule, value)| {
+      rule
+        .rsplit_once(':')
+        .map(|(tag, action)| (tag, action, value))
+    })
+    .filter(|(tag, _, value)| !tag.is_empty() && !value.is_empty())
+    .with_context(|| format!("{argument} isn't tag:action=argument"))?;
+  let action = match action {
+    "move" => Action::Move(value.to_string()),
+    "keyword" => {
+      // https://www.rfc-editor.org/rfc/rfc3501#section-9
+      // flag-keyword    = atom
+      // atom            = 1*ATOM-CHAR
+      // atom-specials   = "(" / ")" / "{" / SP / CTL / list-wildcards / quoted-specials /
+      //                   resp-specials
+      anyhow::ensure!(
+        value
+          .chars()
+          .all(|c| c.is_ascii_graphic() && !"(){%*\"\\]".contains(c)),
+        "{value} isn't a valid keyword"
+      );
+      Action::Keyword(value.to_string())
+    }
+    _ => anyhow::bail!("unknown action {action} (move or keyword)"),
+  };
+  Ok(Rule {
+    tag: tag.to_string(),
+    action,
+  })
+}
+
+fn parse_folder(argument: &str) -> anyhow::Result<path::PathBuf> {
+  let folder = path::PathBuf::from(argument);
+  anyhow::ensure!(
+    folder
+      .components()
+      .all(|component| matches!(component, path::Component::Normal(_)))
+      && folder.components().next().is_some(),
+    "{argument} isn't a folder relative to the maildir"
+  );
+  Ok(folder)
+}
+
+// How many local files a single pull may remove.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MaxPurge {
+  Count(usize),
+  // Of the files of the synchronized messages.
+  Percent(usize),
+}
+
+impl fmt::Display for MaxPurge {
+  fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Self::Count(count) => write!(formatter, "{count}"),
+      Self::Percent(percent) => write!(formatter, "{percent}%"),
+    }
+  }
+}
+
+fn parse_max_purge(argument: &str) -> anyhow::Result<MaxPurge> {
+  let number = |number: &str| {
+    number
+      .parse()
+      .with_context(|| format!("{argument} is neither <n> nor <percent>%"))
+  };
+  Ok(match argument.strip_suffix('%') {
+    Some(percent) => {
+      let percent = number(percent)?;
+      anyhow::ensure!(percent <= 100, "{argument} is more than 100%");
+      MaxPurge::Percent(percent)
+    }
+    None => MaxPurge::Count(number(argument)?),
+  })
+}
+
+// How many new messages a single pull may download.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MaxMessages {
+  Total(usize),
+  PerMailbox(usize),
+}
+
+impl fmt::Display for MaxMessages {
+  fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Self::Total(count) => write!(formatter, "{count}"),
+      Self::PerMailbox(count) => write!(formatter, "{count}/mailbox"),
+    }
+  }
+}
+
+fn parse_max_messages(argument: &str) -> anyhow::Result<MaxMessages> {
+  let number = |number: &str| {
+    number
+      .parse()
+      .with_context(|| format!("{argument} is neither <n> nor <n>/mailbox"))
+  };
+  Ok(match argument.strip_suffix("/mailbox") {
+    Some(count) => MaxMessages::PerMailbox(number(count)?),
+    None => MaxMessages::Total(number(argument)?),
+  })
+}
+
 #[derive(clap::Args)]
 #[group(skip)]
 pub struct Arguments {
-  #[arg(help = "Execution mode: pull | push", hide_possible_values(true))]
-  pub mode: Mode,
-
-  #[arg(long = "address", help = "Server address")]
-  pub address: String,
-  #[arg(long = "port", help = "Server port")]
-  pub port: u16,
-  #[arg(long = "tls", help = "Enable TLS", default_value_t = true)]
+  #[arg(
+    help = "Execution modes, run in order over the same connection: connect-only | pull | push | \
+            watch | check | repair | import | append | state | state-set | state-clear | \
+            migrate-namespace | sieve",
+    hide_possible_values(true),
+    required = true,
+    num_args = 1..
+  )]
+  pub modes: Vec<Mode>,
+
+  #[arg(
+    long = "address",
+    help = "Server address",
+    required_unless_present_any = ["tunnel", "auto"]
+  )]
+  pub address: Option<String>,
+  #[arg(
+    long = "port",
+    help = "Server port",
+    required_unless_present_any = ["tunnel", "auto"]
+  )]
+  pub port: Option<u16>,
+  #[arg(
+    long = "sieve-port",
+    help = "ManageSieve port (STARTTLS is used with --tls)",
+    default_value_t = 4190
+  )]
+  pub sieve_port: u16,
+  #[arg(
+    long = "auto",
+    help = "Discover the server from the user's email address (and remember it)",
+    default_value_t = false,
+    conflicts_with_all = ["address", "port", "tunnel"]
+  )]
+  pub auto: bool,
+  #[arg(
+    long = "tunnel",
+    help = "Command to talk to the server through (e.g.: ssh host dovecot --exec-mail imap)",
+    conflicts_with_all = ["address", "port"]
+  )]
+  pub tunnel: Option<String>,
+  #[arg(
+    long = "tls",
+    help = "Enable TLS",
+    default_value_t = true,
+    conflicts_with = "auto"
+  )]
   pub tls: bool,
-  #[arg(long = "timeout", help = "TCP timeout (in seconds)", value_parser = parse_duration)]
+  #[arg(
+    long = "tls-client-cert",
+    help = "PEM certificate (chain) to authenticate with",
+    requires = "tls_client_key"
+  )]
+  pub tls_client_cert: Option<String>,
+  #[arg(
+    long = "tls-client-key",
+    help = "PEM private key of the client certificate",
+    requires = "tls_client_cert"
+  )]
+  pub tls_client_key: Option<String>,
+  #[arg(
+    long = "tls-ca",
+    help = "PEM certificate(s) to trust, in addition to the system's"
+  )]
+  pub tls_ca: Option<String>,
+  #[arg(
+    long = "tls-pin",
+    help = "SHA-256 fingerprint of the server certificate to accept (bypasses the CA verification)",
+    value_parser = parse_fingerprint
+  )]
+  pub tls_pin: Vec<Fingerprint>,
+  #[arg(
+    long = "tls-insecure",
+    help = "Do not verify the server certificate (for testing only)",
+    default_value_t = false,
+    conflicts_with_all = ["tls_ca", "tls_pin"]
+  )]
+  pub tls_insecure: bool,
+  #[arg(
+    long = "timeout",
+    help = "TCP timeout (in seconds)",
+    value_parser = parse_duration,
+    conflicts_with = "tunnel"
+  )]
   pub timeout: Option<time::Duration>,
   #[arg(
     long = "threads",
-    help = "Number of worker threads to spawn",
+    help = "Number of worker threads (and connections) to pull with",
     default_value_t = num::NonZeroUsize::new(8).unwrap()
   )]
   pub threads: num::NonZeroUsize,
+  #[arg(
+    long = "fetch-batch",
+    help = "Number of messages to download per FETCH command (pull)",
+    default_value_t = num::NonZeroUsize::new(100).unwrap(),
+    value_parser = parse_fetch_batch
+  )]
+  pub fetch_batch: num::NonZeroUsize,
+  #[arg(
+    long = "pipeline-depth",
+    help = "Number of SELECT commands sent before waiting for their responses (pull)",
+    default_value_t = num::NonZeroUsize::new(16).unwrap()
+  )]
+  pub pipeline_depth: num::NonZeroUsize,
+  #[arg(
+    long = "keepalive",
+    help = "Send a NOOP on connections left waiting for that long (in seconds)",
+    default_value = "60",
+    value_parser = parse_duration
+  )]
+  pub keepalive: time::Duration,
 
   #[arg(long = "user", help = "IMAP user")]
   pub user: String,
-  #[arg(last = true, required = true)]
+  #[arg(
+    long = "auth-mechanism",
+    help = "SASL mechanism to use instead of the strongest advertised one"
+  )]
+  pub auth_mechanism: Option<Mechanism>,
+  #[arg(
+    long = "password-keyring",
+    help = "Read the password from the platform keyring (service/account)",
+    value_parser = parse_keyring,
+    conflicts_with_all = ["password_env", "password_prompt", "password_command"]
+  )]
+  pub password_keyring: Option<Keyring>,
+  #[arg(
+    long = "password-env",
+    help = "Read the password from an environment variable",
+    conflicts_with_all = ["password_prompt", "password_command"]
+  )]
+  pub password_env: Option<String>,
+  #[arg(
+    long = "password-prompt",
+    help = "Prompt for the password on the terminal",
+    default_value_t = false,
+    conflicts_with = "password_command"
+  )]
+  pub password_prompt: bool,
+  #[arg(
+    long = "password-retries",
+    help = "How many more times to run the password command (or read the keyring) when it fails",
+    default_value_t = 0
+  )]
+  pub password_retries: usize,
+  #[arg(
+    long = "ask-password",
+    help = "Prompt for the password on the terminal when it couldn't be read otherwise",
+    default_value_t = false,
+    conflicts_with = "password_prompt"
+  )]
+  pub ask_password: bool,
+  // Not needed when the server greets with PREAUTH.
+  #[arg(last = true)]
   pub password_command: Vec<String>,
 
+  #[arg(
+    long = "id-name",
+    help = "Client name sent to servers supporting ID",
+    default_value = "sin"
+  )]
+  pub id_name: String,
+  #[arg(
+    long = "id-version",
+    help = "Client version sent to servers supporting ID",
+    default_value = env!("CARGO_PKG_VERSION")
+  )]
+  pub id_version: String,
+  #[arg(
+    long = "server-quirks",
+    help = "Server quirks to work around instead of detecting them: standard | cyrus",
+    hide_possible_values(true)
+  )]
+  pub server_quirks: Option<Quirks>,
+  #[arg(
+    long = "buffer-limit",
+    help = "Largest server response to hold in memory (in bytes), larger messages fail to download",
+    default_value = "1073741824"
+  )]
+  pub buffer_limit: usize,
+
+  #[arg(
+    long = "backend",
+    help = "Where the synchronization is recorded: notmuch (the default when built with it) | \
+            maildir (a file next to each maildir, only pull and push, --maildir is then relative \
+            to --maildir-root)",
+    hide_possible_values(true)
+  )]
+  pub backend: Option<Backend>,
   #[arg(long = "notmuch", help = "Notmuch directory")]
   pub notmuch: Option<String>,
+  #[arg(
+    long = "maildir-root",
+    help = "Directory holding --maildir and the Sieve script, required by the maildir backend"
+  )]
+  pub maildir_root: Option<String>,
+  #[arg(
+    long = "notmuch-config",
+    help = "Notmuch configuration file, instead of the user's (see notmuch-config(1))"
+  )]
+  pub notmuch_config: Option<String>,
+  #[arg(
+    long = "notmuch-profile",
+    help = "Notmuch profile, instead of $NOTMUCH_PROFILE"
+  )]
+  pub notmuch_profile: Option<String>,
   #[arg(
     long = "maildir",
     help = "Maildir++ directory, relative to the Notmuch directory"
@@ -71,97 +595,348 @@ pub struct Arguments {
     default_value_t = false
   )]
   pub create: bool,
-  #[arg(long = "purgeable", help = "Local mailboxes that can be purged")]
+  #[cfg(feature = "notmuch")]
+  #[arg(
+    long = "decrypt",
+    help = "Decryption policy when indexing instead of index.decrypt: false | true | auto | nostash",
+    hide_possible_values(true)
+  )]
+  pub decrypt: Option<Decrypt>,
+  #[arg(
+    long = "new-tags",
+    help = "Add Notmuch's new.tags to the pulled messages (except the ones mapped to IMAP flags)",
+    default_value_t = false
+  )]
+  pub new_tags: bool,
+  #[arg(
+    long = "mailbox-tag",
+    help = "Tag the messages with their mailboxes, from a template (e.g.: folder::{mailbox})",
+    value_parser = parse_mailbox_tag
+  )]
+  pub mailbox_tag: Option<MailboxTag>,
+  #[arg(
+    long = "upgrade",
+    help = "Upgrade the Notmuch database if it was created by an older version (one too old to \
+            be opened needs notmuch new)",
+    default_value_t = false
+  )]
+  pub upgrade: bool,
+  #[arg(
+    long = "purgeable",
+    help = "Local mailboxes that can be purged (* and % are wildcards, like for IMAP's LIST)"
+  )]
   pub purgeable: Vec<String>,
+  #[arg(
+    long = "purge-all-removed",
+    help = "Purge every local mailbox that has been removed on the server",
+    default_value_t = false
+  )]
+  pub purge_all_removed: bool,
+  #[arg(
+    long = "max-purge",
+    help = "Abort a pull that would remove more local files: <n> or <percent>% (of the files of \
+            the synchronized messages)",
+    value_parser = parse_max_purge
+  )]
+  pub max_purge: Option<MaxPurge>,
+  #[arg(
+    long = "ignore-max-purge",
+    help = "Ignore --max-purge for this run",
+    default_value_t = false
+  )]
+  pub ignore_max_purge: bool,
+  #[arg(
+    long = "max-messages",
+    help = "Download at most this many new messages, the others are left for the next pulls: <n> \
+            or <n>/mailbox",
+    value_parser = parse_max_messages
+  )]
+  pub max_messages: Option<MaxMessages>,
+  #[arg(
+    long = "namespace-include",
+    help = "Also synchronize the mailboxes in these IMAP namespaces: other | shared",
+    hide_possible_values(true)
+  )]
+  pub namespace_include: Vec<NamespaceKind>,
+  #[arg(
+    long = "subscribed-only",
+    help = "Only synchronize the mailboxes subscribed to on the server",
+    default_value_t = false
+  )]
+  pub subscribed_only: bool,
+  #[arg(
+    long = "priority",
+    help = "Mailboxes to pull first, in this order (* and % are wildcards, like for IMAP's LIST), \
+            the others follow",
+    value_delimiter = ','
+  )]
+  pub priority: Vec<String>,
   #[arg(
     long = "namespace",
     help = "Notmuch property namespace",
     default_value_t = String::from("sin")
   )]
   pub namespace: String,
+  #[arg(
+    long = "debounce",
+    help = "Delay after the last local change before pushing, in watch mode (in seconds)",
+    value_parser = parse_duration,
+    default_value = "1"
+  )]
+  pub debounce: time::Duration,
+  #[arg(
+    long = "post-pull-hook",
+    help = "Run Notmuch's post-new hook after a pull that affected messages",
+    default_value_t = false
+  )]
+  pub post_pull_hook: bool,
+  #[arg(
+    long = "post-pull-command",
+    help = "Shell command to run after a pull that affected messages (with $SIN_MESSAGES and $SIN_MAILBOXES)"
+  )]
+  pub post_pull_command: Option<String>,
+  #[arg(
+    long = "lock-timeout",
+    help = "Wait for another instance using the same namespace to finish (in seconds)",
+    value_parser = parse_duration
+  )]
+  pub lock_timeout: Option<time::Duration>,
+  #[arg(
+    long = "durability",
+    help = "Also synchronize the maildir directories to disk after creating or renaming messages \
+            (slower but a crash can't lose them)",
+    default_value_t = false
+  )]
+  pub durability: bool,
+  #[arg(
+    long = "maildir-naming",
+    help = "File names of the downloaded messages: sin (resumable) | conventional \
+            (time.pid_seq.host,S=<size>, also resumable)",
+    hide_possible_values(true)
+  )]
+  pub maildir_naming: Option<Naming>,
+  #[arg(
+    long = "layout",
+    help = "Layout of the mailboxes' maildirs: maildir++ (.folder.subfolder) | fs \
+            (folder/subfolder)",
+    hide_possible_values(true)
+  )]
+  pub layout: Option<Layout>,
+  #[arg(
+    long = "create-mailboxes",
+    help = "Create the mailboxes of the local maildirs that don't exist on the server (their \
+            messages are pushed after the next pull)",
+    default_value_t = false
+  )]
+  pub create_mailboxes: bool,
+  #[arg(
+    long = "flag-merge",
+    help = "When the flags of a message changed on both sides, push: bail (asks for a pull) | \
+            three-way (keeps the changes of both sides)",
+    hide_possible_values(true)
+  )]
+  pub flag_merge: Option<FlagMerge>,
+  #[arg(
+    long = "move-rule",
+    help = "Move the messages tagged with the tag to the mailbox when pushing (e.g.: spam=Junk), \
+            the ones untagged in that mailbox go back to INBOX",
+    value_parser = parse_move_rule
+  )]
+  pub move_rules: Vec<Rule>,
+  #[arg(
+    long = "rule",
+    help = "Act on the messages tagged with the tag when pushing: tag:move=mailbox (like \
+            --move-rule) | tag:keyword=keyword (e.g.: spam:keyword=$Junk)",
+    value_parser = parse_rule
+  )]
+  pub rules: Vec<Rule>,
+  #[arg(
+    long = "local-only",
+    help = "Local maildir never pushed, relative to --maildir like Notmuch's folder: (e.g.: \
+            .archive), it's still indexed",
+    value_parser = parse_folder
+  )]
+  pub local_only: Vec<path::PathBuf>,
+  #[arg(
+    long = "expunge",
+    help = "Expunge the messages tagged deleted from the server when pushing, then remove them \
+            locally",
+    default_value_t = false
+  )]
+  pub expunge: bool,
+  #[arg(
+    long = "query",
+    help = "Notmuch query of the messages whose state to print (the mailboxes' only otherwise)"
+  )]
+  pub query: Option<String>,
+  #[arg(
+    long = "json",
+    help = "Print the state as JSON",
+    default_value_t = false
+  )]
+  pub json: bool,
+  #[arg(
+    long = "property",
+    help = "Property to set: lastmod or <mailbox>.<uidvalidity|uidnext|highestmodseq|separator>",
+    requires = "value"
+  )]
+  pub property: Option<String>,
+  #[arg(long = "value", help = "Value of the property to set")]
+  pub value: Option<String>,
+  #[arg(
+    long = "mailbox",
+    help = "Mailbox to forget (its messages are kept but will be downloaded again), to append to \
+            or to import to"
+  )]
+  pub mailbox: Option<String>,
+  #[arg(
+    long = "message",
+    help = "Message to append, read from stdin otherwise"
+  )]
+  pub message: Option<path::PathBuf>,
+  #[arg(
+    long = "namespace-id",
+    help = "Properties to drop, left behind by a maildir that isn't synchronized anymore",
+    conflicts_with = "mailbox"
+  )]
+  pub namespace_id: Option<u64>,
+  #[arg(
+    long = "migrate-from",
+    help = "Namespace to move the state from, to --namespace"
+  )]
+  pub migrate_from: Option<String>,
+  #[arg(
+    long = "import-from",
+    help = "Tool whose state to import: mbsync | offlineimap | lieer, or messages to upload: mbox \
+            | maildir",
+    hide_possible_values(true)
+  )]
+  pub import_from: Option<Tool>,
+  #[arg(
+    long = "import-state",
+    help = "State directory of the tool (offlineimap's FolderValidity of the remote repository, \
+            lieer's directory)"
+  )]
+  pub import_state: Option<String>,
+  #[arg(
+    long = "import-source",
+    help = "Mbox file or maildir whose messages to import to --mailbox"
+  )]
+  pub import_source: Option<String>,
+  #[arg(
+    long = "trace-file",
+    help = "Record the IMAP session to this file (credentials are redacted)"
+  )]
+  pub trace_file: Option<String>,
 
   #[arg(long = "interruption", help = "Internal testing facility", hide = true)]
   pub interruption: Option<Interruption>,
+  #[arg(
+    long = "fault",
+    help = "Internal testing facility (requires the faults feature)",
+    hide = true,
+    value_parser = Fault::parse
+  )]
+  pub faults: Vec<Fault>,
+  #[arg(long = "replay-file", help = "Internal testing facility", hide = true)]
+  pub replay_file: Option<String>,
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, clap::ValueEnum)]
-pub enum Interruption {
-  AppendIsNotTransactional,
-  MoveOutOfTmpPostRename,
-  StoredFlags,
-  SuccessfulMovePreCommit,
-}
-
-impl fmt::Display for Interruption {
-  fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-    write!(formatter, "{self:?}")
+impl Arguments {
+  // The mode used to be a single one (the modes field replaced it), kept for the library's users.
+  pub fn mode(&self) -> &Mode {
+    &self.modes[0] // Required by clap (and by run).
   }
 }
 
-impl error::Error for Interruption {}
-
-static INTERRUPTIONS: once_cell::sync::Lazy<
-  std::sync::Mutex<collections::HashMap<thread::ThreadId, Interruption>>,
-> = once_cell::sync::Lazy::new(|| std::sync::Mutex::new(collections::HashMap::new()));
-
-pub fn interruption(name: &Option<Interruption>) {
-  match (
-    name,
-    INTERRUPTIONS.lock().unwrap().entry(thread::current().id()),
-  ) {
-    (Some(interruption), collections::hash_map::Entry::Occupied(mut occupied)) => {
-      occupied.insert(*interruption);
-    }
-    (Some(interruption), collections::hash_map::Entry::Vacant(vacant)) => {
-      vacant.insert(*interruption);
-    }
-    (None, collections::hash_map::Entry::Occupied(occupied)) => {
-      occupied.remove();
-    }
-    (None, collections::hash_map::Entry::Vacant(_)) => (),
-  }
+// The current phase, mailbox and per-mailbox progress.
+pub fn log_status() {
+  status::log();
 }
 
-fn interrupt(interruption: Interruption) -> result::Result<(), Interruption> {
-  match INTERRUPTIONS.lock().unwrap().get(&thread::current().id()) {
-    Some(interruption_) if *interruption_ == interruption => Err(interruption),
-    _ => Ok(()),
-  }
-}
-
-fn inner_run<O>(
+#[cfg(feature = "notmuch")]
+fn open_database(
   arguments: &Arguments,
-  open: &O,
-  credentials: &sync::Credentials,
-  stream: &mut imap::Stream<O::RW>,
-) -> anyhow::Result<()>
-where
-  O: sync::Open,
-{
-  // Exchange pleasantries with the server.
-  sync::greetings(stream)?;
-  if arguments.mode == Mode::ConnectOnly {
-    return Ok(());
-  }
-  sync::authenticate(stream, credentials)?;
-  sync::enable(stream)?;
-
+  mode: notmuch::Mode,
+) -> anyhow::Result<notmuch::Database<notmuch::Detached>> {
   // Open (or create) the database.
   let notmuch = arguments.notmuch.as_ref().map(path::Path::new);
-  let database = match notmuch::Database::<notmuch::Detached>::open(notmuch, &arguments.namespace) {
+  let config = arguments.notmuch_config.as_ref().map(path::Path::new);
+  let profile = arguments.notmuch_profile.as_deref();
+  let mut database = match notmuch::Database::<notmuch::Detached>::open(
+    notmuch,
+    config,
+    profile,
+    &arguments.namespace,
+    mode,
+  ) {
     Ok(database) => database,
     Err(error) => match error.downcast_ref::<notmuch::Error>() {
       Some(error)
         if arguments.create
+          && mode == notmuch::Mode::ReadWrite
           && notmuch.is_some()
           && (error.no_database() /* when notmuch is Some */
               || error.file_error()/* when notmuch is None, weirdly */) =>
       {
-        notmuch::Database::<notmuch::Detached>::create(notmuch.unwrap(), &arguments.namespace)?
+        notmuch::Database::<notmuch::Detached>::create(
+          notmuch.unwrap(),
+          config,
+          profile,
+          &arguments.namespace,
+        )?
+      }
+      // Too old to even be opened, --upgrade can't help (see notmuch::Database::upgrade).
+      Some(error) if error.upgrade_required() => {
+        anyhow::bail!(
+          "the database needs to be upgraded and can't be opened until then (--upgrade only \
+           applies to databases that can), run notmuch new"
+        )
       }
       Some(_) | None => Err(error)?,
     },
   };
+  database.upgrade(arguments.upgrade)?;
+  if let Some(account) = account(arguments) {
+    database.set_account(&account);
+  }
+  Ok(database)
+}
+
+// What the maildir is synchronized with, so it's recognized once moved (see
+// notmuch::Database::attach). Nothing reliable through a tunnel.
+#[cfg(feature = "notmuch")]
+fn account(arguments: &Arguments) -> Option<String> {
+  match (&arguments.tunnel, &arguments.address) {
+    (Some(_), _) => None,
+    (None, Some(address)) => Some(format!("{}@{address}", arguments.user)),
+    // --auto, the user is an email address.
+    (None, None) => Some(arguments.user.clone()),
+  }
+}
+
+// The maildirs of the mailboxes, as configured.
+fn maildir_builder(arguments: &Arguments, path: &path::Path) -> io::Result<maildir::Builder> {
+  let mut builder = maildir::Builder::new(path)?;
+  builder.set_durability(arguments.durability);
+  builder.set_naming(arguments.maildir_naming.unwrap_or_default());
+  builder.set_layout(arguments.layout.unwrap_or_default());
+  builder.set_local_only(arguments.local_only.clone());
+  Ok(builder)
+}
+
+#[cfg(feature = "notmuch")]
+fn synchronize<O>(
+  arguments: &Arguments,
+  open: &O,
+  credentials: &credentials::Credentials,
+  stream: &mut imap::Stream<O::RW>,
+  mode: &Mode,
+) -> anyhow::Result<()>
+where
+  O: sync::Open,
+{
+  let mut database = open_database(arguments, notmuch::Mode::ReadWrite)?;
 
   // Open the maildir and tie the database to it.
   let relative_maildir = path::Path::new(&arguments.maildir);
@@ -171,27 +946,112 @@ where
     arguments.maildir,
     database.path(),
   );
-  let maildir_builder = maildir::Builder::new(&database.path().join(relative_maildir))?;
+  let maildir_builder = maildir_builder(arguments, &database.path().join(relative_maildir))?;
+  // Concurrent runs would step on each other (e.g.: appending the same messages twice).
+  let lock = maildir_builder.lock(&arguments.namespace, arguments.lock_timeout)?;
+  if *mode == Mode::Repair {
+    database.restore_root(maildir_builder.path())?;
+  }
   let mut database = database.attach(maildir_builder.path())?;
+  database.check_config()?;
+  database.set_decrypt(arguments.decrypt);
 
   let lastmod = database.lastmod() + 1;
 
+  let new_tags = match mode {
+    Mode::Pull | Mode::Append if arguments.new_tags => database.new_tags()?,
+    _ => Vec::new(),
+  };
+
+  let message = match (mode, &arguments.message) {
+    (Mode::Append, Some(path)) => {
+      fs::read(path).with_context(|| format!("couldn't read {path:?}"))?
+    }
+    (Mode::Append, None) => {
+      let mut message = Vec::new();
+      io::Read::read_to_end(&mut io::stdin(), &mut message)?;
+      message
+    }
+    _ => Vec::new(),
+  };
+
   // Reach consensus with the server.
-  database.transaction(|database| sync::move_out_of_tmp(database, relative_maildir))?;
-  database.transaction(|database| match arguments.mode {
-    Mode::ConnectOnly => unreachable!(),
+  database.transaction(|database| {
+    sync::move_out_of_tmp(database, relative_maildir, arguments.durability)
+  })?;
+  database.transaction(|database| {
+    sync::strip_prefix(stream, database, &maildir_builder, &scope(arguments))
+  })?;
+  database.transaction(|database| match mode {
+    Mode::ConnectOnly
+    | Mode::Watch
+    | Mode::Check
+    | Mode::State
+    | Mode::StateSet
+    | Mode::StateClear
+    | Mode::MigrateNamespace
+    | Mode::Sieve => unreachable!(),
     Mode::Pull => sync::pull::run(
       open,
       credentials,
+      &id(arguments),
       stream,
       database,
       &maildir_builder,
+      &scope(arguments),
+      &arguments.priority,
       &arguments.purgeable,
+      arguments.purge_all_removed,
+      arguments.max_purge.filter(|_| !arguments.ignore_max_purge),
+      arguments.max_messages,
+      &new_tags,
       arguments.threads,
+      arguments.fetch_batch,
+      arguments.pipeline_depth,
+      arguments.keepalive,
+    ),
+    Mode::Push => sync::push::run(
+      stream,
+      database,
+      relative_maildir,
+      &maildir_builder,
+      &scope(arguments),
+      arguments.mailbox_tag.as_ref(),
+      arguments.create_mailboxes,
+      arguments.flag_merge.unwrap_or_default(),
+      arguments.expunge,
+      &[&arguments.move_rules[..], &arguments.rules[..]].concat(),
+    ),
+    Mode::Repair => sync::repair::run(stream, database, &maildir_builder, &scope(arguments)),
+    Mode::Append => sync::push::append_message(
+      stream,
+      database,
+      &maildir_builder,
+      &scope(arguments),
+      arguments
+        .mailbox
+        .as_deref()
+        .context("append requires --mailbox")?,
+      &message,
+      &new_tags,
+    ),
+    Mode::Import => sync::import::run(
+      stream,
+      database,
+      &maildir_builder,
+      &scope(arguments),
+      arguments.import_from,
+      arguments.import_state.as_ref().map(path::Path::new),
+      arguments.import_source.as_ref().map(path::Path::new),
+      arguments.mailbox.as_deref(),
     ),
-    Mode::Push => sync::push::run(stream, database, relative_maildir, &maildir_builder),
   })?;
-  database.transaction(|database| sync::move_out_of_tmp(database, relative_maildir))?;
+  database.transaction(|database| {
+    sync::tag_mailboxes(database, arguments.mailbox_tag.as_ref(), lastmod)
+  })?;
+  database.transaction(|database| {
+    sync::move_out_of_tmp(database, relative_maildir, arguments.durability)
+  })?;
 
   // And show some statistics.
   let mut messages = database.query(&format!(
@@ -201,11 +1061,297 @@ where
     database.lastmod() + 1
   ))?;
   let mut count = 0;
-  while messages.next().is_some() {
-    count += 1
+  let mut mailboxes = collections::BTreeSet::new();
+  while let Some(message) = messages.next() {
+    count += 1;
+    mailboxes.extend(message.mailboxes()?.into_iter().map(str::to_string));
   }
   log::info!("{count} message(s) affected");
 
+  if *mode != Mode::Pull || count == 0 {
+    return Ok(());
+  }
+  // The hooks are likely to write to the database (notmuch tag) or even push: release everything.
+  let (path, hook_dir) = (database.path().to_path_buf(), database.hook_dir());
+  drop(messages);
+  drop(database);
+  drop(lock);
+  // One per line.
+  let mailboxes = mailboxes.into_iter().collect::<Vec<_>>().join("\n");
+  let hooks = [
+    match (arguments.post_pull_hook, hook_dir) {
+      (false, _) => None,
+      // Like Notmuch, a missing hook isn't an error.
+      (true, Some(hook_dir)) if !hook_dir.join("post-new").exists() => {
+        log::debug!("no post-new hook in {hook_dir:?}");
+        None
+      }
+      (true, Some(hook_dir)) => Some(process::Command::new(hook_dir.join("post-new"))),
+      (true, None) => anyhow::bail!("couldn't find Notmuch's hook directory"),
+    },
+    arguments.post_pull_command.as_ref().map(|command| {
+      let mut shell = process::Command::new("sh");
+      shell.args(["-c", command]);
+      shell
+    }),
+  ];
+  for mut hook in hooks.into_iter().flatten() {
+    log::info!("running {hook:?}");
+    // Like Notmuch, from the top of the mail store.
+    let status = hook
+      .current_dir(&path)
+      .env("SIN_MESSAGES", count.to_string())
+      .env("SIN_MAILBOXES", &mailboxes)
+      .status()
+      .with_context(|| format!("couldn't run {hook:?}"))?;
+    anyhow::ensure!(status.success(), "{hook:?} failed with {status}");
+  }
+
+  Ok(())
+}
+
+#[cfg(feature = "notmuch")]
+fn watch<O>(
+  arguments: &Arguments,
+  open: &O,
+  credentials: &credentials::Credentials,
+  stream: &mut imap::Stream<O::RW>,
+) -> anyhow::Result<()>
+where
+  O: sync::Open,
+{
+  // The database must not be kept open while idle: it would prevent any other writer (e.g.:
+  // notmuch tag) from making progress.
+  let watcher = {
+    let database = open_database(arguments, notmuch::Mode::ReadOnly)?;
+    let maildir = maildir::Builder::new(&database.path().join(&arguments.maildir))?;
+    watch::Watcher::new(&[(maildir.path(), true), (&database.xapian_path(), false)])?
+  };
+
+  // Catch up with the changes made while we weren't watching.
+  synchronize(arguments, open, credentials, stream, &Mode::Push)?;
+  status::phase("waiting for local changes");
+  systemd::ready();
+  loop {
+    watcher.wait(arguments.debounce)?;
+    // The push's own writes wake the watcher up too: only the changes it didn't record (made while
+    // it ran or since) call for another one.
+    if !unpushed(arguments)? {
+      continue;
+    }
+    log::info!("local changes detected, pushing");
+    status::phase("push");
+    // The connection may have been dropped by the server in the meantime.
+    if let Err(error) = stream.keepalive(time::Duration::ZERO) {
+      log::info!("reconnecting ({error:#})");
+      *stream = sync::connect(open, credentials, &id(arguments))?;
+    }
+    synchronize(arguments, open, credentials, stream, &Mode::Push)?;
+    status::phase("waiting for local changes");
+  }
+}
+
+// Whether the database changed since the last push recorded its lastmod (see sync::push::run).
+#[cfg(feature = "notmuch")]
+fn unpushed(arguments: &Arguments) -> anyhow::Result<bool> {
+  let database = open_database(arguments, notmuch::Mode::ReadOnly)?;
+  let maildir_builder = maildir::Builder::new(&database.path().join(&arguments.maildir))?;
+  let database = database.attach(maildir_builder.path())?;
+  let lastmod = database.root()?.lastmod()?;
+  Ok(database.lastmod() > lastmod)
+}
+
+#[cfg(feature = "notmuch")]
+fn check<RW>(arguments: &Arguments, stream: &mut imap::Stream<RW>) -> anyhow::Result<()>
+where
+  RW: imap::ReadWrite,
+{
+  // Not locked: nothing is written.
+  let database = open_database(arguments, notmuch::Mode::ReadOnly)?;
+  let maildir_builder = maildir_builder(arguments, &database.path().join(&arguments.maildir))?;
+  let database = database.attach(maildir_builder.path())?;
+  let discrepancies = sync::check::run(stream, &database, &maildir_builder, &scope(arguments))?;
+  anyhow::ensure!(discrepancies == 0, "found {discrepancies} discrepancies");
+  log::info!("no discrepancies found");
+  Ok(())
+}
+
+// Print what the previous synchronizations recorded.
+#[cfg(feature = "notmuch")]
+pub fn state(arguments: &Arguments, output: &mut dyn io::Write) -> anyhow::Result<()> {
+  let database = open_database(arguments, notmuch::Mode::ReadOnly)?;
+  let maildir_builder = maildir::Builder::new(&database.path().join(&arguments.maildir))?;
+  let database = database.attach(maildir_builder.path())?;
+  state::write(
+    &database,
+    arguments.query.as_deref(),
+    arguments.json,
+    output,
+  )
+}
+
+// Guarded edits of what the previous synchronizations recorded, for recovery.
+#[cfg(feature = "notmuch")]
+fn edit_state(arguments: &Arguments, mode: &Mode) -> anyhow::Result<()> {
+  let database = open_database(arguments, notmuch::Mode::ReadWrite)?;
+  let maildir_builder = maildir::Builder::new(&database.path().join(&arguments.maildir))?;
+  let _lock = maildir_builder.lock(&arguments.namespace, arguments.lock_timeout)?;
+  let mut database = database.attach(maildir_builder.path())?;
+  database.transaction(|database| {
+    match (
+      mode,
+      &arguments.property,
+      &arguments.value,
+      &arguments.mailbox,
+      arguments.namespace_id,
+    ) {
+      (Mode::StateSet, Some(property), Some(value), _, _) => state::set(database, property, value),
+      (Mode::StateSet, _, _, _, _) => anyhow::bail!("state-set requires --property and --value"),
+      (Mode::StateClear, _, _, Some(mailbox), None) => state::clear_mailbox(database, mailbox),
+      (Mode::StateClear, _, _, None, Some(id)) => state::clear_namespace(database, id),
+      (Mode::StateClear, _, _, _, _) => {
+        anyhow::bail!("state-clear requires either --mailbox or --namespace-id")
+      }
+      _ => unreachable!(),
+    }
+  })
+}
+
+#[cfg(feature = "notmuch")]
+fn migrate_namespace(arguments: &Arguments) -> anyhow::Result<()> {
+  let old_namespace = arguments
+    .migrate_from
+    .as_ref()
+    .context("migrate-namespace requires --migrate-from")?;
+  let mut database = open_database(arguments, notmuch::Mode::ReadWrite)?;
+  let maildir_builder = maildir::Builder::new(&database.path().join(&arguments.maildir))?;
+  let _locks = [
+    maildir_builder.lock(old_namespace, arguments.lock_timeout)?,
+    maildir_builder.lock(&arguments.namespace, arguments.lock_timeout)?,
+  ];
+  let count = database.migrate(old_namespace)?;
+  log::info!(
+    "migrated {count} message(s) from {old_namespace} to {}",
+    arguments.namespace
+  );
+  Ok(())
+}
+
+// Notmuch when available, unless asked otherwise.
+fn backend(arguments: &Arguments) -> anyhow::Result<Backend> {
+  match arguments.backend {
+    Some(Backend::Notmuch) if !cfg!(feature = "notmuch") => {
+      anyhow::bail!("the notmuch backend requires the notmuch feature")
+    }
+    Some(backend) => Ok(backend),
+    None if cfg!(feature = "notmuch") => Ok(Backend::Notmuch),
+    None => Ok(Backend::Maildir),
+  }
+}
+
+// The maildir backend's counterpart of the Notmuch directory, explicit rather than the current
+// directory.
+fn maildir_root(arguments: &Arguments) -> anyhow::Result<&path::Path> {
+  arguments
+    .maildir_root
+    .as_deref()
+    .map(path::Path::new)
+    .context("the maildir backend requires --maildir-root")
+}
+
+// The maildir backend's counterpart of synchronize, without a database.
+fn plain<RW>(
+  arguments: &Arguments,
+  stream: &mut imap::Stream<RW>,
+  mode: &Mode,
+) -> anyhow::Result<()>
+where
+  RW: imap::ReadWrite,
+{
+  let maildir_builder = maildir_builder(
+    arguments,
+    &maildir_root(arguments)?.join(&arguments.maildir),
+  )?;
+  let _lock = maildir_builder.lock(&arguments.namespace, arguments.lock_timeout)?;
+  let mut store =
+    sync::store::Maildir::new(&maildir_builder, &arguments.namespace, arguments.durability);
+  match mode {
+    Mode::Pull => sync::plain::pull(
+      stream,
+      &mut store,
+      &maildir_builder,
+      &scope(arguments),
+      &arguments.priority,
+      arguments.fetch_batch.get(),
+      &arguments.purgeable,
+    ),
+    Mode::Push => sync::plain::push(
+      stream,
+      &mut store,
+      &maildir_builder,
+      &scope(arguments),
+      arguments.expunge,
+    ),
+    _ => unreachable!(), // See run.
+  }
+}
+
+fn scope(arguments: &Arguments) -> sync::Scope<'_> {
+  sync::Scope {
+    namespaces: &arguments.namespace_include,
+    subscribed_only: arguments.subscribed_only,
+  }
+}
+
+fn id(arguments: &Arguments) -> sync::Id {
+  sync::Id {
+    name: arguments.id_name.clone(),
+    version: arguments.id_version.clone(),
+    quirks: arguments.server_quirks,
+    buffer_limit: Some(arguments.buffer_limit),
+  }
+}
+
+fn inner_run<O>(
+  arguments: &Arguments,
+  open: &O,
+  credentials: &credentials::Credentials,
+  stream: &mut imap::Stream<O::RW>,
+  discovered: Option<(&str, u16)>,
+) -> anyhow::Result<()>
+where
+  O: sync::Open,
+{
+  // Exchange pleasantries with the server.
+  let greetings = sync::greetings(stream)?;
+  if arguments.modes == [Mode::ConnectOnly] {
+    return Ok(());
+  }
+  sync::login(stream, greetings, credentials, &id(arguments))?;
+  #[cfg(feature = "notmuch")]
+  if let Some(server) = discovered {
+    remember_server(arguments, server)?;
+  }
+
+  // The session (and the selected mailbox) is shared by all the modes.
+  let backend = backend(arguments)?;
+  for mode in &arguments.modes {
+    status::phase(mode.to_possible_value().unwrap().get_name()); // Guaranteed by clap.
+    match backend {
+      Backend::Maildir => plain(arguments, stream, mode)?,
+      #[cfg(feature = "notmuch")]
+      Backend::Notmuch => match mode {
+        Mode::Watch => watch(arguments, open, credentials, stream)?,
+        Mode::Check => check(arguments, stream)?,
+        Mode::State => state(arguments, &mut io::stdout())?,
+        Mode::StateSet | Mode::StateClear => edit_state(arguments, mode)?,
+        Mode::MigrateNamespace => migrate_namespace(arguments)?,
+        _ => synchronize(arguments, open, credentials, stream, mode)?,
+      },
+      #[cfg(not(feature = "notmuch"))]
+      Backend::Notmuch => unreachable!(), // See backend.
+    }
+  }
   Ok(())
 }
 
@@ -225,23 +1371,56 @@ impl<'a> sync::Open for TCP<'a> {
       timeout,
       ..
     } = self;
-    let address = (address, port)
+    // https://www.rfc-editor.org/rfc/rfc8305#section-4
+    // [...] the client SHOULD order the destination addresses [...] by interleaving address
+    // families. [...] the first address SHOULD be IPv6 [...]
+    // Connections are attempted one after the other (not in parallel) but an unreachable family
+    // won't prevent the connection.
+    let (mut v6, mut v4): (Vec<_>, Vec<_>) = (address, port)
       .to_socket_addrs()?
-      .next()
-      .with_context(|| format!("couldn't resolve {address}:{port}"))?;
-    log::debug!("connecting to {:?} with timeout {:?}", address, timeout);
-    Ok(match timeout {
-      Some(duration) => {
-        let stream = net::TcpStream::connect_timeout(&address, duration)?;
-        stream.set_read_timeout(Some(duration))?;
-        stream
+      .partition(|address| address.is_ipv6());
+    anyhow::ensure!(
+      !v6.is_empty() || !v4.is_empty(),
+      "couldn't resolve {address}:{port}"
+    );
+    let (mut v6, mut v4) = (v6.drain(..), v4.drain(..));
+    let mut error = None;
+    loop {
+      for address in [v6.next(), v4.next()] {
+        let Some(address) = address else {
+          continue;
+        };
+        log::debug!("connecting to {:?} with timeout {:?}", address, timeout);
+        let stream = match timeout {
+          Some(duration) => {
+            net::TcpStream::connect_timeout(&address, duration).and_then(|stream| {
+              stream.set_read_timeout(Some(duration))?;
+              Ok(stream)
+            })
+          }
+          None => net::TcpStream::connect(address),
+        };
+        match stream {
+          Ok(stream) => return Ok(stream),
+          Err(error_) => {
+            log::warn!("couldn't connect to {address:?}: {error_}");
+            error = Some(error_);
+          }
+        }
       }
-      None => net::TcpStream::connect(address)?,
-    })
+      if v6.len() == 0 && v4.len() == 0 {
+        // At least one address was tried.
+        return Err(error.unwrap())
+          .with_context(|| format!("couldn't connect to {address}:{port}"));
+      }
+    }
   }
 }
 
-struct TLS<'a>(TCP<'a>);
+struct TLS<'a> {
+  tcp: TCP<'a>,
+  config: std::sync::Arc<rustls::ClientConfig>,
+}
 
 #[ouroboros::self_referencing]
 struct TLSStream {
@@ -262,29 +1441,147 @@ impl imap::ReadWrite for TLSStream {
   }
 }
 
+// Certificate pinning needs a custom verifier.
+struct Verifier {
+  pins: Vec<Fingerprint>,
+}
+
+impl rustls::client::ServerCertVerifier for Verifier {
+  fn verify_server_cert(
+    &self,
+    end_entity: &rustls::Certificate,
+    _intermediates: &[rustls::Certificate],
+    _server_name: &rustls::ServerName,
+    _scts: &mut dyn Iterator<Item = &[u8]>,
+    _ocsp_response: &[u8],
+    _now: time::SystemTime,
+  ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+    // The handshake signatures are still verified by the default implementations of
+    // verify_tls1{2,3}_signature (so the server has to own the private key).
+    let fingerprint = Fingerprint(sha2::Sha256::digest(&end_entity.0).into());
+    match self.pins.contains(&fingerprint) {
+      true => Ok(rustls::client::ServerCertVerified::assertion()),
+      false => Err(rustls::Error::General(format!(
+        "certificate fingerprint {} isn't pinned",
+        fingerprint
+          .0
+          .iter()
+          .map(|byte| format!("{byte:02X}"))
+          .collect::<Vec<_>>()
+          .join(":")
+      ))),
+    }
+  }
+}
+
+// Accept anything, useful for tests against self-signed certificates.
+struct Insecure;
+
+impl rustls::client::ServerCertVerifier for Insecure {
+  fn verify_server_cert(
+    &self,
+    _end_entity: &rustls::Certificate,
+    _intermediates: &[rustls::Certificate],
+    _server_name: &rustls::ServerName,
+    _scts: &mut dyn Iterator<Item = &[u8]>,
+    _ocsp_response: &[u8],
+    _now: time::SystemTime,
+  ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+    Ok(rustls::client::ServerCertVerified::assertion())
+  }
+}
+
+impl<'a> TLS<'a> {
+  fn new(tcp: TCP<'a>, arguments: &Arguments) -> anyhow::Result<Self> {
+    let builder = rustls::ClientConfig::builder().with_safe_defaults();
+    let builder = if arguments.tls_insecure {
+      log::warn!(
+        "TLS certificate verification disabled, anybody can impersonate the server and steal the \
+         credentials"
+      );
+      builder.with_custom_certificate_verifier(std::sync::Arc::new(Insecure))
+    } else if arguments.tls_pin.is_empty() {
+      let mut root_store = rustls::RootCertStore::empty();
+      for certificate in rustls_native_certs::load_native_certs()? {
+        root_store.add(&rustls::Certificate(certificate.0))?
+      }
+      if let Some(ca) = &arguments.tls_ca {
+        for certificate in certificates(ca)? {
+          root_store.add(&certificate)?
+        }
+      }
+      // Equivalent to .with_root_certificates(root_store) but the builder's type must match.
+      builder.with_custom_certificate_verifier(std::sync::Arc::new(
+        rustls::client::WebPkiVerifier::new(root_store, None),
+      ))
+    } else {
+      builder.with_custom_certificate_verifier(std::sync::Arc::new(Verifier {
+        pins: arguments.tls_pin.clone(),
+      }))
+    };
+    let config = match (&arguments.tls_client_cert, &arguments.tls_client_key) {
+      (Some(certificate), Some(key)) => {
+        // The server may then offer AUTH=EXTERNAL (used when no password command is given).
+        builder.with_client_auth_cert(certificates(certificate)?, private_key(key)?)?
+      }
+      _ => builder.with_no_client_auth(),
+    };
+    Ok(Self {
+      tcp,
+      config: std::sync::Arc::new(config),
+    })
+  }
+}
+
+fn certificates(path: &str) -> anyhow::Result<Vec<rustls::Certificate>> {
+  let mut reader =
+    io::BufReader::new(fs::File::open(path).with_context(|| format!("couldn't open {path}"))?);
+  let certificates = rustls_pemfile::certs(&mut reader)
+    .with_context(|| format!("couldn't parse {path}"))?
+    .into_iter()
+    .map(rustls::Certificate)
+    .collect::<Vec<_>>();
+  anyhow::ensure!(!certificates.is_empty(), "no certificate found in {path}");
+  Ok(certificates)
+}
+
+fn private_key(path: &str) -> anyhow::Result<rustls::PrivateKey> {
+  let mut reader =
+    io::BufReader::new(fs::File::open(path).with_context(|| format!("couldn't open {path}"))?);
+  loop {
+    match rustls_pemfile::read_one(&mut reader).with_context(|| format!("couldn't parse {path}"))? {
+      Some(
+        rustls_pemfile::Item::RSAKey(key)
+        | rustls_pemfile::Item::PKCS8Key(key)
+        | rustls_pemfile::Item::ECKey(key),
+      ) => break Ok(rustls::PrivateKey(key)),
+      Some(_) => (),
+      None => anyhow::bail!("no private key found in {path}"),
+    }
+  }
+}
+
 impl<'a> sync::Open for TLS<'a> {
   type RW = TLSStream;
 
   fn open(&self) -> anyhow::Result<Self::RW> {
-    let mut root_store = rustls::RootCertStore::empty();
-    for certificate in rustls_native_certs::load_native_certs()? {
-      root_store.add(&rustls::Certificate(certificate.0))?
-    }
+    self.wrap(self.tcp.open()?)
+  }
+}
+
+impl<'a> TLS<'a> {
+  // Also after a STARTTLS (see sieve).
+  fn wrap(&self, tcp_stream: net::TcpStream) -> anyhow::Result<TLSStream> {
     Ok(
       TLSStreamBuilder {
-        tcp_stream: self.0.open()?,
+        tcp_stream,
         tls_connection: rustls::ClientConnection::new(
-          std::sync::Arc::new(
-            rustls::ClientConfig::builder()
-              .with_safe_defaults()
-              .with_root_certificates(root_store)
-              .with_no_client_auth(),
-          ),
+          self.config.clone(),
           self
-            .0
+            .tcp
             .address
             .try_into()
-            .with_context(|| format!("couldn't convert {} to server name", self.0.address))?,
+            .with_context(|| format!("couldn't convert {} to server name", self.tcp.address))?,
         )?,
         tls_stream_builder: |tcp_stream, tls_connection| {
           rustls::Stream::new(tls_connection, tcp_stream)
@@ -295,50 +1592,273 @@ impl<'a> sync::Open for TLS<'a> {
   }
 }
 
-fn credentials(user: &str, password_command: &[String]) -> anyhow::Result<sync::Credentials> {
-  let mut program = process::Command::new(&password_command[0]);
-  let command = program.args(&password_command[1..]);
-  log::info!("getting password from {command:?}");
-  let output = command.output()?;
-  let mut stdout = output.stdout;
-  anyhow::ensure!(
-    output.status.success(),
-    "couldn't get password: {command:?} failed"
-  );
-  let password = str::from_utf8(
-    stdout
-      .split(|byte| *byte == b'\n')
-      .next()
-      .with_context(|| format!("{command:?} didn't output anything"))?,
+// Like mbsync's Tunnel: the server is expected to be reachable via the standard input and output of
+// the command (which is likely to be preauthenticated).
+struct Tunnel<'a>(&'a str);
+
+pub struct TunnelStream(process::Child);
+
+impl ops::Drop for TunnelStream {
+  fn drop(&mut self) {
+    // Don't leave zombies behind.
+    if let Err(error) = self.0.kill().and_then(|_| self.0.wait()) {
+      log::warn!("couldn't kill tunnel {error}")
+    }
+  }
+}
+
+impl imap::ReadWrite for TunnelStream {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    io::Read::read(
+      self.0.stdout.as_mut().unwrap(), /* guaranteed by Tunnel::open */
+      buf,
+    )
+  }
+
+  fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+    let stdin = self.0.stdin.as_mut().unwrap(); // Guaranteed by Tunnel::open.
+    io::Write::write_all(stdin, buf)?;
+    io::Write::flush(stdin)
+  }
+}
+
+impl<'a> sync::Open for Tunnel<'a> {
+  type RW = TunnelStream;
+
+  fn open(&self) -> anyhow::Result<Self::RW> {
+    log::debug!("tunneling through {:?}", self.0);
+    Ok(TunnelStream(
+      process::Command::new("sh")
+        .args(["-c", self.0])
+        .stdin(process::Stdio::piped())
+        .stdout(process::Stdio::piped())
+        .spawn()
+        .with_context(|| format!("couldn't spawn {:?}", self.0))?,
+    ))
+  }
+}
+
+// Look up the server once, the boolean telling whether it's yet to be remembered (see
+// remember_server).
+#[cfg(feature = "notmuch")]
+fn discover(arguments: &Arguments) -> anyhow::Result<((String, u16), bool)> {
+  let database = open_database(arguments, notmuch::Mode::ReadWrite)?;
+  let maildir_builder = maildir::Builder::new(&database.path().join(&arguments.maildir))?;
+  if let Some(server) = database.attach(maildir_builder.path())?.root()?.server()? {
+    log::debug!("using the previously discovered {}:{}", server.0, server.1);
+    return Ok((server, false));
+  }
+  Ok((discovery::srv(&arguments.user)?, true))
+}
+
+// Remember the discovered server for the next runs, once logged in: the DNS answer alone isn't
+// authenticated.
+#[cfg(feature = "notmuch")]
+fn remember_server(arguments: &Arguments, (address, port): (&str, u16)) -> anyhow::Result<()> {
+  let database = open_database(arguments, notmuch::Mode::ReadWrite)?;
+  let maildir_builder = maildir::Builder::new(&database.path().join(&arguments.maildir))?;
+  let mut database = database.attach(maildir_builder.path())?;
+  database.transaction(|database| database.root()?.update_server(address, port))
+}
+
+// The ManageSieve server is expected at the IMAP server's address.
+fn sieve(
+  arguments: &Arguments,
+  tcp: TCP,
+  credentials: &credentials::Credentials,
+) -> anyhow::Result<()> {
+  let directory = match backend(arguments)? {
+    #[cfg(feature = "notmuch")]
+    Backend::Notmuch => open_database(arguments, notmuch::Mode::ReadOnly)?
+      .path()
+      .to_path_buf(),
+    _ => maildir_root(arguments)?.to_path_buf(),
+  };
+  let path = directory.join(format!("{}.sieve", arguments.namespace));
+  let session = sieve::Session::new(tcp.open()?)?;
+  if !arguments.tls {
+    log::warn!("TLS not enabled, credentials will be sent in clear over the wire");
+    return sieve::synchronize(session, credentials, &path, &arguments.namespace);
+  }
+  let stream = session.starttls()?;
+  let tls = TLS::new(tcp, arguments)?;
+  sieve::synchronize(
+    sieve::Session::new(tls.wrap(stream)?)?,
+    credentials,
+    &path,
+    &arguments.namespace,
   )
-  .with_context(|| format!("{command:?} didn't output UTF-8"))?;
-  let credentials = imap::plain(user, password);
-  stdout.zeroize();
-  Ok(sync::Credentials(credentials))
 }
 
 pub fn run(arguments: &Arguments) -> anyhow::Result<()> {
-  interruption(&arguments.interruption);
-  let credentials = credentials(&arguments.user, &arguments.password_command)?;
+  anyhow::ensure!(!arguments.modes.is_empty(), "no mode given");
+  if let [_, _, ..] = arguments.modes.as_slice() {
+    anyhow::ensure!(
+      !arguments.modes.contains(&Mode::ConnectOnly),
+      "connect-only can't be combined with other modes"
+    );
+    anyhow::ensure!(
+      !arguments.modes[..arguments.modes.len() - 1].contains(&Mode::Watch),
+      "watch never returns, it can only be the last mode"
+    );
+    anyhow::ensure!(
+      !arguments.modes.iter().any(Mode::offline),
+      "state, state-set, state-clear and migrate-namespace can't be combined with other modes"
+    );
+    anyhow::ensure!(
+      !arguments.modes.contains(&Mode::Sieve),
+      "sieve can't be combined with other modes"
+    );
+  }
+  if backend(arguments)? == Backend::Maildir {
+    for mode in &arguments.modes {
+      anyhow::ensure!(
+        matches!(
+          mode,
+          Mode::ConnectOnly | Mode::Pull | Mode::Push | Mode::Sieve
+        ),
+        "{} requires the notmuch backend",
+        mode.to_possible_value().unwrap().get_name() // Guaranteed by clap.
+      );
+    }
+    // Rather than silently ignored by sync::plain.
+    for (option, given) in [
+      ("--purge-all-removed", arguments.purge_all_removed),
+      ("--max-purge", arguments.max_purge.is_some()),
+      ("--max-messages", arguments.max_messages.is_some()),
+      ("--flag-merge", arguments.flag_merge.is_some()),
+      ("--rule", !arguments.rules.is_empty()),
+      ("--move-rule", !arguments.move_rules.is_empty()),
+      ("--threads", arguments.threads.get() != 8), // Its default.
+    ] {
+      anyhow::ensure!(!given, "{option} requires the notmuch backend");
+    }
+  }
+  // No connection needed.
+  #[cfg(feature = "notmuch")]
+  match arguments.modes.as_slice() {
+    [Mode::State] => return state(arguments, &mut io::stdout()),
+    [mode @ (Mode::StateSet | Mode::StateClear)] => return edit_state(arguments, mode),
+    [Mode::MigrateNamespace] => return migrate_namespace(arguments),
+    _ => (),
+  }
+  fault::set(arguments.interruption, &arguments.faults)?;
+  status::phase("connecting");
+  // Resolved lazily, only if the server asks for a password.
+  let secret = if let Some(keyring) = &arguments.password_keyring {
+    credentials::Secret::Keyring(keyring.clone())
+  } else if let Some(variable) = &arguments.password_env {
+    credentials::Secret::Environment(variable.clone())
+  } else if arguments.password_prompt {
+    credentials::Secret::Prompt
+  } else if !arguments.password_command.is_empty() {
+    credentials::Secret::Command(arguments.password_command.clone())
+  } else {
+    credentials::Secret::None
+  };
+  let mut credentials =
+    credentials::Credentials::new(&arguments.user, secret, arguments.auth_mechanism);
+  credentials.set_retries(arguments.password_retries);
+  credentials.set_ask(arguments.ask_password);
+  anyhow::ensure!(
+    arguments.modes != [Mode::Sieve]
+      || (arguments.replay_file.is_none() && arguments.tunnel.is_none()),
+    "sieve requires an address"
+  );
+  if let Some(replay) = &arguments.replay_file {
+    return run_with(arguments, &trace::Replay::load(replay)?, &credentials);
+  }
+  if let Some(tunnel) = &arguments.tunnel {
+    return run_with(arguments, &Tunnel(tunnel), &credentials);
+  }
+  let discovered;
+  let (address, port, remember) = match (&arguments.address, arguments.port) {
+    (Some(address), Some(port)) => (address.as_str(), port, false),
+    (None, None) if arguments.auto => {
+      // _imaps is implicit TLS (see discovery::srv).
+      anyhow::ensure!(arguments.tls, "--auto requires TLS");
+      let remember;
+      (discovered, remember) = match backend(arguments)? {
+        #[cfg(feature = "notmuch")]
+        Backend::Notmuch => discover(arguments)?,
+        // Nowhere to remember it.
+        _ => (discovery::srv(&arguments.user)?, false),
+      };
+      (discovered.0.as_str(), discovered.1, remember)
+    }
+    _ => anyhow::bail!("an address and a port are required without a tunnel"),
+  };
   let tcp = TCP {
-    address: &arguments.address,
-    port: arguments.port,
+    address,
+    port,
     timeout: arguments.timeout,
   };
-  if !arguments.tls {
-    log::warn!("TLS not enabled, credentials will be sent in clear over the wire");
-    return inner_run(
+  if arguments.modes == [Mode::Sieve] {
+    return sieve(
       arguments,
-      &tcp,
+      TCP {
+        port: arguments.sieve_port,
+        ..tcp
+      },
       &credentials,
-      &mut imap::Stream::new(tcp.open()?),
     );
   }
-  let tls = TLS(tcp);
-  inner_run(
+  if !arguments.tls {
+    log::warn!("TLS not enabled, credentials will be sent in clear over the wire");
+    return run_with(arguments, &tcp, &credentials);
+  }
+  connect(
     arguments,
-    &tls,
+    &TLS::new(tcp, arguments)?,
     &credentials,
-    &mut imap::Stream::new(tls.open()?),
+    remember.then_some((address, port)),
   )
 }
+
+// Like run but over the given transport: the connection arguments (address, port, tunnel, TLS, ...)
+// and the password ones are ignored.
+pub fn run_with<O>(
+  arguments: &Arguments,
+  open: &O,
+  credentials: &credentials::Credentials,
+) -> anyhow::Result<()>
+where
+  O: sync::Open,
+{
+  connect(arguments, open, credentials, None)
+}
+
+// The server discovered by --auto (see discover) is only remembered once logged in.
+fn connect<O>(
+  arguments: &Arguments,
+  open: &O,
+  credentials: &credentials::Credentials,
+  discovered: Option<(&str, u16)>,
+) -> anyhow::Result<()>
+where
+  O: sync::Open,
+{
+  match &arguments.trace_file {
+    Some(path) => {
+      let recorder = trace::Recorder::create(path)?;
+      let open = trace::Traced {
+        open,
+        recorder: &recorder,
+      };
+      inner_run(
+        arguments,
+        &open,
+        credentials,
+        &mut imap::Stream::new(open.open()?),
+        discovered,
+      )
+    }
+    None => inner_run(
+      arguments,
+      open,
+      credentials,
+      &mut imap::Stream::new(open.open()?),
+      discovered,
+    ),
+  }
+}
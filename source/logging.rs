@@ -0,0 +1,129 @@
+// System logging, for the daemons running without a runtime directory to log into (system users).
+// Both are local datagram sockets, no need for a library.
+
+use anyhow::Context as _;
+use std::{os::unix::net, process};
+
+// The syslog severities (RFC 5424, section 6.2.1), also used by the journal.
+fn severity(level: log::Level) -> u8 {
+  match level {
+    log::Level::Error => 3,
+    log::Level::Warn => 4,
+    log::Level::Info => 6,
+    log::Level::Debug | log::Level::Trace => 7,
+  }
+}
+
+// Set when a mailbox is selected (see imap::Stream::set_selected).
+fn mailbox() -> Option<String> {
+  log_mdc::get("mailbox", |mailbox| mailbox.map(String::from))
+}
+
+fn connect(path: &str) -> anyhow::Result<net::UnixDatagram> {
+  let socket = net::UnixDatagram::unbound()?;
+  socket
+    .connect(path)
+    .with_context(|| format!("couldn't connect to {path}"))?;
+  Ok(socket)
+}
+
+#[derive(Debug)]
+pub struct Syslog {
+  socket: net::UnixDatagram,
+}
+
+impl Syslog {
+  pub fn new() -> anyhow::Result<Self> {
+    Ok(Self {
+      socket: connect("/dev/log")?,
+    })
+  }
+}
+
+impl log4rs::append::Append for Syslog {
+  fn append(&self, record: &log::Record) -> anyhow::Result<()> {
+    // The priority is the facility (user) times 8 plus the severity, the local daemon fills the
+    // timestamp and the hostname in.
+    let message = format!(
+      "<{}>sin[{}]: {} {} - {}",
+      8 + severity(record.level()),
+      process::id(),
+      record.target(),
+      mailbox().as_deref().unwrap_or("-"),
+      record.args()
+    );
+    self.socket.send(message.as_bytes())?;
+    Ok(())
+  }
+
+  fn flush(&self) {}
+}
+
+#[derive(Debug)]
+pub struct Journald {
+  socket: net::UnixDatagram,
+}
+
+impl Journald {
+  pub fn new() -> anyhow::Result<Self> {
+    Ok(Self {
+      socket: connect("/run/systemd/journal/socket")?,
+    })
+  }
+}
+
+// https://systemd.io/JOURNAL_NATIVE_PROTOCOL/
+// Fields are KEY=VALUE lines, values spanning lines are instead written after their length (a
+// little-endian 64-bit integer) on the line following the key.
+fn field(datagram: &mut Vec<u8>, key: &str, value: &str) {
+  datagram.extend_from_slice(key.as_bytes());
+  if value.contains('\n') {
+    datagram.push(b'\n');
+    datagram.extend_from_slice(&(value.len() as u64).to_le_bytes());
+  } else {
+    datagram.push(b'=');
+  }
+  datagram.extend_from_slice(value.as_bytes());
+  datagram.push(b'\n');
+}
+
+impl log4rs::append::Append for Journald {
+  fn append(&self, record: &log::Record) -> anyhow::Result<()> {
+    let mut datagram = Vec::new();
+    field(&mut datagram, "MESSAGE", &record.args().to_string());
+    field(
+      &mut datagram,
+      "PRIORITY",
+      &severity(record.level()).to_string(),
+    );
+    field(&mut datagram, "SYSLOG_IDENTIFIER", "sin");
+    field(&mut datagram, "TARGET", record.target());
+    if let Some(file) = record.file() {
+      field(&mut datagram, "CODE_FILE", file);
+    }
+    if let Some(line) = record.line() {
+      field(&mut datagram, "CODE_LINE", &line.to_string());
+    }
+    if let Some(mailbox) = mailbox() {
+      field(&mut datagram, "MAILBOX", &mailbox);
+    }
+    self.socket.send(&datagram)?;
+    Ok(())
+  }
+
+  fn flush(&self) {}
+}
+
+#[cfg(test)]
+mod tests {
+  #[test]
+  fn field() {
+    let mut datagram = Vec::new();
+    super::field(&mut datagram, "MESSAGE", "one line");
+    super::field(&mut datagram, "MESSAGE", "two\nlines");
+    assert_eq!(
+      &b"MESSAGE=one line\nMESSAGE\n\x09\0\0\0\0\0\0\0two\nlines\n"[..],
+      datagram
+    );
+  }
+}
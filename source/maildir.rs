@@ -8,20 +8,95 @@
 
 use anyhow::Context as _;
 use std::{
-  fs,
+  borrow, collections, ffi, fs,
   io::{self, Write as _},
-  path,
+  path, process,
+  sync::atomic,
+  thread, time,
 };
 
+#[derive(Clone, Copy, Debug, Default, PartialEq, clap::ValueEnum)]
+pub enum Naming {
+  // UUIDs, or what's needed to resume an interrupted pull (see tmp).
+  #[default]
+  Sin,
+  // time.pid_seq.host,S=<size>
+  // https://doc.dovecot.org/admin_manual/mailbox_formats/maildir/
+  // Other tools read the size from the name instead of opening the files (e.g.: quota
+  // recalculation). What's needed to resume an interrupted pull is appended as another field (,K=).
+  Conventional,
+}
+
+// How the mailboxes' maildirs are laid out.
+#[derive(Clone, Copy, Debug, Default, PartialEq, clap::ValueEnum)]
+pub enum Layout {
+  // Every mailbox is a dot-folder of the root (see Builder::maildir).
+  #[default]
+  #[value(name = "maildir++")]
+  MaildirPlusPlus,
+  // https://doc.dovecot.org/admin_manual/mailbox_formats/maildir/
+  // Like Dovecot's LAYOUT=fs: the hierarchy of the mailboxes is the one of the directories.
+  Fs,
+}
+
 #[derive(Debug)]
 pub struct Builder {
   path: path::PathBuf,
+  durability: bool,
+  naming: Naming,
+  layout: Layout,
+  // Relative to the root, see local_only.
+  local_only: Vec<path::PathBuf>,
 }
 
 #[derive(Debug)]
 pub struct Maildir {
   path: path::PathBuf,
+  relative: path::PathBuf,
   root: bool,
+  // Whether the name of the mailbox had to be escaped (see Builder::maildir).
+  escaped: bool,
+  durability: bool,
+  naming: Naming,
+  layout: Layout,
+}
+
+static SEQUENCE: atomic::AtomicU64 = atomic::AtomicU64::new(0);
+
+static HOSTNAME: once_cell::sync::Lazy<String> = once_cell::sync::Lazy::new(|| {
+  extern "C" {
+    // POSIX.
+    fn gethostname(name: *mut ffi::c_char, len: usize) -> ffi::c_int;
+  }
+  let mut buffer = [0u8; 256];
+  // The buffer is large enough for any host name and its size is passed along.
+  let hostname = match unsafe { gethostname(buffer.as_mut_ptr().cast(), buffer.len()) } {
+    0 => ffi::CStr::from_bytes_until_nul(&buffer)
+      .map(|hostname| hostname.to_string_lossy().into_owned())
+      .unwrap_or_default(),
+    _ => String::new(),
+  };
+  match hostname.as_str() {
+    "" => "localhost".to_string(),
+    // Like the other maildir writers, so the unique names stay valid.
+    hostname => hostname.replace('/', "\\057").replace(':', "\\072"),
+  }
+});
+
+// https://man7.org/linux/man-pages/man2/fsync.2.html
+// Calling fsync() does not necessarily ensure that the entry in the directory containing the file
+// has also reached disk. For that an explicit fsync() on a file descriptor for the directory is
+// also needed.
+fn sync_directory(path: &path::Path) -> io::Result<()> {
+  fs::File::open(path)?.sync_all()
+}
+
+// After files were created or renamed in the maildir.
+pub fn sync_directories(path: &path::Path) -> io::Result<()> {
+  for directory in ["tmp", "new", "cur"] {
+    sync_directory(&path.join(directory))?;
+  }
+  Ok(())
 }
 
 impl Builder {
@@ -29,65 +104,306 @@ impl Builder {
     fs::create_dir_all(path)?;
     Ok(Self {
       path: path.to_path_buf(),
+      durability: false,
+      naming: Naming::Sin,
+      layout: Layout::MaildirPlusPlus,
+      local_only: Vec::new(),
     })
   }
 
+  // Whether the directories of the maildirs are synchronized to disk after files are created or
+  // renamed (the files always are).
+  pub fn set_durability(&mut self, durability: bool) {
+    self.durability = durability;
+  }
+
+  pub fn set_naming(&mut self, naming: Naming) {
+    self.naming = naming;
+  }
+
+  pub fn set_layout(&mut self, layout: Layout) {
+    self.layout = layout;
+  }
+
+  pub fn set_local_only(&mut self, local_only: Vec<path::PathBuf>) {
+    self.local_only = local_only;
+  }
+
+  // Whether a maildir (relative to the root) must never be pushed: it's one of the local-only ones
+  // or, with the fs layout, nested in one.
+  pub fn local_only(&self, relative: &path::Path) -> bool {
+    self
+      .local_only
+      .iter()
+      .any(|local_only| relative.starts_with(local_only))
+  }
+
   pub fn path(&self) -> &path::Path {
     self.path.as_path()
   }
 
+  // The lock is held until the returned file is dropped.
+  pub fn lock(&self, name: &str, timeout: Option<time::Duration>) -> anyhow::Result<fs::File> {
+    // In the root, next to the maildir and not in its tmp: the files there are fair game (e.g.: the
+    // leftovers sync::repair removes). Hidden, it can't be mistaken for a Maildir++ folder or a
+    // mailbox of the fs layout (their leading dots are escaped).
+    fs::create_dir_all(&self.path)?;
+    let path = self.path.join(format!(".{name}.lock"));
+    let file = fs::File::create(&path)?;
+    let start = time::Instant::now();
+    loop {
+      match file.try_lock() {
+        Ok(()) => return Ok(file),
+        Err(fs::TryLockError::WouldBlock) => match timeout {
+          Some(timeout) if start.elapsed() < timeout => {
+            log::debug!("waiting for {path:?}");
+            thread::sleep(time::Duration::from_millis(100));
+          }
+          _ => anyhow::bail!("{path:?} is locked, is another instance running?"),
+        },
+        Err(fs::TryLockError::Error(error)) => {
+          Err(error).with_context(|| format!("couldn't lock {path:?}"))?
+        }
+      }
+    }
+  }
+
   pub fn maildir(&self, mailbox: &str, separator: &Option<char>) -> io::Result<Maildir> {
-    // TODO: escape the mailbox (e.g.: is / authorized)?
-    let (path, root) = if mailbox == "INBOX" {
+    let components: Vec<&str> = match separator {
+      Some(separator) => mailbox.split(*separator).collect(),
+      None => vec![mailbox],
+    };
+    let escaped = components.iter().any(|component| needs_escaping(component));
+    let (relative, root) = if mailbox == "INBOX" {
       // https://doc.dovecot.org/admin_manual/mailbox_formats/maildir/#directory-structure
       // ~/Maildir/new, ~/Maildir/cur and ~/Maildir/tmp directories contain the messages for INBOX.
-      (self.path.clone(), true)
-    } else if let Some(separator) = separator {
+      (path::PathBuf::new(), true)
+    } else if self.layout == Layout::Fs {
+      // ~/Maildir/folder/subfolder/ is a subfolder of a folder (i.e. folder/subfolder).
+      let relative: path::PathBuf = components
+        .iter()
+        .map(|component| escape(component).into_owned())
+        .collect();
+      // They'd be mistaken for the parent's subdirectories.
+      if relative
+        .iter()
+        .any(|component| ["cur", "new", "tmp"].iter().any(|name| component == *name))
+      {
+        return Err(io::Error::new(
+          io::ErrorKind::InvalidInput,
+          format!("{mailbox} can't be stored with the fs layout"),
+        ));
+      }
+      (relative, false)
+    } else {
       // https://www.courier-mta.org/imap/README.maildirquota.html
       // Can folders have subfolders, defined in a recursive fashion? The answer is no. If you want
       // to have a client with a hierarchy of folders, emulate it. Pick a hierarchy separator
       // character, say ":". Then, folder foo/bar is subdirectory .foo:bar.
       //
       // https://doc.dovecot.org/admin_manual/mailbox_formats/maildir/#directory-structure
+      // ~/Maildir/.folder/ is a mailbox folder.
       // ~/Maildir/.folder.subfolder/ is a subfolder of a folder (i.e. folder/subfolder).
-      let mut directory = ".".to_string();
-      // .intersperse() is nightly...
-      let n = mailbox.matches(*separator).count();
-      for (i, subdirectory) in mailbox.split(*separator).enumerate() {
-        directory += subdirectory;
-        if i < n {
-          directory.push('.');
+      //
+      // Dots within the components are left alone (folder.subfolder and folder/subfolder share
+      // their directory when the separator is /), as they always were.
+      let components: Vec<_> = components
+        .iter()
+        .map(|component| escape(component))
+        .collect();
+      (
+        path::PathBuf::from(format!(".{}", components.join("."))),
+        false,
+      )
+    };
+    if escaped {
+      self.warn_unescaped(mailbox, &components, &relative);
+    }
+    Maildir::new(self, relative, root, escaped)
+  }
+
+  // Older versions didn't escape anything: a mailbox named a/b with the . separator was stored in
+  // nested directories (.a/b with Maildir++). They're left alone, the messages there would
+  // otherwise be lost to the database, but can't be told apart from another mailbox's (a.b's with
+  // the fs layout) so the user has to move them.
+  fn warn_unescaped(&self, mailbox: &str, components: &[&str], relative: &path::Path) {
+    let unescaped = match self.layout {
+      Layout::MaildirPlusPlus => path::PathBuf::from(format!(".{}", components.join("."))),
+      Layout::Fs => components.iter().collect(),
+    };
+    if self.path.join(&unescaped).join("cur").is_dir() {
+      log::warn!(
+        "{unescaped:?} may have been created for {mailbox} by an older version, its messages now \
+         belong to {relative:?}"
+      );
+    }
+  }
+
+  // The maildirs found on disk besides the root's, relative to it, whether they belong to a mailbox
+  // or not.
+  pub fn maildirs(&self) -> io::Result<Vec<path::PathBuf>> {
+    let mut maildirs = Vec::new();
+    match self.layout {
+      // https://www.courier-mta.org/imap/README.maildirquota.html
+      // Within each subdirectory there's an empty file, maildirfolder.
+      Layout::MaildirPlusPlus => {
+        for entry in fs::read_dir(&self.path)? {
+          let entry = entry?;
+          if entry.file_type()?.is_dir()
+            && entry.file_name().to_string_lossy().starts_with('.')
+            && entry.path().join("maildirfolder").exists()
+          {
+            maildirs.push(path::PathBuf::from(entry.file_name()));
+          }
         }
       }
-      (self.path.join(directory), false)
-    } else {
-      // https://doc.dovecot.org/admin_manual/mailbox_formats/maildir/#directory-structure
-      // ~/Maildir/.folder/ is a mailbox folder.
-      (self.path.join(format!(".{mailbox}")), false)
+      Layout::Fs => fs_maildirs(&self.path, path::Path::new(""), &mut maildirs)?,
+    }
+    maildirs.sort(); // Stable order.
+    Ok(maildirs)
+  }
+
+  // The mailbox a maildir would belong to (the reverse of maildir), if it isn't ambiguous.
+  pub fn mailbox(&self, relative: &path::Path, separator: Option<char>) -> Option<String> {
+    let mut components: Vec<String> = match self.layout {
+      Layout::MaildirPlusPlus => relative
+        .to_str()?
+        .strip_prefix('.')?
+        .split('.')
+        .map(unescape)
+        .collect(),
+      Layout::Fs => relative
+        .iter()
+        .map(|component| component.to_str().map(unescape))
+        .collect::<Option<_>>()?,
+    };
+    match separator {
+      Some(separator) => Some(components.join(&separator.to_string())),
+      None if components.len() == 1 => components.pop(),
+      None => None,
+    }
+  }
+}
+
+// Subdirectories with a cur directory, hidden ones (e.g.: .notmuch) excepted. Symbolic links aren't
+// followed.
+fn fs_maildirs(
+  root: &path::Path,
+  relative: &path::Path,
+  maildirs: &mut Vec<path::PathBuf>,
+) -> io::Result<()> {
+  for entry in fs::read_dir(root.join(relative))? {
+    let entry = entry?;
+    let name = entry.file_name();
+    if !entry.file_type()?.is_dir()
+      || name.to_string_lossy().starts_with('.')
+      || ["cur", "new", "tmp"]
+        .iter()
+        .any(|directory| name == *directory)
+    {
+      continue;
+    }
+    let relative = relative.join(name);
+    if root.join(&relative).join("cur").is_dir() {
+      maildirs.push(relative.clone());
+    }
+    fs_maildirs(root, &relative, maildirs)?;
+  }
+  Ok(())
+}
+
+// What would otherwise change the directory structure: path separators (a mailbox named a/b when the
+// separator is .) and leading dots (., .. and hidden directories with the fs layout, an ambiguous
+// hierarchy with Maildir++). And the escape character itself, so a mailbox literally named %2F
+// doesn't share a directory with the one named /.
+fn needs_escaping(component: &str) -> bool {
+  component.starts_with('.') || component.contains('/') || component.contains('%')
+}
+
+// Percent-encoded, like URLs. The mapping isn't meant to be reversed (the name of the mailbox isn't
+// always recoverable from its directory anyway), the directory is recorded instead (see
+// sync::pull).
+fn escape(component: &str) -> borrow::Cow<'_, str> {
+  if !needs_escaping(component) {
+    return borrow::Cow::Borrowed(component);
+  }
+  let mut escaped = String::with_capacity(component.len() + 2);
+  for (i, c) in component.chars().enumerate() {
+    match c {
+      '.' if i == 0 => escaped.push_str("%2E"),
+      '/' => escaped.push_str("%2F"),
+      '%' => escaped.push_str("%25"),
+      c => escaped.push(c),
+    }
+  }
+  borrow::Cow::Owned(escaped)
+}
+
+fn unescape(component: &str) -> String {
+  let mut unescaped = String::with_capacity(component.len());
+  let mut rest = component;
+  while let Some((before, after)) = rest.split_once('%') {
+    unescaped.push_str(before);
+    let (c, after) = match after.get(..2) {
+      Some("2E") => ('.', &after[2..]),
+      Some("2F") => ('/', &after[2..]),
+      Some("25") => ('%', &after[2..]),
+      // Not escaped by this version.
+      _ => ('%', after),
     };
-    Maildir::new(path, root)
+    unescaped.push(c);
+    rest = after;
   }
+  unescaped.push_str(rest);
+  unescaped
 }
 
 impl Maildir {
   // Making this function pure (by deferring the setup) is more trouble than it's worth.
-  fn new(path: path::PathBuf, root: bool) -> io::Result<Self> {
+  fn new(
+    builder: &Builder,
+    relative: path::PathBuf,
+    root: bool,
+    escaped: bool,
+  ) -> io::Result<Self> {
+    let path = builder.path.join(&relative);
     fs::create_dir_all(&path)?;
     let path = path.canonicalize()?;
     for directory in &["cur", "new", "tmp"] {
       fs::create_dir_all(path.join(directory))?;
     }
-    if !root {
+    if !root && builder.layout == Layout::MaildirPlusPlus {
       // https://www.courier-mta.org/imap/README.maildirquota.html
       // Within each subdirectory there's an empty file, maildirfolder. Its existence tells the mail
       // delivery agent that this Maildir is a really a folder underneath a parent Maildir++.
       fs::File::create(path.join("maildirfolder"))?;
     }
-    Ok(Self { path, root })
+    Ok(Self {
+      path,
+      relative,
+      root,
+      escaped,
+      durability: builder.durability,
+      naming: builder.naming,
+      layout: builder.layout,
+    })
   }
 
   pub fn remove(self) -> io::Result<()> {
-    fs::remove_dir_all(self.path)
+    match self.layout {
+      Layout::MaildirPlusPlus => fs::remove_dir_all(self.path),
+      // The subfolders are nested, keep them.
+      Layout::Fs => {
+        for directory in ["cur", "new", "tmp"] {
+          fs::remove_dir_all(self.path.join(directory))?;
+        }
+        match fs::remove_dir(&self.path) {
+          Ok(_) => Ok(()),
+          Err(error) if error.kind() == io::ErrorKind::DirectoryNotEmpty => Ok(()),
+          Err(error) => Err(error),
+        }
+      }
+    }
   }
 
   pub fn root(&self) -> bool {
@@ -98,6 +414,15 @@ impl Maildir {
     self.path.as_path()
   }
 
+  pub fn escaped(&self) -> bool {
+    self.escaped
+  }
+
+  // Relative to the root maildir (empty for the root itself).
+  pub fn relative(&self) -> &path::Path {
+    self.relative.as_path()
+  }
+
   pub fn has(&self, path: &path::Path) -> bool {
     let parent = path.parent().expect("invalid email");
     self.path.join("cur") == parent
@@ -105,13 +430,69 @@ impl Maildir {
       || self.path.join("tmp") == parent
   }
 
-  pub fn tmp_named_with_size(&self, name: &str, size: u64) -> io::Result<Option<path::PathBuf>> {
-    let path = self.path.join("tmp").join(name);
-    match fs::metadata(&path) {
-      Ok(metadata) if metadata.len() == size => Ok(Some(path)),
-      Ok(_) => Ok(None),
-      Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
-      Err(error) => Err(error),
+  // The files left in tmp by tmp_named_with_digest, by name (there may be more than one if the
+  // content changed). The others (e.g.: another program's) aren't sin's to touch.
+  pub fn tmp_with_digest(&self) -> io::Result<collections::HashMap<String, Vec<path::PathBuf>>> {
+    let mut files: collections::HashMap<_, Vec<_>> = collections::HashMap::new();
+    for entry in fs::read_dir(self.path.join("tmp"))? {
+      let path = entry?.path();
+      if let Some((name, _)) = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .map(|name| name.rsplit_once(",K=").map_or(name, |(_, key)| key))
+        .and_then(|name| name.rsplit_once('_'))
+        .filter(|(_, digest)| {
+          digest.len() == 64 && digest.bytes().all(|byte| byte.is_ascii_hexdigit())
+        })
+      {
+        files.entry(name.to_string()).or_default().push(path);
+      }
+    }
+    Ok(files)
+  }
+
+  // Among some files returned by tmp_with_digest, the one that can be reused. The size alone
+  // wouldn't catch a truncated then padded or an otherwise different content after an interruption.
+  // The others are removed.
+  pub fn tmp_reusable(
+    &self,
+    files: Vec<path::PathBuf>,
+    size: u64,
+  ) -> io::Result<Option<path::PathBuf>> {
+    let mut reusable = None;
+    for path in files {
+      let digest = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.rsplit_once('_'))
+        .map(|(_, digest)| digest.to_string());
+      let matches = reusable.is_none()
+        && fs::metadata(&path)?.len() == size
+        && digest == Some(sha256(&fs::read(&path)?));
+      if matches {
+        reusable = Some(path);
+      } else {
+        log::debug!("removing unusable leftover {path:?}");
+        match fs::remove_file(&path) {
+          Ok(_) => (),
+          Err(error) if error.kind() == io::ErrorKind::NotFound => (),
+          Err(error) => Err(error)?,
+        }
+      }
+    }
+    Ok(reusable)
+  }
+
+  // The SHA-256 of the content is appended to the name: the transaction that would have recorded it
+  // in the database is rolled back by an interruption but the file stays.
+  pub fn tmp_named_with_digest(&self, name: &str, buffer: &[u8]) -> io::Result<path::PathBuf> {
+    let key = format!("{name}_{}", sha256(buffer));
+    match self.naming {
+      Naming::Sin => self.tmp_named(&key, buffer),
+      Naming::Conventional => self.tmp_named(
+        &format!("{},K={key}", conventional_name(buffer.len())),
+        buffer,
+      ),
     }
   }
 
@@ -122,6 +503,9 @@ impl Maildir {
     let mut file = fs::File::create(&path)?;
     file.write_all(buffer)?;
     file.sync_all()?;
+    if self.durability {
+      sync_directory(&self.path.join("tmp"))?;
+    }
     Ok(path)
   }
 
@@ -132,12 +516,33 @@ impl Maildir {
     // start with a dot. Do not try to extract information from unique names.
     //
     // 'Break' the 'standard' and just use an UUID (IDs should never be parsed) whenever the name
-    // wasn't explicitly given.
-    self.tmp_named(
+    // wasn't explicitly given, unless the conventional naming is wanted.
+    let name = match self.naming {
       // Ideally we'd use UUIDv7 (for the timestamp) but the uuid crate consider them unstable.
-      &uuid::Uuid::new_v4().hyphenated().to_string(),
-      buffer,
-    )
+      Naming::Sin => uuid::Uuid::new_v4().hyphenated().to_string(),
+      Naming::Conventional => conventional_name(buffer.len()),
+    };
+    self.tmp_named(&name, buffer)
+  }
+
+  // Move a file of another maildir to the same subdirectory (cur, new or tmp) of this one.
+  pub fn adopt(&self, path: &path::Path) -> io::Result<path::PathBuf> {
+    let (Some(parent), Some(file_name)) = (
+      path.parent().and_then(|parent| parent.file_name()),
+      path.file_name(),
+    ) else {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("{path:?} isn't in a maildir"),
+      ));
+    };
+    let adopted = self.path.join(parent).join(file_name);
+    fs::rename(path, &adopted)?;
+    if self.durability {
+      sync_directory(path.parent().unwrap())?; // Checked above.
+      sync_directory(adopted.parent().unwrap())?;
+    }
+    Ok(adopted)
   }
 
   // Should only be used in integration tests (hence, no #[cfg(test)]).
@@ -145,10 +550,34 @@ impl Maildir {
     let tmp = self.tmp(buffer)?;
     let cur = self.path.join("cur").join(tmp.file_name().unwrap());
     fs::rename(&tmp, &cur)?;
+    if self.durability {
+      sync_directories(&self.path)?;
+    }
     Ok(cur)
   }
 }
 
+fn conventional_name(size: usize) -> String {
+  format!(
+    "{}.{}_{}.{},S={size}",
+    time::SystemTime::now()
+      .duration_since(time::UNIX_EPOCH)
+      .unwrap_or_default()
+      .as_secs(),
+    process::id(),
+    SEQUENCE.fetch_add(1, atomic::Ordering::Relaxed),
+    *HOSTNAME,
+  )
+}
+
+fn sha256(buffer: &[u8]) -> String {
+  use sha2::Digest as _;
+  sha2::Sha256::digest(buffer)
+    .iter()
+    .map(|byte| format!("{byte:02x}"))
+    .collect()
+}
+
 pub fn components(path: &path::Path) -> anyhow::Result<[&path::Path; 3]> {
   let parent = path
     .parent()
@@ -252,4 +681,191 @@ mod tests {
 
     Ok(())
   }
+
+  #[test]
+  fn lock() -> anyhow::Result<()> {
+    let directory = tempfile::tempdir()?;
+    let builder = Builder::new(directory.path())?;
+    let lock = builder.lock("test", None)?;
+    assert!(builder.lock("test", None).is_err());
+    assert!(
+      builder
+        .lock("test", Some(time::Duration::from_millis(200)))
+        .is_err()
+    );
+    assert!(builder.lock("other", None).is_ok());
+    drop(lock);
+    assert!(builder.lock("test", None).is_ok());
+    Ok(())
+  }
+
+  #[test]
+  fn tmp_with_digest() -> anyhow::Result<()> {
+    let directory = tempfile::tempdir()?;
+    let maildir = Builder::new(directory.path())?.maildir("INBOX", &None)?;
+    let path = maildir.tmp_named_with_digest("test_1_2", b"content")?;
+    maildir.tmp(b"content")?;
+    maildir.tmp_named("test_1_2_abc", b"content")?;
+    let mut files = maildir.tmp_with_digest()?;
+    assert_eq!(1, files.len());
+    assert_eq!(
+      Some(path.clone()),
+      maildir.tmp_reusable(files.remove("test_1_2").unwrap(), 7)?
+    );
+
+    // Same size, different content.
+    fs::write(&path, b"CONTENT")?;
+    let mut files = maildir.tmp_with_digest()?;
+    assert_eq!(
+      None,
+      maildir.tmp_reusable(files.remove("test_1_2").unwrap(), 7)?
+    );
+    assert!(!path.exists());
+    Ok(())
+  }
+
+  #[test]
+  fn durability() -> anyhow::Result<()> {
+    let directory = tempfile::tempdir()?;
+    let mut builder = Builder::new(directory.path())?;
+    builder.set_durability(true);
+    let maildir = builder.maildir("folder", &None)?;
+    assert!(maildir.cur(b"content")?.exists());
+    Ok(())
+  }
+
+  #[test]
+  fn adopt() -> anyhow::Result<()> {
+    let directory = tempfile::tempdir()?;
+    let builder = Builder::new(directory.path())?;
+    let inbox = builder.maildir("INBOX", &None)?;
+    let folder = builder.maildir("folder", &None)?;
+    let path = inbox.cur(b"content")?;
+    let adopted = folder.adopt(&path)?;
+    assert_eq!(
+      folder.path.join("cur").join(path.file_name().unwrap()),
+      adopted
+    );
+    assert!(!path.exists() && adopted.exists());
+    Ok(())
+  }
+
+  #[test]
+  fn conventional_naming() -> anyhow::Result<()> {
+    let directory = tempfile::tempdir()?;
+    let mut builder = Builder::new(directory.path())?;
+    builder.set_naming(Naming::Conventional);
+    let maildir = builder.maildir("INBOX", &None)?;
+    let path = maildir.tmp(b"content")?;
+    let name = path.file_name().unwrap().to_str().unwrap();
+    assert!(name.ends_with(&format!(".{},S=7", *HOSTNAME)), "{name}");
+
+    let path = maildir.tmp_named_with_digest("test_1_2", b"content")?;
+    let name = path.file_name().unwrap().to_str().unwrap();
+    assert!(name.contains(",S=7,K=test_1_2_"), "{name}");
+    let mut files = maildir.tmp_with_digest()?;
+    assert_eq!(
+      Some(path.clone()),
+      maildir.tmp_reusable(files.remove("test_1_2").unwrap(), 7)?
+    );
+    Ok(())
+  }
+
+  #[test]
+  fn fs_layout() -> anyhow::Result<()> {
+    let directory = tempfile::tempdir()?;
+    let directory = directory.path();
+    let mut builder = Builder::new(directory)?;
+    builder.set_layout(Layout::Fs);
+
+    let maildir = builder.maildir("folder/subfolder", &Some('/'))?;
+    assert_eq!(directory.join("folder/subfolder"), maildir.path);
+    assert_eq!(path::Path::new("folder/subfolder"), maildir.relative());
+    assert!(!maildir.path.join("maildirfolder").exists());
+    let maildir = builder.maildir("folder", &Some('/'))?;
+    assert_eq!(directory.join("folder"), maildir.path);
+    assert!(builder.maildir("folder/tmp", &Some('/')).is_err());
+
+    // The subfolder is kept.
+    maildir.remove()?;
+    assert!(!directory.join("folder/cur").exists());
+    assert!(directory.join("folder/subfolder/cur").exists());
+    Ok(())
+  }
+
+  #[test]
+  fn escaping() -> anyhow::Result<()> {
+    let directory = tempfile::tempdir()?;
+    let directory = directory.path();
+    let mut builder = Builder::new(directory)?;
+
+    let maildir = builder.maildir("folder", &Some('.'))?;
+    assert!(!maildir.escaped());
+    let maildir = builder.maildir("a/b.c", &Some('.'))?;
+    assert_eq!(directory.join(".a%2Fb.c"), maildir.path);
+    assert!(maildir.escaped());
+    let maildir = builder.maildir("a/.b", &Some('/'))?;
+    assert_eq!(directory.join(".a.%2Eb"), maildir.path);
+
+    builder.set_layout(Layout::Fs);
+    let maildir = builder.maildir("a/b.c", &Some('.'))?;
+    assert_eq!(directory.join("a%2Fb/c"), maildir.path);
+    let maildir = builder.maildir("a/..", &Some('/'))?;
+    assert_eq!(directory.join("a/%2E."), maildir.path);
+    assert_eq!(path::Path::new("a/%2E."), maildir.relative());
+    let maildir = builder.maildir("%2F", &Some('/'))?;
+    assert_eq!(directory.join("%252F"), maildir.path);
+    assert!(maildir.escaped());
+    assert_eq!(
+      Some("%2F".to_string()),
+      builder.mailbox(path::Path::new("%252F"), Some('/'))
+    );
+    assert_eq!(
+      Some("100%.a/b".to_string()),
+      builder.mailbox(path::Path::new("100%25/a%2Fb"), Some('.'))
+    );
+    Ok(())
+  }
+
+  #[test]
+  fn maildirs() -> anyhow::Result<()> {
+    let directory = tempfile::tempdir()?;
+    let directory = directory.path();
+    let mut builder = Builder::new(directory)?;
+    builder.maildir("INBOX", &None)?;
+    builder.maildir("a/b", &Some('.'))?;
+    builder.maildir("c.d", &Some('.'))?;
+    fs::create_dir(directory.join(".notmuch"))?;
+    assert_eq!(
+      vec![path::PathBuf::from(".a%2Fb"), path::PathBuf::from(".c.d")],
+      builder.maildirs()?
+    );
+    assert_eq!(
+      Some("a/b".to_string()),
+      builder.mailbox(path::Path::new(".a%2Fb"), Some('.'))
+    );
+    assert_eq!(
+      Some("c/d".to_string()),
+      builder.mailbox(path::Path::new(".c.d"), Some('/'))
+    );
+    assert_eq!(None, builder.mailbox(path::Path::new(".c.d"), None));
+
+    builder.set_local_only(vec![path::PathBuf::from(".c.d"), path::PathBuf::from("e")]);
+    assert!(builder.local_only(path::Path::new(".c.d")));
+    assert!(!builder.local_only(path::Path::new(".c")));
+    assert!(builder.local_only(path::Path::new("e/%2Ef")));
+
+    builder.set_layout(Layout::Fs);
+    builder.maildir("e", &Some('/'))?;
+    builder.maildir("e/.f", &Some('/'))?;
+    assert_eq!(
+      vec![path::PathBuf::from("e"), path::PathBuf::from("e/%2Ef")],
+      builder.maildirs()?
+    );
+    assert_eq!(
+      Some("e/.f".to_string()),
+      builder.mailbox(path::Path::new("e/%2Ef"), Some('/'))
+    );
+    Ok(())
+  }
 }
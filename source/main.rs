@@ -1,8 +1,51 @@
-use clap::Parser as _;
-use std::path;
+use clap::{CommandFactory as _, FromArgMatches as _};
+use std::{io, path, str::FromStr as _, thread};
+
+mod logging;
+
+#[derive(Clone, Copy, PartialEq, clap::ValueEnum)]
+enum LogTarget {
+  Console,
+  File,
+  Syslog,
+  Journald,
+}
+
+// [<module>=]<level>, the bare level applies to everything else.
+#[derive(Clone)]
+struct Filter {
+  module: Option<String>,
+  level: log::LevelFilter,
+}
+
+fn parse_filter(filter: &str) -> anyhow::Result<Filter> {
+  let (module, level) = match filter.split_once('=') {
+    Some((module, level)) => (Some(module.to_string()), level),
+    None => (None, filter),
+  };
+  Ok(Filter {
+    module,
+    level: log::LevelFilter::from_str(level)
+      .map_err(|_| anyhow::anyhow!("unknown level {level:?}"))?,
+  })
+}
+
+#[derive(clap::Subcommand)]
+enum Command {
+  // For packagers, e.g.: sin completions bash > /usr/share/bash-completion/completions/sin
+  #[command(hide = true)]
+  Completions { shell: clap_complete::Shell },
+}
 
 #[derive(clap::Parser)]
+#[command(
+  args_conflicts_with_subcommands = true,
+  disable_help_subcommand = true,
+  subcommand_negates_reqs = true
+)]
 struct Arguments {
+  #[command(subcommand)]
+  command: Option<Command>,
   #[clap(flatten)]
   arguments: sin::Arguments,
   #[arg(
@@ -11,56 +54,113 @@ struct Arguments {
     default_value_t = String::from("$ENV{XDG_RUNTIME_DIR}")
   )]
   pub log_directory: String,
+  #[arg(
+    long = "log",
+    help = "Comma separated log levels, per module (e.g.: sin::imap=trace,info)",
+    value_delimiter = ',',
+    value_parser = parse_filter
+  )]
+  log: Vec<Filter>,
+  #[arg(
+    long = "log-target",
+    help = "Comma separated log destinations: console | file | syslog | journald",
+    value_delimiter = ',',
+    default_value = "console,file",
+    hide_possible_values(true)
+  )]
+  log_targets: Vec<LogTarget>,
   #[clap(flatten)]
   verbose: clap_verbosity_flag::Verbosity<clap_verbosity_flag::InfoLevel>,
 }
 
 fn main() -> anyhow::Result<()> {
-  let arguments = Arguments::parse();
+  let matches = Arguments::command().get_matches();
+  // The subcommand lifts the requirements of the other arguments, which then can't be parsed.
+  if matches.subcommand().is_some() {
+    let Command::Completions { shell } =
+      Command::from_arg_matches(&matches).unwrap_or_else(|error| error.exit());
+    clap_complete::generate(shell, &mut Arguments::command(), "sin", &mut io::stdout());
+    return Ok(());
+  }
+  let arguments = Arguments::from_arg_matches(&matches).unwrap_or_else(|error| error.exit());
 
+  let mut root = log::LevelFilter::Trace;
+  let mut loggers = Vec::new();
+  for filter in &arguments.log {
+    match &filter.module {
+      Some(module) => loggers.push(log4rs::config::Logger::builder().build(module, filter.level)),
+      None => root = filter.level,
+    }
+  }
+  let mut builder = log4rs::config::Config::builder();
+  let mut appenders = Vec::new();
+  for target in &arguments.log_targets {
+    let (name, threshold, appender): (_, _, Box<dyn log4rs::append::Append>) = match target {
+      LogTarget::Console => (
+        "console",
+        arguments.verbose.log_level_filter(),
+        Box::new(
+          log4rs::append::console::ConsoleAppender::builder()
+            .encoder(Box::new(log4rs::encode::pattern::PatternEncoder::new(
+              "{d(%F %T)} {l} {t} - {m}{n}",
+            )))
+            .build(),
+        ),
+      ),
+      LogTarget::File => (
+        "file",
+        log::LevelFilter::Trace,
+        Box::new(
+          log4rs::append::file::FileAppender::builder()
+            // The mailbox selected by the connection, if any (see imap::Stream::set_selected).
+            .encoder(Box::new(log4rs::encode::pattern::PatternEncoder::new(
+              "{d(%F %T)} {l} {t} {I} {X(mailbox)(-)} - {m}{n}",
+            )))
+            .build(
+              path::Path::new(&arguments.log_directory)
+                .join(format!("{}.log", arguments.arguments.namespace)),
+            )?,
+        ),
+      ),
+      // Like the console, the system logs shouldn't get the protocol unless asked.
+      LogTarget::Syslog => (
+        "syslog",
+        arguments.verbose.log_level_filter(),
+        Box::new(logging::Syslog::new()?),
+      ),
+      LogTarget::Journald => (
+        "journald",
+        arguments.verbose.log_level_filter(),
+        Box::new(logging::Journald::new()?),
+      ),
+    };
+    if appenders.contains(&name) {
+      continue;
+    }
+    builder = builder.appender(
+      log4rs::config::Appender::builder()
+        .filter(Box::new(log4rs::filter::threshold::ThresholdFilter::new(
+          threshold,
+        )))
+        .build(name, appender),
+    );
+    appenders.push(name);
+  }
   log4rs::init_config(
-    log4rs::config::Config::builder()
-      .appender(
-        log4rs::config::Appender::builder()
-          .filter(Box::new(log4rs::filter::threshold::ThresholdFilter::new(
-            log::LevelFilter::Trace,
-          )))
-          .build(
-            "file",
-            Box::new(
-              log4rs::append::file::FileAppender::builder()
-                .encoder(Box::new(log4rs::encode::pattern::PatternEncoder::new(
-                  "{d(%F %T)} {l} {t} {I} - {m}{n}",
-                )))
-                .build(
-                  path::Path::new(&arguments.log_directory)
-                    .join(format!("{}.log", arguments.arguments.namespace)),
-                )?,
-            ),
-          ),
-      )
-      .appender(
-        log4rs::config::Appender::builder()
-          .filter(Box::new(log4rs::filter::threshold::ThresholdFilter::new(
-            arguments.verbose.log_level_filter(),
-          )))
-          .build(
-            "console",
-            Box::new(
-              log4rs::append::console::ConsoleAppender::builder()
-                .encoder(Box::new(log4rs::encode::pattern::PatternEncoder::new(
-                  "{d(%F %T)} {l} {t} - {m}{n}",
-                )))
-                .build(),
-            ),
-          ),
-      )
-      .build(
-        log4rs::config::Root::builder()
-          .appenders(["console", "file"])
-          .build(log::LevelFilter::Trace),
-      )?,
+    builder.loggers(loggers).build(
+      log4rs::config::Root::builder()
+        .appenders(appenders)
+        .build(root),
+    )?,
   )?;
 
+  // On demand, what's being done (e.g.: when a long first pull looks stuck).
+  let mut signals = signal_hook::iterator::Signals::new([signal_hook::consts::SIGUSR1])?;
+  thread::spawn(move || {
+    for _ in signals.forever() {
+      sin::log_status();
+    }
+  });
+
   sin::run(&arguments.arguments)
 }
@@ -30,6 +30,13 @@ impl Error {
     )
   }
 
+  pub fn upgrade_required(&self) -> bool {
+    matches!(
+      self,
+      Error::Status(private::notmuch_status_t_NOTMUCH_STATUS_UPGRADE_REQUIRED)
+    )
+  }
+
   pub fn file_error(&self) -> bool {
     matches!(
       self,
@@ -78,8 +85,83 @@ fn path_to_cstring(path: &path::Path) -> Result<ffi::CString, Error> {
   ))
 }
 
+// A null pointer for None (Notmuch's default).
+fn cstring_ptr(cstring: &Option<ffi::CString>) -> *const ffi::c_char {
+  cstring
+    .as_ref() // Avoid freeing the CString...
+    .map(|cstring| cstring.as_ptr())
+    .unwrap_or(ptr::null())
+}
+
+// https://notmuchmail.org/doc/latest/man1/notmuch-config.html
+// The keys used by sin.
+#[derive(Clone, Copy, Debug)]
+pub enum Config {
+  DatabasePath,
+  HookDir,
+  ExcludeTags,
+  NewTags,
+  SynchronizeFlags,
+}
+
+impl convert::From<Config> for private::notmuch_config_key_t {
+  fn from(key: Config) -> Self {
+    match key {
+      Config::DatabasePath => private::notmuch_config_key_t_NOTMUCH_CONFIG_DATABASE_PATH,
+      Config::HookDir => private::notmuch_config_key_t_NOTMUCH_CONFIG_HOOK_DIR,
+      Config::ExcludeTags => private::notmuch_config_key_t_NOTMUCH_CONFIG_EXCLUDE_TAGS,
+      Config::NewTags => private::notmuch_config_key_t_NOTMUCH_CONFIG_NEW_TAGS,
+      Config::SynchronizeFlags => private::notmuch_config_key_t_NOTMUCH_CONFIG_SYNC_MAILDIR_FLAGS,
+    }
+  }
+}
+
+// https://notmuchmail.org/doc/latest/man1/notmuch-config.html#nmconfig-index.decrypt
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+pub enum Decrypt {
+  False,
+  True,
+  Auto,
+  Nostash,
+}
+
+impl convert::From<Decrypt> for private::notmuch_decryption_policy_t {
+  fn from(decrypt: Decrypt) -> Self {
+    match decrypt {
+      Decrypt::False => private::notmuch_decryption_policy_t_NOTMUCH_DECRYPT_FALSE,
+      Decrypt::True => private::notmuch_decryption_policy_t_NOTMUCH_DECRYPT_TRUE,
+      Decrypt::Auto => private::notmuch_decryption_policy_t_NOTMUCH_DECRYPT_AUTO,
+      Decrypt::Nostash => private::notmuch_decryption_policy_t_NOTMUCH_DECRYPT_NOSTASH,
+    }
+  }
+}
+
+// Defaults to the index.* configuration.
+struct IndexOptions(*mut private::notmuch_indexopts_t);
+
+impl ops::Drop for IndexOptions {
+  fn drop(&mut self) {
+    unsafe { private::notmuch_indexopts_destroy(self.0) }
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+  ReadOnly,
+  ReadWrite,
+}
+
+impl convert::From<Mode> for private::notmuch_database_mode_t {
+  fn from(mode: Mode) -> Self {
+    match mode {
+      Mode::ReadOnly => private::notmuch_database_mode_t_NOTMUCH_DATABASE_MODE_READ_ONLY,
+      Mode::ReadWrite => private::notmuch_database_mode_t_NOTMUCH_DATABASE_MODE_READ_WRITE,
+    }
+  }
+}
+
 #[derive(Debug)]
-pub struct Database(*mut private::notmuch_database_t);
+pub struct Database(*mut private::notmuch_database_t, Mode);
 
 impl ops::Drop for Database {
   fn drop(&mut self) {
@@ -93,40 +175,70 @@ impl ops::Drop for Database {
 }
 
 impl Database {
-  pub fn open(path: Option<&path::Path>) -> Result<Self, Error> {
-    let path = match path {
-      Some(path) => Some(path_to_cstring(path)?),
-      None => None,
-    };
-    let path = path
-      .as_ref() // Avoid freeing the CString...
-      .map(|p| p.as_ptr())
-      .unwrap_or(ptr::null());
+  // Without a configuration file or a profile, the user's (as opposed to --config ''): try to
+  // respect user settings but note that new.tags can't really be enforced.
+  pub fn open(
+    path: Option<&path::Path>,
+    config: Option<&path::Path>,
+    profile: Option<&str>,
+    mode: Mode,
+  ) -> Result<Self, Error> {
+    let path = path.map(path_to_cstring).transpose()?;
+    let config = config.map(path_to_cstring).transpose()?;
+    let profile = profile.map(str_to_cstring).transpose()?;
     let mut database = ptr::null_mut();
     match unsafe {
       private::notmuch_database_open_with_config(
-        path,
-        private::notmuch_database_mode_t_NOTMUCH_DATABASE_MODE_READ_WRITE,
-        // Load the user's configuration (as opposed to --config ''): try to respect user settings but
-        // note that new.tags can't really be enforced.
-        ptr::null(),
-        // Use the user's profile.
-        ptr::null(),
+        cstring_ptr(&path),
+        mode.into(),
+        cstring_ptr(&config),
+        cstring_ptr(&profile),
         &mut database,
         // No error message needed?
         ptr::null_mut(),
       )
     } {
-      private::notmuch_status_t_NOTMUCH_STATUS_SUCCESS => Ok(Self(database)),
+      private::notmuch_status_t_NOTMUCH_STATUS_SUCCESS => Ok(Self(database, mode)),
       status => Err(Error::Status(status)),
     }
   }
 
-  pub fn reopen(&mut self) -> Result<(), Error> {
+  pub fn mode(&self) -> Mode {
+    self.1
+  }
+
+  // Notmuch refuses most changes to a read-only database by itself (the messages' tags and
+  // properties) but not everything fails as early (or as clearly).
+  fn writable(&self) -> Result<(), Error> {
+    match self.1 {
+      Mode::ReadOnly => Err(Error::Status(
+        private::notmuch_status_t_NOTMUCH_STATUS_READ_ONLY_DATABASE,
+      )),
+      Mode::ReadWrite => Ok(()),
+    }
+  }
+
+  pub fn needs_upgrade(&self) -> bool {
+    unsafe { private::notmuch_database_needs_upgrade(self.0) != 0 }
+  }
+
+  // The progress (from 0 to 1) is periodically given to the closure.
+  pub fn upgrade<F>(&mut self, mut progress: F) -> Result<(), Error>
+  where
+    F: FnMut(f64),
+  {
+    unsafe extern "C" fn notify<F>(closure: *mut ffi::c_void, progress: f64)
+    where
+      F: FnMut(f64),
+    {
+      unsafe { (*(closure as *mut F))(progress) }
+    }
+    self.writable()?;
     match unsafe {
-      private::notmuch_database_reopen(
+      private::notmuch_database_upgrade(
         self.0,
-        private::notmuch_database_mode_t_NOTMUCH_DATABASE_MODE_READ_WRITE,
+        Some(notify::<F>),
+        &mut progress as *mut F as *mut ffi::c_void,
       )
     } {
       private::notmuch_status_t_NOTMUCH_STATUS_SUCCESS => Ok(()),
@@ -134,23 +246,34 @@ impl Database {
     }
   }
 
-  pub fn create(path: &path::Path) -> Result<Self, Error> {
+  pub fn reopen(&mut self) -> Result<(), Error> {
+    match unsafe { private::notmuch_database_reopen(self.0, self.1.into()) } {
+      private::notmuch_status_t_NOTMUCH_STATUS_SUCCESS => Ok(()),
+      status => Err(Error::Status(status)),
+    }
+  }
+
+  // Like open.
+  pub fn create(
+    path: &path::Path,
+    config: Option<&path::Path>,
+    profile: Option<&str>,
+  ) -> Result<Self, Error> {
     let path = path_to_cstring(path)?;
+    let config = config.map(path_to_cstring).transpose()?;
+    let profile = profile.map(str_to_cstring).transpose()?;
     let mut database = ptr::null_mut();
     match unsafe {
       private::notmuch_database_create_with_config(
         path.as_ptr(),
-        // Load the user's configuration (as opposed to --config ''): try to respect user settings but
-        // note that new.tags can't really be enforced.
-        ptr::null(),
-        // Use the user's profile.
-        ptr::null(),
+        cstring_ptr(&config),
+        cstring_ptr(&profile),
         &mut database,
         // No error message needed?
         ptr::null_mut(),
       )
     } {
-      private::notmuch_status_t_NOTMUCH_STATUS_SUCCESS => Ok(Self(database)),
+      private::notmuch_status_t_NOTMUCH_STATUS_SUCCESS => Ok(Self(database, Mode::ReadWrite)),
       status => Err(Error::Status(status)),
     }
   }
@@ -199,23 +322,50 @@ impl Database {
     Ok(Messages(query, messages, marker::PhantomData))
   }
 
+  fn index_options(&self, decrypt: Option<Decrypt>) -> Result<IndexOptions, Error> {
+    // https://github.com/notmuch/notmuch/blob/master/lib/notmuch.h
+    // Returns a newly-allocated indexopts object, or NULL on error.
+    let options = unsafe { private::notmuch_database_get_default_indexopts(self.0) };
+    if options.is_null() {
+      return Err(Error::Status(
+        private::notmuch_status_t_NOTMUCH_STATUS_OUT_OF_MEMORY,
+      ));
+    }
+    let options = IndexOptions(options);
+    if let Some(decrypt) = decrypt {
+      match unsafe { private::notmuch_indexopts_set_decrypt_policy(options.0, decrypt.into()) } {
+        private::notmuch_status_t_NOTMUCH_STATUS_SUCCESS => (),
+        status => return Err(Error::Status(status)),
+      }
+    }
+    Ok(options)
+  }
+
   // This doesn't look like it needs to be mut: it won't invalidate existing messages.
-  pub fn index_message(&'_ self, path: &path::Path) -> Result<Message<'_>, Error> {
+  // Also tells whether the message is new (as opposed to another file of a known message).
+  pub fn index_message(
+    &'_ self,
+    path: &path::Path,
+    decrypt: Option<Decrypt>,
+  ) -> Result<(Message<'_>, bool), Error> {
+    self.writable()?;
     let path = path_to_cstring(path)?;
+    let options = self.index_options(decrypt)?;
     let mut message = ptr::null_mut();
-    match unsafe {
-      private::notmuch_database_index_file(self.0, path.as_ptr(), ptr::null_mut(), &mut message)
+    let new = match unsafe {
+      private::notmuch_database_index_file(self.0, path.as_ptr(), options.0, &mut message)
     } {
-      private::notmuch_status_t_NOTMUCH_STATUS_SUCCESS
-      | private::notmuch_status_t_NOTMUCH_STATUS_DUPLICATE_MESSAGE_ID => (),
+      private::notmuch_status_t_NOTMUCH_STATUS_SUCCESS => true,
+      private::notmuch_status_t_NOTMUCH_STATUS_DUPLICATE_MESSAGE_ID => false,
       status => return Err(Error::Status(status)),
     };
     assert!(!message.is_null());
-    Ok(Message(message, marker::PhantomData))
+    Ok((Message(message, marker::PhantomData), new))
   }
 
   // This doesn't look like it needs to be mut: it won't invalidate existing messages.
   pub fn remove_message(&'_ self, path: &path::Path) -> Result<(), Error> {
+    self.writable()?;
     let path = path_to_cstring(path)?;
     match unsafe { private::notmuch_database_remove_message(self.0, path.as_ptr()) } {
       private::notmuch_status_t_NOTMUCH_STATUS_SUCCESS
@@ -254,6 +404,70 @@ impl Database {
     path::Path::new(osstr)
   }
 
+  // https://github.com/notmuch/notmuch/blob/master/lib/notmuch.h
+  // Return value is a string owned by notmuch which should not be modified nor freed by the caller.
+  // If the key is unset, return NULL.
+  fn config(&self, key: Config) -> Option<&ffi::CStr> {
+    unsafe {
+      let value = private::notmuch_config_get(self.0, key.into());
+      (!value.is_null()).then(|| ffi::CStr::from_ptr(value))
+    }
+  }
+
+  pub fn config_path(&self, key: Config) -> Option<&path::Path> {
+    self
+      .config(key)
+      .map(|value| path::Path::new(ffi::OsStr::from_bytes(value.to_bytes())))
+  }
+
+  // For the lists (e.g.: search.exclude_tags).
+  pub fn config_values(&self, key: Config) -> Result<Vec<String>, Error> {
+    let mut result = Vec::new();
+    let values = unsafe { private::notmuch_config_get_values(self.0, key.into()) };
+    // NULL is handled by notmuch_config_values_valid.
+    while unsafe { private::notmuch_config_values_valid(values) } != 0 {
+      let value = unsafe {
+        let value = private::notmuch_config_values_get(values);
+        assert!(!value.is_null());
+        ffi::CStr::from_ptr(value).to_str()
+      };
+      match value {
+        Ok(value) => result.push(value.to_string()),
+        Err(error) => {
+          unsafe { private::notmuch_config_values_destroy(values) };
+          return Err(error.into());
+        }
+      }
+      unsafe { private::notmuch_config_values_move_to_next(values) };
+    }
+    unsafe { private::notmuch_config_values_destroy(values) };
+    Ok(result)
+  }
+
+  pub fn config_bool(&self, key: Config) -> Result<bool, Error> {
+    let mut value = 0;
+    match unsafe { private::notmuch_config_get_bool(self.0, key.into(), &mut value) } {
+      private::notmuch_status_t_NOTMUCH_STATUS_SUCCESS => Ok(value != 0),
+      status => Err(Error::Status(status)),
+    }
+  }
+
+  // Only the database's configuration is updated: the configuration file takes precedence.
+  pub fn set_config(&mut self, key: Config, value: &str) -> Result<(), Error> {
+    self.writable()?;
+    let value = str_to_cstring(value)?;
+    match unsafe { private::notmuch_config_set(self.0, key.into(), value.as_ptr()) } {
+      private::notmuch_status_t_NOTMUCH_STATUS_SUCCESS => Ok(()),
+      status => Err(Error::Status(status)),
+    }
+  }
+
+  pub fn database_path(&self) -> &path::Path {
+    self
+      .config_path(Config::DatabasePath)
+      .unwrap_or_else(|| self.path())
+  }
+
   pub fn lastmod(&self) -> u64 {
     unsafe { private::notmuch_database_get_revision(self.0, ptr::null_mut()) }
   }
@@ -393,6 +607,26 @@ impl<'a> Message<'a> {
     }
   }
 
+  // https://github.com/notmuch/notmuch/blob/master/lib/notmuch.h
+  // Freeze the current state of 'message' within the database. This means that changes to the
+  // message state, (via notmuch_message_add_tag, notmuch_message_remove_tag, and
+  // notmuch_message_remove_all_tags), will not be committed to the database until the message is
+  // thawed with notmuch_message_thaw.
+  // The properties are handled the same way (see lib/message-property.cc).
+  pub fn freeze(&mut self) -> Result<(), Error> {
+    match unsafe { private::notmuch_message_freeze(self.0) } {
+      private::notmuch_status_t_NOTMUCH_STATUS_SUCCESS => Ok(()),
+      status => Err(Error::Status(status)),
+    }
+  }
+
+  pub fn thaw(&mut self) -> Result<(), Error> {
+    match unsafe { private::notmuch_message_thaw(self.0) } {
+      private::notmuch_status_t_NOTMUCH_STATUS_SUCCESS => Ok(()),
+      status => Err(Error::Status(status)),
+    }
+  }
+
   pub fn tags_to_maildir_flags(&mut self) -> Result<(), Error> {
     match unsafe { private::notmuch_message_tags_to_maildir_flags(self.0) } {
       private::notmuch_status_t_NOTMUCH_STATUS_SUCCESS => Ok(()),
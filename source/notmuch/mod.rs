@@ -1,14 +1,97 @@
 // TODO: property keys containing '=' will be refused by Notmuch.
 
-use std::{cmp, collections, fs, io::Write as _, path};
+use std::{
+  cmp, collections, fs,
+  io::{self, Write as _},
+  path,
+};
 
 mod bindings;
-pub use bindings::Error;
+pub use bindings::{Decrypt, Error, Mode};
 
 // Ideally, something that doesn't need quoting.
 pub const ROOT_MARKER: &str = "root";
 pub const MESSAGE_MARKER: &str = "message";
 
+// The layout of the properties, recorded on the root: bumped along with a migration whenever it
+// changes, so a newer layout is never misread and an older one never duplicated.
+pub const SCHEMA: u64 = 1;
+
+type Migration = fn(&mut Database<Detached>, &path::Path, u64) -> anyhow::Result<()>;
+
+// From each schema to the next, given the maildir and its root's ID (see
+// Database<Detached>::migrate_schema). They must be idempotent: a root indexed again starts over
+// from 0 (see Database<Detached>::restore_root).
+const MIGRATIONS: [Migration; SCHEMA as usize] = [migrate_from_0];
+
+// Before the schema was recorded:
+//  - the modseq of a message wasn't scoped by UID (there was a single one, before duplicates),
+//  - the uidnext of the mailboxes wasn't recorded (the UIDs known locally are below it),
+//  - the mailboxes of the personal namespace were recorded with its prefix (only the server knows
+//    it, they're renamed on the next synchronization, see sync::strip_prefix).
+fn migrate_from_0(
+  database: &mut Database<Detached>,
+  path: &path::Path,
+  id: u64,
+) -> anyhow::Result<()> {
+  let namespace = format!("{}.{id}", database.state.namespace);
+  let mut uidnexts: collections::HashMap<String, u64> = collections::HashMap::new();
+  let mut messages = database
+    .inner
+    .query(&format!("property:{namespace}.marker={MESSAGE_MARKER}"))?;
+  while let Some(inner) = messages.next() {
+    let mut message = Message {
+      inner,
+      namespace: &namespace,
+    };
+    let mailboxes: Vec<String> = message.mailboxes()?.into_iter().map(String::from).collect();
+    // Written at once (see Message::update_mailbox_properties).
+    message.inner.freeze()?;
+    for mailbox in mailboxes {
+      let uids = message.uids(&mailbox)?;
+      if let Some(uid) = uids.last() {
+        let uidnext = uidnexts.entry(mailbox.clone()).or_default();
+        *uidnext = cmp::max(*uidnext, uid + 1);
+      }
+      let property_ = format!("{mailbox}.modseq");
+      if let Some(modseq) = property(&message.inner, &namespace, &property_)?.map(String::from) {
+        for uid in uids {
+          replace_property(
+            &mut message.inner,
+            &namespace,
+            &format!("{mailbox}.modseq.{uid}"),
+            None,
+            Some(&modseq),
+          )?;
+        }
+        replace_property(&mut message.inner, &namespace, &property_, None, None)?;
+      }
+    }
+    message.inner.thaw()?;
+  }
+  drop(messages);
+
+  let mut root = database
+    .find(&path.join(&database.state.namespace))?
+    .unwrap(); // Guaranteed by attach.
+  let mailboxes: Vec<String> = root.mailboxes()?.into_iter().map(String::from).collect();
+  for mailbox in &mailboxes {
+    let property_ = format!("{mailbox}.uidnext");
+    if property(&root.inner, root.namespace, &property_)?.is_none() {
+      if let Some(uidnext) = uidnexts.get(mailbox) {
+        replace_property(
+          &mut root.inner,
+          root.namespace,
+          &property_,
+          None,
+          Some(&uidnext.to_string()),
+        )?;
+      }
+    }
+  }
+  root.update_prefixed(!mailboxes.is_empty())
+}
+
 pub fn quote(str: &str) -> String {
   // Properties are just regular terms and should be quoted when they have spaces:
   //  notmuch --config '' search 'property:"sin.folder with spaces.highestmodseq=2"'
@@ -103,7 +186,7 @@ impl<'a> RootMessage<'a> {
     // is only ever returned when the server doesn't support persistent storage. If this ever ends
     // up refused by a server, we could instead go for (2^32-1, 2^63−1) because a server that
     // reaches these values would have painted itself in the corner anyway and would need to wrap
-    // over:
+    // over (see sync::reassigned for one that wouldn't change its uidvalidity):
     //
     // https://www.rfc-editor.org/rfc/rfc3501#section-2.3.1.1
     // A [UID] 32-bit value assigned to each message, which when used with the unique identifier
@@ -132,11 +215,22 @@ impl<'a> RootMessage<'a> {
     Ok((uidvalidity, highestmodseq))
   }
 
+  pub fn uidnext(&self, mailbox: &str) -> anyhow::Result<u64> {
+    // An uidnext of 0 is not supported by the BNF (nz-number) either: every UID is above it.
+    Ok(
+      property(&self.inner, self.namespace, &format!("{mailbox}.uidnext"))?
+        .unwrap_or("0")
+        .parse()
+        .unwrap(), // Guaranteed by update_mailbox_properties.
+    )
+  }
+
   pub fn update_mailbox_properties(
     &mut self,
     mailbox: &str,
     separator: Option<char>,
     uidvalidity: u64,
+    uidnext: u64,
     highestmodseq: u64,
   ) -> anyhow::Result<()> {
     for (property, old_value, new_value) in [
@@ -151,6 +245,11 @@ impl<'a> RootMessage<'a> {
         None,
         Some(uidvalidity.to_string().as_str()),
       ),
+      (
+        &format!("{mailbox}.uidnext"),
+        None,
+        Some(uidnext.to_string().as_str()),
+      ),
       (
         &format!("{mailbox}.highestmodseq"),
         None,
@@ -177,6 +276,72 @@ impl<'a> RootMessage<'a> {
     )
   }
 
+  // 0 for the roots created before it was recorded.
+  pub fn schema(&self) -> anyhow::Result<u64> {
+    Ok(
+      property(&self.inner, self.namespace, "schema")?
+        .unwrap_or("0")
+        .parse()
+        .unwrap(), // Guaranteed by update_schema.
+    )
+  }
+
+  fn update_schema(&mut self, schema: u64) -> anyhow::Result<()> {
+    replace_property(
+      &mut self.inner,
+      self.namespace,
+      "schema",
+      None,
+      Some(&schema.to_string()),
+    )
+  }
+
+  // Whether the mailboxes may still be recorded with the personal namespace's prefix (see
+  // migrate_from_0).
+  pub fn prefixed(&self) -> anyhow::Result<bool> {
+    Ok(property(&self.inner, self.namespace, "prefixed")?.is_some())
+  }
+
+  pub fn update_prefixed(&mut self, prefixed: bool) -> anyhow::Result<()> {
+    replace_property(
+      &mut self.inner,
+      self.namespace,
+      "prefixed",
+      None,
+      prefixed.then_some("true"),
+    )
+  }
+
+  // Move the properties of a mailbox to another name, its maildir is recorded again by the next
+  // pull (see update_maildir).
+  pub fn rename_mailbox(&mut self, old_mailbox: &str, mailbox: &str) -> anyhow::Result<()> {
+    for property_ in ["separator", "uidvalidity", "uidnext", "highestmodseq"] {
+      let value = property(
+        &self.inner,
+        self.namespace,
+        &format!("{old_mailbox}.{property_}"),
+      )?
+      .map(String::from);
+      if let Some(value) = value {
+        replace_property(
+          &mut self.inner,
+          self.namespace,
+          &format!("{mailbox}.{property_}"),
+          None,
+          Some(&value),
+        )?;
+      }
+    }
+    self.remove_mailbox_properties(old_mailbox)?;
+    replace_property(
+      &mut self.inner,
+      self.namespace,
+      "mailbox",
+      Some(mailbox),
+      Some(mailbox),
+    )
+  }
+
   pub fn update_lastmod(&mut self, lastmod: u64) -> anyhow::Result<()> {
     replace_property(
       &mut self.inner,
@@ -192,8 +357,10 @@ impl<'a> RootMessage<'a> {
       ("mailbox", Some(mailbox)),
       // The mailbox properties.
       (&format!("{mailbox}.uidvalidity"), None),
+      (&format!("{mailbox}.uidnext"), None),
       (&format!("{mailbox}.highestmodseq"), None),
       (&format!("{mailbox}.separator"), None),
+      (&format!("{mailbox}.maildir"), None),
     ] {
       replace_property(&mut self.inner, self.namespace, property, old_value, None)?;
     }
@@ -204,6 +371,67 @@ impl<'a> RootMessage<'a> {
     properties(&self.inner, self.namespace, "mailbox")
   }
 
+  pub fn server(&self) -> anyhow::Result<Option<(String, u16)>> {
+    property(&self.inner, self.namespace, "server")?
+      .map(parse_server)
+      .transpose()
+  }
+
+  pub fn update_server(&mut self, address: &str, port: u16) -> anyhow::Result<()> {
+    replace_property(
+      &mut self.inner,
+      self.namespace,
+      "server",
+      None,
+      Some(&format!("{address}:{port}")),
+    )
+  }
+
+  // The account (user@host) the maildir is synchronized with, to recognize it once moved (see
+  // Database<Detached>::relocate).
+  pub fn account(&self) -> anyhow::Result<Option<&str>> {
+    property(&self.inner, self.namespace, "account")
+  }
+
+  pub fn update_account(&mut self, account: &str) -> anyhow::Result<()> {
+    replace_property(
+      &mut self.inner,
+      self.namespace,
+      "account",
+      None,
+      Some(account),
+    )
+  }
+
+  pub fn mailbox_tag(&self) -> anyhow::Result<Option<&str>> {
+    property(&self.inner, self.namespace, "mailbox_tag")
+  }
+
+  pub fn update_mailbox_tag(&mut self, template: Option<&str>) -> anyhow::Result<()> {
+    replace_property(
+      &mut self.inner,
+      self.namespace,
+      "mailbox_tag",
+      None,
+      template,
+    )
+  }
+
+  // The directory of a mailbox whose name had to be escaped, relative to the maildir's root.
+  pub fn maildir(&self, mailbox: &str) -> anyhow::Result<Option<&str>> {
+    property(&self.inner, self.namespace, &format!("{mailbox}.maildir"))
+  }
+
+  pub fn update_maildir(&mut self, mailbox: &str, maildir: Option<&str>) -> anyhow::Result<()> {
+    replace_property(
+      &mut self.inner,
+      self.namespace,
+      &format!("{mailbox}.maildir"),
+      None,
+      maildir,
+    )
+  }
+
   pub fn separator(&self, mailbox: &str) -> anyhow::Result<Option<char>> {
     Ok(
       property(&self.inner, self.namespace, &format!("{mailbox}.separator"))?
@@ -228,23 +456,41 @@ impl<'a> Message<'a> {
     properties(&self.inner, self.namespace, "mailbox")
   }
 
-  pub fn uid(&self, mailbox: &str) -> anyhow::Result<u64> {
+  pub fn uidvalidity(&self, mailbox: &str) -> anyhow::Result<u64> {
     Ok(
-      property(&self.inner, self.namespace, &format!("{mailbox}.uid"))?
-        // Guaranteed by update_mailbox_properties.
-        .unwrap()
-        .parse()
-        .unwrap(),
+      property(
+        &self.inner,
+        self.namespace,
+        &format!("{mailbox}.uidvalidity"),
+      )?
+      // Guaranteed by update_mailbox_properties.
+      .unwrap()
+      .parse()
+      .unwrap(),
     )
   }
 
-  pub fn modseq(&self, mailbox: &str) -> anyhow::Result<u64> {
+  // Usually a single one but the same message (Message-ID) may be duplicated in a mailbox.
+  pub fn uids(&self, mailbox: &str) -> anyhow::Result<Vec<u64>> {
+    let mut uids: Vec<u64> = properties(&self.inner, self.namespace, &format!("{mailbox}.uid"))?
+      .into_iter()
+      .map(|uid| uid.parse().unwrap()) // Guaranteed by update_mailbox_properties.
+      .collect();
+    uids.sort();
+    Ok(uids)
+  }
+
+  pub fn modseq(&self, mailbox: &str, uid: u64) -> anyhow::Result<u64> {
     Ok(
-      property(&self.inner, self.namespace, &format!("{mailbox}.modseq"))?
-        // Guaranteed by update_mailbox_properties.
-        .unwrap()
-        .parse()
-        .unwrap(),
+      property(
+        &self.inner,
+        self.namespace,
+        &format!("{mailbox}.modseq.{uid}"),
+      )?
+      // Guaranteed by update_mailbox_properties.
+      .unwrap()
+      .parse()
+      .unwrap(),
     )
   }
 
@@ -262,17 +508,33 @@ impl<'a> Message<'a> {
 
   pub fn remove_mailbox_properties(&mut self, mailbox: &str) -> anyhow::Result<()> {
     let namespace = self.namespace;
+    // Written at once (see update_mailbox_properties).
+    self.inner.freeze()?;
     for (property, old_value) in [
       // The affected mailbox.
       ("mailbox", Some(mailbox)),
       // The mailbox properties.
       (&format!("{mailbox}.uidvalidity"), None),
-      (&format!("{mailbox}.uid"), None),
-      (&format!("{mailbox}.modseq"), None),
       (&format!("{mailbox}.tag"), None),
     ] {
       replace_property(&mut self.inner, namespace, property, old_value, None)?;
     }
+    for uid in self.uids(mailbox)? {
+      replace_property(
+        &mut self.inner,
+        namespace,
+        &format!("{mailbox}.modseq.{uid}"),
+        None,
+        None,
+      )?;
+    }
+    replace_property(
+      &mut self.inner,
+      namespace,
+      &format!("{mailbox}.uid"),
+      None,
+      None,
+    )?;
     // The marker when there's nothing left.
     let mut count = 0;
     {
@@ -284,6 +546,53 @@ impl<'a> Message<'a> {
     if count == 1 {
       replace_property(&mut self.inner, namespace, "marker", None, None)?;
     }
+    self.inner.thaw()?;
+    Ok(())
+  }
+
+  // Move the properties of a mailbox to another name (see RootMessage::rename_mailbox).
+  pub fn rename_mailbox(&mut self, old_mailbox: &str, mailbox: &str) -> anyhow::Result<()> {
+    let uids = self.uids(old_mailbox)?;
+    let mut properties_ = Vec::new();
+    for property_ in ["uidvalidity", "uid", "tag"] {
+      for value in properties(
+        &self.inner,
+        self.namespace,
+        &format!("{old_mailbox}.{property_}"),
+      )? {
+        properties_.push((property_.to_string(), value.to_string()));
+      }
+    }
+    for uid in uids {
+      let property_ = format!("modseq.{uid}");
+      if let Some(value) = property(
+        &self.inner,
+        self.namespace,
+        &format!("{old_mailbox}.{property_}"),
+      )? {
+        properties_.push((property_, value.to_string()));
+      }
+    }
+    // Written at once (see update_mailbox_properties).
+    self.inner.freeze()?;
+    for (property_, value) in properties_ {
+      replace_property(
+        &mut self.inner,
+        self.namespace,
+        &format!("{mailbox}.{property_}"),
+        Some(&value),
+        Some(&value),
+      )?;
+    }
+    replace_property(
+      &mut self.inner,
+      self.namespace,
+      "mailbox",
+      Some(mailbox),
+      Some(mailbox),
+    )?;
+    self.remove_mailbox_properties(old_mailbox)?;
+    self.inner.thaw()?;
     Ok(())
   }
 
@@ -295,20 +604,19 @@ impl<'a> Message<'a> {
     modseq: u64,
     tags: &collections::HashSet<&str>,
   ) -> anyhow::Result<()> {
-    // TODO? should these properties be multi-valued? I'm not sure what it would bring to the
-    // table...
-    if let Ok(Some(current_uidvalidity)) = property(
+    // Every property and tag is a term of the same Xapian document: it's only written once thawed
+    // rather than after each change. Left frozen on error, the changes are dropped with the message.
+    self.inner.freeze()?;
+    if let Some(current_uidvalidity) = property(
       &self.inner,
       self.namespace,
       &format!("{mailbox}.uidvalidity"),
-    ) {
-      if current_uidvalidity.parse::<u64>().unwrap() == uidvalidity && self.uid(mailbox)? != uid {
-        log::warn!(
-          "message {} has duplicates in {mailbox} but the property system doesn't handle this \
-           edge case currently and if it did, all flags would end up the same given how Notmuch \
-           handles them (get rid of this warning by removing the duplicates)",
-          self.message_id()?
-        );
+    )? {
+      // The UIDs of another UIDVALIDITY are meaningless.
+      if current_uidvalidity.parse::<u64>().unwrap() != uidvalidity {
+        for uid in self.uids(mailbox)? {
+          self.replace_uid(mailbox, uid, None)?;
+        }
       }
     }
 
@@ -323,12 +631,6 @@ impl<'a> Message<'a> {
         None,
         Some(&uidvalidity.to_string()),
       ),
-      (&format!("{mailbox}.uid"), None, Some(&uid.to_string())),
-      (
-        &format!("{mailbox}.modseq"),
-        None,
-        Some(&modseq.to_string()),
-      ),
     ] {
       replace_property(
         &mut self.inner,
@@ -338,6 +640,7 @@ impl<'a> Message<'a> {
         new_value,
       )?;
     }
+    self.replace_uid(mailbox, uid, Some(modseq))?;
     // Update the current tags and the cached copy (so local changes can be detected).
     let cached_tags: Vec<String> = self
       .cached_tags(mailbox)?
@@ -360,9 +663,47 @@ impl<'a> Message<'a> {
       )?;
       self.inner.add_tag(tag)?;
     }
+    self.inner.thaw()?;
+    Ok(())
+  }
+
+  // Forget one of the UIDs (the others are left alone). Returns the number of remaining ones, the
+  // caller is expected to remove the mailbox properties when there's none.
+  pub fn remove_uid(&mut self, mailbox: &str, uid: u64) -> anyhow::Result<usize> {
+    self.replace_uid(mailbox, uid, None)?;
+    Ok(self.uids(mailbox)?.len())
+  }
+
+  fn replace_uid(&mut self, mailbox: &str, uid: u64, modseq: Option<u64>) -> anyhow::Result<()> {
+    let value = uid.to_string();
+    let modseq = modseq.map(|modseq| modseq.to_string());
+    for (property, old_value, new_value) in [
+      (
+        &format!("{mailbox}.uid"),
+        Some(value.as_str()),
+        modseq.as_ref().map(|_| value.as_str()),
+      ),
+      (&format!("{mailbox}.modseq.{uid}"), None, modseq.as_deref()),
+    ] {
+      replace_property(
+        &mut self.inner,
+        self.namespace,
+        property,
+        old_value,
+        new_value,
+      )?;
+    }
     Ok(())
   }
 
+  pub fn add_tag(&mut self, tag: &str) -> anyhow::Result<()> {
+    Ok(self.inner.add_tag(tag)?)
+  }
+
+  pub fn remove_tag(&mut self, tag: &str) -> anyhow::Result<()> {
+    Ok(self.inner.remove_tag(tag)?)
+  }
+
   pub fn tags_to_maildir_flags(&mut self) -> anyhow::Result<()> {
     // If this message is in a maildir, rename it to reflect the updated flags.
     self.inner.tags_to_maildir_flags()?;
@@ -420,7 +761,10 @@ impl<S> Database<S> {
     // https://github.com/vhdirk/notmuch-rs/blob/master/src/database.rs#L498
     // AtomicOperation implements Drop, it's not suitable for our usage: we shouldn't commit if
     // anything failed at all.
-    match body(self) {
+    match body(self).and_then(|result| {
+      crate::fault::notmuch("commit")?;
+      Ok(result)
+    }) {
       Ok(result) => {
         // https://github.com/notmuch/notmuch/blob/master/lib/notmuch.h
         // Indicate the end of an atomic database operation. If repeated (with matching
@@ -452,6 +796,7 @@ impl<S> Database<S> {
   }
 
   pub fn remove(&self, path: &path::Path) -> anyhow::Result<()> {
+    crate::fault::notmuch("remove")?;
     self.inner.remove_message(path)?;
     Ok(())
   }
@@ -463,44 +808,123 @@ impl<S> Database<S> {
   pub fn lastmod(&self) -> u64 {
     self.inner.lastmod()
   }
+
+  // https://notmuchmail.org/doc/latest/man1/notmuch-config.html#nmconfig-database.hook_dir
+  pub fn hook_dir(&self) -> Option<path::PathBuf> {
+    self
+      .inner
+      .config_path(bindings::Config::HookDir)
+      .map(path::Path::to_path_buf)
+  }
+
+  pub fn xapian_path(&self) -> path::PathBuf {
+    // https://notmuchmail.org/doc/latest/man1/notmuch-config.html#nmconfig-database.path
+    // Notmuch will store its database here, (in sub-directory named .notmuch if database.mail_root
+    // is unset).
+    let path = self.inner.database_path();
+    match path.join("xapian") {
+      xapian if xapian.is_dir() => xapian,
+      _ => path.join(".notmuch").join("xapian"),
+    }
+  }
 }
 
 pub struct Detached {
   namespace: String,
+  account: Option<String>,
 }
 
 impl Database<Detached> {
-  pub fn open(path: Option<&path::Path>, namespace: &str) -> anyhow::Result<Database<Detached>> {
+  // The configuration file and the profile default to the user's (see notmuch-config(1)).
+  pub fn open(
+    path: Option<&path::Path>,
+    config: Option<&path::Path>,
+    profile: Option<&str>,
+    namespace: &str,
+    mode: Mode,
+  ) -> anyhow::Result<Database<Detached>> {
     Ok(Database::<Detached> {
-      inner: bindings::Database::open(path)?,
+      inner: bindings::Database::open(path, config, profile, mode)?,
       transaction: false,
       state: Detached {
         namespace: namespace.to_string(),
+        account: None,
       },
     })
   }
 
-  pub fn create(path: &path::Path, namespace: &str) -> anyhow::Result<Database<Detached>> {
+  pub fn create(
+    path: &path::Path,
+    config: Option<&path::Path>,
+    profile: Option<&str>,
+    namespace: &str,
+  ) -> anyhow::Result<Database<Detached>> {
     fs::create_dir_all(path)?;
     Ok(Database::<Detached> {
-      inner: bindings::Database::create(path)?,
+      inner: bindings::Database::create(path, config, profile)?,
       transaction: false,
       state: Detached {
         namespace: namespace.to_string(),
+        account: None,
       },
     })
   }
 
+  // Databases created by older versions of Notmuch can't be written to before being upgraded.
+  pub fn upgrade(&mut self, allowed: bool) -> anyhow::Result<()> {
+    if !self.inner.needs_upgrade() {
+      return Ok(());
+    }
+    anyhow::ensure!(
+      allowed,
+      "the database needs to be upgraded, pass --upgrade or run notmuch new"
+    );
+    log::info!("upgrading the database");
+    let mut reported = 0;
+    self.inner.upgrade(|progress| {
+      let percent = (progress * 100.) as u32;
+      if percent >= reported + 10 {
+        log::info!("upgrading the database: {percent}%");
+        reported = percent;
+      }
+    })?;
+    log::info!("upgraded the database");
+    Ok(())
+  }
+
+  pub fn set_account(&mut self, account: &str) {
+    self.state.account = Some(account.to_string());
+  }
+
   pub fn attach(mut self, path: &path::Path) -> anyhow::Result<Database<Attached>> {
     let root_path = path.join(&self.state.namespace);
     let id = match self.find(&root_path)? {
-      Some(message) => Some(message.id()?),
+      Some(message) => Some((
+        message.id()?,
+        // Roots created before the account was recorded, or whose user or server changed.
+        self.state.account.is_some() && message.account()? != self.state.account.as_deref(),
+      )),
       None => None, // The borrow checker doesn't like calling self.add(&root_path) here.
     };
     let id = match id {
-      Some(id) => id,
-      None => self.add(&root_path)?,
+      Some((id, true)) if self.inner.mode() == Mode::ReadWrite => {
+        self.transaction(|database| {
+          let account = database.state.account.clone().unwrap();
+          database.find(&root_path)?.unwrap().update_account(&account)
+        })?;
+        id
+      }
+      Some((id, _)) => id,
+      None if self.inner.mode() == Mode::ReadOnly => anyhow::bail!(
+        "{} hasn't been synchronized yet (the database is opened read-only)",
+        path.display()
+      ),
+      None => match self.relocate(path)? {
+        Some(id) => id,
+        None => self.add(&root_path)?,
+      },
     };
+    self.migrate_schema(path, id)?;
     let namespace = format!("{}.{id}", self.state.namespace);
     Ok(Database::<Attached> {
       inner: self.inner,
@@ -509,6 +933,7 @@ impl Database<Detached> {
         detached: self.state,
         path: path.to_path_buf(),
         namespace,
+        decrypt: None,
       },
     })
   }
@@ -534,6 +959,14 @@ impl Database<Detached> {
         .collect::<collections::HashSet<_>>()
         .difference(&ids)
       {
+        // Another maildir's root may only have been dropped from the database, its state would be
+        // lost: only IDs whose root file is gone too are reused.
+        if let Some(root) = database.unindexed_root(*id)? {
+          anyhow::bail!(
+            "{root:?} ({id}@{namespace}) isn't in the database anymore but its messages still \
+             are, refusing to reuse its ID: repair that maildir to index it again"
+          );
+        }
         // Cleanup loose ends.
         // TODO? If more than the last ID was removed, we have no way to find out (but the ID will
         // be reused when asked to).
@@ -546,26 +979,277 @@ impl Database<Detached> {
         }
       }
 
-      let mut file = fs::File::create(path)?; // Truncates the file if it exists.
-      file.write_all(
-        format!(
-          "Subject: DO NOT REMOVE, THIS KEEPS TRACKS OF {namespace}'S INTERNAL STATE
-Message-ID: {max_id}@{namespace}
-"
-        )
-        .as_bytes(),
-      )?;
-      file.sync_all()?;
+      write_root(path, namespace, max_id)?;
 
       let mut message = RootMessage {
-        inner: database.inner.index_message(path)?,
+        inner: database.inner.index_message(path, None)?.0,
         namespace: &database.state.namespace,
       };
       message.setup()?;
+      message.update_schema(SCHEMA)?;
+      if let Some(account) = &database.state.account {
+        message.update_account(account)?;
+      }
       message.id()
     })
   }
 
+  // Bring the properties of the root and its messages to the current layout (see SCHEMA).
+  fn migrate_schema(&mut self, path: &path::Path, id: u64) -> anyhow::Result<()> {
+    let root_path = path.join(&self.state.namespace);
+    let schema = self.find(&root_path)?.unwrap().schema()?; // Guaranteed by attach.
+    anyhow::ensure!(
+      schema <= SCHEMA,
+      "{} was synchronized by a newer version of sin (schema {schema}, only up to {SCHEMA} is \
+       known), refusing to touch it",
+      path.display()
+    );
+    if schema == SCHEMA {
+      return Ok(());
+    }
+    anyhow::ensure!(
+      self.inner.mode() == Mode::ReadWrite,
+      "{} must be migrated from schema {schema} to {SCHEMA} first (the database is opened \
+       read-only), synchronize it once",
+      path.display()
+    );
+    self.transaction(|database| {
+      for schema in schema..SCHEMA {
+        log::info!(
+          "migrating {} from schema {schema} to {}",
+          path.display(),
+          schema + 1
+        );
+        MIGRATIONS[schema as usize](database, path, id)?;
+      }
+      database.find(&root_path)?.unwrap().update_schema(SCHEMA)
+    })
+  }
+
+  // A maildir moved elsewhere (e.g.: renamed) before notmuch new noticed: its root is recognized by
+  // the account and, like the messages still carrying its properties, pointed at the new location
+  // instead of starting over. The ID of the root, if one was relocated.
+  fn relocate(&mut self, path: &path::Path) -> anyhow::Result<Option<u64>> {
+    let Some(account) = self.state.account.clone() else {
+      return Ok(None);
+    };
+    let namespace = self.state.namespace.clone();
+    let root_path = path.join(&namespace);
+    let mut roots = Vec::new();
+    let mut messages = self.inner.query(&format!(
+      "property:\"{}.marker={ROOT_MARKER}\" and property:\"{}.account={}\"",
+      quote(&namespace),
+      quote(&namespace),
+      quote(&account)
+    ))?;
+    while let Some(message) = messages.next() {
+      let paths = message.paths()?;
+      // Otherwise, it's another maildir of the same account.
+      if paths.iter().all(|path| !path.exists()) {
+        roots.push((RootMessage::inner_id(&message)?, paths));
+      }
+    }
+    drop(messages);
+    let (id, old_root_paths) = match roots.as_slice() {
+      [] => return Ok(None),
+      [root] => root.clone(),
+      _ => anyhow::bail!(
+        "several maildirs of {account} are gone ({roots:?}), can't tell which one {path:?} was: \
+         run notmuch new to forget them"
+      ),
+    };
+    // Not to be confused with a root that was only dropped from the database (see restore_root).
+    if root_id(&root_path, &namespace).is_some_and(|root_id| root_id != id) {
+      return Ok(None);
+    }
+
+    self.transaction(|database| {
+      log::info!("relocating {old_root_paths:?} to {root_path:?} ({id}@{namespace}, {account})");
+      write_root(&root_path, &namespace, id)?;
+      // The same Message-ID: another file of the same message, its properties are kept.
+      database.inner.index_message(&root_path, None)?;
+      for old_root_path in &old_root_paths {
+        database.inner.remove_message(old_root_path)?;
+      }
+
+      let mut moves = Vec::new();
+      let mut messages = database.inner.query(&format!(
+        "property:{namespace}.{id}.marker={MESSAGE_MARKER}"
+      ))?;
+      while let Some(message) = messages.next() {
+        for message_path in message.paths()? {
+          let new_path = old_root_paths
+            .iter()
+            .filter_map(|old_root_path| message_path.strip_prefix(old_root_path.parent()?).ok())
+            .map(|relative| path.join(relative))
+            .next();
+          match new_path {
+            Some(new_path) if !message_path.exists() && new_path.exists() => {
+              moves.push((message_path, new_path))
+            }
+            _ => (),
+          }
+        }
+      }
+      drop(messages);
+      log::info!("relocating {} file(s)", moves.len());
+      for (old_path, new_path) in moves {
+        database.inner.index_message(&new_path, None)?;
+        database.inner.remove_message(&old_path)?;
+      }
+      Ok(Some(id))
+    })
+  }
+
+  // The root file of an ID, next to the messages still carrying its properties (the maildir's
+  // root), when the root message isn't in the database anymore (see add).
+  fn unindexed_root(&self, id: u64) -> anyhow::Result<Option<path::PathBuf>> {
+    let namespace = &self.state.namespace;
+    let mut directories = collections::HashSet::new();
+    let mut messages = self.inner.query(&format!(
+      "property:{namespace}.{id}.marker={MESSAGE_MARKER}"
+    ))?;
+    while let Some(message) = messages.next() {
+      for path in message.paths()? {
+        for directory in path.ancestors().skip(1) {
+          // Already looked at, like its ancestors.
+          if !directory.starts_with(self.inner.path())
+            || !directories.insert(directory.to_path_buf())
+          {
+            break;
+          }
+          let root = directory.join(namespace);
+          if root_id(&root, namespace) == Some(id) {
+            return Ok(Some(root));
+          }
+        }
+      }
+    }
+    Ok(None)
+  }
+
+  // Index the maildir's root file again (with its ID) if it was dropped from the database. Whether
+  // it was.
+  pub fn restore_root(&mut self, path: &path::Path) -> anyhow::Result<bool> {
+    let root_path = path.join(&self.state.namespace);
+    let Some(id) = root_id(&root_path, &self.state.namespace) else {
+      return Ok(false);
+    };
+    if self.find(&root_path)?.is_some() {
+      return Ok(false);
+    }
+    self.transaction(|database| {
+      let namespace = &database.state.namespace;
+      let mut messages = database
+        .inner
+        .query(&format!("property:{namespace}.marker={ROOT_MARKER}"))?;
+      while let Some(message) = messages.next() {
+        anyhow::ensure!(
+          RootMessage::inner_id(&message)? != id,
+          "{id}@{namespace} is already used by {:?}",
+          message.paths()?
+        );
+      }
+      drop(messages);
+      log::info!("indexing {root_path:?} again ({id}@{namespace})");
+      let mut message = RootMessage {
+        inner: database.inner.index_message(&root_path, None)?.0,
+        namespace: &database.state.namespace,
+      };
+      message.setup()?;
+      Ok(true)
+    })
+  }
+
+  // Move the state recorded under another namespace to this one (the IDs are kept). Returns the
+  // number of migrated messages.
+  pub fn migrate(&mut self, old_namespace: &str) -> anyhow::Result<usize> {
+    self.transaction(|database| {
+      let namespace = database.state.namespace.clone();
+      anyhow::ensure!(
+        old_namespace != namespace,
+        "can't migrate {namespace} to itself"
+      );
+      anyhow::ensure!(
+        database
+          .inner
+          .query(&format!("property:{namespace}.marker={ROOT_MARKER}"))?
+          .next()
+          .is_none(),
+        "{namespace} is already in use"
+      );
+      let mut roots = Vec::new();
+      let mut messages = database
+        .inner
+        .query(&format!("property:{old_namespace}.marker={ROOT_MARKER}"))?;
+      while let Some(message) = messages.next() {
+        roots.push((RootMessage::inner_id(&message)?, message.paths()?));
+      }
+      drop(messages);
+      anyhow::ensure!(!roots.is_empty(), "nothing to migrate from {old_namespace}");
+
+      let (old_prefix, prefix) = (format!("{old_namespace}."), format!("{namespace}."));
+      let mut count = 0;
+      for (id, paths) in roots {
+        for path in paths {
+          // Each maildir has its root file named after the namespace.
+          let new_path = path.with_file_name(&namespace);
+          log::info!("migrating {path:?} to {new_path:?}");
+          write_root(&new_path, &namespace, id)?;
+          let mut root = RootMessage {
+            inner: database.inner.index_message(&new_path, None)?.0,
+            namespace: &database.state.namespace,
+          };
+          root.setup()?;
+          if let Some(old_root) = database.inner.find_message_by_filename(&path)? {
+            for (key, value) in properties_with_prefix(&old_root, &old_prefix)? {
+              let key = format!("{prefix}{}", &key[old_prefix.len()..]);
+              root.inner.add_property(&key, &value)?;
+            }
+          }
+          database.inner.remove_message(&path)?;
+          match fs::remove_file(&path) {
+            Ok(_) => (),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => (),
+            Err(error) => Err(error)?,
+          }
+        }
+
+        let mut messages = database.inner.query(&format!(
+          "property:{old_namespace}.{id}.marker={MESSAGE_MARKER}"
+        ))?;
+        while let Some(mut message) = messages.next() {
+          for (key, value) in properties_with_prefix(&message, &old_prefix)? {
+            let key = format!("{prefix}{}", &key[old_prefix.len()..]);
+            message.add_property(&key, &value)?;
+          }
+          message.remove_all_properties_with_prefix(&old_prefix)?;
+          count += 1;
+        }
+      }
+
+      // https://notmuchmail.org/doc/latest/man1/notmuch-config.html#nmconfig-search.exclude_tags
+      let (old_internal, internal) = (
+        format!("{old_namespace}.internal"),
+        format!("{namespace}.internal"),
+      );
+      let exclude_tags = database
+        .inner
+        .config_values(bindings::Config::ExcludeTags)?;
+      if exclude_tags.contains(&old_internal) {
+        let exclude_tags: Vec<String> = exclude_tags
+          .into_iter()
+          .filter(|tag| *tag != old_internal && *tag != internal)
+          .chain([internal.clone()])
+          .collect();
+        database
+          .inner
+          .set_config(bindings::Config::ExcludeTags, &exclude_tags.join(";"))?;
+      }
+      Ok(count)
+    })
+  }
+
   fn find(&'_ self, path: &path::Path) -> anyhow::Result<Option<RootMessage<'_>>> {
     Ok(
       self
@@ -579,13 +1263,69 @@ Message-ID: {max_id}@{namespace}
   }
 }
 
+fn write_root(path: &path::Path, namespace: &str, id: u64) -> anyhow::Result<()> {
+  let mut file = fs::File::create(path)?; // Truncates the file if it exists.
+  file.write_all(
+    format!(
+      "Subject: DO NOT REMOVE, THIS KEEPS TRACKS OF {namespace}'S INTERNAL STATE
+Message-ID: {id}@{namespace}
+"
+    )
+    .as_bytes(),
+  )?;
+  file.sync_all()?;
+  Ok(())
+}
+
+// The ID in a root file written by write_root, if it's one.
+fn root_id(path: &path::Path, namespace: &str) -> Option<u64> {
+  let content = fs::read_to_string(path).ok()?;
+  content.lines().find_map(|line| {
+    line
+      .strip_prefix("Message-ID: ")?
+      .strip_suffix(&format!("@{namespace}"))?
+      .parse()
+      .ok()
+  })
+}
+
+// As recorded by RootMessage::update_server, though it may have been edited since (the address
+// may be an IPv6 one, hence the last colon).
+fn parse_server(server: &str) -> anyhow::Result<(String, u16)> {
+  let Some((address, port)) = server.rsplit_once(':') else {
+    anyhow::bail!("invalid server {server:?} recorded in the database, expected address:port");
+  };
+  let Ok(port) = port.parse() else {
+    anyhow::bail!("invalid port in the server {server:?} recorded in the database");
+  };
+  Ok((address.to_string(), port))
+}
+
+fn properties_with_prefix(
+  message: &bindings::Message<'_>,
+  prefix: &str,
+) -> anyhow::Result<Vec<(String, String)>> {
+  let mut result = Vec::new();
+  let mut properties = message.properties(prefix, false)?;
+  while let Some((key, value)) = properties.next()? {
+    result.push((key.to_string(), value.to_string()));
+  }
+  Ok(result)
+}
+
 pub struct Attached {
   detached: Detached,
   path: path::PathBuf,
   namespace: String,
+  // Overrides index.decrypt.
+  decrypt: Option<Decrypt>,
 }
 
 impl Database<Attached> {
+  pub fn set_decrypt(&mut self, decrypt: Option<Decrypt>) {
+    self.state.decrypt = decrypt;
+  }
+
   pub fn root_namespace(&self) -> &str {
     &self.state.detached.namespace
   }
@@ -594,13 +1334,87 @@ impl Database<Attached> {
     &self.state.namespace
   }
 
-  pub fn add(&'_ self, path: &path::Path) -> anyhow::Result<Message<'_>> {
+  // The tags are only added to new messages (not to another file of a known message).
+  pub fn add(&'_ self, path: &path::Path, tags: &[String]) -> anyhow::Result<Message<'_>> {
+    crate::fault::notmuch("add")?;
+    let (mut inner, new) = self.inner.index_message(path, self.state.decrypt)?;
+    if new {
+      // Written at once (see Message::update_mailbox_properties).
+      inner.freeze()?;
+      for tag in tags {
+        inner.add_tag(tag)?;
+      }
+      inner.thaw()?;
+    }
     Ok(Message {
-      inner: self.inner.index_message(path)?,
+      inner,
       namespace: &self.state.namespace,
     })
   }
 
+  // https://notmuchmail.org/doc/latest/man1/notmuch-config.html#nmconfig-new.tags
+  // The tags synchronized with the IMAP flags (see flags_to_tags) are left out: they'd be overridden
+  // anyway.
+  pub fn new_tags(&self) -> anyhow::Result<Vec<String>> {
+    let internal = format!("{}.internal", self.root_namespace());
+    Ok(
+      self
+        .inner
+        .config_values(bindings::Config::NewTags)?
+        .into_iter()
+        .filter(|tag| !["draft", "flagged", "replied", "unread", &internal].contains(&tag.as_str()))
+        .collect(),
+    )
+  }
+
+  // Drop the properties left behind by a maildir whose root message is gone. Returns the number of
+  // affected messages.
+  pub fn remove_namespace(&mut self, id: u64) -> anyhow::Result<usize> {
+    let namespace = self.root_namespace();
+    let mut roots = self
+      .inner
+      .query(&format!("property:{namespace}.marker={ROOT_MARKER}"))?;
+    while let Some(root) = roots.next() {
+      anyhow::ensure!(
+        RootMessage::inner_id(&root)? != id,
+        "{namespace}.{id} is still in use"
+      );
+    }
+    let prefix = format!("{namespace}.{id}.");
+    let mut messages = self
+      .inner
+      .query(&format!("property:{prefix}marker={MESSAGE_MARKER}"))?;
+    let mut count = 0;
+    while let Some(mut message) = messages.next() {
+      message.remove_all_properties_with_prefix(&prefix)?;
+      count += 1;
+    }
+    Ok(count)
+  }
+
+  // Make sure the configuration doesn't get in the way.
+  pub fn check_config(&mut self) -> anyhow::Result<()> {
+    // https://notmuchmail.org/doc/latest/man1/notmuch-config.html#nmconfig-maildir.synchronize_flags
+    if !self.inner.config_bool(bindings::Config::SynchronizeFlags)? {
+      log::warn!(
+        "maildir.synchronize_flags is disabled: the flags pulled won't be reflected in the file \
+         names and the ones in the file names won't be pushed"
+      );
+    }
+    // https://notmuchmail.org/doc/latest/man1/notmuch-config.html#nmconfig-search.exclude_tags
+    // The root messages would otherwise show up in every search.
+    let internal = format!("{}.internal", self.root_namespace());
+    let mut exclude_tags = self.inner.config_values(bindings::Config::ExcludeTags)?;
+    if !exclude_tags.contains(&internal) && self.inner.mode() == Mode::ReadWrite {
+      log::info!("adding {internal} to search.exclude_tags");
+      exclude_tags.push(internal);
+      self
+        .inner
+        .set_config(bindings::Config::ExcludeTags, &exclude_tags.join(";"))?;
+    }
+    Ok(())
+  }
+
   pub fn query(&'_ self, query: &str) -> anyhow::Result<Messages<'_>> {
     let query = query.trim(); // The query might be indented for readability.
     log::debug!("? {query}");
@@ -672,7 +1486,11 @@ pub fn flags_to_tags<'a>(
   tags
 }
 
-pub fn tags_to_flags<'a>(tags: &'_ collections::HashSet<&'a str>) -> collections::HashSet<&'a str> {
+// Without keywords (the mailbox doesn't allow them), only the system flags are kept.
+pub fn tags_to_flags<'a>(
+  tags: &'_ collections::HashSet<&'a str>,
+  keywords: bool,
+) -> collections::HashSet<&'a str> {
   let mut flags = collections::HashSet::new();
   let mut unread = false;
   for tag in tags {
@@ -685,8 +1503,11 @@ pub fn tags_to_flags<'a>(tags: &'_ collections::HashSet<&'a str>) -> collections
       "\\Flagged"
     } else if *tag == "draft" {
       "\\Draft"
-    } else {
+    } else if keywords {
       tag
+    } else {
+      log::debug!("dropping tag {tag}, keywords aren't allowed");
+      continue;
     });
   }
   if !unread {
@@ -708,11 +1529,12 @@ mod tests {
     let path = directory.path();
     create(
       path,
-      &mut Database::<Detached>::create(&path, "test")?.attach(&path)?,
+      &mut Database::<Detached>::create(&path, None, None, "test")?.attach(&path)?,
     )?;
     open(
       path,
-      &mut Database::<Detached>::open(Some(&path), "test")?.attach(&path)?,
+      &mut Database::<Detached>::open(Some(&path), None, None, "test", Mode::ReadWrite)?
+        .attach(&path)?,
     )?;
     Ok(())
   }
@@ -740,10 +1562,10 @@ Message-ID: {id}"
     test(
       |path, database| -> _ {
         let tags = collections::HashSet::from(["tag1", "tag2"]);
-        let mut message = database.add(&email(path, "test1", "id1")?)?;
+        let mut message = database.add(&email(path, "test1", "id1")?, &[])?;
         message.update_mailbox_properties("INBOX", 0, 1, 2, &tags)?;
         message.tags_to_maildir_flags()?;
-        let mut message = database.add(&email(path, "test2", "id2")?)?;
+        let mut message = database.add(&email(path, "test2", "id2")?, &[])?;
         message.update_mailbox_properties("INBOX", 0, 2, 3, &tags)?;
         message.tags_to_maildir_flags()?;
         Ok(())
@@ -756,7 +1578,7 @@ Message-ID: {id}"
            and property:test.0.mailbox=INBOX \
            and property:test.0.INBOX.uidvalidity=0 \
            and property:test.0.INBOX.uid=1 \
-           and property:test.0.INBOX.modseq=2 \
+           and property:test.0.INBOX.modseq.1=2 \
            and property:test.0.INBOX.tag=tag1",
         ))?;
         while let Some(message) = messages.next() {
@@ -772,11 +1594,278 @@ Message-ID: {id}"
     )
   }
 
+  #[test]
+  fn duplicates() -> anyhow::Result<()> {
+    test(
+      |path, database| -> _ {
+        let tags = collections::HashSet::new();
+        let mut message = database.add(&email(path, "test1", "id1")?, &[])?;
+        message.update_mailbox_properties("INBOX", 1, 1, 2, &tags)?;
+        let mut message = database.add(&email(path, "test2", "id1")?, &[])?;
+        message.update_mailbox_properties("INBOX", 1, 3, 4, &tags)?;
+        assert_eq!(vec![1, 3], message.uids("INBOX")?);
+        assert_eq!(
+          (2, 4),
+          (message.modseq("INBOX", 1)?, message.modseq("INBOX", 3)?)
+        );
+        Ok(())
+      },
+      |_, database| -> _ {
+        let mut messages = database.query("id:id1")?;
+        let mut message = messages.next().unwrap();
+        assert_eq!(2, message.paths()?.len());
+        assert_eq!(1, message.remove_uid("INBOX", 1)?);
+        assert_eq!(vec![3], message.uids("INBOX")?);
+        assert_eq!(4, message.modseq("INBOX", 3)?);
+        // The UIDs of another UIDVALIDITY are forgotten.
+        message.update_mailbox_properties("INBOX", 2, 1, 8, &collections::HashSet::new())?;
+        assert_eq!(vec![1], message.uids("INBOX")?);
+        assert_eq!(0, message.remove_uid("INBOX", 1)?);
+        Ok(())
+      },
+    )
+  }
+
+  #[test]
+  fn rename_mailbox() -> anyhow::Result<()> {
+    test(
+      |path, database| -> _ {
+        let tags = collections::HashSet::from(["tag1"]);
+        let mut message = database.add(&email(path, "test1", "id1")?, &[])?;
+        message.update_mailbox_properties("INBOX.Sent", 1, 1, 2, &tags)?;
+        message.update_mailbox_properties("INBOX.Sent", 1, 3, 4, &tags)?;
+        message.update_mailbox_properties("INBOX", 5, 6, 7, &tags)?;
+        database
+          .root()?
+          .update_mailbox_properties("INBOX.Sent", Some('.'), 1, 4, 4)?;
+        Ok(())
+      },
+      |_, database| -> _ {
+        let mut root = database.root()?;
+        root.rename_mailbox("INBOX.Sent", "Sent")?;
+        assert_eq!(collections::HashSet::from(["Sent"]), root.mailboxes()?);
+        assert_eq!(
+          (Some('.'), (1, 4), 4),
+          (
+            root.separator("Sent")?,
+            root.validity("Sent")?,
+            root.uidnext("Sent")?
+          )
+        );
+        assert_eq!(None, root.separator("INBOX.Sent")?);
+        let mut messages = database.query("id:id1")?;
+        let mut message = messages.next().unwrap();
+        message.rename_mailbox("INBOX.Sent", "Sent")?;
+        assert_eq!(
+          collections::HashSet::from(["INBOX", "Sent"]),
+          message.mailboxes()?
+        );
+        assert_eq!(
+          (1, vec![1, 3], (2, 4)),
+          (
+            message.uidvalidity("Sent")?,
+            message.uids("Sent")?,
+            (message.modseq("Sent", 1)?, message.modseq("Sent", 3)?)
+          )
+        );
+        assert_eq!(
+          collections::HashSet::from(["tag1"]),
+          message.cached_tags("Sent")?
+        );
+        assert!(message.uids("INBOX.Sent")?.is_empty());
+        assert_eq!(vec![6], message.uids("INBOX")?);
+        Ok(())
+      },
+    )
+  }
+
+  #[test]
+  fn add_tags() -> anyhow::Result<()> {
+    test(
+      |path, database| -> _ {
+        let tags = ["inbox".to_string()];
+        database.add(&email(path, "test1", "id1")?, &tags)?;
+        // Another file of the same message.
+        let mut message = database.add(&email(path, "test2", "id1")?, &["other".to_string()])?;
+        assert_eq!(collections::HashSet::from(["inbox"]), message.tags()?);
+        message.tags_to_maildir_flags()?;
+        Ok(())
+      },
+      |_, database| -> _ {
+        let mut messages = database.query("tag:inbox and not tag:other")?;
+        assert!(messages.next().is_some());
+        Ok(())
+      },
+    )
+  }
+
+  #[test]
+  fn unindexed_root() -> anyhow::Result<()> {
+    let directory = tempfile::tempdir()?;
+    let path = directory.path();
+    let (first, second, third) = (path.join("first"), path.join("second"), path.join("third"));
+    for maildir in [&first, &second, &third] {
+      fs::create_dir_all(maildir)?;
+    }
+    let database = Database::<Detached>::create(path, None, None, "test")?.attach(&first)?;
+    database.add(&email(&first, "test1", "id1")?, &[])?;
+    drop(database);
+    let database = Database::<Detached>::open(Some(path), None, None, "test", Mode::ReadWrite)?
+      .attach(&second)?;
+    assert_eq!("test.1", database.namespace());
+    database
+      .add(&email(&second, "test2", "id2")?, &[])?
+      .update_mailbox_properties("INBOX", 1, 1, 2, &collections::HashSet::new())?;
+    drop(database);
+
+    let mut database = Database::<Detached>::open(Some(path), None, None, "test", Mode::ReadWrite)?;
+    database.inner.remove_message(&second.join("test"))?;
+    assert_eq!(
+      format!(
+        "{:?} (1@test) isn't in the database anymore but its messages still are, refusing to \
+         reuse its ID: repair that maildir to index it again",
+        second.join("test")
+      ),
+      database.add(&third.join("test")).unwrap_err().to_string()
+    );
+
+    assert!(database.restore_root(&second)?);
+    assert!(!database.restore_root(&second)?);
+    let database = database.attach(&second)?;
+    assert_eq!("test.1", database.namespace());
+    let database = Database::<Detached>::open(Some(path), None, None, "test", Mode::ReadWrite)?
+      .attach(&third)?;
+    assert_eq!("test.2", database.namespace());
+    Ok(())
+  }
+
+  #[test]
+  fn relocate() -> anyhow::Result<()> {
+    let directory = tempfile::tempdir()?;
+    let path = directory.path();
+    let (first, second) = (path.join("first"), path.join("second"));
+    fs::create_dir_all(&first)?;
+    let mut database = Database::<Detached>::create(path, None, None, "test")?;
+    database.set_account("user@host");
+    let database = database.attach(&first)?;
+    assert_eq!(Some("user@host"), database.root()?.account()?);
+    database
+      .add(&email(&first, "test1", "id1")?, &[])?
+      .update_mailbox_properties("INBOX", 1, 1, 2, &collections::HashSet::new())?;
+    drop(database);
+    fs::rename(&first, &second)?;
+
+    let mut database = Database::<Detached>::open(Some(path), None, None, "test", Mode::ReadWrite)?;
+    database.set_account("user@host");
+    let database = database.attach(&second)?;
+    assert_eq!("test.0", database.namespace());
+    assert_eq!(vec![second.join("test")], database.root()?.inner.paths()?);
+    {
+      let mut messages = database.query("id:id1")?;
+      let message = messages.next().unwrap();
+      assert_eq!(vec![second.join("cur").join("test1")], message.paths()?);
+      assert_eq!(vec![1], message.uids("INBOX")?);
+    }
+    drop(database);
+
+    // Without the account, it's another maildir.
+    let third = path.join("third");
+    fs::create_dir_all(&third)?;
+    let database = Database::<Detached>::open(Some(path), None, None, "test", Mode::ReadWrite)?
+      .attach(&third)?;
+    assert_eq!("test.1", database.namespace());
+    Ok(())
+  }
+
+  #[test]
+  fn schema() -> anyhow::Result<()> {
+    let directory = tempfile::tempdir()?;
+    let path = directory.path();
+    let database = Database::<Detached>::create(path, None, None, "test")?.attach(path)?;
+    assert_eq!(SCHEMA, database.root()?.schema()?);
+    assert!(!database.root()?.prefixed()?);
+    // Before it was recorded (see migrate_from_0).
+    let mut message = database.add(&email(path, "test1", "id1")?, &[])?;
+    message.update_mailbox_properties("INBOX.Sent", 1, 3, 4, &collections::HashSet::new())?;
+    message
+      .inner
+      .remove_all_properties("test.0.INBOX.Sent.modseq.3")?;
+    message
+      .inner
+      .add_property("test.0.INBOX.Sent.modseq", "5")?;
+    let mut root = database.root()?;
+    root.update_mailbox_properties("INBOX.Sent", Some('.'), 1, 9, 5)?;
+    root
+      .inner
+      .remove_all_properties("test.INBOX.Sent.uidnext")?;
+    root.inner.remove_all_properties("test.schema")?;
+    drop((message, root));
+    drop(database);
+    let open = |mode| Database::<Detached>::open(Some(path), None, None, "test", mode);
+    assert_eq!(
+      format!(
+        "{} must be migrated from schema 0 to {SCHEMA} first (the database is opened read-only), \
+         synchronize it once",
+        path.display()
+      ),
+      open(Mode::ReadOnly)?
+        .attach(path)
+        .err()
+        .unwrap()
+        .to_string()
+    );
+    let database = open(Mode::ReadWrite)?.attach(path)?;
+    assert_eq!(SCHEMA, database.root()?.schema()?);
+    {
+      let root = database.root()?;
+      assert_eq!((4, true), (root.uidnext("INBOX.Sent")?, root.prefixed()?));
+      let mut messages = database.query("id:id1")?;
+      let message = messages.next().unwrap();
+      assert_eq!(5, message.modseq("INBOX.Sent", 3)?);
+      assert_eq!(
+        None,
+        property(&message.inner, "test.0", "INBOX.Sent.modseq")?
+      );
+    }
+    database.root()?.update_schema(SCHEMA + 1)?;
+    drop(database);
+    assert_eq!(
+      format!(
+        "{} was synchronized by a newer version of sin (schema {}, only up to {SCHEMA} is known), \
+         refusing to touch it",
+        path.display(),
+        SCHEMA + 1
+      ),
+      open(Mode::ReadWrite)?
+        .attach(path)
+        .err()
+        .unwrap()
+        .to_string()
+    );
+    Ok(())
+  }
+
+  #[test]
+  fn read_only() -> anyhow::Result<()> {
+    let directory = tempfile::tempdir()?;
+    let path = directory.path();
+    assert!(
+      Database::<Detached>::open(Some(path), None, None, "test", Mode::ReadOnly)
+        .and_then(|database| database.attach(path))
+        .is_err()
+    );
+    Database::<Detached>::create(path, None, None, "test")?.attach(path)?;
+    let database =
+      Database::<Detached>::open(Some(path), None, None, "test", Mode::ReadOnly)?.attach(path)?;
+    assert!(database.add(&email(path, "test", "id")?, &[]).is_err());
+    Ok(())
+  }
+
   #[test]
   #[should_panic(expected = "nested transactions aren't supported")]
   fn nested_transaction() {
     let directory = tempfile::tempdir().unwrap();
-    let mut database = Database::<Detached>::create(&directory.path(), "test").unwrap();
+    let mut database = Database::<Detached>::create(&directory.path(), None, None, "test").unwrap();
     database
       .transaction(|database| database.transaction(|_| Ok(())))
       .unwrap();
@@ -787,7 +1876,7 @@ Message-ID: {id}"
     test(
       |path, database| -> _ {
         match database.transaction(|database| -> anyhow::Result<(), _> {
-          let mut message = database.add(&email(path, "uncommited", "uncommited")?)?;
+          let mut message = database.add(&email(path, "uncommited", "uncommited")?, &[])?;
           message.update_mailbox_properties("INBOX", 0, 1, 2, &collections::HashSet::new())?;
           anyhow::bail!("uncommitted");
         }) {
@@ -795,7 +1884,7 @@ Message-ID: {id}"
           Err(error) => assert_eq!("uncommitted", error.root_cause().to_string()),
         };
         database.transaction(|database| -> _ {
-          let mut message = database.add(&email(path, "commited", "commited")?)?;
+          let mut message = database.add(&email(path, "commited", "commited")?, &[])?;
           message.update_mailbox_properties("INBOX", 0, 2, 3, &collections::HashSet::new())?;
           Ok(())
         })?;
@@ -815,4 +1904,29 @@ Message-ID: {id}"
       },
     )
   }
+
+  #[test]
+  fn server() -> anyhow::Result<()> {
+    assert_eq!(
+      ("imap.example.com".to_string(), 993),
+      parse_server("imap.example.com:993")?
+    );
+    assert_eq!(("::1".to_string(), 143), parse_server("::1:143")?);
+    assert!(parse_server("imap.example.com").is_err());
+    assert!(parse_server("imap.example.com:imaps").is_err());
+    Ok(())
+  }
+
+  #[test]
+  fn tags_to_flags() {
+    let tags = collections::HashSet::from(["flagged", "keyword"]);
+    assert_eq!(
+      collections::HashSet::from(["\\Flagged", "\\Seen", "keyword"]),
+      super::tags_to_flags(&tags, true)
+    );
+    assert_eq!(
+      collections::HashSet::from(["\\Flagged", "\\Seen"]),
+      super::tags_to_flags(&tags, false)
+    );
+  }
 }
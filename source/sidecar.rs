@@ -0,0 +1,200 @@
+// The state of the maildir backend (see crate::Backend): what the Notmuch properties record
+// otherwise, in a file next to each mailbox's maildir (like Dovecot's dovecot-uidlist). The flags
+// are the ones of the file names.
+//
+// The first line is the version then the mailbox's UIDVALIDITY, UIDNEXT and HIGHESTMODSEQ, the
+// other lines are the messages' UIDs and their file names as of the last synchronization:
+//  1 1700000000 3 7
+//  1 0b8d2b3a-4a50-4c1a-9d02-b2b4c3e4e63c:2,S
+//  2 4f0e2c7d-95c4-4a7a-8f0a-4f7b6b8e5a51:2,FS
+
+use crate::maildir;
+use anyhow::Context as _;
+use std::{
+  collections, fs,
+  io::{self, Write as _},
+  path,
+};
+
+const VERSION: u64 = 1;
+
+// https://cr.yp.to/proto/maildir.html
+// Flag "P" (passed): the user has resent/forwarded/bounced this message to someone else. Flag "R"
+// (replied): the user has replied to this message. Flag "S" (seen): the user has viewed this
+// message [...]. Flag "T" (trashed): the user has moved this message to the trash; the trash will
+// be emptied by a later user action. Flag "D" (draft): the user considers this message a draft;
+// toggled at user discretion. Flag "F" (flagged): user-defined flag; toggled at user discretion.
+// [...] Flags must be stored in ASCII order: e.g., "2,FRS".
+//
+// Passed has no system flag, the $Forwarded keyword is the closest (see RFC 5788). The other
+// keywords aren't synchronized.
+const FLAGS: [(char, &str); 6] = [
+  ('D', "\\Draft"),
+  ('F', "\\Flagged"),
+  ('P', "$Forwarded"),
+  ('R', "\\Answered"),
+  ('S', "\\Seen"),
+  ('T', "\\Deleted"),
+];
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Sidecar {
+  pub uidvalidity: u64,
+  pub uidnext: u64,
+  pub highestmodseq: u64,
+  // By UID, the file name (in new or cur) as of the last synchronization.
+  pub messages: collections::BTreeMap<u64, String>,
+}
+
+impl Sidecar {
+  pub fn path(maildir: &maildir::Maildir, namespace: &str) -> path::PathBuf {
+    maildir.path().join(format!("{namespace}-uidlist"))
+  }
+
+  // Empty when the mailbox was never synchronized.
+  pub fn load(path: &path::Path) -> anyhow::Result<Self> {
+    match fs::read_to_string(path) {
+      Ok(content) => Self::parse(&content).with_context(|| format!("couldn't parse {path:?}")),
+      Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+      Err(error) => Err(error).with_context(|| format!("couldn't read {path:?}")),
+    }
+  }
+
+  fn parse(content: &str) -> anyhow::Result<Self> {
+    let mut lines = content.lines();
+    let header: Vec<u64> = lines
+      .next()
+      .context("the header is missing")?
+      .split(' ')
+      .map(str::parse)
+      .collect::<Result<_, _>>()
+      .context("invalid header")?;
+    let [version, uidvalidity, uidnext, highestmodseq] = header[..] else {
+      anyhow::bail!("invalid header");
+    };
+    anyhow::ensure!(version == VERSION, "unsupported version {version}");
+    let mut messages = collections::BTreeMap::new();
+    for line in lines {
+      let (uid, name) = line
+        .split_once(' ')
+        .filter(|(_, name)| !name.is_empty())
+        .with_context(|| format!("invalid line {line:?}"))?;
+      let uid = uid
+        .parse()
+        .with_context(|| format!("invalid UID in {line:?}"))?;
+      messages.insert(uid, name.to_string());
+    }
+    Ok(Self {
+      uidvalidity,
+      uidnext,
+      highestmodseq,
+      messages,
+    })
+  }
+
+  fn serialize(&self) -> String {
+    let mut content = format!(
+      "{VERSION} {} {} {}\n",
+      self.uidvalidity, self.uidnext, self.highestmodseq
+    );
+    for (uid, name) in &self.messages {
+      content += &format!("{uid} {name}\n");
+    }
+    content
+  }
+
+  // Written aside then renamed over: an interruption leaves the previous one.
+  pub fn save(&self, path: &path::Path, durability: bool) -> anyhow::Result<()> {
+    let mut temporary = path.as_os_str().to_os_string();
+    temporary.push(".tmp");
+    let temporary = path::PathBuf::from(temporary);
+    let mut file =
+      fs::File::create(&temporary).with_context(|| format!("couldn't create {temporary:?}"))?;
+    file.write_all(self.serialize().as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&temporary, path).with_context(|| format!("couldn't rename {temporary:?}"))?;
+    if durability {
+      fs::File::open(path.parent().unwrap())?.sync_all()?; // In the maildir.
+    }
+    Ok(())
+  }
+}
+
+// The part of a file name that doesn't change with the flags.
+pub fn unique(name: &str) -> &str {
+  name.split_once(':').map_or(name, |(unique, _)| unique)
+}
+
+// The file name standing for the IMAP flags, the ones without a letter are dropped.
+pub fn name(unique: &str, flags: &[String]) -> String {
+  let letters: String = FLAGS
+    .iter()
+    .filter(|(_, flag)| flags.iter().any(|flag_| flag_.eq_ignore_ascii_case(flag)))
+    .map(|(letter, _)| *letter)
+    .collect();
+  format!("{unique}:2,{letters}")
+}
+
+// The IMAP flags a file name stands for (none while in new, without info).
+pub fn flags(name: &str) -> collections::BTreeSet<&'static str> {
+  let letters = name.split_once(":2,").map_or("", |(_, letters)| letters);
+  FLAGS
+    .iter()
+    .filter(|(letter, _)| letters.contains(*letter))
+    .map(|(_, flag)| *flag)
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parse() -> anyhow::Result<()> {
+    let sidecar = Sidecar {
+      uidvalidity: 1700000000,
+      uidnext: 4,
+      highestmodseq: 7,
+      messages: collections::BTreeMap::from([
+        (1, "a:2,S".to_string()),
+        (3, "b with spaces".to_string()),
+      ]),
+    };
+    let content = sidecar.serialize();
+    assert_eq!("1 1700000000 4 7\n1 a:2,S\n3 b with spaces\n", content);
+    assert_eq!(sidecar, Sidecar::parse(&content)?);
+    assert!(Sidecar::parse("").is_err());
+    assert!(Sidecar::parse("2 1 1 1\n").is_err());
+    assert!(Sidecar::parse("1 1 1\n").is_err());
+    assert!(Sidecar::parse("1 1 1 1\n1\n").is_err());
+    Ok(())
+  }
+
+  #[test]
+  fn save() -> anyhow::Result<()> {
+    let directory = tempfile::tempdir()?;
+    let path = directory.path().join("sin-uidlist");
+    assert_eq!(Sidecar::default(), Sidecar::load(&path)?);
+    let sidecar = Sidecar {
+      uidvalidity: 1,
+      uidnext: 2,
+      highestmodseq: 3,
+      messages: collections::BTreeMap::from([(1, "a".to_string())]),
+    };
+    sidecar.save(&path, true)?;
+    assert_eq!(sidecar, Sidecar::load(&path)?);
+    Ok(())
+  }
+
+  #[test]
+  fn flags() {
+    let flags = ["\\Seen", "\\flagged", "$Junk", "\\Recent"].map(str::to_string);
+    assert_eq!("a:2,FS", name("a", &flags));
+    assert_eq!("a:2,", name(unique("a:2,DS"), &[]));
+    assert_eq!(
+      collections::BTreeSet::from(["$Forwarded", "\\Answered", "\\Seen"]),
+      super::flags("a:2,PRS")
+    );
+    assert!(super::flags("a").is_empty());
+  }
+}
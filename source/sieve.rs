@@ -0,0 +1,657 @@
+// https://www.rfc-editor.org/rfc/rfc5804 - A Protocol for Remotely Managing Sieve Scripts
+// Only what's needed to keep the active script in sync with a local file (see synchronize): the
+// server's script is the one to edit locally, not a collection of them.
+
+use crate::{credentials, imap, sync};
+use anyhow::Context as _;
+use std::{fs, io, path};
+
+// What the responses are made of: atoms (e.g.: OK, ACTIVE, the parentheses of the response codes)
+// and strings (quoted or literals).
+#[derive(Debug, PartialEq)]
+enum Token {
+  Atom(String),
+  String(Vec<u8>),
+}
+
+#[derive(Debug, PartialEq)]
+enum Status {
+  Ok,
+  No,
+  Bye,
+}
+
+// https://www.rfc-editor.org/rfc/rfc5804#section-4
+// OK, NO or BYE, optionally followed by a response code (in parentheses) and a human-readable
+// string.
+#[derive(Debug, PartialEq)]
+struct Response {
+  status: Status,
+  code: Vec<Token>,
+  message: Option<String>,
+}
+
+impl Response {
+  fn parse(tokens: Vec<Token>) -> Result<Self, Vec<Token>> {
+    let status = match tokens.first() {
+      Some(Token::Atom(atom)) if atom.eq_ignore_ascii_case("OK") => Status::Ok,
+      Some(Token::Atom(atom)) if atom.eq_ignore_ascii_case("NO") => Status::No,
+      Some(Token::Atom(atom)) if atom.eq_ignore_ascii_case("BYE") => Status::Bye,
+      _ => return Err(tokens),
+    };
+    let mut tokens = tokens.into_iter().skip(1).peekable();
+    let mut code = Vec::new();
+    if tokens.next_if_eq(&Token::Atom("(".to_string())).is_some() {
+      for token in tokens.by_ref() {
+        if token == Token::Atom(")".to_string()) {
+          break;
+        }
+        code.push(token);
+      }
+    }
+    let message = match tokens.next() {
+      Some(Token::String(message)) => Some(String::from_utf8_lossy(&message).into_owned()),
+      _ => None,
+    };
+    Ok(Self {
+      status,
+      code,
+      message,
+    })
+  }
+
+  fn ensure_ok(&self, command: &str) -> anyhow::Result<()> {
+    anyhow::ensure!(
+      self.status == Status::Ok,
+      "{command} failed: {}",
+      self.message.as_deref().unwrap_or("no reason given")
+    );
+    Ok(())
+  }
+}
+
+pub struct Session<RW> {
+  rw: RW,
+  buffer: Vec<u8>,
+  // As announced with the greeting (or after STARTTLS).
+  capabilities: Vec<(String, Option<String>)>,
+}
+
+impl<RW> Session<RW>
+where
+  RW: imap::ReadWrite,
+{
+  // https://www.rfc-editor.org/rfc/rfc5804#section-1.7
+  // The server sends its capabilities upon connection (and after a successful STARTTLS).
+  pub fn new(rw: RW) -> anyhow::Result<Self> {
+    let mut session = Self {
+      rw,
+      buffer: Vec::new(),
+      capabilities: Vec::new(),
+    };
+    let (lines, response) = session.responses()?;
+    response.ensure_ok("connection")?;
+    session.capabilities = capabilities(lines);
+    Ok(session)
+  }
+
+  fn capability(&self, name: &str) -> Option<Option<&str>> {
+    self
+      .capabilities
+      .iter()
+      .find(|(name_, _)| name_.eq_ignore_ascii_case(name))
+      .map(|(_, value)| value.as_deref())
+  }
+
+  fn fill(&mut self) -> anyhow::Result<()> {
+    let mut buffer = [0; 4096];
+    let read = self.rw.read(&mut buffer)?;
+    anyhow::ensure!(read != 0, "the server closed the connection");
+    self.buffer.extend_from_slice(&buffer[..read]);
+    Ok(())
+  }
+
+  fn take(&mut self, count: usize) -> anyhow::Result<Vec<u8>> {
+    while self.buffer.len() < count {
+      self.fill()?;
+    }
+    Ok(self.buffer.drain(..count).collect())
+  }
+
+  fn peek(&mut self) -> anyhow::Result<u8> {
+    if self.buffer.is_empty() {
+      self.fill()?;
+    }
+    Ok(self.buffer[0])
+  }
+
+  // A line of tokens, literals included.
+  fn line(&mut self) -> anyhow::Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    loop {
+      match self.peek()? {
+        b' ' => {
+          self.take(1)?;
+        }
+        b'\r' => {
+          anyhow::ensure!(self.take(2)? == b"\r\n", "invalid line ending");
+          return Ok(tokens);
+        }
+        b'"' => {
+          self.take(1)?;
+          let mut string = Vec::new();
+          loop {
+            match self.take(1)?[0] {
+              b'"' => break,
+              b'\\' => string.push(self.take(1)?[0]),
+              b'\r' | b'\n' => anyhow::bail!("unterminated quoted string"),
+              byte => string.push(byte),
+            }
+          }
+          tokens.push(Token::String(string));
+        }
+        b'{' => {
+          self.take(1)?;
+          let mut count = String::new();
+          loop {
+            match self.take(1)?[0] {
+              b'}' => break,
+              // Only the client is supposed to send non-synchronizing literals.
+              b'+' => (),
+              byte => count.push(byte as char),
+            }
+          }
+          let count = count
+            .parse()
+            .with_context(|| format!("invalid literal length {count:?}"))?;
+          anyhow::ensure!(self.take(2)? == b"\r\n", "invalid literal");
+          tokens.push(Token::String(self.take(count)?));
+        }
+        byte @ (b'(' | b')') => {
+          self.take(1)?;
+          tokens.push(Token::Atom((byte as char).to_string()));
+        }
+        _ => {
+          let mut atom = String::new();
+          while !matches!(self.peek()?, b' ' | b'\r' | b'(' | b')' | b'"' | b'{') {
+            atom.push(self.take(1)?[0] as char);
+          }
+          tokens.push(Token::Atom(atom));
+        }
+      }
+    }
+  }
+
+  // The lines preceding the response.
+  fn responses(&mut self) -> anyhow::Result<(Vec<Vec<Token>>, Response)> {
+    let mut lines = Vec::new();
+    loop {
+      match Response::parse(self.line()?) {
+        Ok(response) => return Ok((lines, response)),
+        Err(tokens) => lines.push(tokens),
+      }
+    }
+  }
+
+  // The command isn't logged, it may hold the credentials or a whole script.
+  fn exchange(
+    &mut self,
+    name: &str,
+    command: &[u8],
+  ) -> anyhow::Result<(Vec<Vec<Token>>, Response)> {
+    log::debug!("sending {name}");
+    self.rw.write_all(command)?;
+    let (lines, response) = self.responses()?;
+    anyhow::ensure!(
+      response.status != Status::Bye,
+      "the server closed the connection: {}",
+      response.message.as_deref().unwrap_or("no reason given")
+    );
+    Ok((lines, response))
+  }
+
+  // https://www.rfc-editor.org/rfc/rfc5804#section-2.2
+  // The TLS negotiation starts right after the server's OK, the stream is given back to be wrapped.
+  pub fn starttls(mut self) -> anyhow::Result<RW> {
+    anyhow::ensure!(
+      self.capability("STARTTLS").is_some(),
+      "STARTTLS is missing from the capabilities"
+    );
+    let (_, response) = self.exchange("STARTTLS", b"STARTTLS\r\n")?;
+    response.ensure_ok("STARTTLS")?;
+    anyhow::ensure!(
+      self.buffer.is_empty(),
+      "the server sent data before the TLS negotiation"
+    );
+    Ok(self.rw)
+  }
+
+  // https://www.rfc-editor.org/rfc/rfc5804#section-2.1
+  // Like sync::authenticate but the challenges and responses are strings.
+  fn authenticate(&mut self, credentials: &credentials::Credentials) -> anyhow::Result<()> {
+    let advertised: Vec<Vec<u8>> = self
+      .capability("SASL")
+      .flatten()
+      .unwrap_or_default()
+      .split(' ')
+      .map(|mechanism| format!("AUTH={}", mechanism.to_ascii_uppercase()).into_bytes())
+      .collect();
+    let mechanism = sync::mechanism(&advertised, credentials)?;
+    log::debug!("authenticating with {}", mechanism.name());
+    let password = match mechanism {
+      imap::Mechanism::External => zeroize::Zeroizing::new(String::new()),
+      _ => credentials.password()?,
+    };
+    let mut sasl = imap::Sasl::new(mechanism, &credentials.user, &password);
+    let mut command = zeroize::Zeroizing::new(format!("AUTHENTICATE {}", quote(mechanism.name())));
+    if let Some(initial) = sasl.initial() {
+      command.push_str(&format!(" {}", quote(&initial)));
+    }
+    command.push_str("\r\n");
+    log::debug!("sending AUTHENTICATE");
+    self.rw.write_all(command.as_bytes())?;
+    let response = loop {
+      let tokens = self.line()?;
+      let challenge = match tokens.as_slice() {
+        [Token::String(challenge)] => challenge,
+        _ => {
+          break Response::parse(tokens)
+            .map_err(|tokens| anyhow::anyhow!("unexpected response {tokens:?}"))?;
+        }
+      };
+      let response = sasl.step(challenge)?;
+      let response = zeroize::Zeroizing::new(format!("{}\r\n", quote(&response)));
+      self.rw.write_all(response.as_bytes())?;
+    };
+    if let Err(error) = response.ensure_ok("AUTHENTICATE") {
+      // Like sync::authenticate, the password may have expired.
+      credentials.invalidate();
+      return Err(error);
+    }
+    // https://www.rfc-editor.org/rfc/rfc5804#section-2.1
+    // The server's additional data with success (e.g.: SCRAM's signature) is in the SASL response
+    // code.
+    if let [Token::Atom(atom), Token::String(data)] = response.code.as_slice() {
+      if atom.eq_ignore_ascii_case("SASL") {
+        sasl.step(data)?;
+      }
+    }
+    sasl.finish()
+  }
+
+  // https://www.rfc-editor.org/rfc/rfc5804#section-2.7
+  // The name of the active script, if any.
+  fn active(&mut self) -> anyhow::Result<Option<String>> {
+    let (lines, response) = self.exchange("LISTSCRIPTS", b"LISTSCRIPTS\r\n")?;
+    response.ensure_ok("LISTSCRIPTS")?;
+    Ok(lines.into_iter().find_map(|line| match line.as_slice() {
+      [Token::String(name), Token::Atom(active)] if active.eq_ignore_ascii_case("ACTIVE") => {
+        Some(String::from_utf8_lossy(name).into_owned())
+      }
+      _ => None,
+    }))
+  }
+
+  // https://www.rfc-editor.org/rfc/rfc5804#section-2.9
+  fn get(&mut self, name: &str) -> anyhow::Result<Vec<u8>> {
+    let (mut lines, response) = self.exchange(
+      "GETSCRIPT",
+      format!("GETSCRIPT {}\r\n", quote(name)).as_bytes(),
+    )?;
+    response.ensure_ok("GETSCRIPT")?;
+    let script = match lines.pop() {
+      Some(mut line) if lines.is_empty() && line.len() == 1 => line.pop(),
+      _ => None,
+    };
+    match script {
+      Some(Token::String(script)) => Ok(script),
+      _ => anyhow::bail!("unexpected GETSCRIPT response"),
+    }
+  }
+
+  // https://www.rfc-editor.org/rfc/rfc5804#section-2.6
+  // The server checks the script before storing it, the reason it's refused is in the response.
+  fn put(&mut self, name: &str, script: &[u8]) -> anyhow::Result<()> {
+    // Non-synchronizing: no round trip before the script is sent.
+    let mut command = format!("PUTSCRIPT {} {{{}+}}\r\n", quote(name), script.len()).into_bytes();
+    command.extend_from_slice(script);
+    command.extend_from_slice(b"\r\n");
+    let (_, response) = self.exchange("PUTSCRIPT", &command)?;
+    response.ensure_ok("PUTSCRIPT")
+  }
+
+  // https://www.rfc-editor.org/rfc/rfc5804#section-2.8
+  // An empty name deactivates the active script (which is kept).
+  fn set_active(&mut self, name: &str) -> anyhow::Result<()> {
+    let (_, response) = self.exchange(
+      "SETACTIVE",
+      format!("SETACTIVE {}\r\n", quote(name)).as_bytes(),
+    )?;
+    response.ensure_ok("SETACTIVE")
+  }
+
+  // https://www.rfc-editor.org/rfc/rfc5804#section-2.3
+  fn logout(mut self) -> anyhow::Result<()> {
+    log::debug!("sending LOGOUT");
+    self.rw.write_all(b"LOGOUT\r\n")?;
+    let (_, response) = self.responses()?;
+    anyhow::ensure!(
+      response.status != Status::No,
+      "LOGOUT failed: {}",
+      response.message.as_deref().unwrap_or("no reason given")
+    );
+    Ok(())
+  }
+}
+
+// What both sides had after the last synchronization: the script's name and its SHA-256, next to
+// the local file. Without it, the first synchronization only goes one way (or both sides must
+// already agree).
+#[derive(Debug, PartialEq)]
+struct Synchronized {
+  name: String,
+  sha256: String,
+}
+
+fn synchronized_path(path: &path::Path) -> path::PathBuf {
+  let mut synchronized = path.as_os_str().to_os_string();
+  synchronized.push(".synchronized");
+  path::PathBuf::from(synchronized)
+}
+
+impl Synchronized {
+  fn load(path: &path::Path) -> anyhow::Result<Option<Self>> {
+    let content = match fs::read_to_string(path) {
+      Ok(content) => content,
+      Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+      Err(error) => return Err(error).with_context(|| format!("couldn't read {path:?}")),
+    };
+    let (sha256, name) = content
+      .trim_end_matches('\n')
+      .split_once(' ')
+      .with_context(|| format!("couldn't parse {path:?}"))?;
+    Ok(Some(Self {
+      name: name.to_string(),
+      sha256: sha256.to_string(),
+    }))
+  }
+
+  fn save(synchronized: Option<Self>, path: &path::Path) -> anyhow::Result<()> {
+    match synchronized {
+      Some(Self { name, sha256 }) => write(path, format!("{sha256} {name}\n").as_bytes()),
+      None => remove(path),
+    }
+  }
+}
+
+fn sha256(buffer: &[u8]) -> String {
+  use sha2::Digest as _;
+  sha2::Sha256::digest(buffer)
+    .iter()
+    .map(|byte| format!("{byte:02x}"))
+    .collect()
+}
+
+// Written aside then renamed over: an interruption leaves the previous one.
+fn write(path: &path::Path, content: &[u8]) -> anyhow::Result<()> {
+  let mut temporary = path.as_os_str().to_os_string();
+  temporary.push(".tmp");
+  fs::write(&temporary, content).with_context(|| format!("couldn't write {temporary:?}"))?;
+  fs::rename(&temporary, path).with_context(|| format!("couldn't rename {temporary:?}"))
+}
+
+fn remove(path: &path::Path) -> anyhow::Result<()> {
+  match fs::remove_file(path) {
+    Err(error) if error.kind() != io::ErrorKind::NotFound => {
+      Err(error).with_context(|| format!("couldn't remove {path:?}"))
+    }
+    _ => Ok(()),
+  }
+}
+
+// Two-way: whichever side changed since the last synchronization wins, both changing is a conflict
+// left to the user. A script uploaded from the local file is named after the active one (or name,
+// when none is) and activated, a removed local file only deactivates the server's.
+pub fn synchronize<RW>(
+  mut session: Session<RW>,
+  credentials: &credentials::Credentials,
+  path: &path::Path,
+  name: &str,
+) -> anyhow::Result<()>
+where
+  RW: imap::ReadWrite,
+{
+  session.authenticate(credentials)?;
+  script(&mut session, path, name)?;
+  session.logout()
+}
+
+fn script<RW>(session: &mut Session<RW>, path: &path::Path, name: &str) -> anyhow::Result<()>
+where
+  RW: imap::ReadWrite,
+{
+  let server = match session.active()? {
+    Some(name) => {
+      let script = session.get(&name)?;
+      Some((name, script))
+    }
+    None => None,
+  };
+  let local = match fs::read(path) {
+    Ok(script) => Some(script),
+    Err(error) if error.kind() == io::ErrorKind::NotFound => None,
+    Err(error) => return Err(error).with_context(|| format!("couldn't read {path:?}")),
+  };
+  let synchronized_path = synchronized_path(path);
+  let synchronized = Synchronized::load(&synchronized_path)?;
+
+  let server_sha256 = server.as_ref().map(|(_, script)| sha256(script));
+  let local_sha256 = local.as_deref().map(sha256);
+  let synchronized_sha256 = synchronized
+    .as_ref()
+    .map(|synchronized| &synchronized.sha256);
+  let record = if server_sha256 == local_sha256 {
+    log::info!("the sieve script is up to date");
+    server.map(|(name, _)| Synchronized {
+      name,
+      sha256: local_sha256.unwrap(), // Same as the server's.
+    })
+  } else if local_sha256.as_ref() == synchronized_sha256 {
+    match server {
+      Some((name, script)) => {
+        log::info!("downloading the sieve script {name:?} to {path:?}");
+        write(path, &script)?;
+        Some(Synchronized {
+          name,
+          sha256: server_sha256.unwrap(), // Guaranteed by the match.
+        })
+      }
+      None => {
+        log::info!("no sieve script is active anymore, removing {path:?}");
+        remove(path)?;
+        None
+      }
+    }
+  } else if server_sha256.as_ref() == synchronized_sha256 {
+    match local {
+      Some(script) => {
+        let name = server
+          .map(|(name, _)| name)
+          .or(synchronized.map(|synchronized| synchronized.name))
+          .unwrap_or(name.to_string());
+        log::info!("uploading {path:?} as the sieve script {name:?}");
+        session.put(&name, &script)?;
+        session.set_active(&name)?;
+        Some(Synchronized {
+          name,
+          sha256: local_sha256.unwrap(), // Guaranteed by the match.
+        })
+      }
+      None => {
+        log::info!("{path:?} was removed, deactivating the sieve script");
+        session.set_active("")?;
+        None
+      }
+    }
+  } else {
+    anyhow::bail!(
+      "the sieve script changed both on the server and in {path:?}, remove {path:?} and \
+       {synchronized_path:?} to download the server's"
+    );
+  };
+  Synchronized::save(record, &synchronized_path)
+}
+
+// Quoted strings can't hold line breaks, they're only used for names and SASL data.
+fn quote(string: &str) -> String {
+  format!("\"{}\"", string.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+// https://www.rfc-editor.org/rfc/rfc5804#section-1.7
+// Each capability is a string, followed by its value (a string) for some of them.
+fn capabilities(lines: Vec<Vec<Token>>) -> Vec<(String, Option<String>)> {
+  lines
+    .into_iter()
+    .filter_map(|line| {
+      let mut tokens = line.into_iter();
+      let Some(Token::String(name)) = tokens.next() else {
+        return None;
+      };
+      let value = match tokens.next() {
+        Some(Token::String(value)) => Some(String::from_utf8_lossy(&value).into_owned()),
+        _ => None,
+      };
+      Some((String::from_utf8_lossy(&name).into_owned(), value))
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // Replies with the canned responses, whatever the commands.
+  struct Server {
+    responses: io::Cursor<Vec<u8>>,
+    commands: Vec<u8>,
+  }
+
+  impl Server {
+    fn new(responses: &[u8]) -> Self {
+      Self {
+        responses: io::Cursor::new(responses.to_vec()),
+        commands: Vec::new(),
+      }
+    }
+  }
+
+  impl io::Read for Server {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+      self.responses.read(buf)
+    }
+  }
+
+  impl io::Write for Server {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+      self.commands.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+      Ok(())
+    }
+  }
+
+  // Without the greeting.
+  fn session(server: &mut Server) -> Session<&mut Server> {
+    Session {
+      rw: server,
+      buffer: Vec::new(),
+      capabilities: Vec::new(),
+    }
+  }
+
+  #[test]
+  fn greeting() -> anyhow::Result<()> {
+    let mut server = Server::new(
+      b"\"IMPLEMENTATION\" \"Dovecot Pigeonhole\"\r\n\"SASL\" \"PLAIN LOGIN\"\r\n\
+        \"STARTTLS\"\r\n\"VERSION\" \"1.0\"\r\nOK \"Ready.\"\r\n",
+    );
+    let session = Session::new(&mut server)?;
+    assert_eq!(Some(Some("PLAIN LOGIN")), session.capability("sasl"));
+    assert_eq!(Some(None), session.capability("STARTTLS"));
+    assert_eq!(None, session.capability("NOTIFY"));
+    Ok(())
+  }
+
+  #[test]
+  fn responses() -> anyhow::Result<()> {
+    let mut server =
+      Server::new(b"{12}\r\nkeep;\r\nstop;\r\nNO (QUOTA/MAXSIZE) \"Too \\\"big\\\".\"\r\nbye\r\n");
+    let mut session = session(&mut server);
+    let (lines, response) = session.responses()?;
+    assert_eq!(vec![vec![Token::String(b"keep;\r\nstop;".to_vec())]], lines);
+    assert_eq!(
+      Response {
+        status: Status::No,
+        code: vec![Token::Atom("QUOTA/MAXSIZE".to_string())],
+        message: Some("Too \"big\".".to_string()),
+      },
+      response
+    );
+    assert_eq!(
+      "PUTSCRIPT failed: Too \"big\".",
+      response.ensure_ok("PUTSCRIPT").unwrap_err().to_string()
+    );
+    // Case insensitive.
+    assert_eq!(Status::Bye, session.responses()?.1.status);
+    Ok(())
+  }
+
+  #[test]
+  fn synchronize() -> anyhow::Result<()> {
+    let directory = tempfile::tempdir()?;
+    let path = directory.path().join("sin.sieve");
+    let listscripts = b"\"other\"\r\n\"filters\" ACTIVE\r\nOK\r\n";
+
+    // Only on the server, downloaded.
+    let mut server = Server::new(&[&listscripts[..], b"{5}\r\nkeep;\r\nOK\r\n"].concat());
+    script(&mut session(&mut server), &path, "sin")?;
+    assert_eq!("keep;", fs::read_to_string(&path)?);
+    assert_eq!(
+      Some(Synchronized {
+        name: "filters".to_string(),
+        sha256: sha256(b"keep;"),
+      }),
+      Synchronized::load(&synchronized_path(&path))?
+    );
+
+    // Changed locally, uploaded under the active script's name.
+    fs::write(&path, "discard;")?;
+    let mut server =
+      Server::new(&[&listscripts[..], b"{5}\r\nkeep;\r\nOK\r\nOK\r\nOK\r\n"].concat());
+    script(&mut session(&mut server), &path, "sin")?;
+    assert_eq!(
+      b"LISTSCRIPTS\r\nGETSCRIPT \"filters\"\r\nPUTSCRIPT \"filters\" {8+}\r\ndiscard;\r\n\
+        SETACTIVE \"filters\"\r\n",
+      &server.commands[..]
+    );
+
+    // Changed on both sides.
+    fs::write(&path, "stop;")?;
+    let mut server = Server::new(&[&listscripts[..], b"{5}\r\nkeep;\r\nOK\r\n"].concat());
+    let error = script(&mut session(&mut server), &path, "sin").unwrap_err();
+    assert!(error.to_string().contains("changed both on the server"));
+
+    // Removed locally (after agreeing again), deactivated.
+    fs::remove_file(synchronized_path(&path))?;
+    fs::remove_file(&path)?;
+    let mut server = Server::new(&[&listscripts[..], b"{5}\r\nkeep;\r\nOK\r\n"].concat());
+    script(&mut session(&mut server), &path, "sin")?;
+    fs::remove_file(&path)?;
+    let mut server = Server::new(&[&listscripts[..], b"{5}\r\nkeep;\r\nOK\r\nOK\r\n"].concat());
+    script(&mut session(&mut server), &path, "sin")?;
+    assert!(server.commands.ends_with(b"SETACTIVE \"\"\r\n"));
+    assert_eq!(None, Synchronized::load(&synchronized_path(&path))?);
+    Ok(())
+  }
+}
@@ -0,0 +1,350 @@
+// What the previous synchronizations recorded in the database (the properties of the root message
+// and of the synchronized messages), instead of grepping notmuch dump.
+// And, for recovery, guarded edits since there's no way to edit properties via the Notmuch CLI.
+
+use crate::{notmuch, sync};
+use anyhow::Context as _;
+use std::{collections, fmt::Write as _, io};
+
+struct Mailbox {
+  name: String,
+  separator: Option<char>,
+  uidvalidity: u64,
+  uidnext: u64,
+  highestmodseq: u64,
+  // Only for the escaped names (see maildir::Builder::maildir).
+  maildir: Option<String>,
+}
+
+struct Mapping {
+  mailbox: String,
+  uidvalidity: u64,
+  uid: u64,
+  modseq: u64,
+  tags: Vec<String>,
+}
+
+struct Message {
+  id: String,
+  paths: Vec<String>,
+  mappings: Vec<Mapping>,
+}
+
+struct State {
+  lastmod: u64,
+  mailboxes: Vec<Mailbox>,
+  messages: Vec<Message>,
+}
+
+fn sorted<'a>(strings: impl IntoIterator<Item = &'a str>) -> Vec<String> {
+  strings
+    .into_iter()
+    .collect::<collections::BTreeSet<_>>()
+    .into_iter()
+    .map(String::from)
+    .collect()
+}
+
+impl State {
+  fn new(
+    database: &notmuch::Database<notmuch::Attached>,
+    query: Option<&str>,
+  ) -> anyhow::Result<Self> {
+    let root = database.root()?;
+    let mut mailboxes = Vec::new();
+    for name in sorted(root.mailboxes()?) {
+      let (uidvalidity, highestmodseq) = root.validity(&name)?;
+      mailboxes.push(Mailbox {
+        separator: root.separator(&name)?,
+        uidvalidity,
+        uidnext: root.uidnext(&name)?,
+        highestmodseq,
+        maildir: root.maildir(&name)?.map(String::from),
+        name,
+      });
+    }
+
+    let mut messages = Vec::new();
+    if let Some(query) = query {
+      let mut results = database.query(&format!(
+        "property:\"{}.marker={}\" and ({query})",
+        notmuch::quote(database.namespace()),
+        notmuch::MESSAGE_MARKER,
+      ))?;
+      while let Some(message) = results.next() {
+        let mut mappings = Vec::new();
+        for mailbox in sorted(message.mailboxes()?) {
+          // One per duplicate.
+          for uid in message.uids(&mailbox)? {
+            mappings.push(Mapping {
+              uidvalidity: message.uidvalidity(&mailbox)?,
+              uid,
+              modseq: message.modseq(&mailbox, uid)?,
+              tags: sorted(message.cached_tags(&mailbox)?),
+              mailbox: mailbox.clone(),
+            });
+          }
+        }
+        messages.push(Message {
+          id: message.message_id()?.to_string(),
+          paths: message
+            .paths()?
+            .iter()
+            .map(|path| path.to_string_lossy().into_owned())
+            .collect(),
+          mappings,
+        });
+      }
+      messages.sort_by(|a, b| a.id.cmp(&b.id)); // Stable output.
+    }
+
+    Ok(Self {
+      lastmod: root.lastmod()?,
+      mailboxes,
+      messages,
+    })
+  }
+
+  fn human(&self) -> String {
+    let mut output = String::new();
+    // Writing to a String can't fail.
+    writeln!(output, "lastmod {}", self.lastmod).unwrap();
+    for mailbox in &self.mailboxes {
+      writeln!(
+        output,
+        "mailbox {} uidvalidity {} uidnext {} highestmodseq {} separator {}",
+        mailbox.name,
+        mailbox.uidvalidity,
+        mailbox.uidnext,
+        mailbox.highestmodseq,
+        mailbox
+          .separator
+          .map_or("none".to_string(), |separator| separator.to_string()),
+      )
+      .unwrap();
+      if let Some(maildir) = &mailbox.maildir {
+        writeln!(output, "  maildir {maildir}").unwrap();
+      }
+    }
+    for message in &self.messages {
+      writeln!(output, "message {}", message.id).unwrap();
+      for path in &message.paths {
+        writeln!(output, "  path {path}").unwrap();
+      }
+      for mapping in &message.mappings {
+        writeln!(
+          output,
+          "  mailbox {} uidvalidity {} uid {} modseq {} tags {}",
+          mapping.mailbox,
+          mapping.uidvalidity,
+          mapping.uid,
+          mapping.modseq,
+          mapping.tags.join(" "),
+        )
+        .unwrap();
+      }
+    }
+    output
+  }
+
+  fn json(&self) -> String {
+    let strings = |strings: &[String]| -> String {
+      let strings: Vec<String> = strings.iter().map(|string| json_string(string)).collect();
+      format!("[{}]", strings.join(","))
+    };
+    let mailboxes: Vec<String> = self
+      .mailboxes
+      .iter()
+      .map(|mailbox| {
+        format!(
+          "{{\"name\":{},\"separator\":{},\"uidvalidity\":{},\"uidnext\":{},\"highestmodseq\":{},\"maildir\":{}}}",
+          json_string(&mailbox.name),
+          mailbox.separator.map_or("null".to_string(), |separator| {
+            json_string(&separator.to_string())
+          }),
+          mailbox.uidvalidity,
+          mailbox.uidnext,
+          mailbox.highestmodseq,
+          mailbox
+            .maildir
+            .as_deref()
+            .map_or("null".to_string(), json_string),
+        )
+      })
+      .collect();
+    let messages: Vec<String> = self
+      .messages
+      .iter()
+      .map(|message| {
+        let mappings: Vec<String> = message
+          .mappings
+          .iter()
+          .map(|mapping| {
+            format!(
+              "{{\"mailbox\":{},\"uidvalidity\":{},\"uid\":{},\"modseq\":{},\"tags\":{}}}",
+              json_string(&mapping.mailbox),
+              mapping.uidvalidity,
+              mapping.uid,
+              mapping.modseq,
+              strings(&mapping.tags),
+            )
+          })
+          .collect();
+        format!(
+          "{{\"id\":{},\"paths\":{},\"mailboxes\":[{}]}}",
+          json_string(&message.id),
+          strings(&message.paths),
+          mappings.join(","),
+        )
+      })
+      .collect();
+    format!(
+      "{{\"lastmod\":{},\"mailboxes\":[{}],\"messages\":[{}]}}\n",
+      self.lastmod,
+      mailboxes.join(","),
+      messages.join(","),
+    )
+  }
+}
+
+// https://www.rfc-editor.org/rfc/rfc8259#section-7
+// All Unicode characters may be placed within the quotation marks, except for the characters that
+// MUST be escaped: quotation mark, reverse solidus, and the control characters (U+0000 through
+// U+001F).
+fn json_string(string: &str) -> String {
+  let mut output = String::with_capacity(string.len() + 2);
+  output.push('"');
+  for c in string.chars() {
+    match c {
+      '"' => output.push_str("\\\""),
+      '\\' => output.push_str("\\\\"),
+      '\n' => output.push_str("\\n"),
+      '\r' => output.push_str("\\r"),
+      '\t' => output.push_str("\\t"),
+      c if c < '\u{20}' => write!(output, "\\u{:04x}", c as u32).unwrap(),
+      c => output.push(c),
+    }
+  }
+  output.push('"');
+  output
+}
+
+pub fn write(
+  database: &notmuch::Database<notmuch::Attached>,
+  query: Option<&str>,
+  json: bool,
+  output: &mut dyn io::Write,
+) -> anyhow::Result<()> {
+  let state = State::new(database, query)?;
+  output.write_all(
+    match json {
+      true => state.json(),
+      false => state.human(),
+    }
+    .as_bytes(),
+  )?;
+  Ok(())
+}
+
+// lastmod or <mailbox>.<uidvalidity|uidnext|highestmodseq|separator>, the mailbox must be known.
+pub fn set(
+  database: &mut notmuch::Database<notmuch::Attached>,
+  property: &str,
+  value: &str,
+) -> anyhow::Result<()> {
+  let number = || {
+    value
+      .parse::<u64>()
+      .with_context(|| format!("{value:?} isn't a number"))
+  };
+  let mut root = database.root()?;
+  if property == "lastmod" {
+    log::info!("setting {property} to {value:?}");
+    return root.update_lastmod(number()?);
+  }
+  let (mailbox, key) = property.rsplit_once('.').with_context(|| {
+    format!(
+      "{property} is neither lastmod nor <mailbox>.<uidvalidity|uidnext|highestmodseq|separator>"
+    )
+  })?;
+  anyhow::ensure!(
+    root.mailboxes()?.contains(mailbox),
+    "unknown mailbox {mailbox}"
+  );
+  let (mut uidvalidity, mut highestmodseq) = root.validity(mailbox)?;
+  let (mut uidnext, mut separator) = (root.uidnext(mailbox)?, root.separator(mailbox)?);
+  match key {
+    "uidvalidity" => {
+      uidvalidity = number()?;
+      // https://www.rfc-editor.org/rfc/rfc3501#section-9
+      // uniqueid = nz-number
+      anyhow::ensure!(uidvalidity != 0, "the uidvalidity can't be 0");
+    }
+    "uidnext" => uidnext = number()?,
+    "highestmodseq" => {
+      highestmodseq = number()?;
+      // https://www.rfc-editor.org/rfc/rfc7162.html#section-3.1
+      // this version of the document redefines them as unsigned 63-bit values.
+      anyhow::ensure!(
+        highestmodseq <= i64::MAX as u64,
+        "the highestmodseq is a 63-bit value"
+      );
+    }
+    "separator" => {
+      separator = match value.chars().collect::<Vec<_>>()[..] {
+        [] => None,
+        [separator] => Some(separator),
+        _ => anyhow::bail!("the separator is a single character"),
+      }
+    }
+    _ => anyhow::bail!("unknown property {key}"),
+  }
+  log::info!("setting {property} to {value:?}");
+  root.update_mailbox_properties(mailbox, separator, uidvalidity, uidnext, highestmodseq)
+}
+
+// Forget a mailbox (the files are left alone): the next pull will consider it new.
+pub fn clear_mailbox(
+  database: &mut notmuch::Database<notmuch::Attached>,
+  mailbox: &str,
+) -> anyhow::Result<()> {
+  let mut root = database.root()?;
+  anyhow::ensure!(
+    root.mailboxes()?.contains(mailbox),
+    "unknown mailbox {mailbox}"
+  );
+  root.remove_mailbox_properties(mailbox)?;
+  let mut count = 0;
+  let mut messages = sync::search_mailbox(database, mailbox)?;
+  while let Some(mut message) = messages.next() {
+    message.remove_mailbox_properties(mailbox)?;
+    count += 1;
+  }
+  log::info!("cleared {mailbox} and the properties of {count} message(s)");
+  Ok(())
+}
+
+// Drop the properties of another maildir that isn't synchronized anymore.
+pub fn clear_namespace(
+  database: &mut notmuch::Database<notmuch::Attached>,
+  id: u64,
+) -> anyhow::Result<()> {
+  let count = database.remove_namespace(id)?;
+  log::info!(
+    "cleared {}.{id} from {count} message(s)",
+    database.root_namespace()
+  );
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  #[test]
+  fn json_string() {
+    assert_eq!(r#""INBOX""#, super::json_string("INBOX"));
+    assert_eq!(
+      r#""a \"quoted\" \\ path\n\u0001""#,
+      super::json_string("a \"quoted\" \\ path\n\u{1}")
+    );
+  }
+}
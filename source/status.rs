@@ -0,0 +1,122 @@
+// What's currently being done, logged on demand (SIGUSR1, see main.rs) so a long synchronization
+// that looks stuck can be inspected, and reported to systemd along the way.
+
+use crate::systemd;
+use std::{collections, fmt::Write as _, sync, time};
+
+#[derive(Default)]
+struct Counters {
+  done: usize,
+  total: usize,
+}
+
+#[derive(Default)]
+struct Status {
+  phase: String,
+  since: Option<time::Instant>,
+  mailbox: Option<String>,
+  // Per mailbox, the messages to download (pull) or to upload and update (push).
+  mailboxes: collections::BTreeMap<String, Counters>,
+}
+
+impl Status {
+  fn remaining(&self) -> usize {
+    self
+      .mailboxes
+      .values()
+      .map(|counters| counters.total - counters.done)
+      .sum()
+  }
+
+  fn summary(&self) -> String {
+    let mut summary = self.phase.clone();
+    if let Some(mailbox) = &self.mailbox {
+      write!(summary, " (mailbox {mailbox})").unwrap();
+    }
+    match self.remaining() {
+      0 => summary,
+      remaining => format!("{summary}, {remaining} message(s) remaining"),
+    }
+  }
+
+  fn report(&self) -> String {
+    let mut report = self.summary();
+    if let Some(since) = self.since {
+      write!(report, ", for {}s", since.elapsed().as_secs()).unwrap();
+    }
+    for (mailbox, Counters { done, total }) in &self.mailboxes {
+      write!(report, "\n  {mailbox}: {done}/{total} message(s)").unwrap();
+    }
+    report
+  }
+}
+
+static STATUS: once_cell::sync::Lazy<sync::Mutex<Status>> =
+  once_cell::sync::Lazy::new(Default::default);
+
+// A new phase starts (e.g.: a mode), the counters of the previous one are dropped.
+pub fn phase(phase: &str) {
+  let mut status = STATUS.lock().unwrap();
+  *status = Status {
+    phase: phase.to_string(),
+    since: Some(time::Instant::now()),
+    ..Default::default()
+  };
+  systemd::status(&status.summary());
+}
+
+pub fn mailbox(mailbox: &str) {
+  let mut status = STATUS.lock().unwrap();
+  status.mailbox = Some(mailbox.to_string());
+  status.mailboxes.entry(mailbox.to_string()).or_default();
+  systemd::status(&status.summary());
+}
+
+// More messages to process in the mailbox.
+pub fn expect(mailbox: &str, count: usize) {
+  let mut status = STATUS.lock().unwrap();
+  status
+    .mailboxes
+    .entry(mailbox.to_string())
+    .or_default()
+    .total += count;
+}
+
+// A message of the mailbox was processed.
+pub fn done(mailbox: &str) {
+  let mut status = STATUS.lock().unwrap();
+  let counters = status.mailboxes.entry(mailbox.to_string()).or_default();
+  counters.done += 1;
+  counters.total = counters.total.max(counters.done);
+  systemd::progress(|| status.summary());
+}
+
+pub fn log() {
+  log::info!("status: {}", STATUS.lock().unwrap().report());
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn report() {
+    let mut status = Status {
+      phase: "pull".to_string(),
+      ..Default::default()
+    };
+    assert_eq!("pull", status.report());
+    status.mailbox = Some("INBOX".to_string());
+    status
+      .mailboxes
+      .insert("Archives".to_string(), Counters { done: 2, total: 2 });
+    status
+      .mailboxes
+      .insert("INBOX".to_string(), Counters { done: 1, total: 4 });
+    assert_eq!(
+      "pull (mailbox INBOX), 3 message(s) remaining\n  Archives: 2/2 message(s)\n  INBOX: 1/4 \
+       message(s)",
+      status.report()
+    );
+  }
+}
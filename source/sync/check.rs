@@ -0,0 +1,124 @@
+use crate::{imap, maildir, notmuch, sync};
+use std::collections;
+
+// Compare what the server reports with what was recorded by the previous synchronizations, without
+// changing anything. Returns the number of discrepancies.
+pub fn run<RW>(
+  stream: &mut imap::Stream<RW>,
+  database: &notmuch::Database<notmuch::Attached>,
+  maildir_builder: &maildir::Builder,
+  scope: &sync::Scope,
+) -> anyhow::Result<usize>
+where
+  RW: imap::ReadWrite,
+{
+  let mut discrepancies = 0;
+  let mut report = |message: String| {
+    log::warn!("{message}");
+    discrepancies += 1;
+  };
+
+  let mailboxes = sync::list(stream, scope)?;
+  let root = database.root()?;
+  for known_mailbox in root.mailboxes()? {
+    if !mailboxes
+      .iter()
+      .any(|mailbox| mailbox.string == known_mailbox)
+    {
+      report(format!(
+        "mailbox {known_mailbox:?} is known locally but missing on the server"
+      ));
+    }
+  }
+
+  for mailbox in &mailboxes {
+    let (uidvalidity, highestmodseq) = root.validity(&mailbox.string)?;
+    if uidvalidity == 0 {
+      log::info!("mailbox {:?} hasn't been pulled yet", mailbox.string);
+      continue;
+    }
+    log::debug!("checking mailbox {:?}", mailbox.string);
+    // Everything since the beginning: the flags of all the messages.
+    let mut select = sync::select(stream, &mailbox.bytes, uidvalidity, 0)?;
+    if select.uidvalidity != uidvalidity {
+      report(format!(
+        "mailbox {:?} changed UIDVALIDITY (from {uidvalidity} to {})",
+        mailbox.string, select.uidvalidity
+      ));
+      continue;
+    }
+    if select.highestmodseq < highestmodseq {
+      report(format!(
+        "mailbox {:?} went back in time (HIGHESTMODSEQ from {highestmodseq} to {})",
+        mailbox.string, select.highestmodseq
+      ));
+    }
+
+    let maildir = maildir_builder.maildir(&mailbox.string, &mailbox.separator)?;
+    let mut pending = 0;
+    let mut messages = sync::search_mailbox(database, &mailbox.string)?;
+    while let Some(message) = messages.next() {
+      let id = message.message_id()?;
+      let uids = message.uids(&mailbox.string)?;
+      for &uid in &uids {
+        match select.changes.remove(&uid) {
+          None => report(format!(
+            "message {id:?} (UID {uid} in {:?}) is missing on the server",
+            mailbox.string
+          )),
+          // Changed on the server since the last pull, the next one will take care of it.
+          Some(changes) if changes.modseq > message.modseq(&mailbox.string, uid)? => pending += 1,
+          Some(changes) => {
+            let flags = notmuch::flags_to_tags(&changes.flags.iter().map(String::as_str).collect());
+            let cached_tags = message.cached_tags(&mailbox.string)?;
+            if flags != cached_tags {
+              report(format!(
+                "message {id:?} (UID {uid} in {:?}) drifted: {:?} on the server, {:?} cached",
+                mailbox.string,
+                collections::BTreeSet::from_iter(flags),
+                collections::BTreeSet::from_iter(cached_tags),
+              ));
+            }
+          }
+        }
+      }
+      let paths: Vec<_> = message
+        .paths()?
+        .into_iter()
+        .filter(|path| maildir.has(path))
+        .collect();
+      if paths.len() < uids.len() {
+        report(format!(
+          "message {id:?} (UIDs {uids:?} in {:?}) has {} file(s) in {:?}",
+          mailbox.string,
+          paths.len(),
+          maildir.path()
+        ));
+      }
+      for path in paths.iter().filter(|path| !path.exists()) {
+        report(format!("message {id:?} is missing its file {path:?}"));
+      }
+    }
+
+    let uidnext = root.uidnext(&mailbox.string)?;
+    for uid in select.changes.into_keys() {
+      if uid >= uidnext {
+        // Arrived since the last pull.
+        pending += 1;
+      } else {
+        report(format!(
+          "UID {uid} in {:?} is missing locally",
+          mailbox.string
+        ));
+      }
+    }
+    if pending > 0 {
+      log::info!(
+        "{pending} message(s) in {:?} changed since the last pull",
+        mailbox.string
+      );
+    }
+  }
+
+  Ok(discrepancies)
+}
@@ -0,0 +1,224 @@
+use crate::{imap, import, maildir, notmuch, sync};
+use anyhow::Context as _;
+use std::{cmp, collections, fs, io, path};
+
+// Seed the properties of the mailboxes that haven't been synchronized yet from another tool's
+// state: the next pull only downloads what's missing (and refreshes the flags).
+#[allow(clippy::too_many_arguments)]
+pub fn run<RW>(
+  stream: &mut imap::Stream<RW>,
+  database: &mut notmuch::Database<notmuch::Attached>,
+  maildir_builder: &maildir::Builder,
+  scope: &sync::Scope,
+  tool: Option<import::Tool>,
+  state: Option<&path::Path>,
+  source: Option<&path::Path>,
+  mailbox: Option<&str>,
+) -> anyhow::Result<()>
+where
+  RW: imap::ReadWrite,
+{
+  let tool = tool.context("import requires --import-from")?;
+  if let import::Tool::Mbox | import::Tool::Maildir = tool {
+    return messages(
+      stream,
+      database,
+      maildir_builder,
+      scope,
+      tool,
+      source.context("importing messages requires --import-source")?,
+      mailbox.context("importing messages requires --mailbox")?,
+    );
+  }
+  let lieer = match tool {
+    import::Tool::Lieer => {
+      import::lieer(state.context("importing from lieer requires --import-state")?)?
+    }
+    _ => collections::HashMap::new(),
+  };
+  let mut root = database.root()?;
+  for mailbox in sync::list(stream, scope)? {
+    if root.validity(&mailbox.string)? != (0, 0) {
+      log::warn!(
+        "mailbox {} is already synchronized, skipping it",
+        mailbox.string
+      );
+      continue;
+    }
+    let maildir = maildir_builder.maildir(&mailbox.string, &mailbox.separator)?;
+    let imported = match tool {
+      import::Tool::Mbsync => import::mbsync(maildir.path())?,
+      import::Tool::Offlineimap => import::offlineimap(
+        maildir.path(),
+        state.context("importing from offlineimap requires --import-state")?,
+        &mailbox.string,
+      )?,
+      import::Tool::Lieer => Some(link_lieer(stream, &mailbox, &maildir, &lieer)?),
+      import::Tool::Mbox | import::Tool::Maildir => unreachable!(), // See above.
+    };
+    let Some(imported) = imported else {
+      log::info!("no state to import for mailbox {}", mailbox.string);
+      continue;
+    };
+
+    log::info!(
+      "importing {} message(s) of mailbox {} (uidvalidity:{})",
+      imported.uids.len(),
+      mailbox.string,
+      imported.uidvalidity
+    );
+    let mut uidnext = 1;
+    for (path, uid) in &imported.uids {
+      let mut message = database.add(path, &[])?;
+      // The flags as last synchronized, the next pull will update them (the modseq is unknown).
+      message.update_mailbox_properties(
+        &mailbox.string,
+        imported.uidvalidity,
+        *uid,
+        0,
+        &notmuch::flags_to_tags(&import::flags(path)),
+      )?;
+      uidnext = cmp::max(uidnext, uid + 1);
+    }
+    // Without a highestmodseq, the next pull will get the flags of all the messages.
+    root.update_mailbox_properties(
+      &mailbox.string,
+      mailbox.separator,
+      imported.uidvalidity,
+      uidnext,
+      0,
+    )?;
+  }
+  Ok(())
+}
+
+// Copy the messages of an mbox file or of another maildir to the mailbox's maildir and index them:
+// like the ones delivered locally, the next push uploads them (with their flags).
+fn messages<RW>(
+  stream: &mut imap::Stream<RW>,
+  database: &mut notmuch::Database<notmuch::Attached>,
+  maildir_builder: &maildir::Builder,
+  scope: &sync::Scope,
+  tool: import::Tool,
+  source: &path::Path,
+  mailbox: &str,
+) -> anyhow::Result<()>
+where
+  RW: imap::ReadWrite,
+{
+  let mailbox = sync::list(stream, scope)?
+    .into_iter()
+    .find(|mailbox_| mailbox_.string == mailbox)
+    .with_context(|| format!("mailbox {mailbox} doesn't exist on the server"))?;
+  let maildir = maildir_builder.maildir(&mailbox.string, &mailbox.separator)?;
+  // Returns whether the message was imported.
+  let add = |buffer: &[u8], flags: &collections::HashSet<&str>| -> anyhow::Result<bool> {
+    // Straight to new: unlike the pulled messages (see sync::move_out_of_tmp), they aren't
+    // recorded as synchronized.
+    let tmp = maildir.tmp(buffer)?;
+    let new = maildir.path().join("new").join(
+      tmp.file_name().unwrap(), /* Guaranteed by maildir::Maildir::tmp. */
+    );
+    fs::rename(&tmp, &new).with_context(|| format!("couldn't rename {tmp:?}"))?;
+    let tags: Vec<String> = notmuch::flags_to_tags(flags)
+      .into_iter()
+      .map(str::to_string)
+      .collect();
+    let mut message = database.add(&new, &tags)?;
+    // Already in the mailbox (pulled or imported before, with the same Message-ID): the push would
+    // upload it again.
+    if message.paths()?.iter().any(|path| {
+      path != &new && path.parent().and_then(path::Path::parent) == Some(maildir.path())
+    }) {
+      log::debug!(
+        "message {} is already in the mailbox",
+        message.message_id()?
+      );
+      database.remove(&new)?;
+      sync::unlink(&new)?;
+      return Ok(false);
+    }
+    log::debug!("imported message {}", message.message_id()?);
+    message.tags_to_maildir_flags()?; // If necessary, move from new to cur based on flags.
+    Ok(true)
+  };
+  let mut imported = Vec::new();
+  match tool {
+    import::Tool::Mbox => {
+      for message in import::mbox(source)? {
+        let (message, flags) = import::mbox_message(&message);
+        imported.push(add(&message, &flags)?);
+      }
+    }
+    import::Tool::Maildir => {
+      for path in import::maildir(source)? {
+        let buffer = fs::read(&path).with_context(|| format!("couldn't read {path:?}"))?;
+        imported.push(add(&buffer, &import::flags(&path))?);
+      }
+    }
+    _ => unreachable!(), // See run.
+  }
+  let count = imported.iter().filter(|imported| **imported).count();
+  let skipped = imported.len() - count;
+  if skipped > 0 {
+    log::info!(
+      "skipped {skipped} message(s) already in mailbox {}",
+      mailbox.string
+    );
+  }
+  log::info!(
+    "imported {count} message(s) to mailbox {}, the next push uploads them",
+    mailbox.string
+  );
+  Ok(())
+}
+
+// Ask the server for the Gmail message IDs of the mailbox and link the matching files from lieer's
+// maildir into the mailbox's.
+fn link_lieer<RW>(
+  stream: &mut imap::Stream<RW>,
+  mailbox: &sync::Mailbox,
+  maildir: &maildir::Maildir,
+  files: &collections::HashMap<u64, path::PathBuf>,
+) -> anyhow::Result<import::Mailbox>
+where
+  RW: imap::ReadWrite,
+{
+  let select = sync::select(stream, &mailbox.bytes, 0, 0)?;
+  let command: &[&[u8]] = &[b"import UID FETCH 1:* (X-GM-MSGID)\r\n"];
+  stream.input(command, command.len())?;
+  let mut ids = Vec::new();
+  loop {
+    match stream.expect(imap::parser::start)? {
+      b"*" => match stream.parse(imap::parser::fetch_gmail_message_id_data)? {
+        Some(id) => ids.push(id),
+        None => stream.expect(imap::parser::skip)?,
+      },
+      b"import" => break stream.expect(imap::parser::ok)?,
+      tag => anyhow::bail!("unexpected tag {tag:?}"),
+    }
+  }
+
+  let mut uids = Vec::new();
+  for (uid, id) in ids {
+    let Some(file) = files.get(&id) else {
+      continue;
+    };
+    let path = maildir.path().join("cur").join(
+      file.file_name().unwrap(), /* Guaranteed by import::lieer. */
+    );
+    // Fall back to a copy across file systems.
+    match fs::hard_link(file, &path) {
+      Ok(_) => (),
+      Err(error) if error.kind() == io::ErrorKind::AlreadyExists => (),
+      Err(_) => {
+        fs::copy(file, &path)?;
+      }
+    }
+    uids.push((path, uid));
+  }
+  Ok(import::Mailbox {
+    uidvalidity: select.uidvalidity,
+    uids,
+  })
+}
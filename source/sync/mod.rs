@@ -1,12 +1,24 @@
-use crate::{imap, maildir, notmuch};
+#[cfg(feature = "notmuch")]
+use crate::notmuch;
+use crate::{credentials::Credentials, imap, maildir};
 use anyhow::Context as _;
-use std::{borrow, collections, fs, io, path, str};
+use std::{borrow, cmp, collections, error, fmt, fs, io, path, str};
 
+// The notmuch backend.
+#[cfg(feature = "notmuch")]
+pub mod check;
+#[cfg(feature = "notmuch")]
+pub mod import;
+#[cfg(feature = "notmuch")]
 pub mod pull;
+#[cfg(feature = "notmuch")]
 pub mod push;
-
-#[derive(zeroize::ZeroizeOnDrop)]
-pub struct Credentials(pub String);
+#[cfg(feature = "notmuch")]
+pub mod repair;
+// The maildir backend.
+pub mod plain;
+// Where the backends keep the messages.
+pub mod store;
 
 // Establish a connection to the server.
 pub trait Open: Send + Sync {
@@ -14,84 +26,412 @@ pub trait Open: Send + Sync {
   fn open(&self) -> anyhow::Result<Self::RW>;
 }
 
-pub fn greetings<RW>(stream: &mut imap::Stream<RW>) -> anyhow::Result<()>
+// Needed to talk to the server at all.
+const GREETINGS_CAPABILITIES: &[&str] = &[
+  // https://www.rfc-editor.org/rfc/rfc3501
+  "IMAP4rev1",
+  // https://www.rfc-editor.org/rfc/rfc5161
+  "ENABLE",
+];
+
+// Needed once authenticated.
+const AUTHENTICATED_CAPABILITIES: &[&str] = &[
+  // https://www.rfc-editor.org/rfc/rfc2342
+  "NAMESPACE",
+  // https://www.rfc-editor.org/rfc/rfc4315 (for APPENDUID, COPYUID)
+  "UIDPLUS",
+  // https://www.rfc-editor.org/rfc/rfc6851
+  "MOVE",
+  // https://www.rfc-editor.org/rfc/rfc7162 (for UNCHANGEDSINCE)
+  "CONDSTORE",
+  // QRESYNC is preferred but ESEARCH will do (see enable).
+];
+
+fn ensure_capabilities(available: &[Vec<u8>], expected: &[&str]) -> anyhow::Result<()> {
+  for capability in expected {
+    anyhow::ensure!(
+      available.iter().any(|c| c == capability.as_bytes()),
+      format!("{capability} is missing from CAPABILITY list")
+    );
+  }
+  Ok(())
+}
+
+fn to_owned_capabilities(capabilities: &[&[u8]]) -> Vec<Vec<u8>> {
+  capabilities.iter().map(|c| c.to_vec()).collect()
+}
+
+// https://www.rfc-editor.org/rfc/rfc3501#section-6.1.1
+// The CAPABILITY command requests a listing of capabilities that the server supports.
+fn capability<RW>(stream: &mut imap::Stream<RW>) -> anyhow::Result<Vec<Vec<u8>>>
+where
+  RW: imap::ReadWrite,
+{
+  let command: &[&[u8]] = &[b"capability CAPABILITY\r\n"];
+  stream.input(command, command.len())?;
+  let mut capabilities = None;
+  loop {
+    match stream.expect(imap::parser::start)? {
+      b"*" => match stream.parse(imap::parser::capability_response)? {
+        Some(capabilities_) => capabilities = Some(to_owned_capabilities(&capabilities_)),
+        None => stream.expect(imap::parser::skip)?,
+      },
+      b"capability" => break stream.expect(imap::parser::ok)?,
+      tag => anyhow::bail!("unexpected tag {tag:?}"),
+    }
+  }
+  capabilities.context("CAPABILITY is missing from CAPABILITY")
+}
+
+pub struct Greetings {
+  // The connection has already been authenticated (PREAUTH).
+  pub preauthenticated: bool,
+  capabilities: Vec<Vec<u8>>,
+  // Detected from the greeting's text.
+  quirks: Option<imap::Quirks>,
+}
+
+pub fn greetings<RW>(stream: &mut imap::Stream<RW>) -> anyhow::Result<Greetings>
 where
   RW: imap::ReadWrite,
 {
   // Fetch some data first (the Stream doesn't pull, it bufferizes each response to completion).
   // Assumme we won't end up with a partial read of the greetings.
-  stream.read(&mut [0; 32 * 1024])?;
-  let capabilities = loop {
+  stream.read(32 * 1024)?;
+  let (preauthenticated, capabilities, quirks) = loop {
     match stream.expect(imap::parser::start)? {
       b"*" => {
         // Some servers send notices.
-        if let Ok(Some(capabilities)) = stream.parse(imap::parser::available_capabilities) {
-          break capabilities;
+        if let Ok(Some(greeting)) = stream.parse(imap::parser::greeting) {
+          break (
+            greeting.preauthenticated,
+            greeting
+              .capabilities
+              .map(|capabilities| to_owned_capabilities(&capabilities)),
+            imap::Quirks::detect(greeting.text),
+          );
         }
       }
       tag => anyhow::bail!("unexpected tag {tag:?}"),
     }
   };
-  for capability in [
-    // https://www.rfc-editor.org/rfc/rfc3501
-    "IMAP4rev1",
-    "AUTH=PLAIN",
-    // https://www.rfc-editor.org/rfc/rfc5161
-    "ENABLE",
-    // https://www.rfc-editor.org/rfc/rfc7888
-    "LITERAL+",
-  ] {
+  let capabilities = match capabilities {
+    Some(capabilities) => capabilities,
+    None => capability(stream)?,
+  };
+  ensure_capabilities(&capabilities, GREETINGS_CAPABILITIES)?;
+  if preauthenticated {
+    // https://www.rfc-editor.org/rfc/rfc3501#section-7.1.4
+    // The PREAUTH response is always untagged, and is one of three possible greetings at connection
+    // startup. It indicates that the connection has already been authenticated by external means;
+    // thus no LOGIN command is needed.
+    log::debug!("connection preauthenticated");
+    ensure_capabilities(&capabilities, AUTHENTICATED_CAPABILITIES)?;
+  }
+  Ok(Greetings {
+    preauthenticated,
+    capabilities,
+    quirks,
+  })
+}
+
+pub fn mechanism(
+  capabilities: &[Vec<u8>],
+  credentials: &Credentials,
+) -> anyhow::Result<imap::Mechanism> {
+  let advertised = |mechanism: imap::Mechanism| {
+    ensure_capabilities(capabilities, &[&format!("AUTH={}", mechanism.name())]).is_ok()
+  };
+  if let Some(mechanism) = credentials.mechanism {
+    if !advertised(mechanism) {
+      log::warn!("AUTH={} isn't advertised, trying anyway", mechanism.name());
+    }
     anyhow::ensure!(
-      capabilities.contains(&capability.as_bytes()),
-      format!("{capability} is missing from CAPABILITY list")
+      mechanism == imap::Mechanism::External || credentials.has_password(),
+      "{} requires a password command",
+      mechanism.name()
     );
+    return Ok(mechanism);
   }
-  Ok(())
+  if !credentials.has_password() {
+    anyhow::ensure!(
+      advertised(imap::Mechanism::External),
+      "the server requires authentication but no password command was given"
+    );
+    return Ok(imap::Mechanism::External);
+  }
+  [
+    imap::Mechanism::ScramSha256,
+    imap::Mechanism::CramMd5,
+    imap::Mechanism::Plain,
+    imap::Mechanism::Login,
+  ]
+  .into_iter()
+  .find(|mechanism| advertised(*mechanism))
+  .context("the server doesn't advertise any supported authentication mechanism")
 }
 
-pub fn authenticate<RW>(
+// https://www.rfc-editor.org/rfc/rfc3501#section-6.2.2
+// The AUTHENTICATE command indicates a [SASL] authentication mechanism to the server. [...] The
+// authentication protocol exchange consists of a series of server challenges and client responses
+// that are specific to the authentication mechanism.
+fn authenticate<RW>(
   stream: &mut imap::Stream<RW>,
+  greetings: &Greetings,
   credentials: &Credentials,
-) -> anyhow::Result<()>
+) -> anyhow::Result<Vec<Vec<u8>>>
 where
   RW: imap::ReadWrite,
 {
-  let command: &[&[u8]] = &[b"authenticate AUTHENTICATE PLAIN "];
-  let result = stream.input(
-    &[command, &[credentials.0.as_bytes(), b"\r\n"]].concat(),
-    command.len(),
-  );
-  result?;
+  let mechanism = mechanism(&greetings.capabilities, credentials)?;
+  log::debug!("authenticating with {}", mechanism.name());
+  // Only resolved when actually needed.
+  let password = match mechanism {
+    imap::Mechanism::External => zeroize::Zeroizing::new(String::new()),
+    _ => credentials.password()?,
+  };
+  let mut sasl = imap::Sasl::new(mechanism, &credentials.user, &password);
+  let mut initial = sasl.initial();
+  let mut command = vec![
+    imap::Segment::Public(b"authenticate AUTHENTICATE "),
+    imap::Segment::Public(mechanism.name().as_bytes()),
+  ];
+  // https://www.rfc-editor.org/rfc/rfc4959#section-3
+  // [...] a zero-length initial response MUST be sent as a single equals sign ("=").
+  let inline = match ensure_capabilities(&greetings.capabilities, &["SASL-IR"]) {
+    Ok(()) => initial.take(),
+    Err(_) => None,
+  };
+  if let Some(inline) = &inline {
+    command.push(imap::Segment::Public(b" "));
+    command.push(imap::Segment::Secret(match inline.is_empty() {
+      true => b"=",
+      false => inline.as_bytes(),
+    }));
+  }
+  command.push(imap::Segment::Public(b"\r\n"));
+  stream.exchange(&command, b"authenticate")?;
+  // Without SASL-IR, the initial response is sent after an empty challenge.
+  while let Some(challenge) = stream.parse(imap::parser::continue_req)? {
+    let response = match initial.take() {
+      Some(response) => response,
+      None => sasl.step(challenge)?,
+    };
+    let command = [
+      imap::Segment::Secret(response.as_bytes()),
+      imap::Segment::Public(b"\r\n"),
+    ];
+    stream.exchange(&command, b"authenticate")?;
+  }
   let capabilities = loop {
     match stream.expect(imap::parser::start)? {
       b"*" => stream.expect(imap::parser::skip)?,
-      b"authenticate" => break stream.expect(imap::parser::available_capabilities)?,
+      b"authenticate" => {
+        let capabilities = stream
+          .parse(imap::parser::available_capabilities)?
+          .map(|capabilities| to_owned_capabilities(&capabilities));
+        if capabilities.is_none() {
+          if let Err(error) = stream.expect(imap::parser::ok) {
+            // The password may have expired (OAuth tokens), resolve it again on the next
+            // connection.
+            credentials.invalidate();
+            return Err(error);
+          }
+        }
+        sasl.finish()?;
+        match capabilities {
+          Some(capabilities) => break capabilities,
+          // The capabilities may change once authenticated.
+          None => break capability(stream)?,
+        }
+      }
       tag => anyhow::bail!("unexpected tag {tag:?}"),
     }
   };
-  for capability in [
-    // https://www.rfc-editor.org/rfc/rfc2342
-    "NAMESPACE",
-    // https://www.rfc-editor.org/rfc/rfc4315 (for APPENDUID, COPYUID)
-    "UIDPLUS",
-    // https://www.rfc-editor.org/rfc/rfc6851
-    "MOVE",
-    // https://www.rfc-editor.org/rfc/rfc7162 (for UNCHANGEDSINCE)
-    "CONDSTORE",
-    "QRESYNC",
-  ] {
-    anyhow::ensure!(
-      capabilities.contains(&capability.as_bytes()),
-      format!("{capability} is missing from CAPABILITY list")
-    );
+  ensure_capabilities(&capabilities, AUTHENTICATED_CAPABILITIES)?;
+  Ok(capabilities)
+}
+
+// What the client tells about itself.
+pub struct Id {
+  pub name: String,
+  pub version: String,
+  // Overrides the quirks detected from the server's greeting or ID.
+  pub quirks: Option<imap::Quirks>,
+  // Not related to ID but, like the quirks, set on the stream once logged in.
+  pub buffer_limit: Option<usize>,
+}
+
+// https://www.rfc-editor.org/rfc/rfc2971#section-3.1
+// The sole purpose of the ID extension is to enable clients and servers to exchange information on
+// their implementations for the purposes of statistical analysis and problem determination.
+//
+// Some servers refuse to serve clients that don't identify themselves. The server's name is used to
+// detect its quirks.
+pub fn id<RW>(
+  stream: &mut imap::Stream<RW>,
+  capabilities: &[Vec<u8>],
+  id: &Id,
+) -> anyhow::Result<Option<imap::Quirks>>
+where
+  RW: imap::ReadWrite,
+{
+  if ensure_capabilities(capabilities, &["ID"]).is_err() {
+    return Ok(None);
   }
-  Ok(())
+  let command: &[&[u8]] = &[
+    b"id ID (\"name\" {",
+    &id.name.len().to_string().into_bytes(),
+    b"+}\r\n",
+    id.name.as_bytes(),
+    b" \"version\" {",
+    &id.version.len().to_string().into_bytes(),
+    b"+}\r\n",
+    id.version.as_bytes(),
+    b")\r\n",
+  ];
+  stream.input(command, command.len())?;
+  let mut quirks = None;
+  loop {
+    match stream.expect(imap::parser::start)? {
+      b"*" => match stream.parse(imap::parser::id_response)? {
+        Some(parameters) => {
+          quirks = parameters
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(b"name"))
+            .and_then(|(_, value)| imap::Quirks::detect(value.as_ref()?));
+          let parameters: Vec<_> = parameters
+            .iter()
+            .map(|(key, value)| {
+              format!(
+                "{}={}",
+                String::from_utf8_lossy(key),
+                value
+                  .as_ref()
+                  .map_or(borrow::Cow::Borrowed("NIL"), |value| {
+                    String::from_utf8_lossy(value)
+                  })
+              )
+            })
+            .collect();
+          log::info!("server ID: {}", parameters.join(", "));
+        }
+        None => stream.expect(imap::parser::skip)?,
+      },
+      b"id" => break stream.expect(imap::parser::ok)?,
+      tag => anyhow::bail!("unexpected tag {tag:?}"),
+    }
+  }
+  Ok(quirks)
+}
+
+// Open a new connection, ready to SELECT.
+pub fn connect<O>(
+  open: &O,
+  credentials: &Credentials,
+  id: &Id,
+) -> anyhow::Result<imap::Stream<O::RW>>
+where
+  O: Open,
+{
+  let mut stream = imap::Stream::new(open.open()?);
+  let greetings = greetings(&mut stream)?;
+  login(&mut stream, greetings, credentials, id)?;
+  Ok(stream)
+}
+
+// How many times an idempotent command is repeated on a new connection.
+const RETRIES: usize = 3;
+
+// A read timeout (see --timeout) or a connection reset by the server (or anything in between) is
+// worth another connection.
+pub fn retryable(error: &anyhow::Error) -> bool {
+  error.chain().any(|cause| {
+    cause.downcast_ref::<io::Error>().is_some_and(|error| {
+      matches!(
+        error.kind(),
+        // The read timeout is reported as WouldBlock on Unix.
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock | io::ErrorKind::ConnectionReset
+      )
+    })
+  })
+}
+
+// Only for idempotent commands (e.g.: LIST, SELECT, FETCH): the connection is replaced (the new one
+// has nothing selected) and the command repeated. The command must keep track of what it already
+// reported if it's only partially repeatable.
+pub fn retry<O, F, R>(
+  open: &O,
+  credentials: &Credentials,
+  id: &Id,
+  stream: &mut imap::Stream<O::RW>,
+  mut command: F,
+) -> anyhow::Result<R>
+where
+  O: Open,
+  F: FnMut(&mut imap::Stream<O::RW>) -> anyhow::Result<R>,
+{
+  let mut attempts = 0;
+  loop {
+    match command(stream) {
+      Err(error) if attempts < RETRIES && retryable(&error) => {
+        attempts += 1;
+        log::warn!("reconnecting after {error:#} ({attempts}/{RETRIES})");
+        *stream = connect(open, credentials, id)?;
+      }
+      result => break result,
+    }
+  }
+}
+
+// Authenticate (unless preauthenticated), ready to SELECT.
+pub fn login<RW>(
+  stream: &mut imap::Stream<RW>,
+  greetings: Greetings,
+  credentials: &Credentials,
+  id: &Id,
+) -> anyhow::Result<()>
+where
+  RW: imap::ReadWrite,
+{
+  let capabilities = match greetings.preauthenticated {
+    true => greetings.capabilities,
+    false => authenticate(stream, &greetings, credentials)?,
+  };
+  // https://www.rfc-editor.org/rfc/rfc7888
+  // Without it, each (large) literal costs a round trip.
+  if ensure_capabilities(&capabilities, &["LITERAL+"]).is_err() {
+    log::warn!("LITERAL+ is missing from CAPABILITY list, falling back to synchronizing literals");
+  }
+  stream.set_capabilities(&capabilities);
+  let detected = self::id(stream, &capabilities, id)?.or(greetings.quirks);
+  let quirks = id.quirks.or(detected).unwrap_or_default();
+  if quirks != imap::Quirks::Standard {
+    log::info!("working around {quirks:?} quirks");
+  }
+  stream.set_quirks(quirks);
+  stream.set_buffer_limit(id.buffer_limit);
+  enable(stream, &capabilities)
 }
 
-pub fn enable<RW>(stream: &mut imap::Stream<RW>) -> anyhow::Result<()>
+fn enable<RW>(stream: &mut imap::Stream<RW>, capabilities: &[Vec<u8>]) -> anyhow::Result<()>
 where
   RW: imap::ReadWrite,
 {
+  // https://www.rfc-editor.org/rfc/rfc4731
+  // Without QRESYNC, the changes are searched for after each SELECT instead (see
+  // select_condstore), ESEARCH keeps the results compact.
+  if !stream.qresync() {
+    anyhow::ensure!(
+      ensure_capabilities(capabilities, &["ESEARCH"]).is_ok(),
+      "QRESYNC is missing from CAPABILITY list (and ESEARCH, to do without it)"
+    );
+    log::warn!(
+      "QRESYNC is missing from CAPABILITY list, falling back to CONDSTORE and ESEARCH (slower)"
+    );
+    return Ok(());
+  }
+
   // https://www.rfc-editor.org/rfc/rfc7162
   // The Quick Mailbox Resynchronization (QRESYNC) IMAP extension is an extension [...] that allows
   // a reconnecting client to perform full resynchronization, including discovery of expunged
@@ -131,51 +471,346 @@ where
 }
 
 #[derive(Debug)]
-struct Mailbox {
+pub struct Mailbox {
   bytes: Vec<u8>,
   string: String,
   separator: Option<char>,
+  // https://www.rfc-editor.org/rfc/rfc6154#section-2
+  // \Drafts This mailbox is used to hold draft messages -- typically, messages that are being
+  // composed but have not yet been sent.
+  drafts: bool,
+  // https://www.rfc-editor.org/rfc/rfc3501#section-7.2.2
+  // \Marked The mailbox has been marked "interesting" by the server; the mailbox probably contains
+  // messages that have been added since the last time the mailbox was selected.
+  // \Unmarked The mailbox does not contain any additional messages since the last time the mailbox
+  // was selected.
+  // None when the server didn't say.
+  marked: Option<bool>,
+}
+
+#[derive(Debug, Default)]
+struct Namespace {
+  prefix: Vec<u8>,
+  separator: Option<u8>,
+}
+
+impl Namespace {
+  fn new(namespace: imap::Namespace) -> Self {
+    Self {
+      prefix: namespace.prefix.into_owned(),
+      separator: namespace.separator,
+    }
+  }
+}
+
+#[derive(Debug, Default)]
+struct Namespaces {
+  personal: Namespace,
+  other: Vec<Namespace>,
+  shared: Vec<Namespace>,
 }
 
-fn list<RW>(stream: &mut imap::Stream<RW>) -> anyhow::Result<Vec<Mailbox>>
+// https://www.rfc-editor.org/rfc/rfc2342#section-5
+// Personal Namespace: A namespace that the server considers within the personal scope of the
+// authenticated user on a particular connection. [...] If a server supports multiple personal
+// namespaces, the one listed first is the default.
+fn namespaces<RW>(stream: &mut imap::Stream<RW>) -> anyhow::Result<Namespaces>
 where
   RW: imap::ReadWrite,
 {
-  let command: &[&[u8]] = &[b"list LIST \"\" \"*\"\r\n"];
+  let command: &[&[u8]] = &[b"namespace NAMESPACE\r\n"];
   stream.input(command, command.len())?;
-  let mut mailboxes = Vec::new();
+  let mut namespaces = Namespaces::default();
   loop {
     match stream.expect(imap::parser::start)? {
-      b"*" => match stream.parse(imap::parser::list_mailbox)? {
-        Some((flags, separator, mailbox)) => {
-          if flags.contains(&&b"\\Noselect"[..]) {
-            // https://www.rfc-editor.org/rfc/rfc3501#section-7.2.2
-            // \Noselect It is not possible to use this name as a selectable mailbox.
-            continue;
+      b"*" => match stream.parse(imap::parser::namespace_response)? {
+        Some(namespaces_) => {
+          namespaces = Namespaces {
+            // NIL: no personal mailboxes besides INBOX.
+            personal: namespaces_
+              .personal
+              .into_iter()
+              .next()
+              .map(Namespace::new)
+              .unwrap_or_default(),
+            other: namespaces_.other.into_iter().map(Namespace::new).collect(),
+            shared: namespaces_.shared.into_iter().map(Namespace::new).collect(),
           }
-          let bytes = match mailbox {
-            imap::Mailbox::Inbox => b"INBOX".to_vec(),
-            imap::Mailbox::Other(borrow::Cow::Owned(mailbox)) => mailbox,
-            imap::Mailbox::Other(borrow::Cow::Borrowed(mailbox)) => mailbox.to_vec(),
-          };
-          mailboxes.push(Mailbox {
-            string: imap::utf7_to_utf8(&bytes)
-              .with_context(|| format!("mailbox {bytes:?} isn't proper modified UTF-7"))?,
-            bytes,
-            separator: separator.map(|s| s as char /* guaranteed by TEXT-CHAR */),
-          });
         }
         None => stream.expect(imap::parser::skip)?,
       },
-      b"list" => break stream.expect(imap::parser::ok)?,
+      b"namespace" => break stream.expect(imap::parser::ok)?,
+      tag => anyhow::bail!("unexpected tag {tag:?}"),
+    }
+  }
+  Ok(namespaces)
+}
+
+// Which mailboxes to synchronize.
+pub struct Scope<'a> {
+  // Besides the personal namespace.
+  pub namespaces: &'a [crate::NamespaceKind],
+  pub subscribed_only: bool,
+}
+
+fn mailbox_bytes(mailbox: imap::Mailbox) -> Vec<u8> {
+  match mailbox {
+    imap::Mailbox::Inbox => b"INBOX".to_vec(),
+    imap::Mailbox::Other(borrow::Cow::Owned(mailbox)) => mailbox,
+    imap::Mailbox::Other(borrow::Cow::Borrowed(mailbox)) => mailbox.to_vec(),
+  }
+}
+
+// https://www.rfc-editor.org/rfc/rfc3501#section-6.3.9
+// The LSUB command returns a subset of names from the set of names that the user has declared as
+// being "active" or "subscribed".
+//
+// LIST-EXTENDED's LIST (SUBSCRIBED) would save a round trip but LSUB is always available.
+fn lsub<RW>(stream: &mut imap::Stream<RW>, pattern: &[u8]) -> anyhow::Result<Vec<Vec<u8>>>
+where
+  RW: imap::ReadWrite,
+{
+  let command: &[&[u8]] = &[
+    b"lsub LSUB \"\" {",
+    &pattern.len().to_string().into_bytes(),
+    b"+}\r\n",
+    pattern,
+    b"\r\n",
+  ];
+  stream.input(command, command.len())?;
+  let mut mailboxes = Vec::new();
+  loop {
+    match stream.expect(imap::parser::start)? {
+      b"*" => match stream.parse(imap::parser::lsub_mailbox)? {
+        Some((_, _, mailbox)) => mailboxes.push(mailbox_bytes(mailbox)),
+        None => stream.expect(imap::parser::skip)?,
+      },
+      b"lsub" => break stream.expect(imap::parser::ok)?,
       tag => anyhow::bail!("unexpected tag {tag:?}"),
     }
   }
   Ok(mailboxes)
 }
 
+// The mailboxes in the personal namespace, without the prefix, and the ones in the included
+// namespaces, with their prefix (so they end up in their own local subtree).
+fn list<RW>(stream: &mut imap::Stream<RW>, scope: &Scope) -> anyhow::Result<Vec<Mailbox>>
+where
+  RW: imap::ReadWrite,
+{
+  let namespaces = namespaces(stream)?;
+  log::debug!(
+    "personal namespace {} (separator {:?})",
+    String::from_utf8_lossy(&namespaces.personal.prefix),
+    namespaces.personal.separator.map(char::from)
+  );
+  let mut included = Vec::new();
+  for kind in scope.namespaces {
+    let namespaces = match kind {
+      crate::NamespaceKind::Other => &namespaces.other,
+      crate::NamespaceKind::Shared => &namespaces.shared,
+    };
+    if namespaces.is_empty() {
+      log::warn!("the server has no {kind:?} namespace");
+    }
+    included.extend(namespaces);
+  }
+  // Mailboxes in the other namespaces may be listed along the personal ones (when its prefix is
+  // empty).
+  let foreign: Vec<_> = namespaces
+    .other
+    .iter()
+    .chain(&namespaces.shared)
+    .map(|namespace| &namespace.prefix)
+    .filter(|prefix| !prefix.is_empty())
+    .collect();
+
+  let mut patterns = vec![(true, [&namespaces.personal.prefix[..], b"*"].concat())];
+  // https://www.rfc-editor.org/rfc/rfc2342#section-4
+  // [...] the INBOX may or may not be within a namespace (e.g.: "INBOX." on some servers).
+  if !namespaces.personal.prefix.is_empty() {
+    patterns.push((true, b"INBOX".to_vec()));
+  }
+  for namespace in included {
+    log::debug!(
+      "including namespace {}",
+      String::from_utf8_lossy(&namespace.prefix)
+    );
+    patterns.push((false, [&namespace.prefix[..], b"*"].concat()));
+  }
+
+  let mut mailboxes: Vec<Mailbox> = Vec::new();
+  for (personal, pattern) in patterns {
+    let subscribed = match scope.subscribed_only {
+      true => Some(lsub(stream, &pattern)?),
+      false => None,
+    };
+    let command: &[&[u8]] = &[
+      b"list LIST \"\" {",
+      &pattern.len().to_string().into_bytes(),
+      b"+}\r\n",
+      &pattern,
+      b"\r\n",
+    ];
+    stream.input(command, command.len())?;
+    loop {
+      match stream.expect(imap::parser::start)? {
+        b"*" => match stream.parse(imap::parser::list_mailbox)? {
+          Some((flags, separator, mailbox)) => {
+            if flags.contains(&&b"\\Noselect"[..]) {
+              // https://www.rfc-editor.org/rfc/rfc3501#section-7.2.2
+              // \Noselect It is not possible to use this name as a selectable mailbox.
+              continue;
+            }
+            let bytes = mailbox_bytes(mailbox);
+            if mailboxes.iter().any(|mailbox| mailbox.bytes == bytes)
+              || personal && foreign.iter().any(|prefix| bytes.starts_with(prefix))
+            {
+              continue;
+            }
+            if let Some(subscribed) = &subscribed {
+              if !subscribed.contains(&bytes) {
+                log::debug!("skipping unsubscribed mailbox {bytes:?}");
+                continue;
+              }
+            }
+            // The local name doesn't need to mirror the server's hierarchy.
+            let name = match bytes.strip_prefix(&namespaces.personal.prefix[..]) {
+              Some(name) if personal && bytes != b"INBOX" => match namespaces.personal.separator {
+                Some(separator) => name.strip_prefix(&[separator]).unwrap_or(name),
+                None => name,
+              },
+              _ => &bytes,
+            };
+            anyhow::ensure!(
+              !name.is_empty(),
+              "mailbox {bytes:?} is the personal namespace"
+            );
+            let string = imap::utf7_to_utf8(name)
+              .with_context(|| format!("mailbox {bytes:?} isn't proper modified UTF-7"))?;
+            anyhow::ensure!(
+              !mailboxes.iter().any(|mailbox| mailbox.string == string),
+              "mailbox {bytes:?} would clash with another once stripped from the personal \
+               namespace"
+            );
+            mailboxes.push(Mailbox {
+              string,
+              bytes,
+              separator: separator.map(|s| s as char /* guaranteed by TEXT-CHAR */),
+              drafts: flags
+                .iter()
+                .any(|flag| flag.eq_ignore_ascii_case(b"\\Drafts")),
+              marked: flags.iter().find_map(|flag| {
+                if flag.eq_ignore_ascii_case(b"\\Marked") {
+                  Some(true)
+                } else if flag.eq_ignore_ascii_case(b"\\Unmarked") {
+                  Some(false)
+                } else {
+                  None
+                }
+              }),
+            });
+          }
+          None => stream.expect(imap::parser::skip)?,
+        },
+        b"list" => break stream.expect(imap::parser::ok)?,
+        tag => anyhow::bail!("unexpected tag {tag:?}"),
+      }
+    }
+  }
+  Ok(mailboxes)
+}
+
+// The mailboxes recorded before the personal namespace's prefix was stripped from their local name
+// (see list and notmuch::migrate_from_0) are renamed, along with their maildir, once the server
+// tells which ones it was stripped from.
+#[cfg(feature = "notmuch")]
+pub fn strip_prefix<RW>(
+  stream: &mut imap::Stream<RW>,
+  database: &mut notmuch::Database<notmuch::Attached>,
+  maildir_builder: &maildir::Builder,
+  scope: &Scope,
+) -> anyhow::Result<()>
+where
+  RW: imap::ReadWrite,
+{
+  if !database.root()?.prefixed()? {
+    return Ok(());
+  }
+  for mailbox in list(stream, scope)? {
+    let old_mailbox = imap::utf7_to_utf8(&mailbox.bytes).unwrap(); // Guaranteed by list.
+    {
+      let root = database.root()?;
+      let known = root.mailboxes()?;
+      if old_mailbox == mailbox.string
+        || !known.contains(old_mailbox.as_str())
+        || known.contains(mailbox.string.as_str())
+      {
+        continue;
+      }
+    }
+    log::info!(
+      "renaming mailbox {old_mailbox} to {}, stripped from the personal namespace",
+      mailbox.string
+    );
+    let old_maildir = maildir_builder.maildir(&old_mailbox, &mailbox.separator)?;
+    let maildir = maildir_builder.maildir(&mailbox.string, &mailbox.separator)?;
+    // Everything, not only the files in the database (e.g.: new ones, not pushed yet).
+    for directory in ["cur", "new", "tmp"] {
+      for entry in fs::read_dir(old_maildir.path().join(directory))? {
+        maildir.adopt(&entry?.path())?;
+      }
+    }
+    {
+      let mut messages = search_mailbox(database, &old_mailbox)?;
+      while let Some(mut message) = messages.next() {
+        message.rename_mailbox(&old_mailbox, &mailbox.string)?;
+        for path in message.paths()? {
+          let components = maildir::components(&path)?;
+          let [_, parent_name, file_name] = maildir::components_to_str(&components)?;
+          let new_path = maildir.path().join(parent_name).join(file_name);
+          // Moved above, or by an earlier attempt that didn't go through.
+          if old_maildir.has(&path) && new_path.exists() {
+            database.add(&new_path, &[])?;
+            database.remove(&path)?;
+          }
+        }
+      }
+    }
+    database
+      .root()?
+      .rename_mailbox(&old_mailbox, &mailbox.string)?;
+    old_maildir.remove()?;
+  }
+  database.root()?.update_prefixed(false)
+}
+
+// The order in which the mailboxes are pulled: the ones matching the --priority patterns first (in
+// their order), then the ones probably with new messages, so they show up early in a long pull,
+// then by name.
+fn ordered<'a>(
+  mailboxes: impl IntoIterator<Item = &'a Mailbox>,
+  priority: &[String],
+) -> Vec<&'a Mailbox> {
+  let rank = |mailbox: &Mailbox| {
+    let pattern = priority
+      .iter()
+      .position(|pattern| matches(pattern, &mailbox.string, mailbox.separator))
+      .unwrap_or(priority.len());
+    let marked = match mailbox.marked {
+      Some(true) => 0,
+      None => 1,
+      Some(false) => 2,
+    };
+    (pattern, marked)
+  };
+  let mut ordered: Vec<_> = mailboxes.into_iter().collect();
+  ordered.sort_by(|a, b| rank(a).cmp(&rank(b)).then_with(|| a.string.cmp(&b.string)));
+  ordered
+}
+
 #[derive(Clone, Debug)]
-struct Changes {
+pub struct Changes {
   flags: Vec<String>,
   modseq: u64,
 }
@@ -183,11 +818,45 @@ struct Changes {
 #[derive(Debug)]
 struct Select {
   uidvalidity: u64,
+  // Required by RFC 3501 but not sent by every server: without it, UIDs reassigned by the server go
+  // unnoticed and nothing left in tmp can be trusted (see pull::Workers::fetch_once).
+  uidnext: Option<u64>,
   highestmodseq: u64,
+  // Whether keywords can be stored, only system flags otherwise.
+  keywords: bool,
   vanished: Vec<imap::Range>,
+  // Without QRESYNC, nothing is reported as vanished: these are the UIDs still on the server, when
+  // something changed (see select_condstore).
+  existing: Option<Vec<imap::Range>>,
   changes: collections::HashMap<u64 /* uid */, Changes>,
 }
 
+// https://www.rfc-editor.org/rfc/rfc3501#section-2.3.1.1
+// Unique identifiers are assigned in a strictly ascending fashion in the mailbox; as each message
+// is added to the mailbox it is assigned a higher UID than the message(s) which were added
+// previously.
+//
+// UIDs are 32-bit: a server running out of them has to change the UIDVALIDITY to start over.
+pub const MAX_UID: u64 = u32::MAX as u64;
+
+// One that didn't (its UIDNEXT went backwards) reassigned UIDs: the messages known locally can't be
+// told apart from the new ones anymore.
+fn reassigned(select: &Select, uidnext: u64) -> bool {
+  select.uidnext.is_some_and(|uidnext_| uidnext_ < uidnext)
+}
+
+fn changes(flags: &[&[u8]], modseq: u64) -> Changes {
+  let flags = flags
+    .iter()
+    .map(|flag| {
+      str::from_utf8(flag)
+        .unwrap() // Guaranteed by the BNF.
+        .to_string()
+    })
+    .collect();
+  Changes { flags, modseq }
+}
+
 fn select<RW>(
   stream: &mut imap::Stream<RW>,
   mailbox: &[u8],
@@ -197,20 +866,161 @@ fn select<RW>(
 where
   RW: imap::ReadWrite,
 {
-  let command: &[&[u8]] = &[
-    b"select SELECT {",
-    &mailbox.len().to_string().into_bytes(),
-    b"+}\r\n",
-    mailbox,
-    b" (QRESYNC (",
-    &uidvalidity.to_string().into_bytes(),
-    b" ",
-    &highestmodseq.to_string().into_bytes(),
-    b"))\r\n",
-  ];
-  stream.input(command, command.len())?;
-  let (mut user_keywords, mut uidvalidity, mut highestmodseq, mut vanished, mut changes) =
-    (false, None, None, Vec::new(), collections::HashMap::new());
+  Ok(
+    select_many(stream, &[(mailbox, uidvalidity, highestmodseq)])?
+      .pop()
+      .unwrap(), // One SELECT, one result.
+  )
+}
+
+// https://www.rfc-editor.org/rfc/rfc7162#section-4
+// [...] a client should limit the length of the command lines it generates to approximately 8192
+// octets (including all quoted strings but not including literals).
+//
+// How many UIDs fit in a command besides its other arguments: a UID takes at most 10 digits
+// (32-bit) and a comma in a sequence set, 64 octets are left for the tag and the command itself.
+pub const fn max_uids(arguments: usize) -> usize {
+  8192usize.saturating_sub(64 + arguments) / 11
+}
+
+pub const MAX_FETCH_BATCH: usize = max_uids(0);
+
+// https://www.rfc-editor.org/rfc/rfc3501#section-5.5
+// The client MAY send another command without waiting for the completion result response of a
+// command, subject to ambiguity rules (see below) and flow control constraints on the underlying
+// data stream.
+//
+// Each SELECT deselects the previous mailbox (the server reports it with the CLOSED response code)
+// so only the last one stays selected. Commands that change the selected mailbox can't be completed
+// out of order: the responses are read in the order the SELECTs were sent, the tags only tell where
+// each ends. A failed SELECT fails the pull, the responses to the next ones are left unread on a
+// connection that isn't used again.
+//
+// Only the SELECTs are pipelined (see pull::Workers): each FETCH waits for its response,
+// --fetch-batch is what hides the latency there.
+fn select_many<RW>(
+  stream: &mut imap::Stream<RW>,
+  mailboxes: &[(
+    &[u8],
+    u64, /* uidvalidity */
+    u64, /* highestmodseq */
+  )],
+) -> anyhow::Result<Vec<Select>>
+where
+  RW: imap::ReadWrite,
+{
+  // A failed SELECT also deselects the previous mailbox.
+  stream.set_selected(None);
+  let mut selects = Vec::new();
+  // Without anything known locally, QRESYNC would have the whole mailbox's flags sent along the
+  // SELECT response: they're fetched in batches instead (see select_condstore), in between the
+  // pipelined SELECT commands so the last mailbox stays selected.
+  for mailboxes in mailboxes.chunk_by(|(_, _, a), (_, _, b)| (*a == 0) == (*b == 0)) {
+    match stream.qresync() && mailboxes[0].2 > 0 {
+      true => selects.append(&mut select_many_qresync(stream, mailboxes)?),
+      // The searches need each mailbox to be selected, in turn.
+      false => {
+        for (mailbox, uidvalidity, highestmodseq) in mailboxes {
+          selects.push(select_condstore(
+            stream,
+            mailbox,
+            *uidvalidity,
+            *highestmodseq,
+          )?);
+        }
+      }
+    }
+  }
+  if let (Some((mailbox, _, _)), Some(select)) = (mailboxes.last(), selects.last()) {
+    stream.set_selected(Some(imap::Selected {
+      mailbox: mailbox.to_vec(),
+      uidvalidity: select.uidvalidity,
+      highestmodseq: select.highestmodseq,
+      keywords: select.keywords,
+    }));
+  }
+  Ok(selects)
+}
+
+fn select_many_qresync<RW>(
+  stream: &mut imap::Stream<RW>,
+  mailboxes: &[(&[u8], u64, u64)],
+) -> anyhow::Result<Vec<Select>>
+where
+  RW: imap::ReadWrite,
+{
+  let tags: Vec<String> = (0..mailboxes.len())
+    .map(|index| format!("select{index}"))
+    .collect();
+  let commands: Vec<Vec<u8>> = mailboxes
+    .iter()
+    .zip(&tags)
+    .map(|((mailbox, uidvalidity, highestmodseq), tag)| {
+      [
+        tag.as_bytes(),
+        b" SELECT {",
+        &mailbox.len().to_string().into_bytes(),
+        b"+}\r\n",
+        mailbox,
+        b" (QRESYNC (",
+        &uidvalidity.to_string().into_bytes(),
+        b" ",
+        &highestmodseq.to_string().into_bytes(),
+        b"))\r\n",
+      ]
+      .concat()
+    })
+    .collect();
+  let command: Vec<&[u8]> = commands.iter().map(Vec::as_slice).collect();
+  stream.input(&command, command.len())?;
+  tags
+    .iter()
+    .map(|tag| select_response(stream, tag.as_bytes()))
+    .collect()
+}
+
+// SELECT the mailbox unless it already is, when the changes since the highestmodseq don't matter.
+// The UIDVALIDITY can't change while the mailbox is selected (the server would have to close the
+// connection).
+pub fn ensure_selected<RW>(
+  stream: &mut imap::Stream<RW>,
+  mailbox: &[u8],
+  uidvalidity: u64,
+  highestmodseq: u64,
+) -> anyhow::Result<imap::Selected>
+where
+  RW: imap::ReadWrite,
+{
+  if let Some(selected) = stream
+    .selected()
+    .filter(|selected| selected.mailbox == mailbox)
+  {
+    log::debug!("mailbox already selected");
+    return Ok(selected.clone());
+  }
+  select(stream, mailbox, uidvalidity, highestmodseq)?;
+  Ok(stream.selected().unwrap().clone()) // Set by select_many.
+}
+
+fn select_response<RW>(stream: &mut imap::Stream<RW>, tag: &[u8]) -> anyhow::Result<Select>
+where
+  RW: imap::ReadWrite,
+{
+  let (
+    mut user_keywords,
+    mut uidvalidity,
+    mut uidnext,
+    mut highestmodseq,
+    mut vanished,
+    mut changes,
+  ) = (
+    false,
+    None,
+    None,
+    None,
+    Vec::new(),
+    collections::HashMap::new(),
+  );
   loop {
     match stream.expect(imap::parser::start)? {
       b"*" => match stream.parse(imap::parser::select_data)? {
@@ -219,27 +1029,32 @@ where
         // possible to create new keywords by attempting to store those flags in the mailbox.
         Some(imap::Select::Flags(flags)) => user_keywords = flags.contains(&&b"\\*"[..]),
         Some(imap::Select::UIDValidity(uidvalidity_)) => uidvalidity = Some(uidvalidity_),
+        Some(imap::Select::UIDNext(uidnext_)) => uidnext = Some(uidnext_),
         Some(imap::Select::HighestModSeq(highestmodseq_)) => highestmodseq = Some(highestmodseq_),
         Some(imap::Select::Vanished(mut uids)) => vanished.append(&mut uids),
         Some(imap::Select::Fetch(imap::SelectFetch { uid, flags, modseq })) => {
-          let flags = flags
-            .iter()
-            .map(|flag| {
-              str::from_utf8(flag)
-                .unwrap() // Guaranteed by the BNF.
-                .to_string()
-            })
-            .collect();
-          changes.insert(uid, Changes { flags, modseq });
+          changes.insert(uid, self::changes(&flags, modseq));
+        }
+        None => {
+          if let imap::Untagged::Exists(exists) = stream.expect(imap::parser::untagged)? {
+            log::debug!("{exists} messages in the mailbox");
+          }
         }
-        None => stream.expect(imap::parser::skip)?,
       },
-      b"select" => break stream.expect(imap::parser::ok)?,
+      tag_ if tag_ == tag => break stream.expect(imap::parser::ok)?,
       tag => anyhow::bail!("unexpected tag {tag:?}"),
     }
   }
-  anyhow::ensure!(user_keywords, "PERMANENTFLAGS \\* is missing from SELECT");
+  if !user_keywords && stream.quirks() == imap::Quirks::Cyrus {
+    log::warn!(
+      "PERMANENTFLAGS \\* is missing from SELECT, the mailbox probably has too many keywords \
+       already: only the system flags are stored"
+    );
+  }
   anyhow::ensure!(uidvalidity.is_some(), "UIDVALIDITY is missing from SELECT");
+  if uidnext.is_none() {
+    log::debug!("UIDNEXT is missing from SELECT");
+  }
   anyhow::ensure!(
     highestmodseq.is_some(),
     "HIGHESTMODSEQ is missing from SELECT"
@@ -251,30 +1066,644 @@ where
   anyhow::ensure!(highestmodseq > 0, "HIGHESTMODSEQ is not properly supported");
   Ok(Select {
     uidvalidity: uidvalidity.unwrap(),
+    uidnext,
     highestmodseq,
+    keywords: user_keywords,
     vanished,
+    existing: None,
     changes,
   })
 }
 
-pub fn move_out_of_tmp(
-  database: &mut notmuch::Database<notmuch::Attached>,
-  relative_maildir: &path::Path,
-) -> anyhow::Result<()> {
-  let folder = relative_maildir
-    .file_name()
-    .with_context(|| format!("couldn't get file name for {relative_maildir:?}"))?;
-  let folder = folder
-    .to_str()
-    .with_context(|| format!("couldn't convert {folder:?} to string"))?;
-  let mut messages = database.query(&format!(
-    "    property:\"{}.marker={}\" \
-     and path:\"{}/**\" \
+// https://www.rfc-editor.org/rfc/rfc7162#section-3.1.5
+// The MODSEQ search criterion can be used to find the messages whose metadata changed since a
+// mod-sequence.
+//
+// What SELECT (QRESYNC) would have returned, for the servers without it: the changed messages are
+// searched for then their flags fetched, and the UIDs still on the server are searched for too
+// since the expunged ones can't be (a removal increments the HIGHESTMODSEQ, so that's only needed
+// when it changed). Also used with QRESYNC on the first synchronization (see select_many).
+fn select_condstore<RW>(
+  stream: &mut imap::Stream<RW>,
+  mailbox: &[u8],
+  uidvalidity: u64,
+  highestmodseq: u64,
+) -> anyhow::Result<Select>
+where
+  RW: imap::ReadWrite,
+{
+  let command: &[&[u8]] = &[
+    b"select SELECT {",
+    &mailbox.len().to_string().into_bytes(),
+    b"+}\r\n",
+    mailbox,
+    b" (CONDSTORE)\r\n",
+  ];
+  stream.input(command, command.len())?;
+  let mut select = select_response(stream, b"select")?;
+  // Like QRESYNC, nothing is returned when the UIDVALIDITY changed.
+  if select.uidvalidity != uidvalidity || select.highestmodseq == highestmodseq {
+    return Ok(select);
+  }
+  // The first synchronization: every message is new, there's nothing to search for.
+  if highestmodseq == 0 {
+    let last = match select.uidnext {
+      Some(uidnext) => uidnext.saturating_sub(1),
+      None => uid_search(stream, "ALL")?
+        .last()
+        .map_or(0, |imap::Range(_, end)| *end),
+    };
+    let mut start = 1;
+    while start <= last {
+      let end = cmp::min(start.saturating_add(BOOTSTRAP_BATCH - 1), last);
+      fetch_flags(stream, &format!("{start}:{end}"), &mut select.changes)?;
+      if last > BOOTSTRAP_BATCH {
+        log::info!("fetched the flags up to UID {end} of {last}");
+      }
+      start = end + 1;
+    }
+    return Ok(select);
+  }
+  let changed = uid_search(stream, &format!("MODSEQ {}", highestmodseq + 1))?;
+  if !changed.is_empty() {
+    fetch_flags(stream, &sequence_set(&changed), &mut select.changes)?;
+  }
+  select.existing = Some(uid_search(stream, "ALL")?);
+  Ok(select)
+}
+
+// How many UIDs the flags are fetched for at once on the first synchronization of a mailbox: the
+// responses are small but a large mailbox shouldn't be one endless response.
+const BOOTSTRAP_BATCH: u64 = 10000;
+
+fn fetch_flags<RW>(
+  stream: &mut imap::Stream<RW>,
+  set: &str,
+  changes: &mut collections::HashMap<u64, Changes>,
+) -> anyhow::Result<()>
+where
+  RW: imap::ReadWrite,
+{
+  let command: &[&[u8]] = &[b"fetch UID FETCH ", set.as_bytes(), b" (FLAGS MODSEQ)\r\n"];
+  stream.input(command, command.len())?;
+  loop {
+    match stream.expect(imap::parser::start)? {
+      b"*" => match stream.parse(imap::parser::fetch_flags_data)? {
+        Some((uid, (flags, modseq))) => {
+          changes.insert(uid, self::changes(&flags, modseq));
+        }
+        None => stream.expect(imap::parser::skip)?,
+      },
+      b"fetch" => break stream.expect(imap::parser::ok)?,
+      tag => anyhow::bail!("unexpected tag {tag:?}"),
+    }
+  }
+  Ok(())
+}
+
+// https://www.rfc-editor.org/rfc/rfc4731#section-3.1
+// ALL Return all message numbers/UIDs that satisfy the SEARCH criteria using the sequence-set
+// syntax.
+fn uid_search<RW>(stream: &mut imap::Stream<RW>, criteria: &str) -> anyhow::Result<Vec<imap::Range>>
+where
+  RW: imap::ReadWrite,
+{
+  let command: &[&[u8]] = &[
+    b"search UID SEARCH RETURN (ALL) ",
+    criteria.as_bytes(),
+    b"\r\n",
+  ];
+  stream.input(command, command.len())?;
+  let mut uids = Vec::new();
+  loop {
+    match stream.expect(imap::parser::start)? {
+      b"*" => match stream.parse(imap::parser::esearch)? {
+        Some(uids_) => uids = uids_,
+        None => stream.expect(imap::parser::skip)?,
+      },
+      b"search" => break stream.expect(imap::parser::ok)?,
+      tag => anyhow::bail!("unexpected tag {tag:?}"),
+    }
+  }
+  uids.sort_by_key(|imap::Range(start, _)| *start);
+  Ok(uids)
+}
+
+pub fn sequence_set(ranges: &[imap::Range]) -> String {
+  ranges
+    .iter()
+    .map(|imap::Range(start, end)| match start == end {
+      true => start.to_string(),
+      false => format!("{start}:{end}"),
+    })
+    .collect::<Vec<_>>()
+    .join(",")
+}
+
+// https://www.rfc-editor.org/rfc/rfc7162#section-3.1.4.1
+// The CHANGEDSINCE FETCH modifier allows to further subset the list of messages described by the
+// sequence set.
+//
+// The messages of the selected mailbox changed since the modseq, with their own. None when
+// something else was reported along (e.g.: another client expunged a message).
+pub fn changed_since<RW>(
+  stream: &mut imap::Stream<RW>,
+  modseq: u64,
+) -> anyhow::Result<Option<collections::HashMap<u64, u64>>>
+where
+  RW: imap::ReadWrite,
+{
+  let command: &[&[u8]] = &[
+    b"changedsince UID FETCH 1:* (MODSEQ) (CHANGEDSINCE ",
+    &modseq.to_string().into_bytes(),
+    b")\r\n",
+  ];
+  stream.input(command, command.len())?;
+  let (mut changes, mut other) = (collections::HashMap::new(), false);
+  loop {
+    match stream.expect(imap::parser::start)? {
+      b"*" => match stream.parse(imap::parser::store_data)? {
+        Some(imap::Store { uid, modseq }) => {
+          changes.insert(uid, modseq);
+        }
+        None => {
+          stream.expect(imap::parser::skip)?;
+          other = true;
+        }
+      },
+      b"changedsince" => break stream.expect(imap::parser::ok)?,
+      tag => anyhow::bail!("unexpected tag {tag:?}"),
+    }
+  }
+  Ok((!other).then_some(changes))
+}
+
+// The sorted UIDs, consecutive ones merged.
+pub fn ranges(uids: &[u64]) -> Vec<imap::Range> {
+  let mut ranges: Vec<imap::Range> = Vec::new();
+  for &uid in uids {
+    match ranges.last_mut() {
+      Some(imap::Range(_, end)) if end.checked_add(1) == Some(uid) => *end = uid,
+      _ => ranges.push(imap::Range(uid, uid)),
+    }
+  }
+  ranges
+}
+
+// Whether the UID is in the sorted ranges.
+pub fn contains(ranges: &[imap::Range], uid: u64) -> bool {
+  let index = ranges.partition_point(|imap::Range(_, end)| *end < uid);
+  ranges
+    .get(index)
+    .is_some_and(|imap::Range(start, _)| *start <= uid)
+}
+
+pub fn fetch<'a, P, R, RW>(
+  stream: &'a mut imap::Stream<RW>,
+  uid: u64,
+  property: &str,
+  parser: P,
+) -> anyhow::Result<R>
+where
+  P: Fn(
+    &'a [u8],
+  )
+    -> Result<(usize, (u64, R)), peg::error::ParseError<<[u8] as ::peg::Parse>::PositionRepr>>,
+  RW: imap::ReadWrite,
+{
+  let mut result = None;
+  fetch_many(stream, &[uid], property, parser, |_, result_| {
+    result = Some(result_);
+    Ok(())
+  })?;
+  Ok(result.unwrap()) // Every UID was returned.
+}
+
+// Several messages with a single command: each result is handed over as soon as it's parsed (the
+// server may return them in any order).
+pub fn fetch_many<'a, P, R, RW>(
+  stream: &'a mut imap::Stream<RW>,
+  uids: &[u64],
+  property: &str,
+  parser: P,
+  mut each: impl FnMut(u64, R) -> anyhow::Result<()>,
+) -> anyhow::Result<()>
+where
+  P: Fn(
+    &'a [u8],
+  )
+    -> Result<(usize, (u64, R)), peg::error::ParseError<<[u8] as ::peg::Parse>::PositionRepr>>,
+  RW: imap::ReadWrite,
+{
+  let mut remaining: collections::HashSet<u64> = uids.iter().copied().collect();
+  let set = sequence_set(&ranges(uids));
+  let command: &[&[u8]] = &[
+    b"fetch UID FETCH ",
+    set.as_bytes(),
+    b" (",
+    property.as_bytes(),
+    b" )\r\n",
+  ];
+  stream.input(command, command.len())?;
+  loop {
+    match stream.expect(imap::parser::start)? {
+      b"*" => match stream.parse(&parser)? {
+        Some((uid, result)) => {
+          anyhow::ensure!(remaining.remove(&uid), "invalid UID returned from FETCH");
+          each(uid, result)?;
+        }
+        None => {
+          // Another client removed a message in the meantime, the FETCH may come back empty.
+          if let imap::Untagged::Expunge(sequence) = stream.expect(imap::parser::untagged)? {
+            log::warn!("message {sequence} has been expunged by another client");
+          }
+        }
+      },
+      b"fetch" => break stream.expect(imap::parser::ok)?,
+      tag => anyhow::bail!("unexpected tag {tag:?}"),
+    }
+  }
+  anyhow::ensure!(remaining.is_empty(), "{property} is missing from FETCH");
+  Ok(())
+}
+
+// The server refused the APPEND because the mailbox doesn't exist (anymore), even after creating it.
+#[derive(Debug)]
+pub struct TryCreateError {
+  pub mailbox: String,
+}
+
+impl fmt::Display for TryCreateError {
+  fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+    write!(
+      formatter,
+      "couldn't append to {}: the mailbox doesn't exist on the server, rerun a pull",
+      self.mailbox
+    )
+  }
+}
+
+impl error::Error for TryCreateError {}
+
+fn create<RW>(stream: &mut imap::Stream<RW>, mailbox: &[u8]) -> anyhow::Result<()>
+where
+  RW: imap::ReadWrite,
+{
+  let command: &[&[u8]] = &[
+    b"create CREATE {",
+    &mailbox.len().to_string().into_bytes(),
+    b"+}\r\n",
+    mailbox,
+    b"\r\n",
+  ];
+  stream.input(command, command.len())?;
+  loop {
+    match stream.expect(imap::parser::start)? {
+      b"*" => stream.expect(imap::parser::skip)?,
+      b"create" => break stream.expect(imap::parser::ok),
+      tag => anyhow::bail!("unexpected tag {tag:?}"),
+    }
+  }
+}
+
+struct Append {
+  uidvalidity: u64,
+  uid: u64,
+  highestmodseq: u64,
+}
+
+// https://www.rfc-editor.org/rfc/rfc4469
+// A single text part is a regular APPEND, more are concatenated by the server (CATENATE), the URLs
+// referencing sections of messages it already has instead of uploading them again.
+enum Part<'a> {
+  Text(&'a [u8]),
+  Url(String),
+}
+
+fn append<RW>(
+  stream: &mut imap::Stream<RW>,
+  mailbox: &[u8],
+  flags: &collections::HashSet<&str>,
+  parts: &[Part],
+) -> anyhow::Result<Append>
+where
+  RW: imap::ReadWrite,
+{
+  // .intersperse() is nightly...
+  let mut flags_ = "".to_string();
+  for (i, flag) in flags.iter().enumerate() {
+    flags_ += flag;
+    if i + 1 < flags.len() {
+      flags_ += " ";
+    }
+  }
+  let mut buffers: Vec<borrow::Cow<[u8]>> = vec![
+    b"append APPEND {"[..].into(),
+    mailbox.len().to_string().into_bytes().into(),
+    b"+}\r\n"[..].into(),
+    mailbox.into(),
+    b" ("[..].into(),
+    flags_.as_bytes().into(),
+    b")"[..].into(),
+  ];
+  // Only the command is logged, not the messages.
+  let mut log = None;
+  match parts {
+    [Part::Text(buffer)] => {
+      buffers.push(format!(" {{{}+}}\r\n", buffer.len()).into_bytes().into());
+      log = Some(buffers.len());
+      buffers.push((*buffer).into());
+    }
+    _ => {
+      buffers.push(b" CATENATE ("[..].into());
+      for (i, part) in parts.iter().enumerate() {
+        if i > 0 {
+          buffers.push(b" "[..].into());
+        }
+        match part {
+          Part::Text(buffer) => {
+            buffers.push(
+              format!("TEXT {{{}+}}\r\n", buffer.len())
+                .into_bytes()
+                .into(),
+            );
+            log.get_or_insert(buffers.len());
+            buffers.push((*buffer).into());
+          }
+          Part::Url(url) => buffers.push(format!("URL \"{url}\"").into_bytes().into()),
+        }
+      }
+      buffers.push(b")"[..].into());
+    }
+  }
+  buffers.push(b"\r\n"[..].into());
+  let log = log.unwrap_or(buffers.len());
+  let buffers: Vec<&[u8]> = buffers.iter().map(AsRef::as_ref).collect();
+  let mut created = false;
+  let mut highestmodseq = None;
+  let imap::Append { uidvalidity, uid } = 'append: loop {
+    stream.input(&buffers, log)?;
+    loop {
+      match stream.expect(imap::parser::start)? {
+        b"*" => match stream.parse(imap::parser::append_data)? {
+          highestmodseq_ @ Some(_) => highestmodseq = highestmodseq_,
+          None => stream.expect(imap::parser::skip)?,
+        },
+        b"append" => match stream.parse(imap::parser::trycreate)? {
+          // https://www.rfc-editor.org/rfc/rfc3501#section-6.3.11
+          // This gives a hint to the client that it can attempt a CREATE command and retry the
+          // APPEND if the CREATE is successful.
+          Some(()) => {
+            let name = String::from_utf8_lossy(mailbox).to_string();
+            anyhow::ensure!(!created, TryCreateError { mailbox: name });
+            log::warn!("{name} doesn't exist on the server, creating it");
+            create(stream, mailbox).context(TryCreateError { mailbox: name })?;
+            created = true;
+            continue 'append;
+          }
+          None => break 'append stream.expect(imap::parser::append)?,
+        },
+        tag => anyhow::bail!("unexpected tag {tag:?}"),
+      }
+    }
+  };
+  anyhow::ensure!(
+    highestmodseq.is_some(),
+    "HIGHESTMODSEQ is missing from APPEND"
+  );
+  // https://www.rfc-editor.org/rfc/rfc4551#section-3.6
+  // If the server doesn't support the persistent storage of mod-sequences for the mailbox [...],
+  // the server MUST return 0 as the value of HIGHESTMODSEQ status data item.
+  let highestmodseq = highestmodseq.unwrap();
+  anyhow::ensure!(highestmodseq > 0, "HIGHESTMODSEQ is not properly supported");
+  Ok(Append {
+    uidvalidity,
+    uid,
+    highestmodseq,
+  })
+}
+
+#[derive(Clone, Copy, Eq, Ord, PartialEq, PartialOrd)]
+enum Diff {
+  Add,
+  Delete,
+}
+
+fn store<RW>(
+  stream: &mut imap::Stream<RW>,
+  uid: u64,
+  modseq: u64,
+  flags: &collections::HashSet<String>,
+  diff: Diff,
+) -> anyhow::Result<Option<imap::Store>>
+where
+  RW: imap::ReadWrite,
+{
+  let stored = store_many(stream, &[uid], modseq, flags, diff)?;
+  Ok(stored.get(&uid).map(|modseq| imap::Store {
+    uid,
+    modseq: *modseq,
+  }))
+}
+
+// Several messages with a single command, the UIDs are sorted. The messages changed since the modseq
+// are left alone (the server reports them as MODIFIED), the others are returned with their new
+// modseq.
+fn store_many<RW>(
+  stream: &mut imap::Stream<RW>,
+  uids: &[u64],
+  modseq: u64,
+  flags: &collections::HashSet<String>,
+  diff: Diff,
+) -> anyhow::Result<collections::HashMap<u64, u64>>
+where
+  RW: imap::ReadWrite,
+{
+  // While it's not part of the RFC, specifying both +FLAGS.SILENT and -FLAGS.SILENT will result in
+  // Dovecot silently ignoring the last occurence.
+  let operator = match diff {
+    Diff::Add => b"+",
+    Diff::Delete => b"-",
+  };
+  // .intersperse() is nightly...
+  let mut flags_ = "".to_string();
+  for (i, flag) in flags.iter().enumerate() {
+    flags_ += flag;
+    if i + 1 < flags.len() {
+      flags_ += " ";
+    }
+  }
+  let set = sequence_set(&ranges(uids));
+  let command: &[&[u8]] = &[
+    b"store UID STORE ",
+    set.as_bytes(),
+    b" (UNCHANGEDSINCE ",
+    &modseq.to_string().into_bytes(),
+    b") ",
+    operator,
+    b"FLAGS.SILENT (",
+    flags_.as_bytes(),
+    b")\r\n",
+  ];
+  stream.input(command, command.len())?;
+  let mut stored = collections::HashMap::new();
+  let modified = loop {
+    match stream.expect(imap::parser::start)? {
+      b"*" => match stream.parse(imap::parser::store_data)? {
+        Some(imap::Store { uid, modseq }) => {
+          stored.insert(uid, modseq);
+        }
+        None => stream.expect(imap::parser::skip)?,
+      },
+      b"store" => break stream.expect(imap::parser::store)?.unwrap_or_default(),
+      tag => anyhow::bail!("unexpected tag {tag:?}"),
+    }
+  };
+  let count = uids.iter().filter(|uid| contains(&modified, **uid)).count();
+  anyhow::ensure!(
+    modified
+      .iter()
+      .map(|imap::Range(start, end)| end - start + 1)
+      .sum::<u64>()
+      == count as u64,
+    "invalid UID from STORE"
+  );
+  // Other clients' changes may be reported along.
+  stored.retain(|uid, _| uids.binary_search(uid).is_ok() && !contains(&modified, *uid));
+  anyhow::ensure!(
+    stored.len() + count == uids.len(),
+    "FETCH is missing from STORE"
+  );
+  Ok(stored)
+}
+
+// https://www.rfc-editor.org/rfc/rfc4315#section-2.1
+// The UID EXPUNGE command permanently removes all messages that both have the \Deleted flag set and
+// have a UID that is included in the specified sequence set from the currently selected mailbox.
+fn uid_expunge<RW>(stream: &mut imap::Stream<RW>, uids: &[u64]) -> anyhow::Result<()>
+where
+  RW: imap::ReadWrite,
+{
+  let uids = uids
+    .iter()
+    .map(u64::to_string)
+    .collect::<Vec<_>>()
+    .join(",");
+  let command: &[&[u8]] = &[b"expunge UID EXPUNGE ", uids.as_bytes(), b"\r\n"];
+  stream.input(command, command.len())?;
+  loop {
+    match stream.expect(imap::parser::start)? {
+      // The EXPUNGE (or VANISHED) responses: the messages are removed locally below.
+      b"*" => stream.expect(imap::parser::skip)?,
+      b"expunge" => break stream.expect(imap::parser::ok),
+      tag => anyhow::bail!("unexpected tag {tag:?}"),
+    }
+  }
+}
+
+// The messages synchronized with a mailbox.
+// Whether a mailbox matches one of the --purgeable patterns, with the wildcards of LIST:
+// https://www.rfc-editor.org/rfc/rfc3501#section-6.3.8
+// The character "*" is a wildcard, and matches zero or more characters at this position. The
+// character "%" is similar to "*", but it does not match a hierarchy delimiter.
+fn purgeable(patterns: &[String], mailbox: &str, separator: Option<char>) -> bool {
+  patterns
+    .iter()
+    .any(|pattern| matches(pattern, mailbox, separator))
+}
+
+fn matches(pattern: &str, mailbox: &str, separator: Option<char>) -> bool {
+  let mut characters = pattern.chars();
+  match characters.next() {
+    None => mailbox.is_empty(),
+    Some(wildcard @ ('*' | '%')) => {
+      let pattern = characters.as_str();
+      for (i, c) in mailbox.char_indices() {
+        if matches(pattern, &mailbox[i..], separator) {
+          return true;
+        }
+        if wildcard == '%' && Some(c) == separator {
+          return false;
+        }
+      }
+      matches(pattern, "", separator)
+    }
+    Some(c) => mailbox
+      .strip_prefix(c)
+      .is_some_and(|mailbox| matches(characters.as_str(), mailbox, separator)),
+  }
+}
+
+// Removing from the file system is always okay:
+//  - If it's a duplicate, the search query will still find a reference to it and clean up the
+//    properties.
+//  - If it's the last message under this message ID and the transaction is interrupted, another
+//    'notmuch new' will simply remove all leftovers (unless it's in tmp, in this case it will be
+//    ignored and the search query will still find it).
+pub fn unlink(path: &path::Path) -> anyhow::Result<()> {
+  match fs::remove_file(path) {
+    Ok(_) => Ok(()),
+    // Might have been previously removed but interrupted.
+    Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(()),
+    Err(error) => Err(error)?,
+  }
+}
+
+// The local maildirs that don't belong to any of the synchronized mailboxes (e.g.: created by hand).
+fn orphans<'a>(
+  maildir_builder: &maildir::Builder,
+  maildirs: impl IntoIterator<Item = &'a maildir::Maildir>,
+) -> io::Result<Vec<path::PathBuf>> {
+  let synchronized: collections::HashSet<&path::Path> = maildirs
+    .into_iter()
+    .map(maildir::Maildir::relative)
+    .collect();
+  Ok(
+    maildir_builder
+      .maildirs()?
+      .into_iter()
+      .filter(|relative| {
+        !synchronized.contains(relative.as_path()) && !maildir_builder.local_only(relative)
+      })
+      .collect(),
+  )
+}
+
+#[cfg(feature = "notmuch")]
+pub fn search_mailbox<'a>(
+  database: &'a notmuch::Database<notmuch::Attached>,
+  mailbox: &str,
+) -> anyhow::Result<notmuch::Messages<'a>> {
+  let namespace = notmuch::quote(database.namespace());
+  let mailbox = notmuch::quote(mailbox);
+  database.query(&format!(
+    "    property:\"{namespace}.marker={}\" \
+     and property:\"{namespace}.mailbox={mailbox}\"",
+    notmuch::MESSAGE_MARKER,
+  ))
+}
+
+#[cfg(feature = "notmuch")]
+pub fn move_out_of_tmp(
+  database: &mut notmuch::Database<notmuch::Attached>,
+  relative_maildir: &path::Path,
+  durability: bool,
+) -> anyhow::Result<()> {
+  let folder = relative_maildir
+    .file_name()
+    .with_context(|| format!("couldn't get file name for {relative_maildir:?}"))?;
+  let folder = folder
+    .to_str()
+    .with_context(|| format!("couldn't convert {folder:?} to string"))?;
+  let mut messages = database.query(&format!(
+    "    property:\"{}.marker={}\" \
+     and path:\"{}/**\" \
      and path:/tmp/[^/]+$/",
     notmuch::quote(database.namespace()),
     notmuch::MESSAGE_MARKER,
     notmuch::quote(folder),
   ))?;
+  let mut maildirs = collections::HashSet::new();
   while let Some(message) = messages.next() {
     for path in message.paths()? {
       let components @ [grandparent, _, _] = maildir::components(&path)?;
@@ -290,12 +1719,250 @@ pub fn move_out_of_tmp(
           Err(error) if error.kind() == io::ErrorKind::NotFound => (),
           Err(error) => Err(error)?,
         }
-        crate::interrupt(crate::Interruption::MoveOutOfTmpPostRename)?;
-        let mut message = database.add(&new)?;
+        crate::fault::interrupt(crate::fault::Interruption::MoveOutOfTmpPostRename)?;
+        let mut message = database.add(&new, &[])?;
         message.tags_to_maildir_flags()?; // If necessary, move from new to cur based on flags.
         database.remove(&path)?;
+        maildirs.insert(grandparent.to_path_buf());
+      }
+    }
+  }
+  // Before the transaction is committed, the database shouldn't refer to renames that were lost.
+  if durability {
+    for maildir in maildirs {
+      maildir::sync_directories(&maildir)?;
+    }
+  }
+  Ok(())
+}
+
+// Keep the mailbox tags in line with the mailboxes of the messages changed since lastmod (or of all
+// the messages when the template changed).
+#[cfg(feature = "notmuch")]
+pub fn tag_mailboxes(
+  database: &mut notmuch::Database<notmuch::Attached>,
+  mailbox_tag: Option<&crate::MailboxTag>,
+  lastmod: u64,
+) -> anyhow::Result<()> {
+  let previous = database
+    .root()?
+    .mailbox_tag()?
+    .map(crate::parse_mailbox_tag)
+    .transpose()?;
+  let all = previous.as_ref() != mailbox_tag;
+  if !all && mailbox_tag.is_none() {
+    return Ok(());
+  }
+  if all {
+    log::info!("the mailbox tag template changed, retagging all the messages");
+  }
+  {
+    let mut messages = database.query(&format!(
+      "property:\"{}.marker={}\"{}",
+      notmuch::quote(database.namespace()),
+      notmuch::MESSAGE_MARKER,
+      match all {
+        true => String::new(),
+        false => format!(" and lastmod:{lastmod}.."),
+      }
+    ))?;
+    while let Some(mut message) = messages.next() {
+      let expected: collections::HashSet<String> = match mailbox_tag {
+        Some(mailbox_tag) => message
+          .mailboxes()?
+          .into_iter()
+          .map(|mailbox| mailbox_tag.tag(mailbox))
+          .collect(),
+        None => collections::HashSet::new(),
+      };
+      let current: collections::HashSet<String> = message
+        .tags()?
+        .into_iter()
+        .filter(|tag| {
+          [previous.as_ref(), mailbox_tag]
+            .into_iter()
+            .flatten()
+            .any(|t| t.matches(tag))
+        })
+        .map(String::from)
+        .collect();
+      for tag in current.difference(&expected) {
+        message.remove_tag(tag)?;
+      }
+      for tag in expected.difference(&current) {
+        message.add_tag(tag)?;
       }
     }
   }
+  if all {
+    database
+      .root()?
+      .update_mailbox_tag(mailbox_tag.map(ToString::to_string).as_deref())?;
+  }
   Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::{env, fmt::Write as _, sync};
+
+  #[test]
+  fn ordered() {
+    let mailbox = |string: &str, marked| Mailbox {
+      bytes: string.as_bytes().to_vec(),
+      string: string.to_string(),
+      separator: Some('/'),
+      drafts: false,
+      marked,
+    };
+    let mailboxes = [
+      mailbox("Archives", Some(false)),
+      mailbox("INBOX", None),
+      mailbox("Lists", Some(true)),
+      mailbox("Drafts", None),
+      mailbox("Sent", None),
+      mailbox("Lists/rust", Some(false)),
+    ];
+    let ordered = |priority: &[&str]| {
+      let priority: Vec<String> = priority.iter().map(|pattern| pattern.to_string()).collect();
+      super::ordered(&mailboxes, &priority)
+        .iter()
+        .map(|mailbox| mailbox.string.as_str())
+        .collect::<Vec<_>>()
+    };
+    assert_eq!(
+      vec!["Lists", "Drafts", "INBOX", "Sent", "Archives", "Lists/rust"],
+      ordered(&[])
+    );
+    assert_eq!(
+      vec!["INBOX", "Sent", "Lists", "Lists/rust", "Drafts", "Archives"],
+      ordered(&["INBOX", "Sent", "Lists*"])
+    );
+  }
+
+  #[test]
+  fn retryable() {
+    let error = |kind| anyhow::Error::from(io::Error::from(kind));
+    assert!(super::retryable(&error(io::ErrorKind::WouldBlock)));
+    assert!(super::retryable(
+      &error(io::ErrorKind::ConnectionReset).context("couldn't select")
+    ));
+    assert!(!super::retryable(&error(io::ErrorKind::PermissionDenied)));
+    assert!(!super::retryable(&anyhow::anyhow!("end of stream")));
+  }
+
+  // Everything logged by the tests, like the log file would (at the trace level).
+  struct Capture(sync::Mutex<String>);
+
+  impl log::Log for Capture {
+    fn enabled(&self, _: &log::Metadata) -> bool {
+      true
+    }
+    fn log(&self, record: &log::Record) {
+      writeln!(self.0.lock().unwrap(), "{}", record.args()).unwrap();
+    }
+    fn flush(&self) {}
+  }
+
+  static CAPTURE: Capture = Capture(sync::Mutex::new(String::new()));
+
+  struct Server {
+    input: io::Cursor<Vec<u8>>,
+    output: sync::Arc<sync::Mutex<Vec<u8>>>,
+  }
+
+  impl io::Read for Server {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+      self.input.read(buf)
+    }
+  }
+
+  impl io::Write for Server {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+      self.output.lock().unwrap().write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+      Ok(())
+    }
+  }
+
+  #[test]
+  fn redaction() {
+    log::set_logger(&CAPTURE).unwrap();
+    log::set_max_level(log::LevelFilter::Trace);
+    env::set_var("SIN_TEST_PASSWORD", "hunter2");
+    let credentials = Credentials::new(
+      "user",
+      crate::credentials::Secret::Environment("SIN_TEST_PASSWORD".to_string()),
+      Some(imap::Mechanism::Plain),
+    );
+    // The PLAIN response, \0user\0hunter2.
+    let response = "AHVzZXIAaHVudGVyMg==";
+    let failure = "authenticate NO [AUTHENTICATIONFAILED] Authentication failed.\r\n";
+    for (capabilities, input) in [
+      // In the command itself.
+      (
+        &["IMAP4rev1", "AUTH=PLAIN", "SASL-IR"][..],
+        failure.to_string(),
+      ),
+      // After an empty challenge.
+      (&["IMAP4rev1", "AUTH=PLAIN"][..], format!("+ \r\n{failure}")),
+    ] {
+      let output = sync::Arc::new(sync::Mutex::new(Vec::new()));
+      let mut stream = imap::Stream::new(Server {
+        input: io::Cursor::new(input.into_bytes()),
+        output: output.clone(),
+      });
+      stream.set_capabilities(&[]);
+      let greetings = Greetings {
+        preauthenticated: false,
+        capabilities: capabilities
+          .iter()
+          .map(|capability| capability.as_bytes().to_vec())
+          .collect(),
+        quirks: None,
+      };
+      let error = authenticate(&mut stream, &greetings, &credentials).unwrap_err();
+      // The credentials were sent but don't appear in the error.
+      assert!(memchr::memmem::find(&output.lock().unwrap(), response.as_bytes()).is_some());
+      let error = format!("{error:?}");
+      assert!(error.contains("in response to ") && error.contains("<redacted>"));
+      assert!(!error.contains(response));
+    }
+    let logs = CAPTURE.0.lock().unwrap();
+    assert!(logs.contains("> authenticate AUTHENTICATE PLAIN"));
+    assert!(logs.contains("<redacted>"));
+    assert!(!logs.contains(response) && !logs.contains("hunter2"));
+  }
+
+  #[test]
+  fn ranges() {
+    let ranges = [imap::Range(1, 3), imap::Range(5, 5), imap::Range(8, 10)];
+    assert_eq!("1:3,5,8:10", sequence_set(&ranges));
+    assert_eq!(ranges[..], super::ranges(&[1, 2, 3, 5, 8, 9, 10]));
+    assert!(super::ranges(&[]).is_empty());
+    for uid in [1, 2, 3, 5, 8, 10] {
+      assert!(contains(&ranges, uid));
+    }
+    for uid in [4, 6, 7, 11] {
+      assert!(!contains(&ranges, uid));
+    }
+    assert!(!contains(&[], 1));
+  }
+
+  #[test]
+  fn purgeable() {
+    let patterns = ["INBOX".to_string(), "Archive/2019/*".to_string()];
+    assert!(super::purgeable(&patterns, "INBOX", Some('/')));
+    assert!(super::purgeable(&patterns, "Archive/2019/a/b", Some('/')));
+    assert!(!super::purgeable(&patterns, "Archive/2019", Some('/')));
+    assert!(!super::purgeable(&patterns, "INBOX/a", Some('/')));
+
+    let patterns = ["Archive/%".to_string()];
+    assert!(super::purgeable(&patterns, "Archive/2019", Some('/')));
+    assert!(!super::purgeable(&patterns, "Archive/2019/a", Some('/')));
+    assert!(super::purgeable(&patterns, "Archive/2019/a", None));
+    assert!(super::purgeable(&["*".to_string()], "", None));
+  }
+}
@@ -0,0 +1,381 @@
+// A lean synchronization over any store (see store::Store), used by the maildir backend (see
+// crate::Backend): a single connection, no message is known under more than one UID and the flags
+// the store can't represent are left alone.
+
+use crate::{
+  imap, maildir, status,
+  sync::{self, store},
+};
+use anyhow::Context as _;
+use std::{collections, fs};
+
+// The messages known locally the server doesn't have anymore.
+fn vanished(select: &sync::Select, uids: &collections::HashSet<u64>) -> collections::HashSet<u64> {
+  // Like pull::finish.
+  let vanished: collections::HashSet<u64> = select
+    .vanished
+    .iter()
+    .flat_map(|imap::Range(start, end)| *start..=*end)
+    .collect();
+  uids
+    .iter()
+    .copied()
+    .filter(|uid| {
+      vanished.contains(uid)
+        || select
+          .existing
+          .as_ref()
+          .is_some_and(|existing| !sync::contains(existing, *uid))
+    })
+    .collect()
+}
+
+// The store leaves the files to the caller: without --max-purge to check first (unlike the notmuch
+// backend, it's refused with this one), they go right away.
+fn remove<S>(
+  store: &mut S,
+  mailbox: &sync::Mailbox,
+  maildir: &maildir::Maildir,
+  uidvalidity: u64,
+  uids: &collections::HashSet<u64>,
+) -> anyhow::Result<()>
+where
+  S: store::Store,
+{
+  for path in store.remove(mailbox, maildir, uidvalidity, uids)? {
+    sync::unlink(&path)?;
+  }
+  Ok(())
+}
+
+pub fn pull<RW, S>(
+  stream: &mut imap::Stream<RW>,
+  store: &mut S,
+  maildir_builder: &maildir::Builder,
+  scope: &sync::Scope,
+  priority: &[String],
+  fetch_batch: usize,
+  purgeable: &[String],
+) -> anyhow::Result<()>
+where
+  RW: imap::ReadWrite,
+  S: store::Store,
+{
+  let mailboxes = sync::list(stream, scope)?;
+  let mut unconfirmed = Vec::new();
+  for mailbox in sync::ordered(&mailboxes, priority) {
+    log::info!("pulling {}", mailbox.string);
+    status::mailbox(&mailbox.string);
+    let maildir = maildir_builder.maildir(&mailbox.string, &mailbox.separator)?;
+    let purgeable = sync::purgeable(purgeable, &mailbox.string, mailbox.separator);
+    if let Some(reason) = pull_mailbox(stream, store, mailbox, &maildir, fetch_batch, purgeable)
+      .with_context(|| format!("couldn't pull {}", mailbox.string))?
+    {
+      unconfirmed.push((mailbox.string.clone(), reason));
+    }
+  }
+  // Like pull::run, once every other mailbox has been pulled.
+  if !unconfirmed.is_empty() {
+    unconfirmed.sort();
+    let list: Vec<String> = unconfirmed
+      .iter()
+      .map(|(mailbox, reason)| format!("{mailbox} ({reason})"))
+      .collect();
+    let arguments: Vec<String> = unconfirmed
+      .iter()
+      .map(|(mailbox, _)| format!("--purgeable {mailbox}"))
+      .collect();
+    anyhow::bail!(
+      "mailboxes changed on the server: {}; allow to purge them locally (all messages will be \
+       removed) by passing {} (or a pattern)",
+      list.join(", "),
+      arguments.join(" ")
+    );
+  }
+  Ok(())
+}
+
+fn pull_mailbox<RW, S>(
+  stream: &mut imap::Stream<RW>,
+  store: &mut S,
+  mailbox: &sync::Mailbox,
+  maildir: &maildir::Maildir,
+  fetch_batch: usize,
+  purgeable: bool,
+) -> anyhow::Result<Option<&'static str>>
+where
+  RW: imap::ReadWrite,
+  S: store::Store,
+{
+  let state = store.state(mailbox)?;
+
+  // Like pull::reselect: with new UIDs, everything is pulled again.
+  let (mut uidvalidity, mut highestmodseq) = (state.uidvalidity, state.highestmodseq);
+  let mut reset = None;
+  let select = loop {
+    let select = sync::select(stream, &mailbox.bytes, uidvalidity, highestmodseq)?;
+    if select.uidvalidity == uidvalidity
+      && (highestmodseq == 0 || !sync::reassigned(&select, state.uidnext))
+    {
+      break select;
+    }
+    reset = reset.or(Some(if select.uidvalidity != uidvalidity {
+      "new validity"
+    } else {
+      "UIDs reassigned"
+    }));
+    (uidvalidity, highestmodseq) = (select.uidvalidity, 0);
+  };
+  // An interruption below starts over from there with the messages recorded so far.
+  if let Some(reason) = reset {
+    let uids = store.uids(mailbox)?;
+    if !uids.is_empty() {
+      // Like pull::run, the messages known locally are only purged with --purgeable.
+      if !purgeable {
+        return Ok(Some(reason));
+      }
+      log::warn!(
+        "{}'s UIDs changed on the server, downloading its messages again",
+        mailbox.string
+      );
+    }
+    remove(store, mailbox, maildir, state.uidvalidity, &uids)?;
+    store.set_state(
+      mailbox,
+      store::State {
+        uidvalidity: select.uidvalidity,
+        ..Default::default()
+      },
+    )?;
+  }
+
+  let vanished = vanished(&select, &store.uids(mailbox)?);
+  remove(store, mailbox, maildir, select.uidvalidity, &vanished)?;
+
+  // Changed on the server: the known messages are updated, the others downloaded.
+  let mut changes = select.changes.clone();
+  store.update(mailbox, maildir, select.uidvalidity, &mut changes)?;
+  let mut new: Vec<u64> = changes.into_keys().collect();
+  new.sort();
+  if !new.is_empty() {
+    log::info!("downloading {} message(s)", new.len());
+  }
+  status::expect(&mailbox.string, new.len());
+  for batch in new.chunks(fetch_batch) {
+    // https://www.rfc-editor.org/rfc/rfc3501#section-6.4.5
+    // BODY.PEEK[<section>]<<partial>> An alternate form of BODY[<section>] that does not
+    // implicitly set the \Seen flag.
+    sync::fetch_many(
+      stream,
+      batch,
+      "BODY.PEEK[]",
+      imap::parser::fetch_body_data,
+      |uid, body| {
+        let tmp = maildir.tmp(&body.context("BODY.PEEK[] returned NIL")?)?;
+        store.add(
+          mailbox,
+          maildir,
+          select.uidvalidity,
+          uid,
+          &select.changes[&uid],
+          &tmp,
+        )?;
+        status::done(&mailbox.string);
+        Ok(())
+      },
+    )?;
+    // Otherwise an interruption would have them downloaded twice.
+    store.commit(mailbox)?;
+  }
+
+  store.set_state(
+    mailbox,
+    store::State {
+      uidvalidity: select.uidvalidity,
+      uidnext: select.uidnext.unwrap_or(0),
+      highestmodseq: select.highestmodseq,
+    },
+  )?;
+  store.commit(mailbox)?;
+  Ok(None)
+}
+
+pub fn push<RW, S>(
+  stream: &mut imap::Stream<RW>,
+  store: &mut S,
+  maildir_builder: &maildir::Builder,
+  scope: &sync::Scope,
+  expunge: bool,
+) -> anyhow::Result<()>
+where
+  RW: imap::ReadWrite,
+  S: store::Store,
+{
+  let mailboxes = sync::list(stream, scope)?;
+  for mailbox in &mailboxes {
+    let state = store.state(mailbox)?;
+    if state.uidvalidity == 0 {
+      log::debug!("{} was never pulled, not pushing it", mailbox.string);
+      continue;
+    }
+    status::mailbox(&mailbox.string);
+    let maildir = maildir_builder.maildir(&mailbox.string, &mailbox.separator)?;
+    push_mailbox(stream, store, mailbox, &maildir, state, expunge)
+      .with_context(|| format!("couldn't push {}", mailbox.string))?;
+  }
+  Ok(())
+}
+
+fn push_mailbox<RW, S>(
+  stream: &mut imap::Stream<RW>,
+  store: &mut S,
+  mailbox: &sync::Mailbox,
+  maildir: &maildir::Maildir,
+  state: store::State,
+  expunge: bool,
+) -> anyhow::Result<()>
+where
+  RW: imap::ReadWrite,
+  S: store::Store,
+{
+  let select = sync::select(
+    stream,
+    &mailbox.bytes,
+    state.uidvalidity,
+    state.highestmodseq,
+  )?;
+  anyhow::ensure!(
+    select.uidvalidity == state.uidvalidity && !sync::reassigned(&select, state.uidnext),
+    "{}'s UIDs changed on the server, rerun a pull",
+    mailbox.string
+  );
+
+  // The flags changed locally since the last synchronization. The server's changes since then are
+  // only ignored when they're the same (e.g.: a previous push). The messages removed from the
+  // server are left to the next pull.
+  let vanished = vanished(&select, &store.uids(mailbox)?);
+  let deleted = "\\Deleted".to_string();
+  let changed: Vec<store::Changed> = store
+    .changed(mailbox, maildir)?
+    .into_iter()
+    .filter(|changed| !vanished.contains(&changed.uid))
+    // Like the notmuch backend's deleted tag, \Deleted (the T flag) only goes with --expunge.
+    .filter(|changed| {
+      let marked = changed.flags.contains(&deleted) && !changed.recorded.contains(&deleted);
+      if marked && !expunge {
+        log::debug!("leaving message {} to a push with --expunge", changed.uid);
+      }
+      !marked || expunge
+    })
+    .collect();
+  status::expect(&mailbox.string, changed.len());
+
+  let (mut stored, mut marked) = (collections::HashMap::new(), Vec::new());
+  for store::Changed {
+    uid,
+    modseq,
+    recorded,
+    flags,
+  } in changed
+  {
+    let mut modseq = match select.changes.get(&uid) {
+      Some(changes) => {
+        anyhow::ensure!(
+          changes
+            .flags
+            .iter()
+            .cloned()
+            .collect::<collections::HashSet<_>>()
+            == recorded,
+          "message {uid} changed on the server, rerun a pull"
+        );
+        changes.modseq
+      }
+      None => modseq.unwrap_or(state.highestmodseq),
+    };
+    for (diff, flags) in [
+      (sync::Diff::Delete, recorded.difference(&flags)),
+      (sync::Diff::Add, flags.difference(&recorded)),
+    ] {
+      let flags: collections::HashSet<String> = flags.cloned().collect();
+      if flags.is_empty() {
+        continue;
+      }
+      modseq = sync::store(stream, uid, modseq, &flags, diff)?
+        .with_context(|| format!("message {uid} changed on the server, rerun a pull"))?
+        .modseq;
+    }
+    log::debug!("stored the flags of message {uid}");
+    if flags.contains(&deleted) && !recorded.contains(&deleted) {
+      marked.push(uid);
+    }
+    stored.insert(
+      uid,
+      sync::Changes {
+        flags: flags.into_iter().collect(),
+        modseq,
+      },
+    );
+    status::done(&mailbox.string);
+  }
+  store.update(mailbox, maildir, select.uidvalidity, &mut stored)?;
+
+  // Only the messages marked above, not the ones flagged by other clients (see readme.org).
+  if !marked.is_empty() {
+    log::info!(
+      "expunging {} message(s) from {}",
+      marked.len(),
+      mailbox.string
+    );
+    sync::uid_expunge(stream, &marked)?;
+    remove(
+      store,
+      mailbox,
+      maildir,
+      select.uidvalidity,
+      &marked.into_iter().collect(),
+    )?;
+  }
+  store.commit(mailbox)?;
+
+  // The messages added locally (e.g.: by a mail client).
+  let added = store.added(mailbox, maildir)?;
+  if !added.is_empty() {
+    log::info!("appending {} message(s)", added.len());
+  }
+  status::expect(&mailbox.string, added.len());
+  for (path, flags) in added {
+    let buffer = fs::read(&path).with_context(|| format!("couldn't read {path:?}"))?;
+    let sync::Append {
+      uidvalidity,
+      uid,
+      highestmodseq: modseq,
+    } = sync::append(
+      stream,
+      &mailbox.bytes,
+      &flags.iter().map(String::as_str).collect(),
+      &[sync::Part::Text(&buffer)],
+    )?;
+    anyhow::ensure!(
+      uidvalidity == state.uidvalidity,
+      "{}'s UIDs changed on the server, rerun a pull",
+      mailbox.string
+    );
+    log::debug!("appended {path:?} as message {uid}");
+    store.add(
+      mailbox,
+      maildir,
+      uidvalidity,
+      uid,
+      &sync::Changes {
+        flags: flags.into_iter().collect(),
+        modseq,
+      },
+      &path,
+    )?;
+    // Recorded right away: an interruption would have it appended twice.
+    store.commit(mailbox)?;
+    status::done(&mailbox.string);
+  }
+  Ok(())
+}
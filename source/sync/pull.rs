@@ -1,17 +1,22 @@
-use crate::{imap, maildir, notmuch, sync};
+use crate::{credentials, fault, imap, maildir, notmuch, status, sync};
 use anyhow::Context as _;
 use crossbeam_utils::thread;
-use std::{cmp, collections, fs, io, num, path, str, sync::mpsc};
+use std::{
+  cmp, collections, mem, num, ops, path, str,
+  sync::{atomic, mpsc},
+  time,
+};
 
 fn reselect<RW>(
   stream: &mut imap::Stream<RW>,
   mailbox: &[u8],
-  mut uidvalidity: u64,
-  mut highestmodseq: u64,
+  known: &Known,
+  mut select: sync::Select,
 ) -> anyhow::Result<sync::Select>
 where
   RW: imap::ReadWrite,
 {
+  let mut uidvalidity = known.validity.0;
   loop {
     // https://www.rfc-editor.org/rfc/rfc3501#section-2.3.1.1
     // If unique identifiers from an earlier session fail to persist in this session, the unique
@@ -20,54 +25,22 @@ where
     // The unique identifier of a message MUST NOT change during the session, and SHOULD NOT change
     // between sessions. Any change of unique identifiers between sessions MUST be detectable using
     // the UIDVALIDITY mechanism [...]
-    let select = sync::select(stream, mailbox, uidvalidity, highestmodseq)?;
     if select.uidvalidity != uidvalidity {
-      (uidvalidity, highestmodseq) = (select.uidvalidity, 0);
+      uidvalidity = select.uidvalidity;
+      select = sync::select(stream, mailbox, uidvalidity, 0)?;
     } else {
+      // Like with a new UIDVALIDITY, everything is pulled again (see sync::reassigned).
+      if uidvalidity == known.validity.0
+        && known.validity.1 != 0
+        && sync::reassigned(&select, known.uidnext)
+      {
+        select = sync::select(stream, mailbox, uidvalidity, 0)?;
+      }
       return Ok(select);
     }
   }
 }
 
-fn fetch<'a, P, R, RW>(
-  stream: &'a mut imap::Stream<RW>,
-  uid: u64,
-  property: &str,
-  parser: P,
-) -> anyhow::Result<R>
-where
-  P: Fn(
-    &'a [u8],
-  )
-    -> Result<(usize, (u64, R)), peg::error::ParseError<<[u8] as ::peg::Parse>::PositionRepr>>,
-  RW: imap::ReadWrite,
-{
-  let command: &[&[u8]] = &[
-    b"fetch UID FETCH ",
-    &uid.to_string().into_bytes(),
-    b" (",
-    property.as_bytes(),
-    b" )\r\n",
-  ];
-  stream.input(command, command.len())?;
-  let mut result = None;
-  loop {
-    match stream.expect(imap::parser::start)? {
-      b"*" => match stream.parse(&parser)? {
-        Some((uid_, result_)) => {
-          anyhow::ensure!(uid == uid_, "invalid UID returned from FETCH");
-          result = Some(result_);
-        }
-        None => stream.expect(imap::parser::skip)?,
-      },
-      b"fetch" => break stream.expect(imap::parser::ok)?,
-      tag => anyhow::bail!("unexpected tag {tag:?}"),
-    }
-  }
-  anyhow::ensure!(result.is_some(), "{property} is missing from FETCH");
-  Ok(result.unwrap())
-}
-
 fn search_not_uidvalidity<'a>(
   database: &'a mut notmuch::Database<notmuch::Attached>,
   mailbox: &str,
@@ -83,7 +56,7 @@ fn search_not_uidvalidity<'a>(
   ))
 }
 
-fn search_uids<'a>(
+pub fn search_uids<'a>(
   database: &'a notmuch::Database<notmuch::Attached>,
   mailbox: &str,
   uidvalidity: u64,
@@ -109,145 +82,334 @@ fn search_uids<'a>(
   ))
 }
 
-fn remove_message(
+// The files are only unlinked by the caller (see sync::unlink), once everything else went through.
+pub fn remove_message(
   mailbox: &str,
   maildir: &maildir::Maildir,
   message: &mut notmuch::Message<'_>,
 ) -> anyhow::Result<Vec<path::PathBuf>> {
   log::debug!(
-    "removing message {} (uid:{})",
+    "removing message {} (uids:{:?})",
     message.message_id()?,
-    message.uid(mailbox)?
+    message.uids(mailbox)?
   );
-  let mut removals = Vec::new();
-  for path in message.paths()? {
-    if maildir.has(&path) {
-      // Removing from the file system is always okay:
-      //  - If it's a duplicate, the search query will still find a reference to it and clean up the
-      //    properties.
-      //  - If it's the last message under this message ID and the transaction is interrupted,
-      //    another 'notmuch new' will simply remove all leftovers (unless it's in tmp, in this case
-      //    it will be ignored and the search query will still find it).
-      match fs::remove_file(&path) {
-        Ok(_) => (),
-        // Might have been previously removed but interrupted.
-        Err(error) if error.kind() == io::ErrorKind::NotFound => (),
-        Err(error) => Err(error)?,
-      }
-      removals.push(path);
-    }
-  }
+  let removals = message
+    .paths()?
+    .into_iter()
+    .filter(|path| maildir.has(path))
+    .collect();
   message.remove_mailbox_properties(mailbox)?;
   Ok(removals)
 }
 
-pub fn run<O>(
-  open: &O,
-  credentials: &sync::Credentials,
-  stream: &mut imap::Stream<O::RW>,
-  database: &mut notmuch::Database<notmuch::Attached>,
-  maildir_builder: &maildir::Builder,
-  purgeable: &[String],
-  threads: num::NonZeroUsize,
-) -> anyhow::Result<()>
+// Like remove_message but the message may still be duplicated under other UIDs.
+pub fn remove_uids(
+  mailbox: &str,
+  maildir: &maildir::Maildir,
+  message: &mut notmuch::Message<'_>,
+  uids: &collections::HashSet<u64>,
+) -> anyhow::Result<Vec<path::PathBuf>> {
+  let mut remaining = 0;
+  for uid in message.uids(mailbox)? {
+    if uids.contains(&uid) {
+      log::debug!(
+        "removing duplicate {uid} of message {}",
+        message.message_id()?
+      );
+      remaining = message.remove_uid(mailbox, uid)?;
+    }
+  }
+  if remaining == 0 {
+    return remove_message(mailbox, maildir, message);
+  }
+  // Each UID was downloaded to its own file but nothing tells which one, so keep as many as there
+  // are remaining UIDs.
+  Ok(
+    message
+      .paths()?
+      .into_iter()
+      .filter(|path| maildir.has(path))
+      .skip(remaining)
+      .collect(),
+  )
+}
+
+// What's known locally about a mailbox, before pulling.
+struct Known {
+  validity: (u64 /* uidvalidity */, u64 /* highestmodseq */),
+  uidnext: u64,
+}
+
+// Download some new messages of a mailbox.
+struct Fetch {
+  mailbox: usize,
+  uidvalidity: u64,
+  highestmodseq: u64,
+  uidnext: u64, // Below it, nothing left in tmp is reused (see fetch_once).
+  changes: Vec<(u64 /* uid */, sync::Changes)>,
+}
+
+// What the workers report, the database updates are serialized to the main thread.
+enum Event {
+  Selected(usize, sync::Select),
+  Fetched(usize, u64 /* uid */, sync::Changes, path::PathBuf),
+}
+
+// Each worker has its own connection. The mailboxes are SELECTed in batches, the main thread then
+// dispatches the messages to download (possibly to another worker).
+struct Workers<'a, O> {
+  open: &'a O,
+  credentials: &'a credentials::Credentials,
+  id: &'a sync::Id,
+  mailboxes: &'a [&'a sync::Mailbox],
+  known: &'a [Known],
+  maildirs: &'a [maildir::Maildir],
+  root_namespace: &'a str,
+  threads: usize,
+  fetch_batch: usize,
+  pipeline_depth: usize,
+  keepalive: time::Duration,
+  selects: atomic::AtomicUsize,
+  fetches: std::sync::Mutex<mpsc::Receiver<Fetch>>,
+}
+
+impl<O> Workers<'_, O>
 where
   O: sync::Open,
 {
-  let mut removals = Vec::new();
-
-  let mailboxes: collections::HashMap<String, sync::Mailbox> = sync::list(stream)?
-    .into_iter()
-    .map(|m| (m.string.clone(), m))
-    .collect();
+  // Share the remaining mailboxes between the workers, in pipelined batches.
+  fn next_selects(&self) -> Option<ops::Range<usize>> {
+    let remaining = self
+      .mailboxes
+      .len()
+      .saturating_sub(self.selects.load(atomic::Ordering::Relaxed));
+    let batch = remaining
+      .div_ceil(self.threads)
+      .clamp(1, self.pipeline_depth);
+    let start = self.selects.fetch_add(batch, atomic::Ordering::Relaxed);
+    let end = cmp::min(start + batch, self.mailboxes.len());
+    (start < end).then_some(start..end)
+  }
 
-  for sync::Mailbox {
-    bytes: mailbox_bytes,
-    string: mailbox_string,
-    separator,
-  } in mailboxes.values()
-  {
-    log::info!("pulling from mailbox {mailbox_string}");
-    let maildir = maildir_builder.maildir(mailbox_string, separator)?;
-
-    let validity = database.root()?.validity(mailbox_string)?;
-
-    // https://www.rfc-editor.org/rfc/rfc7162#section-3.1.2.1
-    // A disconnected client can use the value of HIGHESTMODSEQ to check if it has to refetch
-    // metadata from the server. If the UIDVALIDITY value has changed for the selected mailbox,
-    // the client MUST delete the cached value of HIGHESTMODSEQ. If UIDVALIDITY for the mailbox is
-    // the same, and if the HIGHESTMODSEQ value stored in the client's cache is less than the
-    // value returned by the server, then some metadata items on the server have changed since the
-    // last synchronization, and the client needs to update its cache.
-    let sync::Select {
-      vanished,
-      mut changes,
-      uidvalidity,
-      highestmodseq,
-    } = reselect(stream, mailbox_bytes, validity.0, validity.1)?;
+  fn work(&self, events: &mpsc::Sender<anyhow::Result<Event>>) -> anyhow::Result<()> {
+    let mut stream = sync::connect(self.open, self.credentials, self.id)?;
+    loop {
+      // Downloads first, they're waited upon.
+      let fetch = self.fetches.lock().unwrap().try_recv();
+      if let Ok(fetch) = fetch {
+        self.fetch(&mut stream, fetch, events)?;
+        continue;
+      }
+      if let Some(mut indices) = self.next_selects() {
+        sync::retry(
+          self.open,
+          self.credentials,
+          self.id,
+          &mut stream,
+          |stream| self.select(stream, &mut indices, events),
+        )?;
+        continue;
+      }
+      let fetch = self.fetches.lock().unwrap().recv_timeout(self.keepalive);
+      match fetch {
+        Ok(fetch) => self.fetch(&mut stream, fetch, events)?,
+        // The main thread is busy (e.g.: indexing what the other workers downloaded).
+        Err(mpsc::RecvTimeoutError::Timeout) => stream.keepalive(self.keepalive)?,
+        Err(mpsc::RecvTimeoutError::Disconnected) => break Ok(()), // Everything has been pulled.
+      }
+    }
+  }
 
-    {
-      // Sanity checking, just in case. There's currently no good way for a user to get out of this
-      // predicament: there's no way to edit properties via the Notmuch CLI... Best course of action
-      // would be for the server to change the uidvalidity.
-      let separator_ = database.root()?.separator(mailbox_string)?;
-      anyhow::ensure!(
-        validity == (0, 0) || *separator == separator_,
-        "separator for {mailbox_string} has changed from {separator_:?} to {separator:?}, \
-         refusing to continue"
-      );
+  // The mailboxes are removed from the range once reported (see sync::retry).
+  fn select(
+    &self,
+    stream: &mut imap::Stream<O::RW>,
+    indices: &mut ops::Range<usize>,
+    events: &mpsc::Sender<anyhow::Result<Event>>,
+  ) -> anyhow::Result<()> {
+    let selects: Vec<_> = indices
+      .clone()
+      .map(|index| {
+        let (uidvalidity, highestmodseq) = self.known[index].validity;
+        (
+          self.mailboxes[index].bytes.as_slice(),
+          uidvalidity,
+          highestmodseq,
+        )
+      })
+      .collect();
+    for (index, select) in indices.clone().zip(sync::select_many(stream, &selects)?) {
+      let select = reselect(
+        stream,
+        &self.mailboxes[index].bytes,
+        &self.known[index],
+        select,
+      )?;
+      events.send(Ok(Event::Selected(index, select)))?;
+      indices.start = index + 1;
     }
+    Ok(())
+  }
 
-    // https://www.rfc-editor.org/rfc/rfc4549#section-2
-    // If the UIDVALIDITY value returned by the server differs, the client MUST empty the local
-    // cache of the mailbox and remove any pending "actions" that refer to UIDs in that mailbox
-    // (and consider them failed).
-    if uidvalidity != validity.0 {
-      // TODO? should we also do a threshold check on the number of vanished messages?
-      anyhow::ensure!(
-        validity == (0, 0) || purgeable.contains(mailbox_string),
-        "{mailbox_string}'s validity has changed on the server, allow to purge it locally (all \
-         messages will be removed) by passing --purgeable {mailbox_string}"
-      );
+  // Repeated on a new connection after a timeout, without the messages already reported.
+  fn fetch(
+    &self,
+    stream: &mut imap::Stream<O::RW>,
+    job: Fetch,
+    events: &mpsc::Sender<anyhow::Result<Event>>,
+  ) -> anyhow::Result<()> {
+    let mut fetched = collections::HashSet::new();
+    sync::retry(self.open, self.credentials, self.id, stream, |stream| {
+      self.fetch_once(stream, &job, &mut fetched, events)
+    })
+  }
 
-      log::debug!(
-        "purging messages (uidvalidity:({} -> {uidvalidity}))",
-        validity.0
-      );
-      let mut messages = search_not_uidvalidity(database, mailbox_string, uidvalidity)?;
-      while let Some(mut message) = messages.next() {
-        removals.append(&mut remove_message(mailbox_string, &maildir, &mut message)?);
+  fn fetch_once(
+    &self,
+    stream: &mut imap::Stream<O::RW>,
+    job: &Fetch,
+    fetched: &mut collections::HashSet<u64>,
+    events: &mpsc::Sender<anyhow::Result<Event>>,
+  ) -> anyhow::Result<()> {
+    let &Fetch {
+      mailbox,
+      uidvalidity,
+      highestmodseq,
+      uidnext,
+      ref changes,
+    } = job;
+    let (mailbox_string, maildir) = (&self.mailboxes[mailbox].string, &self.maildirs[mailbox]);
+    // The highestmodseq doesn't matter since we aren't interested in changes. Use the latest.
+    let selected = sync::ensure_selected(
+      stream,
+      &self.mailboxes[mailbox].bytes,
+      uidvalidity,
+      highestmodseq,
+    )?;
+    anyhow::ensure!(
+      selected.uidvalidity == uidvalidity,
+      // Better stop here and let the main thread deal with it properly.
+      "{mailbox_string}'s validity has changed on the server, rerun a pull"
+    );
+    // Something somewhat unique but not as much as recommended by the maildir 'standard' so we
+    // can resume after an interruption. It should never be relied on anywhere else (that's what
+    // properties are for): that would break FCC that we can not control.
+    let name = |uid| format!("{}_{uidvalidity}_{uid}", self.root_namespace);
+    let mut leftovers = None;
+    for batch in changes.chunks(self.fetch_batch) {
+      let mut batch: collections::HashMap<u64, sync::Changes> = batch
+        .iter()
+        .filter(|(uid, _)| !fetched.contains(uid))
+        .cloned()
+        .collect();
+      // The messages above the uidnext weren't on the server during the last pull, they may have
+      // been left in tmp by an interruption.
+      let mut new: Vec<u64> = batch
+        .keys()
+        .copied()
+        .filter(|uid| *uid >= uidnext)
+        .collect();
+      new.sort();
+      if !new.is_empty() {
+        // Listed once: the files of the whole mailbox may be waiting in tmp.
+        let leftovers = match &mut leftovers {
+          Some(leftovers) => leftovers,
+          None => leftovers.insert(maildir.tmp_with_digest()?),
+        };
+        // https://www.rfc-editor.org/rfc/rfc3501#section-6.4.5
+        // RFC822.SIZE The [RFC-2822] size of the message.
+        let mut sizes = Vec::new();
+        sync::fetch_many(
+          stream,
+          &new,
+          "RFC822.SIZE",
+          imap::parser::fetch_size_data,
+          |uid, size| {
+            sizes.push((uid, size));
+            Ok(())
+          },
+        )?;
+        for (uid, size) in sizes {
+          let files = leftovers.remove(&name(uid)).unwrap_or_default();
+          if let Some(path) = maildir.tmp_reusable(files, size)? {
+            log::debug!(
+              "reusing previously fetched message (uidvalidity:{uidvalidity} uid:{uid} \
+               path:{path:?})",
+            );
+            let changes = batch.remove(&uid).unwrap(); // From the batch.
+            events.send(Ok(Event::Fetched(mailbox, uid, changes, path)))?;
+            fetched.insert(uid);
+          }
+        }
+      }
+      if batch.is_empty() {
+        continue;
       }
+      let mut uids: Vec<u64> = batch.keys().copied().collect();
+      uids.sort();
+      // https://www.rfc-editor.org/rfc/rfc3501#section-6.4.5
+      // BODY.PEEK[<section>]<<partial>> An alternate form of BODY[<section>] that does not
+      // implicitly set the \Seen flag.
+      sync::fetch_many(
+        stream,
+        &uids,
+        "BODY.PEEK[]",
+        imap::parser::fetch_body_data,
+        |uid, body| {
+          let path = maildir
+            .tmp_named_with_digest(&name(uid), &body.context("BODY.PEEK[] returned NIL")?)?;
+          let changes = batch.remove(&uid).unwrap(); // From the batch.
+          events.send(Ok(Event::Fetched(mailbox, uid, changes, path)))?;
+          fetched.insert(uid);
+          Ok(())
+        },
+      )?;
     }
+    Ok(())
+  }
+}
 
-    // The updated messages already exist in the database, update them.
-    let mut messages = search_uids(
-      database,
-      mailbox_string,
-      uidvalidity,
-      &changes.keys().copied().collect(),
-    )?;
-    while let Some(mut message) = messages.next() {
-      let uid = message.uid(mailbox_string)?;
-      let modseq = message.modseq(mailbox_string)?;
-      let sync::Changes {
+// A SELECTed mailbox whose new messages are being downloaded.
+// Update the messages whose flags changed on the server. The changes left are for messages that
+// aren't in the database.
+pub fn update_messages(
+  database: &notmuch::Database<notmuch::Attached>,
+  mailbox: &str,
+  uidvalidity: u64,
+  changes: &mut collections::HashMap<u64, sync::Changes>,
+) -> anyhow::Result<()> {
+  let mut messages = search_uids(
+    database,
+    mailbox,
+    uidvalidity,
+    &changes.keys().copied().collect(),
+  )?;
+  while let Some(mut message) = messages.next() {
+    // The query guarantees at least one of them changed.
+    for uid in message.uids(mailbox)? {
+      let modseq = message.modseq(mailbox, uid)?;
+      // So the messages aren't added back by the caller.
+      let Some(sync::Changes {
         flags,
         modseq: modseq_,
-      } = changes
-        .remove(&uid) // So the messages aren't added back in the next step.
-        .unwrap(); // Guaranteed by the query.
-      if modseq == modseq_ {
+      }) = changes.remove(&uid)
+      else {
+        continue;
+      };
+      if modseq_ <= modseq {
         // The pull updates the modseq but can not update the highestmodseq due to possible race
-        // conditions. Skip to avoid changing the lastmod needlessly.
+        // conditions. Skip to avoid changing the lastmod needlessly. Older ones may come from the
+        // updates reported to a push before the changes it made (see push::apply_updates).
         continue;
       }
       log::debug!(
-        "updating message {} (uidvalidity:{uidvalidity} uid:{uid} modseq:({modseq} -> {modseq_}) \
-         flags:({:?} -> {flags:?}))",
+        "updating message {} (uidvalidity:{uidvalidity} uid:{uid} modseq:({modseq} -> \
+         {modseq_}) flags:({:?} -> {flags:?}))",
         message.message_id()?,
-        notmuch::tags_to_flags(&message.tags()?),
+        notmuch::tags_to_flags(&message.tags()?, true),
       );
       message.update_mailbox_properties(
-        mailbox_string,
+        mailbox,
         uidvalidity,
         uid,
         modseq_,
@@ -256,125 +418,439 @@ where
       // The message already exists, possibly moving to another directory is okay.
       message.tags_to_maildir_flags()?;
     }
+  }
+  Ok(())
+}
 
-    // The updated messages do not already exist in the database, add them.
-    let changes: Vec<(u64, sync::Changes)> = changes.into_iter().collect(); // Stable iteration order.
-    thread::scope(|scope| -> anyhow::Result<()> {
-      let root_namespace = database.root_namespace();
-      let (send, receive) = mpsc::channel();
-
-      // Spawning a bunch of threads for downloading messages is an easy way to greatly increase
-      // throughput.
-      for thread in 0..cmp::min(threads.get(), changes.len()) {
-        let (changes, maildir, send) = (&changes, &maildir, send.clone());
-        scope.spawn(move |_| -> anyhow::Result<()> {
-          // Reestablish a connection.
-          // Ideally, this should be done only once and not for each mailbox but I find Rayon's
-          // initialization of threads painful.
-          let mut stream = imap::Stream::new(open.open()?);
-          sync::greetings(&mut stream)?;
-          sync::authenticate(&mut stream, credentials)?;
-          sync::enable(&mut stream)?;
-          // The highestmodseq doesn't matter since we aren't interested in changes. Use the latest.
-          let select = sync::select(&mut stream, mailbox_bytes, uidvalidity, highestmodseq)?;
-          anyhow::ensure!(
-            select.uidvalidity == uidvalidity,
-            // Better stop here and let the above code deal with it properly.
-            "{mailbox_string}'s validity has changed on the server, rerun a pull"
-          );
+// The messages removed from the server exist in the database, remove them.
+pub fn remove_vanished(
+  database: &notmuch::Database<notmuch::Attached>,
+  mailbox: &str,
+  maildir: &maildir::Maildir,
+  uidvalidity: u64,
+  vanished: &collections::HashSet<u64>,
+) -> anyhow::Result<Vec<path::PathBuf>> {
+  let mut removals = Vec::new();
+  let mut messages = search_uids(
+    database,
+    mailbox,
+    uidvalidity,
+    &vanished.iter().copied().collect(),
+  )?;
+  while let Some(mut message) = messages.next() {
+    removals.append(&mut remove_uids(mailbox, maildir, &mut message, vanished)?);
+  }
+  Ok(removals)
+}
 
-          for (uid, changes) in changes.iter().skip(thread).step_by(threads.get()) {
-            // https://www.rfc-editor.org/rfc/rfc3501#section-6.4.5
-            // RFC822.SIZE The [RFC-2822] size of the message.
-            let size = fetch(
-              &mut stream,
-              *uid,
-              "RFC822.SIZE",
-              imap::parser::fetch_size_data,
-            )?;
-            // Something somewhat unique but not as much as recommended by the maildir 'standard' so
-            // we can resume after an interruption. It should never be relied on anywhere else
-            // (that's what properties are for): that would break FCC that we can not control.
-            let name = format!("{root_namespace}_{uidvalidity}_{uid}");
-            let path = match maildir.tmp_named_with_size(&name, size)? {
-              Some(path) => {
-                log::debug!(
-                  "reusing previously fetched message (uidvalidity:{uidvalidity} uid:{uid} \
-                   path:{path:?})",
-                );
-                path
-              }
-              None => {
-                // https://www.rfc-editor.org/rfc/rfc3501#section-6.4.5
-                // BODY.PEEK[<section>]<<partial>> An alternate form of BODY[<section>] that does
-                // not implicitly set the \Seen flag.
-                let body = fetch(
-                  &mut stream,
-                  *uid,
-                  "BODY.PEEK[]",
-                  imap::parser::fetch_body_data,
-                )?;
-                maildir.tmp_named(&name, &body.with_context(|| "BODY.PEEK[] returned NIL")?)?
-              }
-            };
-            send.send((*uid, changes.clone(), path))?;
-          }
-          Ok(())
-        });
-      }
+struct Pending {
+  select: sync::Select,
+  uidnext: u64, // What was known.
+  fetches: usize,
+}
 
-      // Database updates still need to be serialized to the main thread.
-      drop(send);
-      loop {
-        match receive.recv() {
-          Ok((uid, sync::Changes { flags, modseq }, path)) => {
-            let mut message = database.add(&path)?;
-            log::debug!(
-              "adding message {} (uidvalidity:{uidvalidity} uid:{uid} modseq:{modseq} \
-               flags:{flags:?})",
-              message.message_id()?
-            );
-            message.update_mailbox_properties(
-              mailbox_string,
-              uidvalidity,
-              uid,
-              modseq,
-              &notmuch::flags_to_tags(&flags.iter().map(String::as_str).collect()),
-            )?;
-            // Do not call tags_to_maildir_flags: this would move the message outside of tmp and it
-            // would later be picked by 'notmuch new' even if the transaction fails.
+// Remove the vanished messages and remember how far the mailbox has been pulled.
+fn finish(
+  database: &mut notmuch::Database<notmuch::Attached>,
+  mailbox: &sync::Mailbox,
+  maildir: &maildir::Maildir,
+  known: &Known,
+  pending: Pending,
+) -> anyhow::Result<Vec<path::PathBuf>> {
+  let Pending {
+    select:
+      sync::Select {
+        uidvalidity,
+        uidnext,
+        highestmodseq,
+        vanished,
+        existing,
+        ..
+      },
+    uidnext: known_uidnext,
+    ..
+  } = pending;
+  // Without a UIDNEXT, remembered as unknown.
+  let uidnext = uidnext.unwrap_or(0);
+  let mut removals = Vec::new();
+
+  // The removed messages exist in the database, remove them.
+  let vanished: collections::HashSet<u64> = match existing {
+    // Without QRESYNC, the ones the server doesn't have anymore.
+    Some(existing) => {
+      let mut vanished = collections::HashSet::new();
+      let mut messages = sync::search_mailbox(database, &mailbox.string)?;
+      while let Some(message) = messages.next() {
+        for uid in message.uids(&mailbox.string)? {
+          if !sync::contains(&existing, uid) {
+            vanished.insert(uid);
           }
-          Err(mpsc::RecvError) => break Ok(()), // No sender left.
         }
       }
-    })
-    // A thread has panicked, this is meant to be bubbled up.
-    .unwrap()?;
+      vanished
+    }
+    None => vanished
+      .iter()
+      .flat_map(|imap::Range(start, end)| *start..=*end)
+      .collect(),
+  };
+  removals.append(&mut remove_vanished(
+    database,
+    &mailbox.string,
+    maildir,
+    uidvalidity,
+    &vanished,
+  )?);
 
-    // The removed messages exist in the database, remove them.
-    let mut messages = search_uids(
-      database,
-      mailbox_string,
+  // Avoid spurious lastmod change.
+  if (known.validity, known_uidnext) != ((uidvalidity, highestmodseq), uidnext) {
+    database.root()?.update_mailbox_properties(
+      &mailbox.string,
+      mailbox.separator,
       uidvalidity,
-      &vanished
-        .iter()
-        .flat_map(|imap::Range(start, end)| (*start..=*end))
-        .collect(),
+      uidnext,
+      highestmodseq,
     )?;
-    while let Some(mut message) = messages.next() {
-      removals.append(&mut remove_message(mailbox_string, &maildir, &mut message)?);
+  }
+  // The reverse mapping, for the escaped names only: the others are the directories themselves.
+  let directory = maildir
+    .escaped()
+    .then(|| maildir.relative().to_string_lossy());
+  let mut root = database.root()?;
+  if root.maildir(&mailbox.string)? != directory.as_deref() {
+    root.update_maildir(&mailbox.string, directory.as_deref())?;
+  }
+  Ok(removals)
+}
+
+// The files of the synchronized messages.
+fn files(database: &notmuch::Database<notmuch::Attached>) -> anyhow::Result<usize> {
+  let mut messages = database.query(&format!(
+    "property:\"{}.marker={}\"",
+    notmuch::quote(database.namespace()),
+    notmuch::MESSAGE_MARKER,
+  ))?;
+  let mut count = 0;
+  while let Some(message) = messages.next() {
+    count += message.paths()?.len();
+  }
+  Ok(count)
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn run<O>(
+  open: &O,
+  credentials: &credentials::Credentials,
+  id: &sync::Id,
+  stream: &mut imap::Stream<O::RW>,
+  database: &mut notmuch::Database<notmuch::Attached>,
+  maildir_builder: &maildir::Builder,
+  scope: &sync::Scope,
+  priority: &[String],
+  purgeable: &[String],
+  purge_all_removed: bool,
+  max_purge: Option<crate::MaxPurge>,
+  max_messages: Option<crate::MaxMessages>,
+  new_tags: &[String],
+  threads: num::NonZeroUsize,
+  fetch_batch: num::NonZeroUsize,
+  pipeline_depth: num::NonZeroUsize,
+  keepalive: time::Duration,
+) -> anyhow::Result<()>
+where
+  O: sync::Open,
+{
+  let mut removals = Vec::new();
+  // What --max-purge compares the removals to, before anything changed.
+  let files = match max_purge {
+    Some(crate::MaxPurge::Percent(_)) => files(database)?,
+    _ => 0,
+  };
+  // The mailboxes that would be purged without --purgeable, and why.
+  let mut unconfirmed = Vec::new();
+
+  let mailboxes: collections::HashMap<String, sync::Mailbox> =
+    sync::retry(open, credentials, id, stream, |stream| {
+      sync::list(stream, scope)
+    })?
+    .into_iter()
+    .map(|m| (m.string.clone(), m))
+    .collect();
+
+  let ordered = sync::ordered(mailboxes.values(), priority);
+  let mut known = Vec::new();
+  let mut maildirs = Vec::new();
+  for mailbox in &ordered {
+    known.push(Known {
+      validity: database.root()?.validity(&mailbox.string)?,
+      uidnext: database.root()?.uidnext(&mailbox.string)?,
+    });
+    maildirs.push(maildir_builder.maildir(&mailbox.string, &mailbox.separator)?);
+  }
+  let root_namespace = database.root_namespace().to_string();
+  let threads = cmp::min(threads.get(), ordered.len());
+  let (fetches, fetches_receiver) = mpsc::channel();
+  let workers = Workers {
+    open,
+    credentials,
+    id,
+    mailboxes: &ordered,
+    known: &known,
+    maildirs: &maildirs,
+    root_namespace: &root_namespace,
+    threads,
+    fetch_batch: fetch_batch.get(),
+    pipeline_depth: pipeline_depth.get(),
+    keepalive,
+    selects: atomic::AtomicUsize::new(0),
+    fetches: std::sync::Mutex::new(fetches_receiver),
+  };
+
+  let (events_sender, events) = mpsc::channel();
+  thread::scope(|scope| -> anyhow::Result<()> {
+    // Dropped when returning, so the workers stop.
+    let (fetches, events) = (fetches, events);
+
+    // Spawning a bunch of threads, each with its own connection, is an easy way to greatly increase
+    // throughput.
+    for _ in 0..threads {
+      let (workers, events) = (&workers, events_sender.clone());
+      let faults = fault::current();
+      scope.spawn(move |_| {
+        fault::adopt(faults);
+        if let Err(error) = workers.work(&events) {
+          // The main thread may be gone already.
+          let _ = events.send(Err(error));
+        }
+        fault::adopt(None);
+      });
     }
+    drop(events_sender);
 
-    // Avoid spurious lastmod change.
-    if validity != (uidvalidity, highestmodseq) {
-      database.root()?.update_mailbox_properties(
-        mailbox_string,
-        *separator,
-        uidvalidity,
-        highestmodseq,
-      )?;
+    // Database updates still need to be serialized to the main thread.
+    let mut pending = collections::HashMap::new();
+    // What --max-messages still allows to download, for the whole pull.
+    let mut allowed = match max_messages {
+      Some(crate::MaxMessages::Total(count)) => count,
+      _ => usize::MAX,
+    };
+    let mut remaining = ordered.len();
+    while remaining > 0 {
+      // The main connection waits for the workers and the database.
+      stream.keepalive(keepalive)?;
+      let event = match events.recv_timeout(keepalive) {
+        Ok(event) => event?,
+        Err(mpsc::RecvTimeoutError::Timeout) => continue,
+        Err(mpsc::RecvTimeoutError::Disconnected) => {
+          anyhow::bail!("the workers stopped unexpectedly")
+        }
+      };
+      let index = match event {
+        Event::Selected(index, mut select) => {
+          let sync::Mailbox {
+            string: mailbox_string,
+            separator,
+            ..
+          } = ordered[index];
+          let (validity, maildir) = (known[index].validity, &maildirs[index]);
+          log::info!("pulling from mailbox {mailbox_string}");
+          status::mailbox(mailbox_string);
+
+          // https://www.rfc-editor.org/rfc/rfc7162#section-3.1.2.1
+          // A disconnected client can use the value of HIGHESTMODSEQ to check if it has to refetch
+          // metadata from the server. If the UIDVALIDITY value has changed for the selected
+          // mailbox, the client MUST delete the cached value of HIGHESTMODSEQ. If UIDVALIDITY for
+          // the mailbox is the same, and if the HIGHESTMODSEQ value stored in the client's cache is
+          // less than the value returned by the server, then some metadata items on the server
+          // have changed since the last synchronization, and the client needs to update its cache.
+          let uidvalidity = select.uidvalidity;
+
+          {
+            // Sanity checking, just in case. There's currently no good way for a user to get out
+            // of this predicament: there's no way to edit properties via the Notmuch CLI... Best
+            // course of action would be for the server to change the uidvalidity.
+            let separator_ = database.root()?.separator(mailbox_string)?;
+            anyhow::ensure!(
+              validity == (0, 0) || *separator == separator_,
+              "separator for {mailbox_string} has changed from {separator_:?} to {separator:?}, \
+               refusing to continue"
+            );
+          }
+
+          // https://www.rfc-editor.org/rfc/rfc4549#section-2
+          // If the UIDVALIDITY value returned by the server differs, the client MUST empty the
+          // local cache of the mailbox and remove any pending "actions" that refer to UIDs in that
+          // mailbox (and consider them failed).
+          if uidvalidity != validity.0 {
+            if validity != (0, 0) && !sync::purgeable(purgeable, mailbox_string, *separator) {
+              // Reported along with the others once every mailbox has been pulled.
+              unconfirmed.push((mailbox_string.clone(), "new validity"));
+              remaining -= 1;
+              continue;
+            }
+
+            log::debug!(
+              "purging messages (uidvalidity:({} -> {uidvalidity}))",
+              validity.0
+            );
+            let mut messages = search_not_uidvalidity(database, mailbox_string, uidvalidity)?;
+            while let Some(mut message) = messages.next() {
+              removals.append(&mut remove_message(mailbox_string, maildir, &mut message)?);
+            }
+          }
+
+          // The UIDs known locally now refer to other messages: they're all purged, the
+          // workers already selected the mailbox again without a highestmodseq (see reselect).
+          let reassigned =
+            uidvalidity == validity.0 && sync::reassigned(&select, known[index].uidnext);
+          if reassigned {
+            if !sync::purgeable(purgeable, mailbox_string, *separator) {
+              unconfirmed.push((mailbox_string.clone(), "UIDs reassigned"));
+              remaining -= 1;
+              continue;
+            }
+
+            log::warn!(
+              "purging messages (uidnext:({} -> {:?}))",
+              known[index].uidnext,
+              select.uidnext
+            );
+            let mut messages = search_not_uidvalidity(database, mailbox_string, 0)?;
+            while let Some(mut message) = messages.next() {
+              removals.append(&mut remove_message(mailbox_string, maildir, &mut message)?);
+            }
+          }
+          if select
+            .uidnext
+            .is_some_and(|uidnext| uidnext > sync::MAX_UID)
+          {
+            log::warn!(
+              "{mailbox_string} has no UID left to assign, the server will have to change its \
+               UIDVALIDITY"
+            );
+          }
+
+          // https://www.rfc-editor.org/rfc/rfc3501#section-2.3.1.1
+          // The next unique identifier value is the predicted value that will be assigned to a new
+          // message in the mailbox. [...] the next unique identifier value MUST NOT change unless
+          // new messages are added to the mailbox; and second, the next unique identifier value
+          // MUST change whenever new messages are added to the mailbox, even if those new messages
+          // are subsequently expunged.
+          //
+          // The stored uidnext is only committed along with the messages below it: a message left
+          // in tmp by an interrupted pull can only have a UID above it.
+          let known_uidnext = if uidvalidity == validity.0 && !reassigned {
+            known[index].uidnext
+          } else {
+            0
+          };
+          if select.uidnext == Some(known_uidnext) {
+            log::debug!("no new messages, only flag changes (uidnext:{known_uidnext})");
+          } else {
+            log::debug!(
+              "new messages (uidnext:({known_uidnext} -> {:?}))",
+              select.uidnext
+            );
+          }
+          // Without a UIDNEXT, a message left in tmp may have been downloaded under a UID the
+          // server has since reassigned: none is reused.
+          let reusable_from = match select.uidnext {
+            Some(_) => known_uidnext,
+            None => u64::MAX,
+          };
+
+          // The updated messages already exist in the database, update them.
+          let mut changes = mem::take(&mut select.changes);
+          update_messages(database, mailbox_string, uidvalidity, &mut changes)?;
+
+          // The updated messages do not already exist in the database, have the workers download
+          // them (a large mailbox is split between all of them).
+          let mut changes: Vec<(u64, sync::Changes)> = changes.into_iter().collect();
+          changes.sort_by_key(|(uid, _)| *uid); // Stable iteration order.
+
+          // Past --max-messages, the oldest messages are downloaded and the mailbox is only
+          // remembered as pulled up to the first one left: below its UID for the uidnext and below
+          // the lowest modseq of those left for the highestmodseq, so the server reports them
+          // again to the next pull.
+          let limit = match max_messages {
+            Some(crate::MaxMessages::PerMailbox(count)) => cmp::min(count, allowed),
+            _ => allowed,
+          };
+          let left = changes.split_off(cmp::min(limit, changes.len()));
+          allowed -= changes.len();
+          if let Some((uid, _)) = left.first() {
+            log::info!(
+              "{} new message(s) of {mailbox_string} left for the next pull (--max-messages)",
+              left.len()
+            );
+            select.uidnext = Some(*uid);
+            select.highestmodseq = left
+              .iter()
+              .map(|(_, sync::Changes { modseq, .. })| modseq.saturating_sub(1))
+              .min()
+              .unwrap() // Not empty.
+              .min(select.highestmodseq);
+          }
+          let chunk = cmp::max(1, changes.len().div_ceil(threads));
+          let mut count = 0;
+          for chunk in changes.chunks(chunk) {
+            fetches.send(Fetch {
+              mailbox: index,
+              uidvalidity,
+              highestmodseq: select.highestmodseq,
+              uidnext: reusable_from,
+              changes: chunk.to_vec(),
+            })?;
+            count += chunk.len();
+          }
+          status::expect(mailbox_string, count);
+          pending.insert(
+            index,
+            Pending {
+              select,
+              uidnext: known_uidnext,
+              fetches: count,
+            },
+          );
+          index
+        }
+        Event::Fetched(index, uid, sync::Changes { flags, modseq }, path) => {
+          let uidvalidity = pending[&index].select.uidvalidity;
+          let mut message = database.add(&path, new_tags)?;
+          log::debug!(
+            "adding message {} (uidvalidity:{uidvalidity} uid:{uid} modseq:{modseq} \
+             flags:{flags:?})",
+            message.message_id()?
+          );
+          message.update_mailbox_properties(
+            &ordered[index].string,
+            uidvalidity,
+            uid,
+            modseq,
+            &notmuch::flags_to_tags(&flags.iter().map(String::as_str).collect()),
+          )?;
+          // Do not call tags_to_maildir_flags: this would move the message outside of tmp and it
+          // would later be picked by 'notmuch new' even if the transaction fails.
+          pending.get_mut(&index).unwrap().fetches -= 1; // Guaranteed by Selected.
+          status::done(&ordered[index].string);
+          index
+        }
+      };
+      if pending[&index].fetches == 0 {
+        removals.append(&mut finish(
+          database,
+          ordered[index],
+          &maildirs[index],
+          &known[index],
+          pending.remove(&index).unwrap(),
+        )?);
+        remaining -= 1;
+      }
     }
-  }
+    Ok(())
+  })
+  // A thread has panicked, this is meant to be bubbled up.
+  .unwrap()?;
 
   let known_mailboxes: Vec<String> = database
     .root()?
@@ -382,32 +858,86 @@ where
     .into_iter()
     .map(String::from)
     .collect();
+  let mut removed = Vec::new();
   for known_mailbox in known_mailboxes {
     if !mailboxes.contains_key(&known_mailbox) {
-      anyhow::ensure!(
-        purgeable.contains(&known_mailbox),
-        "{known_mailbox} has been removed on the server, allow to purge it locally (all messages \
-         will be removed) by passing --purgeable {known_mailbox}"
-      );
       let separator = database.root()?.separator(&known_mailbox)?;
-      let maildir = maildir_builder.maildir(&known_mailbox, &separator)?;
-      log::debug!("purging messages (mailbox:{known_mailbox})");
-      {
-        let mut messages = search_not_uidvalidity(database, &known_mailbox, 0)?;
-        while let Some(mut message) = messages.next() {
-          removals.append(&mut remove_message(&known_mailbox, &maildir, &mut message)?);
-        }
+      if purge_all_removed || sync::purgeable(purgeable, &known_mailbox, separator) {
+        removed.push((known_mailbox, separator));
+      } else {
+        unconfirmed.push((known_mailbox, "removed"));
       }
-      maildir.remove()?;
-      database.root()?.remove_mailbox_properties(&known_mailbox)?;
     }
   }
+  if !unconfirmed.is_empty() {
+    unconfirmed.sort();
+    let list: Vec<String> = unconfirmed
+      .iter()
+      .map(|(mailbox, reason)| format!("{mailbox} ({reason})"))
+      .collect();
+    let arguments: Vec<String> = unconfirmed
+      .iter()
+      .map(|(mailbox, _)| format!("--purgeable {mailbox}"))
+      .collect();
+    anyhow::bail!(
+      "mailboxes changed on the server: {}; allow to purge them locally (all messages will be \
+       removed) by passing {} (or a pattern, or --purge-all-removed for the removed ones)",
+      list.join(", "),
+      arguments.join(" ")
+    );
+  }
+
+  let mut removed_maildirs = Vec::new();
+  for (known_mailbox, separator) in removed {
+    let maildir = maildir_builder.maildir(&known_mailbox, &separator)?;
+    log::debug!("purging messages (mailbox:{known_mailbox})");
+    {
+      let mut messages = search_not_uidvalidity(database, &known_mailbox, 0)?;
+      while let Some(mut message) = messages.next() {
+        removals.append(&mut remove_message(&known_mailbox, &maildir, &mut message)?);
+      }
+    }
+    database.root()?.remove_mailbox_properties(&known_mailbox)?;
+    removed_maildirs.push(maildir);
+  }
+
+  for relative in sync::orphans(maildir_builder, &maildirs)? {
+    log::warn!(
+      "{relative:?} doesn't belong to any mailbox on the server, push with --create-mailboxes to \
+       create it"
+    );
+  }
+
+  // A safety net against accidents on the server (e.g.: messages expunged by mistake, a bogus
+  // UIDVALIDITY change), checked before any file is unlinked.
+  removals.sort();
+  removals.dedup();
+  if let Some(max_purge) = max_purge {
+    let limit = match max_purge {
+      crate::MaxPurge::Count(count) => count,
+      crate::MaxPurge::Percent(percent) => files * percent / 100,
+    };
+    anyhow::ensure!(
+      removals.len() <= limit,
+      "the pull would remove {} local file(s), more than --max-purge {max_purge} allows, pass \
+       --ignore-max-purge if that's expected",
+      removals.len()
+    );
+  }
 
   // Perform the removals last so that a move from a mailbox to another (identified via the
   // Message ID) can be noticed by the database, preventing any local state loss.
   for path in removals {
+    sync::unlink(&path)?;
     database.remove(&path)?;
+    stream.keepalive(keepalive)?;
+  }
+  for maildir in removed_maildirs {
+    maildir.remove()?;
   }
+  // Reported to the keepalives, what they were about was pulled (a push would otherwise apply them
+  // again, see push::apply_updates).
+  stream.discard_updates()?;
 
   Ok(())
 }
@@ -1,134 +1,45 @@
-use crate::{imap, maildir, notmuch, sync};
+use crate::{imap, maildir, notmuch, status, sync};
 use anyhow::Context as _;
-use std::{collections, fs, path};
+use std::{collections, fs, io, path};
 
-struct Append {
-  uidvalidity: u64,
-  uid: u64,
-  highestmodseq: u64,
-}
-
-fn append<RW>(
-  stream: &mut imap::Stream<RW>,
-  mailbox: &[u8],
-  flags: &collections::HashSet<&str>,
-  buffer: &[u8],
-) -> anyhow::Result<Append>
-where
-  RW: imap::ReadWrite,
-{
-  // .intersperse() is nightly...
-  let mut flags_ = "".to_string();
-  for (i, flag) in flags.iter().enumerate() {
-    flags_ += flag;
-    if i + 1 < flags.len() {
-      flags_ += " ";
-    }
-  }
-  let command: &[&[u8]] = &[
-    b"append APPEND {",
-    &mailbox.len().to_string().into_bytes(),
-    b"+}\r\n",
-    mailbox,
-    b" (",
-    flags_.as_bytes(),
-    b") {",
-    &buffer.len().to_string().into_bytes(),
-    b"+}\r\n",
-  ];
-  stream.input(&[command, &[buffer, b"\r\n"]].concat(), command.len())?;
-  let mut highestmodseq = None;
-  let imap::Append { uidvalidity, uid } = loop {
-    match stream.expect(imap::parser::start)? {
-      b"*" => match stream.parse(imap::parser::append_data)? {
-        highestmodseq_ @ Some(_) => highestmodseq = highestmodseq_,
-        None => stream.expect(imap::parser::skip)?,
-      },
-      b"append" => break stream.expect(imap::parser::append)?,
-      tag => anyhow::bail!("unexpected tag {tag:?}"),
-    }
-  };
-  anyhow::ensure!(
-    highestmodseq.is_some(),
-    "HIGHESTMODSEQ is missing from APPEND"
-  );
-  // https://www.rfc-editor.org/rfc/rfc4551#section-3.6
-  // If the server doesn't support the persistent storage of mod-sequences for the mailbox [...],
-  // the server MUST return 0 as the value of HIGHESTMODSEQ status data item.
-  let highestmodseq = highestmodseq.unwrap();
-  anyhow::ensure!(highestmodseq > 0, "HIGHESTMODSEQ is not properly supported");
-  Ok(Append {
-    uidvalidity,
-    uid,
-    highestmodseq,
-  })
-}
-
-enum Diff {
-  Add,
-  Delete,
-}
-
-fn store<RW>(
+// Mailboxes in the personal namespace for the local maildirs that don't belong to any. They're only
+// selectable once pulled: their messages are pushed on the next run.
+fn create_orphans<RW>(
   stream: &mut imap::Stream<RW>,
-  uid: u64,
-  modseq: u64,
-  flags: &collections::HashSet<String>,
-  diff: Diff,
-) -> anyhow::Result<Option<imap::Store>>
+  maildir_builder: &maildir::Builder,
+  maildirs: &[maildir::Maildir],
+) -> anyhow::Result<()>
 where
   RW: imap::ReadWrite,
 {
-  // While it's not part of the RFC, specifying both +FLAGS.SILENT and -FLAGS.SILENT will result in
-  // Dovecot silently ignoring the last occurence.
-  let operator = match diff {
-    Diff::Add => b"+",
-    Diff::Delete => b"-",
-  };
-  // .intersperse() is nightly...
-  let mut flags_ = "".to_string();
-  for (i, flag) in flags.iter().enumerate() {
-    flags_ += flag;
-    if i + 1 < flags.len() {
-      flags_ += " ";
-    }
+  let orphans = sync::orphans(maildir_builder, maildirs)?;
+  if orphans.is_empty() {
+    return Ok(());
   }
-  let command: &[&[u8]] = &[
-    b"store UID STORE ",
-    &uid.to_string().into_bytes(),
-    b" (UNCHANGEDSINCE ",
-    &modseq.to_string().into_bytes(),
-    b") ",
-    operator,
-    b"FLAGS.SILENT (",
-    flags_.as_bytes(),
-    b")\r\n",
-  ];
-  stream.input(command, command.len())?;
-  let mut store = None;
-  match loop {
-    match stream.expect(imap::parser::start)? {
-      b"*" => match stream.parse(imap::parser::store_data)? {
-        store_ @ Some(_) => store = store_,
-        None => stream.expect(imap::parser::skip)?,
-      },
-      b"store" => break stream.expect(imap::parser::store)?,
-      tag => anyhow::bail!("unexpected tag {tag:?}"),
+  let sync::Namespace { prefix, separator } = sync::namespaces(stream)?.personal;
+  for relative in orphans {
+    let Some(mailbox) = maildir_builder.mailbox(&relative, separator.map(char::from)) else {
+      log::warn!("{relative:?} can't be mapped to a mailbox, not creating it");
+      continue;
+    };
+    // https://www.rfc-editor.org/rfc/rfc3501#section-5.1.3
+    // In modified UTF-7, printable US-ASCII characters, except for "&", represent themselves
+    if !mailbox.bytes().all(|c| (0x20..=0x7e).contains(&c)) {
+      log::warn!("{mailbox} isn't printable ASCII, not creating it");
+      continue;
     }
-  } {
-    Some(uids) => {
-      anyhow::ensure!(
-        uids.len() == 1 && uids[0].0 == uids[0].1 && uids[0].0 == uid,
-        "invalid UID from STORE"
-      );
-      Ok(None)
-    }
-    None => {
-      anyhow::ensure!(store.is_some(), "FETCH is missing from STORE");
-      let store = store.unwrap();
-      Ok(Some(store))
+    // The reverse of sync::list.
+    let mut bytes = prefix.clone();
+    if let Some(separator) = separator {
+      if !prefix.is_empty() && !prefix.ends_with(&[separator]) {
+        bytes.push(separator);
+      }
     }
+    bytes.extend(mailbox.replace('&', "&-").into_bytes());
+    log::info!("creating mailbox {mailbox} for {relative:?}, pull to synchronize it");
+    sync::create(stream, &bytes)?;
   }
+  Ok(())
 }
 
 struct Move {
@@ -198,28 +109,442 @@ where
   }
 }
 
+// Reflect a local move on the server, the cached tags follow the message.
+fn move_message<RW>(
+  stream: &mut imap::Stream<RW>,
+  message: &mut notmuch::Message,
+  from: &str,
+  to: &sync::Mailbox,
+) -> anyhow::Result<()>
+where
+  RW: imap::ReadWrite,
+{
+  log::debug!("moving message {} to {}", message.message_id()?, to.string);
+  let mut moved = Vec::new();
+  for uid in message.uids(from)? {
+    match r#move(stream, uid, &to.bytes)? {
+      Some(Move {
+        uidvalidity,
+        uid: uid_,
+      }) => {
+        crate::fault::interrupt(crate::fault::Interruption::SuccessfulMovePreCommit)?;
+        // https://www.rfc-editor.org/rfc/rfc6851#section-4.4
+        // When one or more messages are moved to a target mailbox, if the server is capable of
+        // storing modification sequences for the mailbox, the server MUST generate and assign new
+        // modification sequence numbers to the moved messages that are higher than the highest
+        // modification sequence of the messages originally in the mailbox.
+        //
+        // So we can reuse the current one and the pull bump it.
+        moved.push((uidvalidity, uid_, message.modseq(from, uid)?));
+      }
+      None => anyhow::bail!(
+        "message {} couldn't be moved to {}, assuming previously interrupted, rerun a pull",
+        message.message_id()?,
+        to.string
+      ),
+    }
+  }
+  let cached_tags: Vec<String> = message
+    .cached_tags(from)?
+    .into_iter()
+    .map(String::from)
+    .collect();
+  let cached_tags = cached_tags.iter().map(String::as_str).collect();
+  message.remove_mailbox_properties(from)?;
+  for (uidvalidity, uid, modseq) in moved {
+    message.update_mailbox_properties(&to.string, uidvalidity, uid, modseq, &cached_tags)?;
+  }
+  Ok(())
+}
+
+fn copy<RW>(stream: &mut imap::Stream<RW>, uid: u64, mailbox: &[u8]) -> anyhow::Result<Move>
+where
+  RW: imap::ReadWrite,
+{
+  let command: &[&[u8]] = &[
+    b"copy UID COPY ",
+    &uid.to_string().into_bytes(),
+    b" {",
+    &mailbox.len().to_string().into_bytes(),
+    b"+}\r\n",
+    mailbox,
+    b"\r\n",
+  ];
+  stream.input(command, command.len())?;
+  let copy = loop {
+    match stream.expect(imap::parser::start)? {
+      b"*" => stream.expect(imap::parser::skip)?,
+      b"copy" => break stream.expect(imap::parser::copy)?,
+      tag => anyhow::bail!("unexpected tag {tag:?}"),
+    }
+  };
+  match copy {
+    Some(imap::Move {
+      uidvalidity,
+      from,
+      to,
+    }) => {
+      anyhow::ensure!(
+        from.len() == 1
+          && to.len() == 1
+          && from[0].0 == from[0].1
+          && from[0].0 == uid
+          && to[0].0 == to[0].1,
+        "invalid UID from COPY"
+      );
+      Ok(Move {
+        uidvalidity,
+        uid: to[0].0,
+      })
+    }
+    // The copy would be downloaded again by the next pull.
+    None => anyhow::bail!("COPYUID is missing from COPY"),
+  }
+}
+
+// Reflect a local copy on the server (the message has a file in another maildir) instead of
+// uploading it again, the cached tags follow the message like for a move.
+fn copy_message<RW>(
+  stream: &mut imap::Stream<RW>,
+  message: &mut notmuch::Message,
+  from: &str,
+  to: &sync::Mailbox,
+) -> anyhow::Result<()>
+where
+  RW: imap::ReadWrite,
+{
+  log::debug!("copying message {} to {}", message.message_id()?, to.string);
+  // Duplicates share the same content, one is enough.
+  let uid = message.uids(from)?[0]; // Guaranteed by search_modified.
+  let Move {
+    uidvalidity,
+    uid: uid_,
+  } = copy(stream, uid, &to.bytes)?;
+  let cached_tags: Vec<String> = message
+    .cached_tags(from)?
+    .into_iter()
+    .map(String::from)
+    .collect();
+  let cached_tags = cached_tags.iter().map(String::as_str).collect();
+  // Like a move, the modseq is bumped by the next pull.
+  let modseq = message.modseq(from, uid)?;
+  message.update_mailbox_properties(&to.string, uidvalidity, uid_, modseq, &cached_tags)
+}
+
+// Like an APPEND from the mail client (e.g.: to the Sent mailbox) followed by a pull, without
+// downloading the message back: it's written to the maildir and recorded as already synchronized.
+pub fn append_message<RW>(
+  stream: &mut imap::Stream<RW>,
+  database: &mut notmuch::Database<notmuch::Attached>,
+  maildir_builder: &maildir::Builder,
+  scope: &sync::Scope,
+  mailbox: &str,
+  buffer: &[u8],
+  new_tags: &[String],
+) -> anyhow::Result<()>
+where
+  RW: imap::ReadWrite,
+{
+  anyhow::ensure!(!buffer.is_empty(), "the message to append is empty");
+  let mailbox = sync::list(stream, scope)?
+    .into_iter()
+    .find(|mailbox_| mailbox_.string == mailbox)
+    .with_context(|| format!("mailbox {mailbox} doesn't exist on the server"))?;
+  let validity = database.root()?.validity(&mailbox.string)?;
+  // Its UIDs wouldn't be known otherwise and the message would be downloaded again.
+  anyhow::ensure!(
+    validity.0 != 0,
+    "{} hasn't been pulled yet, pull first",
+    mailbox.string
+  );
+  let imap::Selected { uidvalidity, .. } =
+    sync::ensure_selected(stream, &mailbox.bytes, validity.0, validity.1)?;
+  anyhow::ensure!(
+    uidvalidity == validity.0,
+    "uidvalidity has changed ({} -> {uidvalidity}), rerun a pull",
+    validity.0
+  );
+  if let Some(limit) = stream.append_limit() {
+    anyhow::ensure!(
+      buffer.len() as u64 <= limit,
+      "the message is {} bytes, more than the server accepts (APPENDLIMIT={limit})",
+      buffer.len()
+    );
+  }
+
+  // The message was sent: it has been seen.
+  let flags = collections::HashSet::from(["\\Seen"]);
+  let sync::Append {
+    uidvalidity,
+    uid,
+    highestmodseq: modseq,
+  } = sync::append(stream, &mailbox.bytes, &flags, &[sync::Part::Text(buffer)])?;
+  crate::fault::interrupt(crate::fault::Interruption::AppendIsNotTransactional)?;
+
+  // Moved out of tmp once the transaction is over, like the pulled messages.
+  let maildir = maildir_builder.maildir(&mailbox.string, &mailbox.separator)?;
+  let path = maildir.tmp(buffer)?;
+  let mut message = database.add(&path, new_tags)?;
+  log::info!(
+    "appended message {} to {} (uidvalidity:{uidvalidity} uid:{uid})",
+    message.message_id()?,
+    mailbox.string
+  );
+  message.update_mailbox_properties(
+    &mailbox.string,
+    uidvalidity,
+    uid,
+    modseq,
+    &notmuch::flags_to_tags(&flags),
+  )
+}
+
+// The line endings may differ from the server's (e.g.: Dovecot stores LF but sends CRLF).
+fn lf(buffer: &[u8]) -> Vec<u8> {
+  let mut lf = Vec::with_capacity(buffer.len());
+  for (i, byte) in buffer.iter().enumerate() {
+    if *byte != b'\r' || buffer.get(i + 1) != Some(&b'\n') {
+      lf.push(*byte);
+    }
+  }
+  lf
+}
+
+// The header (with the empty line ending it) and the text of a message.
+fn split_message(buffer: &[u8]) -> (&[u8], &[u8]) {
+  for (i, _) in buffer
+    .iter()
+    .enumerate()
+    .filter(|(_, byte)| **byte == b'\n')
+  {
+    for separator in [&b"\n"[..], b"\r\n"] {
+      if buffer[i + 1..].starts_with(separator) {
+        return buffer.split_at(i + 1 + separator.len());
+      }
+    }
+  }
+  (buffer, &[])
+}
+
+// https://www.rfc-editor.org/rfc/rfc5092#section-3.3
+// The mailbox name is UTF-8 and %-escaped (the hierarchy separators are kept as is, like in the
+// examples).
+fn url_mailbox(mailbox: &str) -> String {
+  let mut url = String::with_capacity(mailbox.len());
+  for byte in mailbox.bytes() {
+    match byte {
+      b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+        url.push(byte as char)
+      }
+      _ => url += &format!("%{byte:02X}"),
+    }
+  }
+  url
+}
+
+// With CATENATE, the section of the new version that's identical in a previous one (usually, the
+// text when the recipients or the subject were edited, or the header when the text was) is
+// referenced instead of being uploaded again.
+fn draft_parts<'a>(
+  catenate: bool,
+  buffer: &'a [u8],
+  previous: &[(u64, Vec<u8>)],
+  url: impl Fn(u64) -> String,
+) -> Vec<sync::Part<'a>> {
+  if catenate {
+    let (header, text) = split_message(buffer);
+    let (lf_header, lf_text) = (lf(header), lf(text));
+    for (uid, body) in previous {
+      let (previous_header, previous_text) = split_message(body);
+      // The empty text of a message without one can't be referenced.
+      if !text.is_empty() && lf_text == previous_text {
+        return vec![
+          sync::Part::Text(header),
+          sync::Part::Url(format!("{};SECTION=TEXT", url(*uid))),
+        ];
+      }
+      if lf_header == previous_header {
+        return vec![
+          sync::Part::Url(format!("{};SECTION=HEADER", url(*uid))),
+          sync::Part::Text(text),
+        ];
+      }
+    }
+  }
+  vec![sync::Part::Text(buffer)]
+}
+
+// Editing a draft writes a new file for the same message: once its content has been appended, the
+// previous versions are expunged from the server and their files are removed (to be removed from
+// the database by the caller).
+fn replace_draft<RW>(
+  stream: &mut imap::Stream<RW>,
+  message: &mut notmuch::Message,
+  mailbox: &str,
+  mailbox_bytes: &[u8],
+  maildir: &maildir::Maildir,
+  mailbox_tag: Option<&crate::MailboxTag>,
+  keywords: bool,
+) -> anyhow::Result<Vec<path::PathBuf>>
+where
+  RW: imap::ReadWrite,
+{
+  let uids = message.uids(mailbox)?;
+  let mut bodies = Vec::new();
+  for &uid in &uids {
+    if let Some(body) = sync::fetch(stream, uid, "BODY.PEEK[]", imap::parser::fetch_body_data)? {
+      bodies.push((uid, lf(&body)));
+    }
+  }
+  // The files that aren't on the server are the new versions, the most recent one wins (the mail
+  // client may not have removed the others).
+  let (mut stale, mut versions) = (Vec::new(), Vec::new());
+  for path in message.paths()? {
+    if !maildir.has(&path) || !path.exists() {
+      continue;
+    }
+    let buffer = fs::read(&path)?;
+    let buffer_ = lf(&buffer);
+    match bodies.iter().any(|(_, body)| *body == buffer_) {
+      true => stale.push(path),
+      false => versions.push((fs::metadata(&path)?.modified()?, path, buffer)),
+    }
+  }
+  versions.sort_by_key(|(modified, _, _)| *modified);
+  let Some((_, path, buffer)) = versions.pop() else {
+    return Ok(Vec::new());
+  };
+  stale.extend(versions.into_iter().map(|(_, path, _)| path));
+  log::info!(
+    "replacing draft {} (uids:{uids:?}) with {path:?}",
+    message.message_id()?
+  );
+
+  let tags: Vec<String> = synchronized_tags(message, mailbox_tag)?;
+  let tags = tags.iter().map(String::as_str).collect();
+  let flags = notmuch::tags_to_flags(&tags, keywords);
+  let tags = cacheable(tags, &flags, keywords);
+  let previous_uidvalidity = message.uidvalidity(mailbox)?;
+  let parts = draft_parts(stream.catenate(), &buffer, &bodies, |uid| {
+    format!(
+      "/{};UIDVALIDITY={previous_uidvalidity}/;UID={uid}/",
+      url_mailbox(mailbox)
+    )
+  });
+  let sync::Append {
+    uidvalidity,
+    uid,
+    highestmodseq: modseq,
+  } = sync::append(stream, mailbox_bytes, &flags, &parts)?;
+  crate::fault::interrupt(crate::fault::Interruption::AppendIsNotTransactional)?;
+  message.update_mailbox_properties(mailbox, uidvalidity, uid, modseq, &tags)?;
+
+  // The new version is safe, the previous ones can go (if they changed on the server in the
+  // meantime, they're kept as duplicates).
+  let deleted = collections::HashSet::from(["\\Deleted".to_string()]);
+  let mut expunged = Vec::new();
+  for uid in uids {
+    match sync::store(
+      stream,
+      uid,
+      message.modseq(mailbox, uid)?,
+      &deleted,
+      sync::Diff::Add,
+    )? {
+      Some(_) => expunged.push(uid),
+      None => log::warn!(
+        "the previous version of draft {} (uid:{uid}) changed on the server, keeping it",
+        message.message_id()?
+      ),
+    }
+  }
+  if expunged.is_empty() {
+    return Ok(Vec::new());
+  }
+  sync::uid_expunge(stream, &expunged)?;
+  for uid in expunged {
+    message.remove_uid(mailbox, uid)?;
+  }
+  for path in &stale {
+    match fs::remove_file(path) {
+      Ok(_) => (),
+      Err(error) if error.kind() == io::ErrorKind::NotFound => (),
+      Err(error) => Err(error)?,
+    }
+  }
+  Ok(stale)
+}
+
+// The mailbox a message should be moved to: the first rule whose tag it has or, when it's in the
+// mailbox of a rule whose tag was removed since the last synchronization, INBOX.
+fn move_destination<'a>(
+  message: &notmuch::Message,
+  mailbox: &str,
+  rules: &'a [crate::Rule],
+) -> anyhow::Result<Option<&'a str>> {
+  let (tags, cached_tags) = (message.tags()?, message.cached_tags(mailbox)?);
+  let moves = || {
+    rules.iter().filter_map(|rule| match &rule.action {
+      crate::Action::Move(destination) => Some((rule.tag.as_str(), destination.as_str())),
+      crate::Action::Keyword(_) => None,
+    })
+  };
+  if let Some((_, destination)) = moves().find(|(tag, _)| tags.contains(tag)) {
+    return Ok((destination != mailbox).then_some(destination));
+  }
+  Ok(
+    moves()
+      .any(|(tag, destination)| destination == mailbox && cached_tags.contains(tag))
+      .then_some("INBOX"),
+  )
+}
+
+// The keywords are added as tags: they're then pushed (and cached) like any other.
+fn add_keywords(message: &mut notmuch::Message, rules: &[crate::Rule]) -> anyhow::Result<()> {
+  let keywords: Vec<&str> = {
+    let tags = message.tags()?;
+    rules
+      .iter()
+      .filter_map(|rule| match &rule.action {
+        crate::Action::Keyword(keyword)
+          if tags.contains(rule.tag.as_str()) && !tags.contains(keyword.as_str()) =>
+        {
+          Some(keyword.as_str())
+        }
+        _ => None,
+      })
+      .collect()
+  };
+  for keyword in keywords {
+    log::debug!(
+      "adding keyword {keyword} to message {}",
+      message.message_id()?
+    );
+    message.add_tag(keyword)?;
+  }
+  Ok(())
+}
+
+// https://notmuch.readthedocs.io/en/latest/man7/notmuch-search-terms.html
+// folder:<maildir-folder> or folder:/<regex>/ For maildir, this includes messages in the “new” and
+// “cur” subdirectories. The exact syntax for maildir folders depends on your mail configuration.
+// For maildir++, folder:"" matches the inbox folder (which is the root in maildir++), other folder
+// names always start with ".", and nested folders are separated by "."s, such as
+// folder:.classes.topology.
+//
+// With the fs layout, the folder is the path of the nested directories.
+fn folder(relative_maildir: &path::Path, maildir: &maildir::Maildir) -> anyhow::Result<String> {
+  let folder = relative_maildir.join(maildir.relative());
+  Ok(notmuch::quote(folder.to_str().with_context(|| {
+    format!("couldn't convert {folder:?} to string")
+  })?))
+}
+
 fn search_new<'a>(
   database: &'a notmuch::Database<notmuch::Attached>,
   relative_maildir: &path::Path,
   maildir: &maildir::Maildir,
 ) -> anyhow::Result<notmuch::Messages<'a>> {
-  // https://notmuch.readthedocs.io/en/latest/man7/notmuch-search-terms.html
-  // folder:<maildir-folder> or folder:/<regex>/ For maildir, this includes messages in the “new”
-  // and “cur” subdirectories. The exact syntax for maildir folders depends on your mail
-  // configuration. For maildir++, folder:"" matches the inbox folder (which is the root in
-  // maildir++), other folder names always start with ".", and nested folders are separated by "."s,
-  // such as folder:.classes.topology.
-  let folder = relative_maildir.join(if maildir.root() {
-    ""
-  } else {
-    let path = maildir.path();
-    let folder = path
-      .file_name()
-      .with_context(|| format!("couldn't get file name for {path:?}"))?;
-    folder
-      .to_str()
-      .with_context(|| format!("couldn't convert {folder:?} to string"))?
-  });
   database.query(&format!(
     "    not property:\"{}.marker={}\" \
      and not property:\"{}.marker={}\" \
@@ -228,14 +553,34 @@ fn search_new<'a>(
     notmuch::ROOT_MARKER,
     notmuch::quote(database.namespace()),
     notmuch::MESSAGE_MARKER,
-    notmuch::quote(
-      folder
-        .to_str()
-        .with_context(|| format!("couldn't convert {folder:?} to string"))?
-    )
+    folder(relative_maildir, maildir)?,
   ))
 }
 
+// Whether something changed locally since the last push: a message of the mailbox was modified or a
+// new one was added to its maildir (both bump the lastmod). The mailbox doesn't need to be selected
+// otherwise.
+fn modified_since(
+  database: &notmuch::Database<notmuch::Attached>,
+  relative_maildir: &path::Path,
+  maildir: &maildir::Maildir,
+  mailbox: &str,
+  lastmod: u64,
+) -> anyhow::Result<bool> {
+  let mut messages = database.query(&format!(
+    "    not property:\"{}.marker={}\" \
+     and lastmod:{lastmod}.. \
+     and (property:\"{}.mailbox={}\" or folder:\"{}\")",
+    notmuch::quote(database.root_namespace()),
+    notmuch::ROOT_MARKER,
+    notmuch::quote(database.namespace()),
+    notmuch::quote(mailbox),
+    folder(relative_maildir, maildir)?,
+  ))?;
+  let modified = messages.next().is_some();
+  Ok(modified)
+}
+
 fn search_modified<'a>(
   database: &'a notmuch::Database<notmuch::Attached>,
   mailbox: &str,
@@ -251,11 +596,316 @@ fn search_modified<'a>(
   ))
 }
 
+fn search_deleted<'a>(
+  database: &'a notmuch::Database<notmuch::Attached>,
+  mailbox: &str,
+) -> anyhow::Result<notmuch::Messages<'a>> {
+  let namespace = notmuch::quote(database.namespace());
+  let mailbox = notmuch::quote(mailbox);
+  database.query(&format!(
+    "    property:\"{namespace}.marker={}\" \
+     and property:\"{namespace}.mailbox={mailbox}\" \
+     and tag:deleted",
+    notmuch::MESSAGE_MARKER,
+  ))
+}
+
+// Following Notmuch's convention, the messages tagged deleted are removed from the server (freeing
+// its storage) then locally. Returns the files to remove from the database.
+fn expunge_deleted<RW>(
+  stream: &mut imap::Stream<RW>,
+  database: &notmuch::Database<notmuch::Attached>,
+  mailbox: &str,
+  maildir: &maildir::Maildir,
+) -> anyhow::Result<Vec<path::PathBuf>>
+where
+  RW: imap::ReadWrite,
+{
+  let deleted = collections::HashSet::from(["\\Deleted".to_string()]);
+  let mut uids = Vec::new();
+  let mut messages = search_deleted(database, mailbox)?;
+  while let Some(message) = messages.next() {
+    for uid in message.uids(mailbox)? {
+      anyhow::ensure!(
+        sync::store(
+          stream,
+          uid,
+          message.modseq(mailbox, uid)?,
+          &deleted,
+          sync::Diff::Add
+        )?
+        .is_some(),
+        "message {} in {mailbox} couldn't be flagged as deleted, rerun a pull",
+        message.message_id()?,
+      );
+      uids.push(uid);
+    }
+  }
+  drop(messages);
+  if uids.is_empty() {
+    return Ok(Vec::new());
+  }
+  log::info!("expunging {} message(s) from {mailbox}", uids.len());
+  sync::uid_expunge(stream, &uids)?;
+
+  let mut removals = Vec::new();
+  let mut messages = search_deleted(database, mailbox)?;
+  while let Some(mut message) = messages.next() {
+    removals.append(&mut sync::pull::remove_message(
+      mailbox,
+      maildir,
+      &mut message,
+    )?);
+  }
+  Ok(removals)
+}
+
+// The mailbox tags are derived from the server's state, they aren't keywords.
+fn synchronized_tags(
+  message: &notmuch::Message,
+  mailbox_tag: Option<&crate::MailboxTag>,
+) -> anyhow::Result<Vec<String>> {
+  Ok(
+    message
+      .tags()?
+      .into_iter()
+      .filter(|tag| !mailbox_tag.is_some_and(|mailbox_tag| mailbox_tag.matches(tag)))
+      .map(String::from)
+      .collect(),
+  )
+}
+
+// The tags cached for a mailbox are the ones the server knows about. When it doesn't allow keywords,
+// the others stay local (a pull would remove them otherwise).
+fn cacheable<'a>(
+  tags: collections::HashSet<&'a str>,
+  flags: &collections::HashSet<&'a str>,
+  keywords: bool,
+) -> collections::HashSet<&'a str> {
+  match keywords {
+    true => tags,
+    false => notmuch::flags_to_tags(flags),
+  }
+}
+
+// The tags to cache, the flags and the cached flags of a message.
+fn changes(
+  message: &notmuch::Message,
+  mailbox: &str,
+  mailbox_tag: Option<&crate::MailboxTag>,
+  keywords: bool,
+) -> anyhow::Result<(
+  Vec<String>,
+  collections::HashSet<String>,
+  collections::HashSet<String>,
+)> {
+  let tags: Vec<String> = synchronized_tags(message, mailbox_tag)?;
+  let tags = tags.iter().map(String::as_str).collect();
+  let flags = notmuch::tags_to_flags(&tags, keywords);
+  let cached_flags = notmuch::tags_to_flags(&message.cached_tags(mailbox)?, keywords);
+  Ok((
+    cacheable(tags, &flags, keywords)
+      .into_iter()
+      .map(String::from)
+      .collect(),
+    flags.into_iter().map(String::from).collect(),
+    cached_flags.into_iter().map(String::from).collect(),
+  ))
+}
+
+// The same flags added to or removed from many messages (e.g.: a thread marked as read) are stored
+// with a single command. The messages changed on the server since (or with a more involved update)
+// are left to the loop of the push.
+fn store_batches<RW>(
+  stream: &mut imap::Stream<RW>,
+  database: &notmuch::Database<notmuch::Attached>,
+  mailbox: &str,
+  lastmod: u64,
+  mailbox_tag: Option<&crate::MailboxTag>,
+  keywords: bool,
+  rules: &[crate::Rule],
+) -> anyhow::Result<()>
+where
+  RW: imap::ReadWrite,
+{
+  let mut batches: collections::BTreeMap<_, Vec<(u64, u64)>> = collections::BTreeMap::new();
+  let mut messages = search_modified(database, mailbox, lastmod)?;
+  while let Some(mut message) = messages.next() {
+    add_keywords(&mut message, rules)?;
+    let uids = message.uids(mailbox)?;
+    // A move relies on the cached tags and duplicates share them: leave them to the loop.
+    if move_destination(&message, mailbox, rules)?.is_some() || uids.len() != 1 {
+      continue;
+    }
+    let (_, flags, cached_flags) = changes(&message, mailbox, mailbox_tag, keywords)?;
+    let (added, removed): (Vec<String>, Vec<String>) = (
+      flags.difference(&cached_flags).cloned().collect(),
+      cached_flags.difference(&flags).cloned().collect(),
+    );
+    let (diff, mut flags) = match (added.is_empty(), removed.is_empty()) {
+      (false, true) => (sync::Diff::Add, added),
+      (true, false) => (sync::Diff::Delete, removed),
+      _ => continue, // Nothing or two commands to send.
+    };
+    flags.sort();
+    batches
+      .entry((diff, flags))
+      .or_default()
+      .push((uids[0], message.modseq(mailbox, uids[0])?));
+  }
+
+  let mut stored = collections::HashMap::new();
+  for ((diff, flags), mut uids) in batches {
+    if uids.len() < 2 {
+      continue;
+    }
+    uids.sort();
+    let flags: collections::HashSet<String> = flags.into_iter().collect();
+    let arguments = flags.iter().map(|flag| flag.len() + 1).sum::<usize>();
+    for chunk in uids.chunks(sync::max_uids(64 + arguments)) {
+      // The messages changed on the server since the oldest modseq are reported as modified, even
+      // if their own modseq was more recent: they'll be stored again individually.
+      let modseq = chunk.iter().map(|(_, modseq)| *modseq).min().unwrap(); // Not empty.
+      let uids: Vec<u64> = chunk.iter().map(|(uid, _)| *uid).collect();
+      log::debug!(
+        "storing flags {flags:?} ({}) on {} message(s)",
+        match diff {
+          sync::Diff::Add => "added",
+          sync::Diff::Delete => "removed",
+        },
+        uids.len()
+      );
+      stored.extend(sync::store_many(stream, &uids, modseq, &flags, diff)?);
+    }
+  }
+  if stored.is_empty() {
+    return Ok(());
+  }
+
+  let mut messages = search_modified(database, mailbox, lastmod)?;
+  while let Some(mut message) = messages.next() {
+    for uid in message.uids(mailbox)? {
+      if let Some(modseq) = stored.get(&uid) {
+        let (tags, _, _) = changes(&message, mailbox, mailbox_tag, keywords)?;
+        let tags = tags.iter().map(String::as_str).collect();
+        let uidvalidity = message.uidvalidity(mailbox)?;
+        message.update_mailbox_properties(mailbox, uidvalidity, uid, *modseq, &tags)?;
+      }
+    }
+  }
+  Ok(())
+}
+
+// The changes made by other clients while the mailbox was selected are applied like a pull would
+// (which will still go through them, the highestmodseq isn't updated). Whether there were any is
+// returned along the removals.
+fn apply_updates<RW>(
+  stream: &mut imap::Stream<RW>,
+  database: &notmuch::Database<notmuch::Attached>,
+  mailbox: &str,
+  mailbox_bytes: &[u8],
+  uidvalidity: u64,
+  maildir: &maildir::Maildir,
+) -> anyhow::Result<(Vec<path::PathBuf>, bool)>
+where
+  RW: imap::ReadWrite,
+{
+  let (mut changes, mut vanished, mut expunged) =
+    (collections::HashMap::new(), collections::HashSet::new(), 0);
+  let updates = stream.updates(mailbox_bytes)?;
+  let updated = !updates.is_empty();
+  for update in updates {
+    match update {
+      imap::Update::Fetch { uid, flags, modseq } => {
+        changes.insert(uid, sync::Changes { flags, modseq });
+      }
+      imap::Update::Vanished(uids) => {
+        vanished.extend(
+          uids
+            .iter()
+            .flat_map(|imap::Range(start, end)| *start..=*end),
+        );
+      }
+      imap::Update::Expunge(_) => expunged += 1,
+    }
+  }
+  if expunged > 0 {
+    // Without QRESYNC, there's no telling which UIDs these sequence numbers were.
+    log::info!(
+      "{expunged} message(s) expunged from {mailbox} by another client, pull to remove them"
+    );
+  }
+  changes.retain(|uid, _| !vanished.contains(uid));
+  sync::pull::update_messages(database, mailbox, uidvalidity, &mut changes)?;
+  if !changes.is_empty() {
+    log::info!(
+      "{} new message(s) in {mailbox}, pull to download them",
+      changes.len()
+    );
+  }
+  let removals = sync::pull::remove_vanished(database, mailbox, maildir, uidvalidity, &vanished)?;
+  Ok((removals, updated))
+}
+
+// The push deliberately ignores the highestmodseq (see run) so the next pull goes through the
+// messages it changed again. That's unnecessary when they're the only changes since the last pull:
+// the mailbox didn't change until it was selected and the server reports the modseqs the push
+// recorded. The highestmodseq to advance to, if so.
+fn own_changes<RW>(
+  stream: &mut imap::Stream<RW>,
+  database: &notmuch::Database<notmuch::Attached>,
+  mailbox: &str,
+  uidvalidity: u64,
+  highestmodseq: u64,
+  selected: u64,
+) -> anyhow::Result<Option<u64>>
+where
+  RW: imap::ReadWrite,
+{
+  if selected != highestmodseq {
+    return Ok(None);
+  }
+  let Some(mut changes) = sync::changed_since(stream, highestmodseq)? else {
+    return Ok(None);
+  };
+  let Some(highest) = changes.values().max().copied() else {
+    return Ok(None); // Nothing changed at all.
+  };
+  let mut messages = sync::pull::search_uids(
+    database,
+    mailbox,
+    uidvalidity,
+    &changes.keys().copied().collect(),
+  )?;
+  while let Some(message) = messages.next() {
+    for uid in message.uids(mailbox)? {
+      if changes.get(&uid) == Some(&message.modseq(mailbox, uid)?) {
+        changes.remove(&uid);
+      }
+    }
+  }
+  if !changes.is_empty() {
+    log::debug!(
+      "{} message(s) changed by another client, not advancing the highestmodseq",
+      changes.len()
+    );
+    return Ok(None);
+  }
+  Ok(Some(highest))
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn run<RW>(
   stream: &mut imap::Stream<RW>,
   database: &mut notmuch::Database<notmuch::Attached>,
   relative_maildir: &path::Path,
   maildir_builder: &maildir::Builder,
+  scope: &sync::Scope,
+  mailbox_tag: Option<&crate::MailboxTag>,
+  create_mailboxes: bool,
+  flag_merge: crate::FlagMerge,
+  expunge: bool,
+  rules: &[crate::Rule],
 ) -> anyhow::Result<()>
 where
   RW: imap::ReadWrite,
@@ -272,28 +922,66 @@ where
   //
   // I don't believe we need to handle this in our case: the highestmodseq is completely ignored as
   // part of the push and will be retrieved as part of the pull (at the cost of some wasted effort).
+  // Unless the push's changes are the only ones, see own_changes.
 
   let lastmod = database.root()?.lastmod()?;
+  // The lastmod is stored along with the last push's changes: nothing happened since if it's still
+  // the database's. The server doesn't even need to be asked for its mailboxes (unless some might
+  // have to be created or messages deleted before the last push expunged).
+  if database.lastmod() <= lastmod && !create_mailboxes && !expunge {
+    log::info!("nothing changed locally since the last push (lastmod:{lastmod})");
+    return Ok(());
+  }
+  let mut removals = Vec::new();
 
   let mut mailboxes = collections::HashMap::new();
-  for mailbox in sync::list(stream)? {
+  let mut maildirs = Vec::new();
+  for mailbox in sync::list(stream, scope)? {
     let maildir = maildir_builder.maildir(&mailbox.string, &mailbox.separator)?;
     mailboxes.insert(maildir.path().to_path_buf(), mailbox);
+    maildirs.push(maildir);
+  }
+  if create_mailboxes {
+    create_orphans(stream, maildir_builder, &maildirs)?;
   }
 
   for sync::Mailbox {
     bytes: mailbox_bytes,
     string: mailbox_string,
     separator,
+    drafts,
+    ..
   } in mailboxes.values()
   {
-    log::info!("pushing to mailbox {mailbox_string}");
     let maildir = maildir_builder.maildir(mailbox_string, separator)?;
+    if !modified_since(
+      database,
+      relative_maildir,
+      &maildir,
+      mailbox_string,
+      lastmod,
+    )? && (!expunge || search_deleted(database, mailbox_string)?.next().is_none())
+    {
+      log::debug!("nothing changed locally in mailbox {mailbox_string}");
+      continue;
+    }
+    log::info!("pushing to mailbox {mailbox_string}");
+    status::mailbox(mailbox_string);
 
     let validity = database.root()?.validity(mailbox_string)?;
 
-    let sync::Select { uidvalidity, .. } =
-      sync::select(stream, mailbox_bytes, validity.0, validity.1)?;
+    let imap::Selected {
+      uidvalidity,
+      highestmodseq: selected_highestmodseq,
+      keywords,
+      ..
+    } = sync::ensure_selected(stream, mailbox_bytes, validity.0, validity.1)?;
+    if !keywords {
+      log::warn!(
+        "{mailbox_string} doesn't allow keywords (PERMANENTFLAGS \\* is missing), only \
+         synchronizing the system flags"
+      );
+    }
 
     // If the mailbox has changed, the best course of action is to pull (clearing the local cache).
     anyhow::ensure!(
@@ -303,11 +991,19 @@ where
     );
 
     // New messages exist in the database, synchronize them to the server and initialize them.
-    let mut messages = search_new(database, relative_maildir, &maildir)?;
+    let mut messages = match maildir_builder.local_only(maildir.relative()) {
+      true => {
+        log::debug!("not uploading the new messages of {:?}", maildir.path());
+        notmuch::Messages::none()
+      }
+      false => search_new(database, relative_maildir, &maildir)?,
+    };
     while let Some(mut message) = messages.next() {
-      let tags: Vec<String> = message.tags()?.into_iter().map(String::from).collect();
+      status::done(mailbox_string);
+      let tags: Vec<String> = synchronized_tags(&message, mailbox_tag)?;
       let tags = tags.iter().map(String::as_str).collect();
-      let flags = notmuch::tags_to_flags(&tags);
+      let flags = notmuch::tags_to_flags(&tags, keywords);
+      let tags = cacheable(tags, &flags, keywords);
       log::debug!(
         "uploading message {} (flags:{flags:?})",
         message.message_id()?
@@ -317,61 +1013,167 @@ where
         // they're the same.
         message.paths()?.first().unwrap(), // Guaranteed by Notmuch.
       )?;
-      let Append {
+      // Better skip it here than have the server drop the connection in the middle of the upload.
+      // It stays new locally and is tried again by the next push.
+      if let Some(limit) = stream.append_limit() {
+        if buffer.len() as u64 > limit {
+          log::warn!(
+            "not uploading message {}: it is {} bytes, more than the server accepts \
+             (APPENDLIMIT={limit})",
+            message.message_id()?,
+            buffer.len()
+          );
+          continue;
+        }
+      }
+      let sync::Append {
         uidvalidity,
         uid,
         // Highestmodseq is only used as modseq for this message.
         // Because push and pull are separate operations, it's likely we could miss some changes
         // that haven't been pulled yet if we were to store that into the root.
         highestmodseq: modseq,
-      } = append(stream, mailbox_bytes, &flags, &buffer)?;
+      } = sync::append(stream, mailbox_bytes, &flags, &[sync::Part::Text(&buffer)])?;
       // If interrupted here, we can not know if the append was successful or not. Rerunning the
       // push will result in duplicated emails. The number of duplicated emails can be made smaller
       // by going for smaller transactions. However, the best way to solve this is to always run a
       // pull beforehand, see tests.
       // TODO? when pushing we could generate a lockfile that won't be cleaned up to force users to
       // repull.
-      crate::interrupt(crate::Interruption::AppendIsNotTransactional)?;
+      crate::fault::interrupt(crate::fault::Interruption::AppendIsNotTransactional)?;
       message.update_mailbox_properties(mailbox_string, uidvalidity, uid, modseq, &tags)?;
     }
 
     // Messages were modified locally (the above also counts as a modification so some server
     // operations might be superfluous).
+    if !*drafts {
+      store_batches(
+        stream,
+        database,
+        mailbox_string,
+        lastmod,
+        mailbox_tag,
+        keywords,
+        rules,
+      )?;
+    }
     let mut messages = search_modified(database, mailbox_string, lastmod)?;
     while let Some(mut message) = messages.next() {
+      status::done(mailbox_string);
+      if *drafts {
+        removals.append(&mut replace_draft(
+          stream,
+          &mut message,
+          mailbox_string,
+          mailbox_bytes,
+          &maildir,
+          mailbox_tag,
+          keywords,
+        )?);
+      }
+      add_keywords(&mut message, rules)?;
+      // Before the cached tags are updated below.
+      let destination = move_destination(&message, mailbox_string, rules)?;
+
       // Message tags might have changed, synchronize them to the server.
-      let tags: Vec<String> = message.tags()?.into_iter().map(String::from).collect();
-      let tags = tags.iter().map(String::as_str).collect();
-      let flags = notmuch::tags_to_flags(&tags);
-      let cached_flags: Vec<String> = notmuch::tags_to_flags(&message.cached_tags(mailbox_string)?)
-        .into_iter()
-        .map(String::from)
-        .collect();
-      let cached_flags: collections::HashSet<&str> =
-        cached_flags.iter().map(String::as_str).collect();
+      let (mut tags, mut flags, mut cached_flags) =
+        changes(&message, mailbox_string, mailbox_tag, keywords)?;
       log::debug!(
         "updating message {} (flags:({cached_flags:?} -> {flags:?}))",
         message.message_id()?
       );
-      let uid = message.uid(mailbox_string)?;
-      for (mode, flags) in [
-        (Diff::Delete, cached_flags.difference(&flags)),
-        (Diff::Add, flags.difference(&cached_flags)),
-      ] {
-        let flags: collections::HashSet<_> = flags.map(|f| f.to_string()).collect();
-        if !flags.is_empty() {
-          match store(stream, uid, message.modseq(mailbox_string)?, &flags, mode)? {
-            Some(imap::Store {
-              modseq, ..
-            }) => message.update_mailbox_properties(mailbox_string, uidvalidity, uid, modseq, &tags)?,
-            None => anyhow::bail!(
-              "message {} in {mailbox_string} couldn't be updated with flags {flags:?}, rerun a pull",
-              message.message_id()?,
-            ),
+      // Duplicates share the same tags, they all get the same flags.
+      for uid in message.uids(mailbox_string)? {
+        let mut merged = false;
+        'merge: loop {
+          // The cached tags are only updated once both operations succeeded, they're the base of
+          // the merge otherwise.
+          let mut stored = None;
+          for mode in [sync::Diff::Delete, sync::Diff::Add] {
+            let flags_: collections::HashSet<String> = match mode {
+              sync::Diff::Delete => cached_flags.difference(&flags),
+              sync::Diff::Add => flags.difference(&cached_flags),
+            }
+            .cloned()
+            .collect();
+            if flags_.is_empty() {
+              continue;
+            }
+            let modseq = match stored {
+              Some(modseq) => modseq,
+              None => message.modseq(mailbox_string, uid)?,
+            };
+            match sync::store(stream, uid, modseq, &flags_, mode)? {
+              Some(imap::Store { modseq, .. }) => stored = Some(modseq),
+              // The cached tags are the common base of the local tags and the server's flags: once
+              // the server's changes are applied locally (like a pull would), the local ones can
+              // be stored again. A flag changed on both sides was changed the same way.
+              None if flag_merge == crate::FlagMerge::ThreeWay && !merged => {
+                let (server_flags, modseq) =
+                  sync::fetch(stream, uid, "FLAGS MODSEQ", imap::parser::fetch_flags_data)?;
+                let server_flags: Vec<String> = server_flags
+                  .iter()
+                  .map(|flag| String::from_utf8_lossy(flag).into_owned())
+                  .collect();
+                log::info!(
+                  "merging the flags of message {} (uid:{uid}), changed on the server \
+                   ({server_flags:?})",
+                  message.message_id()?,
+                );
+                message.update_mailbox_properties(
+                  mailbox_string,
+                  uidvalidity,
+                  uid,
+                  modseq,
+                  &notmuch::flags_to_tags(&server_flags.iter().map(String::as_str).collect()),
+                )?;
+                (tags, flags, cached_flags) =
+                  changes(&message, mailbox_string, mailbox_tag, keywords)?;
+                merged = true;
+                continue 'merge;
+              }
+              None => anyhow::bail!(
+                "message {} in {mailbox_string} couldn't be updated with flags {flags_:?}, rerun \
+                 a pull",
+                message.message_id()?,
+              ),
+            }
+          }
+          if let Some(modseq) = stored {
+            let tags = tags.iter().map(String::as_str).collect();
+            message.update_mailbox_properties(mailbox_string, uidvalidity, uid, modseq, &tags)?;
+          }
+          break;
+        }
+      }
+      crate::fault::interrupt(crate::fault::Interruption::StoredFlags)?;
+
+      // Or a rule might move it, the local file follows (or the next push would move it back).
+      if let Some(destination) = destination {
+        match mailboxes
+          .values()
+          .find(|mailbox| mailbox.string == destination)
+        {
+          None => log::warn!(
+            "message {} can't be moved to {destination}, the mailbox doesn't exist on the server",
+            message.message_id()?
+          ),
+          // A duplicate, moving it would only remove the message from this mailbox.
+          Some(_) if message.mailboxes()?.contains(destination) => (),
+          Some(to) => {
+            move_message(stream, &mut message, mailbox_string, to)?;
+            let to_maildir = maildir_builder.maildir(&to.string, &to.separator)?;
+            for path in message.paths()? {
+              if maildir.has(&path) {
+                let adopted = to_maildir.adopt(&path)?;
+                database.add(&adopted, &[])?;
+                database.remove(&path)?;
+              }
+            }
+            continue;
           }
         }
       }
-      crate::interrupt(crate::Interruption::StoredFlags)?;
 
       // Or a message might have moved, reflect the change on the server.
       let mut found = false;
@@ -385,53 +1187,85 @@ where
         }
       }
       let mut cached_mailboxes = message.mailboxes()?;
-      if !found && cached_mailboxes.remove(mailbox_string.as_str()) {
+      if found {
+        // Or a message might have been copied (or was new in several maildirs), have the server
+        // copy it rather than uploading it again.
+        let copies: Vec<&sync::Mailbox> = mailboxes
+          .iter()
+          .filter(|(path, mailbox)| {
+            maildirs.contains(*path) && !cached_mailboxes.contains(mailbox.string.as_str())
+          })
+          .map(|(_, mailbox)| mailbox)
+          .collect();
+        for to in copies {
+          let to_maildir = maildir_builder.maildir(&to.string, &to.separator)?;
+          if !maildir_builder.local_only(to_maildir.relative()) {
+            copy_message(stream, &mut message, mailbox_string, to)?;
+          }
+        }
+      } else if cached_mailboxes.remove(mailbox_string.as_str()) {
         for (path, mailbox) in &mailboxes {
           if !cached_mailboxes.contains(mailbox.string.as_str()) && maildirs.contains(path) {
             // It doesn't matter which destination mailbox is chosen. If duplicates were moved, the
             // end result would be the same.
-            log::debug!(
-              "moving message {} to {}",
-              message.message_id()?,
-              mailbox.string
-            );
-            match r#move(stream, message.uid(mailbox_string)?, &mailbox.bytes)? {
-              Some(Move { uidvalidity, uid }) => {
-                crate::interrupt(crate::Interruption::SuccessfulMovePreCommit)?;
-                // https://www.rfc-editor.org/rfc/rfc6851#section-4.4
-                // When one or more messages are moved to a target mailbox, if the server is capable
-                // of storing modification sequences for the mailbox, the server MUST generate and
-                // assign new modification sequence numbers to the moved messages that are higher
-                // than the highest modification sequence of the messages originally in the mailbox.
-                //
-                // So we can reuse the current one and the pull bump it.
-                let modseq = message.modseq(mailbox_string)?;
-                let cached_tags: Vec<String> = message
-                  .cached_tags(mailbox_string)?
-                  .into_iter()
-                  .map(String::from)
-                  .collect();
-                let cached_tags = cached_tags.iter().map(String::as_str).collect();
-                message.remove_mailbox_properties(mailbox_string)?;
-                message.update_mailbox_properties(
-                  &mailbox.string,
-                  uidvalidity,
-                  uid,
-                  modseq,
-                  &cached_tags,
-                )?;
-                break;
-              }
-              None => anyhow::bail!(
-                "message {} couldn't be moved to {}, assuming previously interrupted, rerun a pull",
-                message.message_id()?,
-                mailbox.string
-              ),
-            }
+            move_message(stream, &mut message, mailbox_string, mailbox)?;
+            break;
           }
         }
       }
     }
+
+    if expunge {
+      removals.append(&mut expunge_deleted(
+        stream,
+        database,
+        mailbox_string,
+        &maildir,
+      )?);
+    }
+
+    let highestmodseq = own_changes(
+      stream,
+      database,
+      mailbox_string,
+      uidvalidity,
+      validity.1,
+      selected_highestmodseq,
+    )?;
+    let (mut removed, updated) = apply_updates(
+      stream,
+      database,
+      mailbox_string,
+      mailbox_bytes,
+      uidvalidity,
+      &maildir,
+    )?;
+    removals.append(&mut removed);
+    // Whatever another client did in the meantime must still be pulled.
+    if let Some(highestmodseq) = highestmodseq.filter(|_| !updated) {
+      log::debug!(
+        "only the push changed {mailbox_string} (highestmodseq:({} -> {highestmodseq}))",
+        validity.1
+      );
+      let mut root = database.root()?;
+      let (separator, uidnext) = (
+        root.separator(mailbox_string)?,
+        root.uidnext(mailbox_string)?,
+      );
+      root.update_mailbox_properties(
+        mailbox_string,
+        separator,
+        uidvalidity,
+        uidnext,
+        highestmodseq,
+      )?;
+    }
+  }
+
+  // Like a pull, perform the removals last.
+  for path in removals {
+    sync::unlink(&path)?;
+    database.remove(&path)?;
   }
 
   // Avoid spurious lastmod change.
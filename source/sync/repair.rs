@@ -0,0 +1,165 @@
+use crate::{imap, maildir, notmuch, sync};
+use std::{collections, fs, io, path};
+
+// Rebuild the recorded state from the server (authoritative for the UIDs and flags) and the local
+// files, without purging the mailboxes.
+pub fn run<RW>(
+  stream: &mut imap::Stream<RW>,
+  database: &mut notmuch::Database<notmuch::Attached>,
+  maildir_builder: &maildir::Builder,
+  scope: &sync::Scope,
+) -> anyhow::Result<()>
+where
+  RW: imap::ReadWrite,
+{
+  let mut removals = Vec::new();
+
+  let mut root = database.root()?;
+  let known_mailboxes: collections::HashSet<String> =
+    root.mailboxes()?.into_iter().map(String::from).collect();
+
+  // Properties of mailboxes that aren't tracked anymore.
+  let mut messages = database.query(&format!(
+    "property:\"{}.marker={}\"",
+    notmuch::quote(database.namespace()),
+    notmuch::MESSAGE_MARKER,
+  ))?;
+  while let Some(mut message) = messages.next() {
+    let orphans: Vec<String> = message
+      .mailboxes()?
+      .into_iter()
+      .filter(|mailbox| !known_mailboxes.contains(*mailbox))
+      .map(String::from)
+      .collect();
+    for mailbox in orphans {
+      log::info!(
+        "removing the orphaned {mailbox} properties of message {}",
+        message.message_id()?
+      );
+      message.remove_mailbox_properties(&mailbox)?;
+    }
+  }
+  drop(messages);
+
+  for mailbox in sync::list(stream, scope)? {
+    let (uidvalidity, highestmodseq) = root.validity(&mailbox.string)?;
+    if uidvalidity == 0 {
+      log::info!("mailbox {} hasn't been pulled yet", mailbox.string);
+      continue;
+    }
+    log::info!("repairing mailbox {}", mailbox.string);
+    // Everything since the beginning: the flags of all the messages.
+    let mut select = sync::select(stream, &mailbox.bytes, uidvalidity, 0)?;
+    if select.uidvalidity != uidvalidity {
+      // Pulling purges the mailbox, if allowed.
+      log::warn!(
+        "{}'s validity has changed on the server, skipping it",
+        mailbox.string
+      );
+      continue;
+    }
+
+    let maildir = maildir_builder.maildir(&mailbox.string, &mailbox.separator)?;
+    let mut refetch = false;
+    let mut paths = collections::HashSet::new();
+    let mut messages = sync::search_mailbox(database, &mailbox.string)?;
+    while let Some(mut message) = messages.next() {
+      let uids = message.uids(&mailbox.string)?;
+      let files: Vec<path::PathBuf> = message
+        .paths()?
+        .into_iter()
+        .filter(|path| maildir.has(path) && path.exists())
+        .collect();
+      if files.is_empty() {
+        log::info!(
+          "forgetting message {} (uids:{uids:?}), it has no file in {:?}",
+          message.message_id()?,
+          maildir.path()
+        );
+        message.remove_mailbox_properties(&mailbox.string)?;
+        refetch = true;
+        continue;
+      }
+      let mut missing = collections::HashSet::new();
+      for uid in uids {
+        match select.changes.remove(&uid) {
+          None => {
+            log::info!(
+              "removing message {} (uid:{uid}), it isn't on the server anymore",
+              message.message_id()?
+            );
+            missing.insert(uid);
+          }
+          Some(changes) => {
+            let flags = notmuch::flags_to_tags(&changes.flags.iter().map(String::as_str).collect());
+            if flags != message.cached_tags(&mailbox.string)? {
+              log::info!(
+                "resetting the cached tags of message {} (uid:{uid}) to {flags:?}",
+                message.message_id()?
+              );
+              message.update_mailbox_properties(
+                &mailbox.string,
+                uidvalidity,
+                uid,
+                changes.modseq,
+                &flags,
+              )?;
+              message.tags_to_maildir_flags()?;
+            }
+          }
+        }
+      }
+      if !missing.is_empty() {
+        removals.append(&mut sync::pull::remove_uids(
+          &mailbox.string,
+          &maildir,
+          &mut message,
+          &missing,
+        )?);
+      }
+      // The files may have been renamed above.
+      paths.extend(message.paths()?);
+    }
+    drop(messages);
+
+    // Messages on the server that aren't known locally (and aren't new either): forget the
+    // highestmodseq so the next pull downloads them.
+    let uidnext = root.uidnext(&mailbox.string)?;
+    if refetch || select.changes.keys().any(|uid| *uid < uidnext) {
+      log::info!(
+        "the next pull will download the messages missing from {}",
+        mailbox.string
+      );
+      root.update_mailbox_properties(
+        &mailbox.string,
+        mailbox.separator,
+        uidvalidity,
+        uidnext,
+        0,
+      )?;
+    } else {
+      log::debug!("keeping highestmodseq:{highestmodseq}");
+    }
+
+    // Leftovers of interrupted downloads (messages in tmp are otherwise moved out of it before),
+    // the other files in tmp aren't sin's.
+    for path in maildir.tmp_with_digest()?.into_values().flatten() {
+      if !paths.contains(&path) {
+        log::info!("removing stale file {path:?}");
+        match fs::remove_file(&path) {
+          Ok(_) => (),
+          Err(error) if error.kind() == io::ErrorKind::NotFound => (),
+          Err(error) => Err(error)?,
+        }
+      }
+    }
+  }
+
+  // Like a pull, perform the removals last.
+  for path in removals {
+    sync::unlink(&path)?;
+    database.remove(&path)?;
+  }
+
+  Ok(())
+}
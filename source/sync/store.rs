@@ -0,0 +1,406 @@
+// Where the messages are kept locally and what was recorded about them, one mailbox at a time:
+// what sync::plain needs besides the server. Implemented by the sidecar files of the maildir
+// backend (see Maildir), another backend (e.g.: an SQLite index) only has to implement it to be
+// synchronized by sync::plain.
+//
+// The notmuch backend doesn't go through it: sync::pull and sync::push work on the database
+// directly (duplicates, messages moved between mailboxes, removals performed once the transaction
+// went through).
+
+use crate::{maildir, sidecar, sync};
+use std::{collections, fs, io, path};
+
+// What the last synchronization of a mailbox recorded, zeros when it never was.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct State {
+  pub uidvalidity: u64,
+  pub uidnext: u64,
+  pub highestmodseq: u64,
+}
+
+// A message whose flags changed locally since the last synchronization.
+#[derive(Debug, PartialEq)]
+pub struct Changed {
+  pub uid: u64,
+  // As of the last synchronization, when recorded (the mailbox's highestmodseq otherwise).
+  pub modseq: Option<u64>,
+  pub recorded: collections::HashSet<String>,
+  pub flags: collections::HashSet<String>,
+}
+
+pub trait Store {
+  fn state(&mut self, mailbox: &sync::Mailbox) -> anyhow::Result<State>;
+
+  fn set_state(&mut self, mailbox: &sync::Mailbox, state: State) -> anyhow::Result<()>;
+
+  // The UIDs of the messages known locally.
+  fn uids(&mut self, mailbox: &sync::Mailbox) -> anyhow::Result<collections::HashSet<u64>>;
+
+  // A message of the server, downloaded to the maildir's tmp or added locally then appended.
+  fn add(
+    &mut self,
+    mailbox: &sync::Mailbox,
+    maildir: &maildir::Maildir,
+    uidvalidity: u64,
+    uid: u64,
+    changes: &sync::Changes,
+    path: &path::Path,
+  ) -> anyhow::Result<()>;
+
+  // The flags changed on the server, the changes left are the ones of the messages not known
+  // locally.
+  fn update(
+    &mut self,
+    mailbox: &sync::Mailbox,
+    maildir: &maildir::Maildir,
+    uidvalidity: u64,
+    changes: &mut collections::HashMap<u64, sync::Changes>,
+  ) -> anyhow::Result<()>;
+
+  // The messages removed from the server. Returns their files, left for the caller to unlink (see
+  // sync::unlink).
+  fn remove(
+    &mut self,
+    mailbox: &sync::Mailbox,
+    maildir: &maildir::Maildir,
+    uidvalidity: u64,
+    uids: &collections::HashSet<u64>,
+  ) -> anyhow::Result<Vec<path::PathBuf>>;
+
+  fn changed(
+    &mut self,
+    mailbox: &sync::Mailbox,
+    maildir: &maildir::Maildir,
+  ) -> anyhow::Result<Vec<Changed>>;
+
+  // The files added locally, unknown to the server, with their flags.
+  fn added(
+    &mut self,
+    mailbox: &sync::Mailbox,
+    maildir: &maildir::Maildir,
+  ) -> anyhow::Result<Vec<(path::PathBuf, collections::HashSet<String>)>>;
+
+  // What was recorded so far survives an interruption.
+  fn commit(&mut self, mailbox: &sync::Mailbox) -> anyhow::Result<()>;
+}
+
+// The sidecar files of the maildir backend (see sidecar), the flags being the file names'.
+pub struct Maildir<'a> {
+  maildir_builder: &'a maildir::Builder,
+  namespace: &'a str,
+  durability: bool,
+  // Loaded on first use, with their paths.
+  sidecars: collections::HashMap<String, (path::PathBuf, sidecar::Sidecar)>,
+}
+
+impl<'a> Maildir<'a> {
+  pub fn new(maildir_builder: &'a maildir::Builder, namespace: &'a str, durability: bool) -> Self {
+    Self {
+      maildir_builder,
+      namespace,
+      durability,
+      sidecars: collections::HashMap::new(),
+    }
+  }
+
+  fn sidecar(&mut self, mailbox: &sync::Mailbox) -> anyhow::Result<&mut sidecar::Sidecar> {
+    let (_, sidecar) = match self.sidecars.entry(mailbox.string.clone()) {
+      collections::hash_map::Entry::Occupied(entry) => entry.into_mut(),
+      collections::hash_map::Entry::Vacant(entry) => {
+        let maildir = self
+          .maildir_builder
+          .maildir(&mailbox.string, &mailbox.separator)?;
+        let path = sidecar::Sidecar::path(&maildir, self.namespace);
+        let sidecar = sidecar::Sidecar::load(&path)?;
+        entry.insert((path, sidecar))
+      }
+    };
+    Ok(sidecar)
+  }
+}
+
+impl Store for Maildir<'_> {
+  fn state(&mut self, mailbox: &sync::Mailbox) -> anyhow::Result<State> {
+    let sidecar = self.sidecar(mailbox)?;
+    Ok(State {
+      uidvalidity: sidecar.uidvalidity,
+      uidnext: sidecar.uidnext,
+      highestmodseq: sidecar.highestmodseq,
+    })
+  }
+
+  fn set_state(&mut self, mailbox: &sync::Mailbox, state: State) -> anyhow::Result<()> {
+    let sidecar = self.sidecar(mailbox)?;
+    // The UIDs of another UIDVALIDITY are meaningless.
+    if sidecar.uidvalidity != state.uidvalidity {
+      sidecar.messages.clear();
+    }
+    (sidecar.uidvalidity, sidecar.uidnext, sidecar.highestmodseq) =
+      (state.uidvalidity, state.uidnext, state.highestmodseq);
+    Ok(())
+  }
+
+  fn uids(&mut self, mailbox: &sync::Mailbox) -> anyhow::Result<collections::HashSet<u64>> {
+    Ok(self.sidecar(mailbox)?.messages.keys().copied().collect())
+  }
+
+  fn add(
+    &mut self,
+    mailbox: &sync::Mailbox,
+    maildir: &maildir::Maildir,
+    _uidvalidity: u64,
+    uid: u64,
+    changes: &sync::Changes,
+    path: &path::Path,
+  ) -> anyhow::Result<()> {
+    // Downloaded, it's named after the server's flags. Otherwise, it's already where it belongs.
+    let name = match path.parent() == Some(&maildir.path().join("tmp")) {
+      true => {
+        let name = sidecar::name(file_name(path), &changes.flags); // From tmp.
+        fs::rename(path, maildir.path().join("cur").join(&name))?;
+        name
+      }
+      false => file_name(path).to_string(),
+    };
+    self.sidecar(mailbox)?.messages.insert(uid, name);
+    Ok(())
+  }
+
+  fn update(
+    &mut self,
+    mailbox: &sync::Mailbox,
+    maildir: &maildir::Maildir,
+    _uidvalidity: u64,
+    changes: &mut collections::HashMap<u64, sync::Changes>,
+  ) -> anyhow::Result<()> {
+    let files = files(maildir)?;
+    let sidecar = self.sidecar(mailbox)?;
+    let mut updated = Vec::new();
+    changes.retain(|uid, changes| {
+      let Some(name) = sidecar.messages.get_mut(uid) else {
+        return true;
+      };
+      *name = sidecar::name(sidecar::unique(name), &changes.flags);
+      updated.push((*uid, name.clone()));
+      false
+    });
+    // The server's flags win over the ones not pushed yet.
+    for (uid, name) in updated {
+      // Removed locally, left alone (see changed).
+      let Some(path) = files.get(sidecar::unique(&name)) else {
+        continue;
+      };
+      if file_name(path) != name {
+        log::debug!("updating the flags of message {uid}");
+        fs::rename(path, maildir.path().join("cur").join(&name))?;
+      }
+    }
+    Ok(())
+  }
+
+  fn remove(
+    &mut self,
+    mailbox: &sync::Mailbox,
+    maildir: &maildir::Maildir,
+    _uidvalidity: u64,
+    uids: &collections::HashSet<u64>,
+  ) -> anyhow::Result<Vec<path::PathBuf>> {
+    let files = files(maildir)?;
+    let sidecar = self.sidecar(mailbox)?;
+    let mut removals = Vec::new();
+    for uid in uids {
+      let Some(name) = sidecar.messages.remove(uid) else {
+        continue;
+      };
+      log::debug!("removing message {uid}");
+      if let Some(path) = files.get(sidecar::unique(&name)) {
+        removals.push(path.clone());
+      }
+    }
+    Ok(removals)
+  }
+
+  fn changed(
+    &mut self,
+    mailbox: &sync::Mailbox,
+    maildir: &maildir::Maildir,
+  ) -> anyhow::Result<Vec<Changed>> {
+    let files = files(maildir)?;
+    let flags = |name| {
+      sidecar::flags(name)
+        .into_iter()
+        .map(String::from)
+        .collect::<collections::HashSet<_>>()
+    };
+    let mut changed = Vec::new();
+    for (uid, name) in &self.sidecar(mailbox)?.messages {
+      // A missing file isn't a removal (see readme.org): only the T flag asks for one.
+      let Some(path) = files.get(sidecar::unique(name)) else {
+        log::debug!("message {uid} is missing locally, leaving it alone");
+        continue;
+      };
+      let (recorded, flags) = (flags(name), flags(file_name(path)));
+      if flags != recorded {
+        changed.push(Changed {
+          uid: *uid,
+          modseq: None, // Only the mailbox's is recorded.
+          recorded,
+          flags,
+        });
+      }
+    }
+    Ok(changed)
+  }
+
+  fn added(
+    &mut self,
+    mailbox: &sync::Mailbox,
+    maildir: &maildir::Maildir,
+  ) -> anyhow::Result<Vec<(path::PathBuf, collections::HashSet<String>)>> {
+    let sidecar = self.sidecar(mailbox)?;
+    let known: collections::HashSet<&str> = sidecar
+      .messages
+      .values()
+      .map(|name| sidecar::unique(name))
+      .collect();
+    let mut added: Vec<path::PathBuf> = files(maildir)?
+      .into_iter()
+      .filter(|(unique, _)| !known.contains(unique.as_str()))
+      .map(|(_, path)| path)
+      .collect();
+    added.sort();
+    Ok(
+      added
+        .into_iter()
+        .map(|path| {
+          let flags = sidecar::flags(file_name(&path))
+            .into_iter()
+            .map(String::from)
+            .collect();
+          (path, flags)
+        })
+        .collect(),
+    )
+  }
+
+  fn commit(&mut self, mailbox: &sync::Mailbox) -> anyhow::Result<()> {
+    let durability = self.durability;
+    self.sidecar(mailbox)?;
+    let (path, sidecar) = &self.sidecars[&mailbox.string]; // Loaded above.
+    // Otherwise an interruption would have the downloaded messages fetched twice.
+    if durability {
+      maildir::sync_directories(path.parent().unwrap())?; // The maildir.
+    }
+    sidecar.save(path, durability)
+  }
+}
+
+// The messages of a maildir (in new or cur), by unique name (see sidecar::unique).
+fn files(maildir: &maildir::Maildir) -> io::Result<collections::HashMap<String, path::PathBuf>> {
+  let mut files = collections::HashMap::new();
+  for directory in ["new", "cur"] {
+    for entry in fs::read_dir(maildir.path().join(directory))? {
+      let path = entry?.path();
+      if let Some(name) = path.file_name().and_then(|name| name.to_str()) {
+        files.insert(sidecar::unique(name).to_string(), path.clone());
+      }
+    }
+  }
+  Ok(files)
+}
+
+fn file_name(path: &path::Path) -> &str {
+  path
+    .file_name()
+    .and_then(|name| name.to_str())
+    .unwrap_or_default() // Listed by files.
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn maildir() -> anyhow::Result<()> {
+    let directory = tempfile::tempdir()?;
+    let maildir_builder = maildir::Builder::new(directory.path())?;
+    let mailbox = sync::Mailbox {
+      bytes: b"INBOX".to_vec(),
+      string: "INBOX".to_string(),
+      separator: Some('/'),
+      drafts: false,
+      marked: None,
+    };
+    let maildir = maildir_builder.maildir(&mailbox.string, &mailbox.separator)?;
+    let changes = |flags: &[&str], modseq| sync::Changes {
+      flags: flags.iter().map(|flag| flag.to_string()).collect(),
+      modseq,
+    };
+
+    let mut store = Maildir::new(&maildir_builder, "sin", false);
+    assert_eq!(State::default(), store.state(&mailbox)?);
+    let state = State {
+      uidvalidity: 1,
+      uidnext: 3,
+      highestmodseq: 2,
+    };
+    store.set_state(&mailbox, state)?;
+    let first = maildir.tmp(b"first")?;
+    store.add(&mailbox, &maildir, 1, 1, &changes(&["\\Seen"], 1), &first)?;
+    let second = maildir.tmp(b"second")?;
+    store.add(&mailbox, &maildir, 1, 2, &changes(&[], 2), &second)?;
+    store.commit(&mailbox)?;
+    assert_eq!(0, fs::read_dir(maildir.path().join("tmp"))?.count());
+    assert_eq!(
+      collections::HashSet::from([1, 2]),
+      Maildir::new(&maildir_builder, "sin", false).uids(&mailbox)?
+    );
+
+    // Only the known messages are updated.
+    let mut updates = collections::HashMap::from([
+      (1, changes(&["\\Flagged", "\\Seen"], 3)),
+      (3, changes(&[], 3)),
+    ]);
+    store.update(&mailbox, &maildir, 1, &mut updates)?;
+    assert_eq!(vec![3], updates.into_keys().collect::<Vec<_>>());
+    let files = files(&maildir)?;
+    let name = |path: &path::Path| file_name(path).to_string();
+    let (first, second) = (
+      files[sidecar::unique(&name(&first))].clone(),
+      files[sidecar::unique(&name(&second))].clone(),
+    );
+    assert!(name(&first).ends_with(":2,FS"));
+    assert!(store.changed(&mailbox, &maildir)?.is_empty());
+
+    // Changed, removed (not reported, see readme.org) and added locally.
+    let first_ = path::PathBuf::from(format!("{}T", first.to_str().unwrap()));
+    fs::rename(&first, &first_)?;
+    fs::remove_file(&second)?;
+    let third = maildir.path().join("new").join("third");
+    fs::write(&third, "third")?;
+    let changed = store.changed(&mailbox, &maildir)?;
+    let flags = |flags: &[&str]| flags.iter().map(|flag| flag.to_string()).collect();
+    assert_eq!(
+      vec![Changed {
+        uid: 1,
+        modseq: None,
+        recorded: flags(&["\\Flagged", "\\Seen"]),
+        flags: flags(&["\\Deleted", "\\Flagged", "\\Seen"]),
+      },],
+      changed
+    );
+    assert_eq!(vec![(third, flags(&[]))], store.added(&mailbox, &maildir)?);
+
+    let mut updates =
+      collections::HashMap::from([(1, changes(&["\\Deleted", "\\Flagged", "\\Seen"], 4))]);
+    store.update(&mailbox, &maildir, 1, &mut updates)?;
+    // Left for the caller to unlink.
+    assert_eq!(
+      vec![first_.clone()],
+      store.remove(&mailbox, &maildir, 1, &collections::HashSet::from([1, 2]))?
+    );
+    assert!(first_.exists());
+    assert!(store.uids(&mailbox)?.is_empty());
+    Ok(())
+  }
+}
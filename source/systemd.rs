@@ -0,0 +1,178 @@
+// https://www.freedesktop.org/software/systemd/man/latest/sd_notify.html
+// Supervision by systemd (Type=notify and WatchdogSec=): a datagram to $NOTIFY_SOCKET, no need for a
+// library. Nothing is sent when not started by systemd and failures are only logged: it's never
+// worth interrupting a synchronization for.
+
+use anyhow::Context as _;
+use std::{cmp, env, ffi, os::unix::net, process, sync, time};
+
+struct Notifier {
+  socket: net::UnixDatagram,
+  // How often to feed the watchdog, if enabled.
+  watchdog: Option<time::Duration>,
+  // When something was last sent, to rate limit the progress reports.
+  last: sync::Mutex<Option<time::Instant>>,
+}
+
+impl Notifier {
+  fn new(
+    socket: Option<ffi::OsString>,
+    watchdog_usec: Option<ffi::OsString>,
+    watchdog_pid: Option<ffi::OsString>,
+  ) -> anyhow::Result<Option<Self>> {
+    let Some(path) = socket else {
+      return Ok(None);
+    };
+    let socket = net::UnixDatagram::unbound()?;
+    match path.as_encoded_bytes().strip_prefix(b"@") {
+      // An abstract socket.
+      #[cfg(target_os = "linux")]
+      Some(name) => {
+        use std::os::linux::net::SocketAddrExt as _;
+        socket.connect_addr(&net::SocketAddr::from_abstract_name(name)?)
+      }
+      _ => socket.connect(&path),
+    }
+    .with_context(|| format!("couldn't connect to {path:?}"))?;
+    // The watchdog may be meant for another process (e.g.: the shell running us).
+    let pid = watchdog_pid
+      .map(|pid| pid.to_string_lossy().parse::<u32>())
+      .transpose()
+      .context("invalid WATCHDOG_PID")?;
+    let watchdog = match watchdog_usec {
+      Some(usec) if pid.is_none_or(|pid| pid == process::id()) => {
+        let usec = usec
+          .to_string_lossy()
+          .parse()
+          .context("invalid WATCHDOG_USEC")?;
+        // Half the timeout, as recommended.
+        Some(time::Duration::from_micros(usec) / 2)
+      }
+      _ => None,
+    };
+    Ok(Some(Self {
+      socket,
+      watchdog,
+      last: sync::Mutex::new(None),
+    }))
+  }
+
+  // Every notification is also a sign of life.
+  fn notify(&self, state: &str) {
+    let state = match self.watchdog {
+      Some(_) => format!("{state}\nWATCHDOG=1"),
+      None => state.to_string(),
+    };
+    log::trace!("notifying {state:?}");
+    if let Err(error) = self.socket.send(state.as_bytes()) {
+      log::debug!("couldn't notify systemd: {error}");
+    }
+    *self.last.lock().unwrap() = Some(time::Instant::now());
+  }
+
+  fn due(&self) -> bool {
+    let interval = cmp::min(
+      self.watchdog.unwrap_or(time::Duration::MAX),
+      time::Duration::from_secs(1),
+    );
+    self
+      .last
+      .lock()
+      .unwrap()
+      .is_none_or(|last| last.elapsed() >= interval)
+  }
+}
+
+static NOTIFIER: once_cell::sync::Lazy<Option<Notifier>> = once_cell::sync::Lazy::new(|| {
+  Notifier::new(
+    env::var_os("NOTIFY_SOCKET"),
+    env::var_os("WATCHDOG_USEC"),
+    env::var_os("WATCHDOG_PID"),
+  )
+  .unwrap_or_else(|error| {
+    log::warn!("not notifying systemd: {error:#}");
+    None
+  })
+});
+
+// The service is up (it's been caught up with the server and now waits for changes).
+pub fn ready() {
+  if let Some(notifier) = &*NOTIFIER {
+    notifier.notify("READY=1");
+  }
+}
+
+pub fn status(status: &str) {
+  if let Some(notifier) = &*NOTIFIER {
+    notifier.notify(&format!("STATUS={status}"));
+  }
+}
+
+// For the loops making progress: the status is only built and sent every so often.
+pub fn progress(status: impl FnOnce() -> String) {
+  if let Some(notifier) = &*NOTIFIER {
+    if notifier.due() {
+      notifier.notify(&format!("STATUS={}", status()));
+    }
+  }
+}
+
+// For the loops waiting: how often keepalive must be called, if at all.
+pub fn keepalive_interval() -> Option<time::Duration> {
+  NOTIFIER.as_ref().and_then(|notifier| notifier.watchdog)
+}
+
+pub fn keepalive() {
+  if let Some(notifier) = &*NOTIFIER {
+    if notifier.watchdog.is_some() && notifier.due() {
+      notifier.notify("WATCHDOG=1");
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io;
+
+  fn receive(socket: &net::UnixDatagram) -> io::Result<String> {
+    let mut buffer = [0; 1024];
+    let length = socket.recv(&mut buffer)?;
+    Ok(String::from_utf8_lossy(&buffer[..length]).into_owned())
+  }
+
+  #[test]
+  fn notifier() -> anyhow::Result<()> {
+    let directory = tempfile::tempdir()?;
+    let path = directory.path().join("notify");
+    let socket = net::UnixDatagram::bind(&path)?;
+    socket.set_nonblocking(true)?;
+
+    assert!(Notifier::new(None, None, None)?.is_none());
+
+    // The watchdog is meant for another process.
+    let notifier = Notifier::new(
+      Some(path.clone().into()),
+      Some("10000000".into()),
+      Some((process::id() + 1).to_string().into()),
+    )?
+    .unwrap();
+    assert_eq!(None, notifier.watchdog);
+    notifier.notify("READY=1");
+    assert_eq!("READY=1", receive(&socket)?);
+
+    let notifier = Notifier::new(
+      Some(path.into()),
+      Some("10000000".into()),
+      Some(process::id().to_string().into()),
+    )?
+    .unwrap();
+    assert_eq!(Some(time::Duration::from_secs(5)), notifier.watchdog);
+    assert!(notifier.due());
+    notifier.notify("STATUS=pulling");
+    assert_eq!("STATUS=pulling\nWATCHDOG=1", receive(&socket)?);
+    // Rate limited.
+    assert!(!notifier.due());
+    Ok(())
+  }
+}
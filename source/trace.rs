@@ -0,0 +1,509 @@
+// Recording of the IMAP exchanges, so a problematic session can be looked at (and replayed) without
+// access to the server.
+//
+// The transcript has one line per read or write, in the order they happened:
+//   <connection> <C|S> <escaped bytes>
+// Where the connection is a number given in opening order, C is the client and S the server. Bytes
+// are escaped like Rust's byte strings so the file stays readable.
+
+use crate::{imap, sync};
+use anyhow::Context as _;
+use std::{
+  cmp, collections, fs, io, ops, str,
+  sync::{Arc, Mutex, atomic},
+};
+
+// Written in place of the secret segments (see imap::Segment).
+const REDACTED: &[u8] = b"...redacted...";
+
+fn escape(buffer: &[u8]) -> String {
+  buffer
+    .iter()
+    .flat_map(|byte| std::ascii::escape_default(*byte))
+    .map(char::from)
+    .collect()
+}
+
+fn unescape(line: &str) -> anyhow::Result<Vec<u8>> {
+  let mut bytes = line.bytes();
+  let mut buffer = Vec::with_capacity(line.len());
+  while let Some(byte) = bytes.next() {
+    if byte != b'\\' {
+      buffer.push(byte);
+      continue;
+    }
+    buffer.push(match bytes.next() {
+      Some(b't') => b'\t',
+      Some(b'r') => b'\r',
+      Some(b'n') => b'\n',
+      Some(byte @ (b'\\' | b'\'' | b'"')) => byte,
+      Some(b'x') => {
+        let digits = [bytes.next(), bytes.next()];
+        let digits: Vec<u8> = digits.into_iter().flatten().collect();
+        u8::from_str_radix(str::from_utf8(&digits)?, 16)?
+      }
+      escape => anyhow::bail!("invalid escape {escape:?}"),
+    });
+  }
+  Ok(buffer)
+}
+
+pub struct Recorder {
+  file: Mutex<fs::File>,
+  connections: atomic::AtomicUsize,
+}
+
+impl Recorder {
+  pub fn create(path: &str) -> anyhow::Result<Self> {
+    Ok(Self {
+      file: Mutex::new(
+        fs::File::create(path).with_context(|| format!("couldn't create {path:?}"))?,
+      ),
+      connections: atomic::AtomicUsize::new(0),
+    })
+  }
+
+  fn record(&self, connection: usize, direction: char, buffer: &[u8]) -> io::Result<()> {
+    // A single write per line: the connections are used concurrently.
+    let line = format!("{connection} {direction} {}\n", escape(buffer));
+    io::Write::write_all(&mut *self.file.lock().unwrap(), line.as_bytes())
+  }
+}
+
+// Wraps the connections opened by another Open so they are recorded.
+pub struct Traced<'a, O> {
+  pub open: &'a O,
+  pub recorder: &'a Recorder,
+}
+
+impl<'a, O> sync::Open for Traced<'a, O>
+where
+  O: sync::Open,
+{
+  type RW = Recording<'a, O::RW>;
+
+  fn open(&self) -> anyhow::Result<Self::RW> {
+    Ok(Recording {
+      rw: self.open.open()?,
+      recorder: self.recorder,
+      connection: self
+        .recorder
+        .connections
+        .fetch_add(1, atomic::Ordering::Relaxed),
+    })
+  }
+}
+
+pub struct Recording<'a, RW> {
+  rw: RW,
+  recorder: &'a Recorder,
+  connection: usize,
+}
+
+impl<'a, RW> imap::ReadWrite for Recording<'a, RW>
+where
+  RW: imap::ReadWrite,
+{
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    let read = self.rw.read(buf)?;
+    self.recorder.record(self.connection, 'S', &buf[..read])?;
+    Ok(read)
+  }
+
+  fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+    self.recorder.record(self.connection, 'C', buf)?;
+    self.rw.write_all(buf)
+  }
+
+  fn write_secret(&mut self, buf: &[u8]) -> io::Result<()> {
+    self.recorder.record(self.connection, 'C', REDACTED)?;
+    self.rw.write_secret(buf)
+  }
+}
+
+// The chunking protocol sends NOOPs tagged with random UUIDs (see imap::Stream::chunk): they will
+// differ from the recorded ones and have to be substituted.
+const NEEDLE: usize = 36;
+
+fn is_needle(buffer: &[u8]) -> bool {
+  buffer.len() >= NEEDLE
+    && buffer[..NEEDLE]
+      .iter()
+      .enumerate()
+      .all(|(index, byte)| match index {
+        8 | 13 | 18 | 23 => *byte == b'-',
+        _ => byte.is_ascii_digit() || (b'a'..=b'f').contains(byte),
+      })
+}
+
+fn needle_positions(buffer: &[u8]) -> Vec<usize> {
+  let (mut positions, mut position) = (Vec::new(), 0);
+  while position + NEEDLE <= buffer.len() {
+    if is_needle(&buffer[position..]) {
+      positions.push(position);
+      position += NEEDLE;
+    } else {
+      position += 1;
+    }
+  }
+  positions
+}
+
+type Needles = collections::HashMap<Vec<u8>, Vec<u8>>;
+
+// Whether what the client wrote is what was recorded, besides the needles (remembered for the
+// substitution in the server's responses).
+fn matches(recorded: &[u8], written: &[u8], needles: &mut Needles) -> bool {
+  if recorded.len() != written.len() {
+    return false;
+  }
+  let (mut pairs, mut start) = (Vec::new(), 0);
+  for position in needle_positions(recorded) {
+    let end = position + NEEDLE;
+    if recorded[start..position] != written[start..position] || !is_needle(&written[position..]) {
+      return false;
+    }
+    let (from, to) = (&recorded[position..end], &written[position..end]);
+    if needles.get(from).is_some_and(|to_| to_ != to) {
+      return false;
+    }
+    pairs.push((from.to_vec(), to.to_vec()));
+    start = end;
+  }
+  if recorded[start..] != written[start..] {
+    return false;
+  }
+  needles.extend(pairs);
+  true
+}
+
+fn substitute(buffer: &mut [u8], needles: &Needles) {
+  for position in needle_positions(buffer) {
+    if let Some(to) = needles.get(&buffer[position..position + NEEDLE]) {
+      buffer[position..position + NEEDLE].copy_from_slice(to);
+    }
+  }
+}
+
+enum Event {
+  Client(Vec<u8>),
+  Server(Vec<u8>),
+}
+
+struct Transcript {
+  connections: Vec<Vec<Event>>,
+  claimed: Mutex<Vec<bool>>,
+}
+
+// Plays a transcript back in place of the server (for testing). The client is expected to behave
+// exactly as it did when recording: each write is compared to the recorded one. Connections
+// opened concurrently don't necessarily happen in the same order so a new connection is matched to
+// all the unclaimed recorded ones until only one remains.
+// Challenge-response mechanisms (like SCRAM) can't be replayed.
+pub struct Replay(Arc<Transcript>);
+
+impl Replay {
+  pub fn load(path: &str) -> anyhow::Result<Self> {
+    let content = fs::read_to_string(path).with_context(|| format!("couldn't read {path:?}"))?;
+    let mut connections: Vec<Vec<Event>> = Vec::new();
+    for (number, line) in content.lines().enumerate() {
+      let mut fields = line.splitn(3, ' ');
+      let (connection, direction, data) = match (fields.next(), fields.next(), fields.next()) {
+        (Some(connection), Some(direction), Some(data)) => (connection, direction, data),
+        _ => anyhow::bail!("{path:?}:{}: malformed line", number + 1),
+      };
+      let connection: usize = connection
+        .parse()
+        .with_context(|| format!("{path:?}:{}: invalid connection", number + 1))?;
+      let data = unescape(data).with_context(|| format!("{path:?}:{}", number + 1))?;
+      if connection >= connections.len() {
+        connections.resize_with(connection + 1, Vec::new);
+      }
+      connections[connection].push(match direction {
+        "C" => Event::Client(data),
+        "S" => Event::Server(data),
+        _ => anyhow::bail!("{path:?}:{}: invalid direction", number + 1),
+      });
+    }
+    let claimed = Mutex::new(vec![false; connections.len()]);
+    Ok(Self(Arc::new(Transcript {
+      connections,
+      claimed,
+    })))
+  }
+}
+
+impl sync::Open for Replay {
+  type RW = Replaying;
+
+  fn open(&self) -> anyhow::Result<Self::RW> {
+    let candidates: Vec<Cursor> = self
+      .0
+      .claimed
+      .lock()
+      .unwrap()
+      .iter()
+      .enumerate()
+      .filter(|(_, claimed)| !**claimed)
+      .map(|(connection, _)| Cursor {
+        connection,
+        event: 0,
+        pending: Vec::new(),
+        served: 0,
+        needles: Needles::new(),
+      })
+      .collect();
+    anyhow::ensure!(
+      !candidates.is_empty(),
+      "no connection left in the transcript"
+    );
+    Ok(Replaying {
+      transcript: self.0.clone(),
+      candidates,
+      claimed: false,
+    })
+  }
+}
+
+// Where a connection is in a recorded one.
+struct Cursor {
+  connection: usize,
+  event: usize,
+  // The server's responses up to the next client's write, with the needles substituted.
+  pending: Vec<u8>,
+  served: usize,
+  needles: Needles,
+}
+
+impl Cursor {
+  fn serve(&mut self, transcript: &Transcript, length: usize) -> &[u8] {
+    let events = &transcript.connections[self.connection];
+    if self.served == self.pending.len() {
+      self.pending.clear();
+      self.served = 0;
+      while let Some(Event::Server(data)) = events.get(self.event) {
+        self.pending.extend_from_slice(data);
+        self.event += 1;
+      }
+      substitute(&mut self.pending, &self.needles);
+    }
+    let start = self.served;
+    self.served += cmp::min(length, self.pending.len() - start);
+    &self.pending[start..self.served]
+  }
+
+  fn write(&mut self, transcript: &Transcript, written: &[u8]) -> bool {
+    if self.served != self.pending.len() {
+      return false;
+    }
+    match transcript.connections[self.connection].get(self.event) {
+      Some(Event::Client(recorded)) if matches(recorded, written, &mut self.needles) => {
+        self.event += 1;
+        true
+      }
+      _ => false,
+    }
+  }
+}
+
+pub struct Replaying {
+  transcript: Arc<Transcript>,
+  candidates: Vec<Cursor>,
+  claimed: bool,
+}
+
+impl Replaying {
+  // Forget about the connections claimed by others and claim the last one standing.
+  fn claim(&mut self) -> io::Result<()> {
+    if !self.claimed {
+      let mut claimed = self.transcript.claimed.lock().unwrap();
+      self
+        .candidates
+        .retain(|candidate| !claimed[candidate.connection]);
+      if let [candidate] = self.candidates.as_slice() {
+        claimed[candidate.connection] = true;
+        self.claimed = true;
+      }
+    }
+    match self.candidates.is_empty() {
+      true => Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "the connection diverged from the transcript",
+      )),
+      false => Ok(()),
+    }
+  }
+
+  fn expected(&self) -> String {
+    match self
+      .candidates
+      .first()
+      .and_then(|candidate| self.transcript.connections[candidate.connection].get(candidate.event))
+    {
+      Some(Event::Client(data)) => format!("expected the client to send {:?}", escape(data)),
+      Some(Event::Server(data)) => format!("expected the server to send {:?}", escape(data)),
+      None => "expected the end of the connection".to_string(),
+    }
+  }
+}
+
+impl ops::Drop for Replaying {
+  fn drop(&mut self) {
+    // Whatever was left, its recording has been consumed.
+    if !self.claimed {
+      let mut claimed = self.transcript.claimed.lock().unwrap();
+      if let Some(candidate) = self
+        .candidates
+        .iter()
+        .find(|candidate| !claimed[candidate.connection])
+      {
+        claimed[candidate.connection] = true;
+      }
+    }
+  }
+}
+
+impl imap::ReadWrite for Replaying {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    self.claim()?;
+    let transcript = self.transcript.clone();
+    let first = &mut self.candidates[0]; // Guaranteed by claim.
+    let data = first.serve(&transcript, buf.len());
+    let read = data.len();
+    buf[..read].copy_from_slice(data);
+    if read == 0 && first.event < transcript.connections[first.connection].len() {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+          "the client is reading but the transcript {}",
+          self.expected()
+        ),
+      ));
+    }
+    // The other candidates must have answered the same.
+    let mut first = true;
+    self.candidates.retain_mut(|candidate| {
+      std::mem::replace(&mut first, false) || candidate.serve(&transcript, read) == &buf[..read]
+    });
+    self.claim()?;
+    Ok(read)
+  }
+
+  fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+    self.claim()?;
+    let expected = self.expected();
+    let transcript = self.transcript.clone();
+    self
+      .candidates
+      .retain_mut(|candidate| candidate.write(&transcript, buf));
+    if self.candidates.is_empty() {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+          "the client sent {:?} but the transcript {expected}",
+          escape(buf)
+        ),
+      ));
+    }
+    self.claim()
+  }
+
+  // The secrets were recorded redacted, they may differ anyway (e.g.: another user).
+  fn write_secret(&mut self, _buf: &[u8]) -> io::Result<()> {
+    self.write_all(REDACTED)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use sync::Open as _;
+
+  #[test]
+  fn escaping() {
+    let bytes: Vec<u8> = (0..=u8::MAX).collect();
+    assert_eq!(bytes, unescape(&escape(&bytes)).unwrap());
+    assert!(unescape("\\q").is_err());
+  }
+
+  #[test]
+  fn redaction() {
+    let directory = tempfile::tempdir().unwrap();
+    let path = directory.path().join("trace");
+    let recorder = Recorder::create(path.to_str().unwrap()).unwrap();
+    let mut recording = Recording {
+      rw: io::Cursor::new(Vec::new()),
+      recorder: &recorder,
+      connection: 0,
+    };
+    imap::ReadWrite::write_all(&mut recording, b"authenticate AUTHENTICATE PLAIN ").unwrap();
+    imap::ReadWrite::write_secret(&mut recording, b"AHVzZXIAcGFzc3dvcmQ=").unwrap();
+    imap::ReadWrite::write_all(&mut recording, b"\r\n").unwrap();
+    // Only the recording is redacted.
+    assert_eq!(
+      b"authenticate AUTHENTICATE PLAIN AHVzZXIAcGFzc3dvcmQ=\r\n",
+      recording.rw.get_ref().as_slice()
+    );
+    drop(recording);
+    assert_eq!(
+      "0 C authenticate AUTHENTICATE PLAIN \n0 C ...redacted...\n0 C \\r\\n\n",
+      fs::read_to_string(&path).unwrap()
+    );
+
+    // Whatever the secret, it matches the redacted one when replaying.
+    let replay = Replay::load(path.to_str().unwrap()).unwrap();
+    let mut stream = replay.open().unwrap();
+    imap::ReadWrite::write_all(&mut stream, b"authenticate AUTHENTICATE PLAIN ").unwrap();
+    imap::ReadWrite::write_secret(&mut stream, b"AG90aGVyAHBhc3N3b3Jk").unwrap();
+    imap::ReadWrite::write_all(&mut stream, b"\r\n").unwrap();
+  }
+
+  #[test]
+  fn replay() {
+    let directory = tempfile::tempdir().unwrap();
+    let path = directory.path().join("trace");
+    let recorded = "00000000-0000-0000-0000-000000000000";
+    fs::write(
+      &path,
+      format!("0 S * OK\\r\\n\n0 C {recorded}\n0 C  NOOP\\r\\n\n0 S {recorded} OK done\\r\\n\n"),
+    )
+    .unwrap();
+    let replay = Replay::load(path.to_str().unwrap()).unwrap();
+    let mut stream = replay.open().unwrap();
+    let mut buffer = [0; 1024];
+
+    let read = imap::ReadWrite::read(&mut stream, &mut buffer).unwrap();
+    assert_eq!(b"* OK\r\n", &buffer[..read]);
+    // The server doesn't say anything more until the client does.
+    assert!(imap::ReadWrite::read(&mut stream, &mut buffer).is_err());
+
+    // The needle is substituted in the server's response.
+    let needle = uuid::Uuid::new_v4().as_hyphenated().to_string();
+    imap::ReadWrite::write_all(&mut stream, needle.as_bytes()).unwrap();
+    imap::ReadWrite::write_all(&mut stream, b" NOOP\r\n").unwrap();
+    let read = imap::ReadWrite::read(&mut stream, &mut buffer).unwrap();
+    assert_eq!(format!("{needle} OK done\r\n").as_bytes(), &buffer[..read]);
+    assert_eq!(0, imap::ReadWrite::read(&mut stream, &mut buffer).unwrap());
+
+    assert!(replay.open().is_err());
+  }
+
+  #[test]
+  fn replay_divergence() {
+    let directory = tempfile::tempdir().unwrap();
+    let path = directory.path().join("trace");
+    fs::write(&path, "0 S * OK\\r\\n\n0 C a CAPABILITY\\r\\n\n").unwrap();
+    let replay = Replay::load(path.to_str().unwrap()).unwrap();
+    let mut stream = replay.open().unwrap();
+    let mut buffer = [0; 1024];
+
+    imap::ReadWrite::read(&mut stream, &mut buffer).unwrap();
+    assert_eq!(
+      "the client sent \"a NOOP\\\\r\\\\n\" but the transcript expected the client to send \
+       \"a CAPABILITY\\\\r\\\\n\"",
+      imap::ReadWrite::write_all(&mut stream, b"a NOOP\r\n")
+        .unwrap_err()
+        .to_string()
+    );
+  }
+}
@@ -0,0 +1,86 @@
+use crate::systemd;
+use notify::Watcher as _;
+use std::{path, sync::mpsc, time};
+
+// Wait for local changes (tag edits end up in the Xapian database, new messages in the maildir).
+pub struct Watcher {
+  // Must be kept alive for the events to be delivered.
+  _watcher: notify::RecommendedWatcher,
+  receiver: mpsc::Receiver<notify::Result<notify::Event>>,
+}
+
+impl Watcher {
+  pub fn new(paths: &[(&path::Path, bool /* recursive */)]) -> anyhow::Result<Self> {
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender)?;
+    for &(path, recursive) in paths {
+      log::debug!("watching {path:?}");
+      watcher.watch(
+        path,
+        match recursive {
+          true => notify::RecursiveMode::Recursive,
+          false => notify::RecursiveMode::NonRecursive,
+        },
+      )?;
+    }
+    Ok(Self {
+      _watcher: watcher,
+      receiver,
+    })
+  }
+
+  // Returns true if a change was observed before the channel became quiet for the given duration.
+  fn receive(&self, timeout: Option<time::Duration>) -> anyhow::Result<bool> {
+    loop {
+      let event = match timeout {
+        Some(timeout) => match self.receiver.recv_timeout(timeout) {
+          Ok(event) => event,
+          Err(mpsc::RecvTimeoutError::Timeout) => return Ok(false),
+          Err(mpsc::RecvTimeoutError::Disconnected) => anyhow::bail!("watcher disconnected"),
+        },
+        None => self.receiver.recv()?,
+      };
+      let event = event?;
+      // Opening a file or reading it isn't a change.
+      if !event.kind.is_access() {
+        log::trace!("{event:?}");
+        return Ok(true);
+      }
+    }
+  }
+
+  // Block until a change happens, then until no other change happened during the given duration so
+  // bursts (e.g.: a notmuch tag invocation on many messages) are coalesced.
+  pub fn wait(&self, debounce: time::Duration) -> anyhow::Result<()> {
+    // Idling isn't being stuck, systemd's watchdog (if any) is kept fed.
+    while !self.receive(systemd::keepalive_interval())? {
+      systemd::keepalive();
+    }
+    while self.receive(Some(debounce))? {}
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::fs;
+
+  #[test]
+  fn debounce() -> anyhow::Result<()> {
+    let directory = tempfile::tempdir()?;
+    let watcher = Watcher::new(&[(directory.path(), true)])?;
+    let debounce = time::Duration::from_millis(100);
+    while watcher.receive(Some(debounce))? {}
+    assert!(!watcher.receive(Some(debounce))?);
+
+    fs::create_dir(directory.path().join("cur"))?;
+    for i in 0..10 {
+      fs::write(directory.path().join("cur").join(i.to_string()), b"")?;
+    }
+    watcher.wait(debounce)?;
+    // All the events have been coalesced.
+    assert!(!watcher.receive(Some(debounce))?);
+    Ok(())
+  }
+}
@@ -3,6 +3,15 @@ use anyhow::Context as _;
 use std::{fs, io::Write as _, process};
 
 pub fn server() -> anyhow::Result<(tempfile::TempDir, common::Child, u16)> {
+  run(false)
+}
+
+// Implicit TLS with a self-signed certificate.
+pub fn tls_server() -> anyhow::Result<(tempfile::TempDir, common::Child, u16)> {
+  run(true)
+}
+
+fn run(tls: bool) -> anyhow::Result<(tempfile::TempDir, common::Child, u16)> {
   let directory = tempfile::tempdir()?;
   let base_dir = directory
     .path()
@@ -29,6 +38,7 @@ user1:{{plain}}password:::multi-user test:::
 user2:{{plain}}password:::multi-user test:::
 user3:{{plain}}password:::multi-user test:::
 separator:{{plain}}password:::separator test:::userdb_namespace/default/separator=.
+prefix:{{plain}}password:::personal namespace test:::userdb_namespace/default/separator=. userdb_namespace/default/prefix=INBOX.
 maildir:{{plain}}password:::static maildir benchmark:::userdb_mail=maildir:/tmp/maildir
 "
     )
@@ -37,6 +47,51 @@ maildir:{{plain}}password:::static maildir benchmark:::userdb_mail=maildir:/tmp/
   file.sync_all()?;
   let passwd = passwd.to_str().unwrap();
 
+  let (ssl, listener) = if tls {
+    let (certificate, key) = (
+      directory.path().join("cert.pem"),
+      directory.path().join("key.pem"),
+    );
+    let status = process::Command::new("openssl")
+      .args([
+        "req", "-x509", "-newkey", "rsa:2048", "-nodes", "-days", "1",
+      ])
+      .args(["-subj", "/CN=localhost", "-keyout"])
+      .arg(&key)
+      .arg("-out")
+      .arg(&certificate)
+      .stderr(process::Stdio::null())
+      .status()?;
+    anyhow::ensure!(status.success(), "couldn't generate a certificate");
+    (
+      format!(
+        "ssl = required
+ssl_cert = <{}
+ssl_key = <{}",
+        certificate.display(),
+        key.display()
+      ),
+      format!(
+        "inet_listener imap {{
+    port = 0
+  }}
+  inet_listener imaps {{
+    port = {port}
+    ssl = yes
+  }}"
+      ),
+    )
+  } else {
+    (
+      "ssl = no".to_string(),
+      format!(
+        "inet_listener imap {{
+    port = {port}
+  }}"
+      ),
+    )
+  };
+
   // Can't name it dovecot.conf because this will be a symlink to the configuration.
   let configuration = directory.path().join("test.conf");
   let mut file = fs::File::create(&configuration)?;
@@ -54,16 +109,15 @@ auth_debug_passwords = yes
 mail_debug = yes
 
 protocols = imap
-ssl = no
+{ssl}
+auth_mechanisms = plain login cram-md5 scram-sha-256
 
 service anvil {{
   chroot =
 }}
 service imap-login {{
   chroot =
-  inet_listener imap {{
-    port = {port}
-  }}
+  {listener}
 }}
 
 passdb {{
@@ -82,6 +136,9 @@ userdb {{
 namespace default {{
   inbox = yes
   separator = /
+  mailbox Drafts {{
+    special_use = \\Drafts
+  }}
 }}
 "
     )
@@ -96,5 +153,5 @@ namespace default {{
       configuration.to_str().with_context(|| "invalid file")?,
     ])
     .spawn()?;
-  Ok((directory, common::Child(child), port))
+  Ok((directory, common::Child::Process(child), port))
 }
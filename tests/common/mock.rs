@@ -0,0 +1,1252 @@
+// A small IMAP server implementing just what sin needs, so tests don't depend on Dovecot.
+//
+// Like with Dovecot, the mailboxes are Maildir++ directories ({directory}/{user}/maildir, with /
+// as the separator) that are rescanned on each command: tests can deliver, flag or remove messages
+// directly (see Runner::server_maildir). What can't be stored in the file names (UIDs,
+// mod-sequences and keywords) is kept in a mock-uidlist file in each maildir, removing it changes
+// the UIDVALIDITY.
+//
+// Any user is accepted with the password "password".
+//
+// Some quirks of other servers can be reproduced (see Quirks).
+
+use crate::common;
+use anyhow::Context as _;
+use base64::Engine as _;
+use std::{
+  collections, fs,
+  io::{self, BufRead as _, Read as _, Write as _},
+  net, path,
+  sync::{Arc, Mutex, atomic},
+  thread, time,
+};
+
+const CAPABILITIES: &str = "IMAP4rev1 LITERAL+ SASL-IR AUTH=PLAIN ENABLE ID";
+const AUTHENTICATED_CAPABILITIES: &str =
+  "IMAP4rev1 LITERAL+ ENABLE ID NAMESPACE UIDPLUS MOVE CONDSTORE QRESYNC";
+const CONDSTORE_CAPABILITIES: &str =
+  "IMAP4rev1 LITERAL+ ENABLE ID NAMESPACE UIDPLUS MOVE CONDSTORE ESEARCH";
+const UIDLIST: &str = "mock-uidlist";
+// https://cr.yp.to/proto/maildir.html
+// Flag "P" (passed) [...] Flag "R" (replied) [...] Flag "S" (seen) [...] Flag "T" (trashed) [...]
+// Flag "D" (draft) [...] Flag "F" (flagged) [...] Flags must be stored in ASCII order.
+const FLAGS: &[(char, &str)] = &[
+  ('D', "\\Draft"),
+  ('F', "\\Flagged"),
+  ('R', "\\Answered"),
+  ('S', "\\Seen"),
+  ('T', "\\Deleted"),
+];
+
+// How other servers deviate from the RFCs.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Quirks {
+  None,
+  // Identifies as Cyrus and doesn't announce \* in PERMANENTFLAGS (as Cyrus does once a mailbox has
+  // no room for another keyword).
+  Cyrus,
+  // Doesn't support QRESYNC, only CONDSTORE and ESEARCH.
+  Condstore,
+  // Leaves UIDNEXT out of the response to SELECT, even though RFC 3501 requires it.
+  NoUIDNext,
+  // Not another server's deviation: the first message download goes unanswered for longer than
+  // the tests' timeout (see Runner::with_timeout), like over a flaky network.
+  Stall,
+  // Not another server's deviation either: another client flags every message of the selected
+  // mailbox right after each STORE, reported in the response to the next NOOP.
+  Concurrent,
+  // Not another server's deviation either: another client removes the mailbox right before the
+  // first APPEND (the client selected it beforehand and the selection goes away with it).
+  Removal,
+}
+
+const STALL: time::Duration = time::Duration::from_secs(2);
+
+#[derive(Debug)]
+pub struct Server {
+  port: u16,
+  stop: Arc<atomic::AtomicBool>,
+  thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Drop for Server {
+  fn drop(&mut self) {
+    self.stop.store(true, atomic::Ordering::Relaxed);
+    // Wake up the listener.
+    let _ = net::TcpStream::connect(("127.0.0.1", self.port));
+    if let Some(thread) = self.thread.take() {
+      let _ = thread.join();
+    }
+  }
+}
+
+pub fn server() -> anyhow::Result<(tempfile::TempDir, common::Child, u16)> {
+  run(Quirks::None)
+}
+
+pub fn cyrus_server() -> anyhow::Result<(tempfile::TempDir, common::Child, u16)> {
+  run(Quirks::Cyrus)
+}
+
+pub fn condstore_server() -> anyhow::Result<(tempfile::TempDir, common::Child, u16)> {
+  run(Quirks::Condstore)
+}
+
+pub fn no_uidnext_server() -> anyhow::Result<(tempfile::TempDir, common::Child, u16)> {
+  run(Quirks::NoUIDNext)
+}
+
+pub fn concurrent_server() -> anyhow::Result<(tempfile::TempDir, common::Child, u16)> {
+  run(Quirks::Concurrent)
+}
+
+pub fn stalling_server() -> anyhow::Result<(tempfile::TempDir, common::Child, u16)> {
+  run(Quirks::Stall)
+}
+
+pub fn removing_server() -> anyhow::Result<(tempfile::TempDir, common::Child, u16)> {
+  run(Quirks::Removal)
+}
+
+fn run(quirks: Quirks) -> anyhow::Result<(tempfile::TempDir, common::Child, u16)> {
+  let directory = tempfile::tempdir()?;
+  let listener = net::TcpListener::bind(("127.0.0.1", 0))?;
+  let port = listener.local_addr()?.port();
+  let stop = Arc::new(atomic::AtomicBool::new(false));
+  let (root, stop_) = (directory.path().to_path_buf(), stop.clone());
+  // Connections are served concurrently but commands are applied one at a time.
+  let lock = Arc::new(Mutex::new(()));
+  // Whether the quirks happening once (Stall, Removal) did.
+  let triggered = Arc::new(atomic::AtomicBool::new(false));
+  let thread = thread::spawn(move || {
+    for stream in listener.incoming() {
+      if stop_.load(atomic::Ordering::Relaxed) {
+        break;
+      }
+      let (root, lock, triggered) = (root.clone(), lock.clone(), triggered.clone());
+      match stream {
+        Ok(stream) => {
+          thread::spawn(move || {
+            if let Err(error) = Connection::new(&root, &lock, quirks, &triggered).serve(stream) {
+              log::debug!("mock connection closed: {error:?}");
+            }
+          });
+        }
+        Err(error) => log::warn!("couldn't accept a mock connection: {error}"),
+      }
+    }
+  });
+  log::debug!(
+    "running the mock server from {root:?} on port {port}",
+    root = directory.path()
+  );
+  Ok((
+    directory,
+    common::Child::Thread(Server {
+      port,
+      stop,
+      thread: Some(thread),
+    }),
+    port,
+  ))
+}
+
+#[derive(Debug)]
+enum Token {
+  Atom(String),
+  String(Vec<u8>),
+  List(Vec<Token>),
+}
+
+impl Token {
+  fn atom(&self) -> anyhow::Result<&str> {
+    match self {
+      Token::Atom(atom) => Ok(atom),
+      _ => anyhow::bail!("expected an atom, got {self:?}"),
+    }
+  }
+
+  fn astring(&self) -> anyhow::Result<String> {
+    match self {
+      Token::Atom(atom) => Ok(atom.clone()),
+      Token::String(string) => Ok(String::from_utf8(string.clone())?),
+      _ => anyhow::bail!("expected a string, got {self:?}"),
+    }
+  }
+
+  fn list(&self) -> anyhow::Result<&[Token]> {
+    match self {
+      Token::List(list) => Ok(list),
+      _ => anyhow::bail!("expected a list, got {self:?}"),
+    }
+  }
+}
+
+enum Item {
+  Byte(u8),
+  Literal(Vec<u8>),
+}
+
+// Read a whole command, including its literals.
+fn read_command(
+  reader: &mut io::BufReader<net::TcpStream>,
+  writer: &mut net::TcpStream,
+) -> anyhow::Result<Option<Vec<Token>>> {
+  let mut items = Vec::new();
+  loop {
+    let mut line = Vec::new();
+    if reader.read_until(b'\n', &mut line)? == 0 {
+      return Ok(None);
+    }
+    let line = line
+      .strip_suffix(b"\r\n")
+      .context("command not terminated by CRLF")?;
+    // literal = "{" number64 ["+"] "}" CRLF *CHAR8
+    let literal = line.strip_suffix(b"}").and_then(|line| {
+      let start = line.iter().rposition(|byte| *byte == b'{')?;
+      let (number, synchronizing) = match line[start + 1..].strip_suffix(b"+") {
+        Some(number) => (number, false),
+        None => (&line[start + 1..], true),
+      };
+      let length = std::str::from_utf8(number).ok()?.parse::<usize>().ok()?;
+      Some((start, length, synchronizing))
+    });
+    match literal {
+      Some((start, length, synchronizing)) => {
+        items.extend(line[..start].iter().copied().map(Item::Byte));
+        if synchronizing {
+          writer.write_all(b"+ Ready for literal data\r\n")?;
+        }
+        let mut literal = vec![0; length];
+        reader.read_exact(&mut literal)?;
+        items.push(Item::Literal(literal));
+      }
+      None => {
+        items.extend(line.iter().copied().map(Item::Byte));
+        break;
+      }
+    }
+  }
+  let mut items = items.into_iter().peekable();
+  let tokens = tokens(&mut items, false)?;
+  anyhow::ensure!(items.next().is_none(), "unbalanced parentheses");
+  Ok(Some(tokens))
+}
+
+fn tokens(
+  items: &mut std::iter::Peekable<std::vec::IntoIter<Item>>,
+  nested: bool,
+) -> anyhow::Result<Vec<Token>> {
+  let mut tokens = Vec::new();
+  loop {
+    match items.peek() {
+      None => {
+        anyhow::ensure!(!nested, "unterminated list");
+        break;
+      }
+      Some(Item::Byte(b' ')) => {
+        items.next();
+      }
+      Some(Item::Byte(b')')) if nested => {
+        items.next();
+        break;
+      }
+      Some(Item::Byte(b'(')) => {
+        items.next();
+        tokens.push(Token::List(self::tokens(items, true)?));
+      }
+      Some(Item::Byte(b'"')) => {
+        items.next();
+        let mut string = Vec::new();
+        loop {
+          match items.next() {
+            Some(Item::Byte(b'"')) => break,
+            Some(Item::Byte(b'\\')) => match items.next() {
+              Some(Item::Byte(byte)) => string.push(byte),
+              _ => anyhow::bail!("invalid quoted string"),
+            },
+            Some(Item::Byte(byte)) => string.push(byte),
+            _ => anyhow::bail!("invalid quoted string"),
+          }
+        }
+        tokens.push(Token::String(string));
+      }
+      Some(Item::Literal(_)) => match items.next() {
+        Some(Item::Literal(literal)) => tokens.push(Token::String(literal)),
+        _ => unreachable!(),
+      },
+      Some(Item::Byte(_)) => {
+        let mut atom = Vec::new();
+        while let Some(Item::Byte(byte)) = items.peek() {
+          if matches!(byte, b' ' | b'(' | b')') {
+            break;
+          }
+          atom.push(*byte);
+          items.next();
+        }
+        anyhow::ensure!(!atom.is_empty(), "unexpected parenthesis");
+        tokens.push(Token::Atom(String::from_utf8(atom)?));
+      }
+    }
+  }
+  Ok(tokens)
+}
+
+fn quote(string: &str) -> String {
+  format!("\"{}\"", string.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+// UID FETCH <set> BODY.PEEK[], as sent by the pull.
+fn is_body_fetch(tokens: &[Token]) -> bool {
+  match tokens {
+    [
+      Token::Atom(uid),
+      Token::Atom(fetch),
+      _,
+      Token::Atom(item),
+      ..,
+    ] => {
+      uid.eq_ignore_ascii_case("UID")
+        && fetch.eq_ignore_ascii_case("FETCH")
+        && item.eq_ignore_ascii_case("BODY.PEEK[]")
+    }
+    _ => false,
+  }
+}
+
+fn is_store(tokens: &[Token]) -> bool {
+  match tokens {
+    [Token::Atom(uid), Token::Atom(store), ..] => {
+      uid.eq_ignore_ascii_case("UID") && store.eq_ignore_ascii_case("STORE")
+    }
+    _ => false,
+  }
+}
+
+fn flag_list(flags: &collections::BTreeSet<String>) -> String {
+  let flags: Vec<&str> = flags.iter().map(String::as_str).collect();
+  format!("({})", flags.join(" "))
+}
+
+// sequence-set, with * being the largest UID in use.
+fn uid_set(set: &str, largest: u64) -> anyhow::Result<Vec<(u64, u64)>> {
+  let number = |number: &str| -> anyhow::Result<u64> {
+    Ok(match number {
+      "*" => largest,
+      number => number.parse()?,
+    })
+  };
+  set
+    .split(',')
+    .map(|range| {
+      Ok(match range.split_once(':') {
+        Some((start, end)) => {
+          let (start, end) = (number(start)?, number(end)?);
+          (start.min(end), start.max(end))
+        }
+        None => (number(range)?, number(range)?),
+      })
+    })
+    .collect()
+}
+
+fn uid_list(uids: &[u64]) -> String {
+  let uids: Vec<String> = uids.iter().map(u64::to_string).collect();
+  uids.join(",")
+}
+
+#[derive(Debug)]
+struct Message {
+  uid: u64,
+  modseq: u64,
+  key: String,
+  flags: collections::BTreeSet<String>,
+}
+
+impl Message {
+  fn name(&self) -> String {
+    let mut info = String::new();
+    for (letter, flag) in FLAGS {
+      if self.flags.contains(*flag) {
+        info.push(*letter);
+      }
+    }
+    format!("{}:2,{info}", self.key)
+  }
+}
+
+#[derive(Debug)]
+struct Mailbox {
+  path: path::PathBuf,
+  uidvalidity: u64,
+  uidnext: u64,
+  highestmodseq: u64,
+  messages: Vec<Message>,
+  vanished: Vec<(u64 /* uid */, u64 /* modseq */)>,
+  // Where the messages currently are.
+  files: collections::HashMap<String, path::PathBuf>,
+}
+
+impl Mailbox {
+  fn open(path: &path::Path) -> anyhow::Result<Self> {
+    let mut mailbox = Self {
+      path: path.to_path_buf(),
+      uidvalidity: time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)?
+        .as_secs(),
+      uidnext: 1,
+      highestmodseq: 1,
+      messages: Vec::new(),
+      vanished: Vec::new(),
+      files: collections::HashMap::new(),
+    };
+    match fs::read_to_string(path.join(UIDLIST)) {
+      Ok(content) => mailbox.load(&content)?,
+      // Remember the UIDVALIDITY.
+      Err(error) if error.kind() == io::ErrorKind::NotFound => mailbox.save()?,
+      Err(error) => Err(error)?,
+    }
+    mailbox.scan()?;
+    Ok(mailbox)
+  }
+
+  // <uidvalidity> <uidnext> <highestmodseq>
+  // M <uid> <modseq> <key> <flag>...
+  // V <uid> <modseq>
+  fn load(&mut self, content: &str) -> anyhow::Result<()> {
+    let mut lines = content.lines();
+    let header: Vec<u64> = lines
+      .next()
+      .context("empty uidlist")?
+      .split(' ')
+      .map(str::parse)
+      .collect::<Result<_, _>>()?;
+    anyhow::ensure!(header.len() == 3, "invalid uidlist header");
+    (self.uidvalidity, self.uidnext, self.highestmodseq) = (header[0], header[1], header[2]);
+    for line in lines {
+      let fields: Vec<&str> = line.split(' ').collect();
+      match fields.as_slice() {
+        ["M", uid, modseq, key, flags @ ..] => self.messages.push(Message {
+          uid: uid.parse()?,
+          modseq: modseq.parse()?,
+          key: key.to_string(),
+          flags: flags.iter().map(|flag| flag.to_string()).collect(),
+        }),
+        ["V", uid, modseq] => self.vanished.push((uid.parse()?, modseq.parse()?)),
+        _ => anyhow::bail!("invalid uidlist line {line:?}"),
+      }
+    }
+    Ok(())
+  }
+
+  fn save(&self) -> io::Result<()> {
+    let mut content = format!(
+      "{} {} {}\n",
+      self.uidvalidity, self.uidnext, self.highestmodseq
+    );
+    for message in &self.messages {
+      content += &format!("M {} {} {}", message.uid, message.modseq, message.key);
+      for flag in &message.flags {
+        content += &format!(" {flag}");
+      }
+      content += "\n";
+    }
+    for (uid, modseq) in &self.vanished {
+      content += &format!("V {uid} {modseq}\n");
+    }
+    fs::write(self.path.join(UIDLIST), content)
+  }
+
+  // Reconcile with what's on disk: new files get a UID, removed ones are expunged and changed flags
+  // bump the mod-sequence.
+  fn scan(&mut self) -> anyhow::Result<()> {
+    let mut files = collections::BTreeMap::new();
+    for directory in ["new", "cur"] {
+      let entries = match fs::read_dir(self.path.join(directory)) {
+        Ok(entries) => entries,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => continue,
+        Err(error) => Err(error)?,
+      };
+      for entry in entries {
+        let path = entry?.path();
+        let name = path
+          .file_name()
+          .and_then(|name| name.to_str())
+          .context("invalid file name")?;
+        let (key, info) = name.split_once(":2,").unwrap_or((name, ""));
+        let flags: collections::BTreeSet<String> = FLAGS
+          .iter()
+          .filter(|(letter, _)| info.contains(*letter))
+          .map(|(_, flag)| flag.to_string())
+          .collect();
+        files.insert(key.to_string(), (path.clone(), flags));
+      }
+    }
+    let mut changed = false;
+    let mut messages = Vec::new();
+    for mut message in self.messages.drain(..) {
+      match files.remove(&message.key) {
+        Some((path, mut flags)) => {
+          // Keywords can't be stored in the file name.
+          flags.extend(
+            message
+              .flags
+              .iter()
+              .filter(|flag| !flag.starts_with('\\'))
+              .cloned(),
+          );
+          if flags != message.flags {
+            self.highestmodseq += 1;
+            (message.flags, message.modseq) = (flags, self.highestmodseq);
+            changed = true;
+          }
+          self.files.insert(message.key.clone(), path);
+          messages.push(message);
+        }
+        None => {
+          self.highestmodseq += 1;
+          self.vanished.push((message.uid, self.highestmodseq));
+          changed = true;
+        }
+      }
+    }
+    for (key, (path, flags)) in files {
+      self.highestmodseq += 1;
+      messages.push(Message {
+        uid: self.uidnext,
+        modseq: self.highestmodseq,
+        key: key.clone(),
+        flags,
+      });
+      self.uidnext += 1;
+      self.files.insert(key, path);
+      changed = true;
+    }
+    self.messages = messages;
+    if changed {
+      self.save()?;
+    }
+    Ok(())
+  }
+
+  fn sequence(&self, uid: u64) -> usize {
+    self
+      .messages
+      .iter()
+      .position(|message| message.uid == uid)
+      .unwrap()
+      + 1
+  }
+
+  fn largest(&self) -> u64 {
+    self.messages.last().map_or(0, |message| message.uid)
+  }
+
+  fn select(&self, set: &[(u64, u64)]) -> Vec<u64> {
+    self
+      .messages
+      .iter()
+      .map(|message| message.uid)
+      .filter(|uid| set.iter().any(|(start, end)| start <= uid && uid <= end))
+      .collect()
+  }
+
+  fn message(&self, uid: u64) -> &Message {
+    self
+      .messages
+      .iter()
+      .find(|message| message.uid == uid)
+      .unwrap()
+  }
+
+  fn message_mut(&mut self, uid: u64) -> &mut Message {
+    self
+      .messages
+      .iter_mut()
+      .find(|message| message.uid == uid)
+      .unwrap()
+  }
+
+  // Move the file so its name reflects the system flags.
+  fn rename(&mut self, uid: u64) -> io::Result<()> {
+    let message = self.message(uid);
+    let path = self.path.join("cur").join(message.name());
+    fs::rename(&self.files[&message.key], &path)?;
+    self.files.insert(message.key.clone(), path);
+    Ok(())
+  }
+
+  // Deliver a message and return its UID.
+  fn deliver(&mut self, name: &str, content: &[u8], keywords: &[String]) -> anyhow::Result<u64> {
+    let path = self.path.join("cur").join(name);
+    fs::write(&path, content)?;
+    self.scan()?;
+    let key = name.split_once(":2,").map_or(name, |(key, _)| key);
+    let message = self
+      .messages
+      .iter_mut()
+      .find(|message| message.key == key)
+      .context("delivered message not found")?;
+    message.flags.extend(keywords.iter().cloned());
+    let uid = message.uid;
+    self.save()?;
+    Ok(uid)
+  }
+}
+
+struct Connection<'a> {
+  root: &'a path::Path,
+  lock: &'a Mutex<()>,
+  quirks: Quirks,
+  triggered: &'a atomic::AtomicBool,
+  user: Option<String>,
+  qresync: bool,
+  selected: Option<String>,
+  // The selected mailbox's highestmodseq as far as this connection knows (see notify).
+  notified: u64,
+  logout: bool,
+}
+
+static DELIVERIES: atomic::AtomicUsize = atomic::AtomicUsize::new(0);
+
+impl<'a> Connection<'a> {
+  fn new(
+    root: &'a path::Path,
+    lock: &'a Mutex<()>,
+    quirks: Quirks,
+    triggered: &'a atomic::AtomicBool,
+  ) -> Self {
+    Self {
+      root,
+      lock,
+      quirks,
+      triggered,
+      user: None,
+      qresync: false,
+      selected: None,
+      notified: 0,
+      logout: false,
+    }
+  }
+
+  fn serve(&mut self, stream: net::TcpStream) -> anyhow::Result<()> {
+    let mut reader = io::BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    writer.write_all(format!("* OK [CAPABILITY {CAPABILITIES}] Mock ready.\r\n").as_bytes())?;
+    while let Some(tokens) = read_command(&mut reader, &mut writer)? {
+      let tag = tokens.first().context("empty command")?.atom()?.to_string();
+      if self.quirks == Quirks::Stall
+        && is_body_fetch(&tokens[1..])
+        && !self.triggered.swap(true, atomic::Ordering::Relaxed)
+      {
+        // Without holding the lock, the client reconnects in the meantime.
+        thread::sleep(STALL);
+        return Ok(());
+      }
+      let mut output = Vec::new();
+      let completion = {
+        let _guard = self.lock.lock().unwrap();
+        let highestmodseq = self.highestmodseq();
+        let completion = self.command(&tokens[1..], &mut output);
+        // The changes made by its own commands were reported along with them.
+        if highestmodseq == Some(self.notified) {
+          self.notified = self.highestmodseq().unwrap_or(0);
+        }
+        if self.quirks == Quirks::Concurrent && is_store(&tokens[1..]) {
+          self.flag_all()?;
+        }
+        completion
+      };
+      match completion {
+        Ok(completion) => output.extend(format!("{tag} {completion}\r\n").as_bytes()),
+        Err(error) => output.extend(format!("{tag} BAD {error}\r\n").as_bytes()),
+      }
+      writer.write_all(&output)?;
+      if self.logout {
+        break;
+      }
+    }
+    Ok(())
+  }
+
+  fn maildir(&self) -> anyhow::Result<path::PathBuf> {
+    let user = self.user.as_ref().context("not authenticated")?;
+    Ok(self.root.join(user).join("maildir"))
+  }
+
+  fn path(&self, mailbox: &str) -> anyhow::Result<path::PathBuf> {
+    let maildir = self.maildir()?;
+    Ok(match mailbox {
+      "INBOX" => maildir,
+      mailbox => maildir.join(format!(".{}", mailbox.replace('/', "."))),
+    })
+  }
+
+  fn exists(&self, mailbox: &str) -> anyhow::Result<bool> {
+    Ok(self.path(mailbox)?.join("cur").is_dir())
+  }
+
+  fn mailboxes(&self) -> anyhow::Result<Vec<String>> {
+    let mut mailboxes = vec!["INBOX".to_string()];
+    for entry in fs::read_dir(self.maildir()?)? {
+      let path = entry?.path();
+      let name = path.file_name().and_then(|name| name.to_str());
+      if let Some(name) = name.and_then(|name| name.strip_prefix('.')) {
+        if path.join("cur").is_dir() {
+          mailboxes.push(name.replace('.', "/"));
+        }
+      }
+    }
+    mailboxes.sort();
+    Ok(mailboxes)
+  }
+
+  fn authenticated_capabilities(&self) -> &'static str {
+    match self.quirks {
+      Quirks::Condstore => CONDSTORE_CAPABILITIES,
+      Quirks::None
+      | Quirks::Cyrus
+      | Quirks::NoUIDNext
+      | Quirks::Stall
+      | Quirks::Concurrent
+      | Quirks::Removal => AUTHENTICATED_CAPABILITIES,
+    }
+  }
+
+  fn selected(&self) -> anyhow::Result<Mailbox> {
+    let mailbox = self.selected.as_ref().context("no mailbox selected")?;
+    Mailbox::open(&self.path(mailbox)?)
+  }
+
+  fn highestmodseq(&self) -> Option<u64> {
+    self
+      .selected
+      .as_ref()
+      .and_then(|_| self.selected().ok())
+      .map(|state| state.highestmodseq)
+  }
+
+  // Only the flag changes made by other clients are reported, not the expunges.
+  fn notify(&mut self, output: &mut Vec<u8>) -> anyhow::Result<()> {
+    if self.selected.is_none() {
+      return Ok(());
+    }
+    let state = self.selected()?;
+    for (index, message) in state.messages.iter().enumerate() {
+      if message.modseq > self.notified {
+        output.extend(
+          format!(
+            "* {} FETCH (UID {} FLAGS {} MODSEQ ({}))\r\n",
+            index + 1,
+            message.uid,
+            flag_list(&message.flags),
+            message.modseq
+          )
+          .as_bytes(),
+        );
+      }
+    }
+    self.notified = state.highestmodseq;
+    Ok(())
+  }
+
+  // See Quirks::Concurrent.
+  fn flag_all(&mut self) -> anyhow::Result<()> {
+    let mut state = self.selected()?;
+    let uids: Vec<u64> = state.messages.iter().map(|message| message.uid).collect();
+    for uid in uids {
+      if state.message_mut(uid).flags.insert("\\Flagged".to_string()) {
+        state.highestmodseq += 1;
+        state.message_mut(uid).modseq = state.highestmodseq;
+        state.rename(uid)?;
+      }
+    }
+    state.save()?;
+    Ok(())
+  }
+
+  fn command(&mut self, tokens: &[Token], output: &mut Vec<u8>) -> anyhow::Result<String> {
+    let (command, arguments) = tokens.split_first().context("missing command")?;
+    let command = command.atom()?.to_uppercase();
+    match (command.as_str(), arguments) {
+      ("CAPABILITY", []) => {
+        let capabilities = match self.user {
+          Some(_) => self.authenticated_capabilities(),
+          None => CAPABILITIES,
+        };
+        output.extend(format!("* CAPABILITY {capabilities}\r\n").as_bytes());
+        Ok("OK Capability completed.".to_string())
+      }
+      ("NOOP", []) => {
+        self.notify(output)?;
+        Ok("OK NOOP completed.".to_string())
+      }
+      ("LOGOUT", []) => {
+        self.logout = true;
+        output.extend(b"* BYE Logging out\r\n");
+        Ok("OK Logout completed.".to_string())
+      }
+      ("AUTHENTICATE", [mechanism, initial]) => {
+        anyhow::ensure!(
+          mechanism.atom()?.eq_ignore_ascii_case("PLAIN"),
+          "unsupported mechanism"
+        );
+        // https://www.rfc-editor.org/rfc/rfc4616#section-2
+        // message = [authzid] UTF8NUL authcid UTF8NUL passwd
+        let message = base64::engine::general_purpose::STANDARD.decode(initial.atom()?)?;
+        let fields: Vec<&[u8]> = message.split(|byte| *byte == 0).collect();
+        match fields.as_slice() {
+          [_, user, b"password"] => {
+            let user = String::from_utf8(user.to_vec())?;
+            // Like Dovecot, the INBOX always exists.
+            for directory in ["cur", "new", "tmp"] {
+              fs::create_dir_all(self.root.join(&user).join("maildir").join(directory))?;
+            }
+            self.user = Some(user);
+            Ok(format!(
+              "OK [CAPABILITY {}] Logged in",
+              self.authenticated_capabilities()
+            ))
+          }
+          _ => Ok("NO [AUTHENTICATIONFAILED] Authentication failed.".to_string()),
+        }
+      }
+      ("ID", [_]) => {
+        let name = match self.quirks {
+          Quirks::Cyrus => "Cyrus IMAPD",
+          Quirks::None
+          | Quirks::Condstore
+          | Quirks::NoUIDNext
+          | Quirks::Stall
+          | Quirks::Concurrent
+          | Quirks::Removal => "mock",
+        };
+        output.extend(format!("* ID (\"name\" \"{name}\")\r\n").as_bytes());
+        Ok("OK ID completed.".to_string())
+      }
+      _ if self.user.is_none() => anyhow::bail!("not authenticated"),
+      ("ENABLE", capabilities) => {
+        for capability in capabilities {
+          if capability.atom()?.eq_ignore_ascii_case("QRESYNC") && self.quirks != Quirks::Condstore
+          {
+            self.qresync = true;
+            output.extend(b"* ENABLED QRESYNC\r\n");
+          }
+        }
+        Ok("OK Enabled.".to_string())
+      }
+      ("NAMESPACE", []) => {
+        // The mailboxes under Shared/ are listed along the personal ones but belong to the shared
+        // namespace.
+        output.extend(b"* NAMESPACE ((\"\" \"/\")) NIL ((\"Shared/\" \"/\"))\r\n");
+        Ok("OK Namespace completed.".to_string())
+      }
+      (command @ ("LIST" | "LSUB"), [_, pattern]) => {
+        let pattern = pattern.astring()?;
+        let subscriptions = match command {
+          "LSUB" => match fs::read_to_string(self.maildir()?.join("subscriptions")) {
+            Ok(content) => Some(content.lines().map(str::to_string).collect()),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Some(Vec::new()),
+            Err(error) => Err(error)?,
+          },
+          _ => None,
+        };
+        for mailbox in self.mailboxes()? {
+          let subscribed = subscriptions
+            .as_ref()
+            .is_none_or(|subscriptions: &Vec<String>| subscriptions.contains(&mailbox));
+          if subscribed && matches(&pattern, &mailbox) {
+            let name = match mailbox.as_str() {
+              "INBOX" => mailbox.clone(),
+              _ => quote(&mailbox),
+            };
+            // Roughly like Dovecot: the mailboxes with messages in new are marked.
+            let marked = fs::read_dir(self.path(&mailbox)?.join("new"))
+              .is_ok_and(|mut entries| entries.next().is_some());
+            let flags = if marked { "\\Marked" } else { "" };
+            output.extend(format!("* {command} ({flags}) \"/\" {name}\r\n").as_bytes());
+          }
+        }
+        Ok(format!("OK {command} completed."))
+      }
+      ("CREATE", [mailbox]) => {
+        let mailbox = mailbox.astring()?;
+        if self.exists(&mailbox)? {
+          return Ok("NO [ALREADYEXISTS] Mailbox already exists.".to_string());
+        }
+        let path = self.path(&mailbox)?;
+        for directory in ["cur", "new", "tmp"] {
+          fs::create_dir_all(path.join(directory))?;
+        }
+        fs::File::create(path.join("maildirfolder"))?;
+        Ok("OK Create completed.".to_string())
+      }
+      ("SELECT" | "EXAMINE", [mailbox, parameters @ ..]) => {
+        let mailbox = mailbox.astring()?;
+        if self.selected.take().is_some() {
+          // https://www.rfc-editor.org/rfc/rfc7162#section-3.2.11
+          output.extend(b"* OK [CLOSED] Previous mailbox closed.\r\n");
+        }
+        if !self.exists(&mailbox)? {
+          return Ok("NO Mailbox doesn't exist.".to_string());
+        }
+        // (QRESYNC (uidvalidity modseq)) or (CONDSTORE)
+        let qresync = match parameters {
+          [] => None,
+          [parameters] => match parameters.list()? {
+            [name] if name.atom()?.eq_ignore_ascii_case("CONDSTORE") => None,
+            [name, parameters] if name.atom()?.eq_ignore_ascii_case("QRESYNC") => {
+              anyhow::ensure!(self.qresync, "QRESYNC isn't enabled");
+              match parameters.list()? {
+                [uidvalidity, modseq, ..] => Some((
+                  uidvalidity.atom()?.parse::<u64>()?,
+                  modseq.atom()?.parse::<u64>()?,
+                )),
+                _ => anyhow::bail!("invalid QRESYNC parameters"),
+              }
+            }
+            _ => anyhow::bail!("unsupported SELECT parameters"),
+          },
+          _ => anyhow::bail!("unsupported SELECT parameters"),
+        };
+        let state = Mailbox::open(&self.path(&mailbox)?)?;
+        let keywords = match self.quirks {
+          Quirks::Cyrus => "",
+          Quirks::None
+          | Quirks::Condstore
+          | Quirks::NoUIDNext
+          | Quirks::Stall
+          | Quirks::Concurrent
+          | Quirks::Removal => " \\*",
+        };
+        output.extend(
+          format!(
+            "* FLAGS (\\Answered \\Flagged \\Deleted \\Seen \\Draft)\r\n\
+             * OK [PERMANENTFLAGS (\\Answered \\Flagged \\Deleted \\Seen \\Draft{keywords})] Flags \
+             permitted.\r\n\
+             * {} EXISTS\r\n\
+             * 0 RECENT\r\n\
+             * OK [UIDVALIDITY {}] UIDs valid\r\n",
+            state.messages.len(),
+            state.uidvalidity,
+          )
+          .as_bytes(),
+        );
+        if self.quirks != Quirks::NoUIDNext {
+          output
+            .extend(format!("* OK [UIDNEXT {}] Predicted next UID\r\n", state.uidnext).as_bytes());
+        }
+        output
+          .extend(format!("* OK [HIGHESTMODSEQ {}] Highest\r\n", state.highestmodseq).as_bytes());
+        if let Some((uidvalidity, modseq)) = qresync {
+          if uidvalidity == state.uidvalidity {
+            let vanished: Vec<u64> = state
+              .vanished
+              .iter()
+              .filter(|(_, modseq_)| modseq > 0 && *modseq_ > modseq)
+              .map(|(uid, _)| *uid)
+              .collect();
+            if !vanished.is_empty() {
+              output.extend(format!("* VANISHED (EARLIER) {}\r\n", uid_list(&vanished)).as_bytes());
+            }
+            for (index, message) in state.messages.iter().enumerate() {
+              if message.modseq > modseq {
+                output.extend(
+                  format!(
+                    "* {} FETCH (UID {} FLAGS {} MODSEQ ({}))\r\n",
+                    index + 1,
+                    message.uid,
+                    flag_list(&message.flags),
+                    message.modseq
+                  )
+                  .as_bytes(),
+                );
+              }
+            }
+          }
+        }
+        self.notified = state.highestmodseq;
+        self.selected = Some(mailbox);
+        Ok("OK [READ-WRITE] Select completed.".to_string())
+      }
+      ("APPEND", [mailbox, parameters @ .., message]) => {
+        let mailbox = mailbox.astring()?;
+        if self.quirks == Quirks::Removal && !self.triggered.swap(true, atomic::Ordering::Relaxed) {
+          fs::remove_dir_all(self.path(&mailbox)?)?;
+          self.selected = None;
+        }
+        if !self.exists(&mailbox)? {
+          return Ok("NO [TRYCREATE] Mailbox doesn't exist.".to_string());
+        }
+        let flags: Vec<String> = match parameters.first() {
+          Some(Token::List(flags)) => flags
+            .iter()
+            .map(|flag| Ok(flag.atom()?.to_string()))
+            .collect::<anyhow::Result<_>>()?,
+          _ => Vec::new(),
+        };
+        let Token::String(message) = message else {
+          anyhow::bail!("expected a literal");
+        };
+        let mut state = Mailbox::open(&self.path(&mailbox)?)?;
+        let mut info = String::new();
+        for (letter, flag) in FLAGS {
+          if flags.iter().any(|flag_| flag_.eq_ignore_ascii_case(flag)) {
+            info.push(*letter);
+          }
+        }
+        let name = format!(
+          "{}.M{}.mock:2,{info}",
+          state.uidvalidity,
+          DELIVERIES.fetch_add(1, atomic::Ordering::Relaxed)
+        );
+        let keywords: Vec<String> = flags
+          .into_iter()
+          .filter(|flag| !flag.starts_with('\\'))
+          .collect();
+        let uid = state.deliver(&name, message, &keywords)?;
+        output
+          .extend(format!("* OK [HIGHESTMODSEQ {}] Highest\r\n", state.highestmodseq).as_bytes());
+        Ok(format!(
+          "OK [APPENDUID {} {uid}] Append completed.",
+          state.uidvalidity
+        ))
+      }
+      ("UID", [command, arguments @ ..]) => {
+        let command = command.atom()?.to_uppercase();
+        self.uid(&command, arguments, output)
+      }
+      _ => anyhow::bail!("unsupported command"),
+    }
+  }
+
+  fn uid(
+    &mut self,
+    command: &str,
+    arguments: &[Token],
+    output: &mut Vec<u8>,
+  ) -> anyhow::Result<String> {
+    let mut state = self.selected()?;
+    match (command, arguments) {
+      ("FETCH", [set, items, modifiers @ ..]) => {
+        let uids = state.select(&uid_set(set.atom()?, state.largest())?);
+        let items: Vec<String> = match items {
+          Token::List(items) => items
+            .iter()
+            .map(|item| Ok(item.atom()?.to_uppercase()))
+            .collect::<anyhow::Result<_>>()?,
+          item => vec![item.atom()?.to_uppercase()],
+        };
+        // (CHANGEDSINCE modseq)
+        let changedsince = match modifiers {
+          [] => 0,
+          [modifiers] => match modifiers.list()? {
+            [name, modseq, ..] if name.atom()?.eq_ignore_ascii_case("CHANGEDSINCE") => {
+              modseq.atom()?.parse()?
+            }
+            _ => anyhow::bail!("unsupported FETCH modifiers"),
+          },
+          _ => anyhow::bail!("unsupported FETCH modifiers"),
+        };
+        for uid in uids {
+          let sequence = state.sequence(uid);
+          let message = state.message(uid);
+          if message.modseq <= changedsince {
+            continue;
+          }
+          let mut response = format!("* {sequence} FETCH (UID {uid}").into_bytes();
+          for item in &items {
+            match item.as_str() {
+              "UID" => continue,
+              "FLAGS" => {
+                response.extend(format!(" FLAGS {}", flag_list(&message.flags)).as_bytes())
+              }
+              "MODSEQ" => response.extend(format!(" MODSEQ ({})", message.modseq).as_bytes()),
+              // Gmail's message IDs (see sin's import), the UIDs will do with a single mailbox.
+              "X-GM-MSGID" => response.extend(format!(" X-GM-MSGID {uid}").as_bytes()),
+              "RFC822.SIZE" => {
+                let size = fs::metadata(&state.files[&message.key])?.len();
+                response.extend(format!(" RFC822.SIZE {size}").as_bytes());
+              }
+              "BODY.PEEK[]" | "BODY[]" => {
+                let body = fs::read(&state.files[&message.key])?;
+                response.extend(format!(" BODY[] {{{}}}\r\n", body.len()).as_bytes());
+                response.extend(body);
+              }
+              item => anyhow::bail!("unsupported FETCH item {item}"),
+            }
+          }
+          response.extend(b")\r\n");
+          output.extend(response);
+        }
+        Ok("OK Fetch completed.".to_string())
+      }
+      ("STORE", [set, arguments @ ..]) => {
+        let uids = state.select(&uid_set(set.atom()?, state.largest())?);
+        // [(UNCHANGEDSINCE modseq)] (+|-)FLAGS[.SILENT] (flags)
+        let (unchangedsince, operation, flags_) = match arguments {
+          [modifiers, operation, flags] => match modifiers.list()? {
+            [name, modseq] if name.atom()?.eq_ignore_ascii_case("UNCHANGEDSINCE") => (
+              Some(modseq.atom()?.parse::<u64>()?),
+              operation.atom()?.to_uppercase(),
+              flags,
+            ),
+            _ => anyhow::bail!("unsupported STORE modifiers"),
+          },
+          [operation, flags] => (None, operation.atom()?.to_uppercase(), flags),
+          _ => anyhow::bail!("invalid STORE"),
+        };
+        let flags_: collections::BTreeSet<String> = match flags_ {
+          Token::List(flags) => flags
+            .iter()
+            .map(|flag| Ok(flag.atom()?.to_string()))
+            .collect::<anyhow::Result<_>>()?,
+          flag => [flag.atom()?.to_string()].into(),
+        };
+        let silent = operation.ends_with(".SILENT");
+        let mut modified = Vec::new();
+        for uid in uids {
+          let sequence = state.sequence(uid);
+          if unchangedsince.is_some_and(|modseq| state.message(uid).modseq > modseq) {
+            modified.push(uid);
+            continue;
+          }
+          let message = state.message(uid);
+          let flags = match operation.trim_end_matches(".SILENT") {
+            "+FLAGS" => message.flags.union(&flags_).cloned().collect(),
+            "-FLAGS" => message.flags.difference(&flags_).cloned().collect(),
+            "FLAGS" => flags_.clone(),
+            operation => anyhow::bail!("unsupported STORE operation {operation}"),
+          };
+          if flags != message.flags {
+            state.highestmodseq += 1;
+            let modseq = state.highestmodseq;
+            let message = state.message_mut(uid);
+            (message.flags, message.modseq) = (flags, modseq);
+            state.rename(uid)?;
+            state.save()?;
+          }
+          let message = state.message(uid);
+          // https://www.rfc-editor.org/rfc/rfc7162#section-3.2
+          // An untagged FETCH response MUST be sent, even if the .SILENT suffix is specified, and
+          // the response MUST include the MODSEQ message data item.
+          let flags = match silent {
+            true => String::new(),
+            false => format!(" FLAGS {}", flag_list(&message.flags)),
+          };
+          output.extend(
+            format!(
+              "* {sequence} FETCH (UID {uid}{flags} MODSEQ ({}))\r\n",
+              message.modseq
+            )
+            .as_bytes(),
+          );
+        }
+        Ok(match modified.is_empty() {
+          true => "OK Store completed.".to_string(),
+          false => format!(
+            "OK [MODIFIED {}] Conditional store failed.",
+            uid_list(&modified)
+          ),
+        })
+      }
+      // RETURN (ALL) ALL or RETURN (ALL) MODSEQ modseq, answered with ESEARCH.
+      ("SEARCH", [return_, options, criteria @ ..]) => {
+        anyhow::ensure!(
+          return_.atom()?.eq_ignore_ascii_case("RETURN"),
+          "unsupported SEARCH"
+        );
+        match options.list()? {
+          [option] if option.atom()?.eq_ignore_ascii_case("ALL") => (),
+          _ => anyhow::bail!("unsupported SEARCH return options"),
+        }
+        let modseq = match criteria {
+          [all] if all.atom()?.eq_ignore_ascii_case("ALL") => 0,
+          [name, modseq] if name.atom()?.eq_ignore_ascii_case("MODSEQ") => {
+            modseq.atom()?.parse()?
+          }
+          _ => anyhow::bail!("unsupported SEARCH criteria"),
+        };
+        let uids: Vec<u64> = state
+          .messages
+          .iter()
+          .filter(|message| message.modseq >= modseq)
+          .map(|message| message.uid)
+          .collect();
+        let all = match uids.is_empty() {
+          true => String::new(),
+          false => format!(" ALL {}", uid_list(&uids)),
+        };
+        output.extend(format!("* ESEARCH UID{all}\r\n").as_bytes());
+        Ok("OK Search completed.".to_string())
+      }
+      ("MOVE", [set, mailbox]) => {
+        let uids = state.select(&uid_set(set.atom()?, state.largest())?);
+        let mailbox = mailbox.astring()?;
+        if !self.exists(&mailbox)? {
+          return Ok("NO [TRYCREATE] Mailbox doesn't exist.".to_string());
+        }
+        if uids.is_empty() {
+          return Ok("OK No messages found.".to_string());
+        }
+        let mut destination = Mailbox::open(&self.path(&mailbox)?)?;
+        let sequences: Vec<usize> = uids.iter().map(|uid| state.sequence(*uid)).collect();
+        let mut moved = Vec::new();
+        for uid in &uids {
+          let message = state.message(*uid);
+          let keywords: Vec<String> = message
+            .flags
+            .iter()
+            .filter(|flag| !flag.starts_with('\\'))
+            .cloned()
+            .collect();
+          let (name, key) = (message.name(), message.key.clone());
+          let content = fs::read(&state.files[&key])?;
+          fs::remove_file(&state.files[&key])?;
+          moved.push(destination.deliver(&name, &content, &keywords)?);
+        }
+        // The removed files are expunged.
+        state.scan()?;
+        output.extend(
+          format!(
+            "* OK [COPYUID {} {} {}] Moved UIDs.\r\n",
+            destination.uidvalidity,
+            uid_list(&uids),
+            uid_list(&moved)
+          )
+          .as_bytes(),
+        );
+        // https://www.rfc-editor.org/rfc/rfc7162#section-3.2.10
+        // Once a client has enabled QRESYNC, the server MUST use the VANISHED response rather than
+        // the EXPUNGE response.
+        match self.qresync {
+          true => output.extend(format!("* VANISHED {}\r\n", uid_list(&uids)).as_bytes()),
+          // Backwards, so the sequence numbers stay valid.
+          false => {
+            for sequence in sequences.iter().rev() {
+              output.extend(format!("* {sequence} EXPUNGE\r\n").as_bytes());
+            }
+          }
+        }
+        Ok("OK Move completed.".to_string())
+      }
+      _ => anyhow::bail!("unsupported UID command"),
+    }
+  }
+}
+
+// LIST patterns: * matches anything, % anything but the separator.
+fn matches(pattern: &str, mailbox: &str) -> bool {
+  match pattern.chars().next() {
+    None => mailbox.is_empty(),
+    Some('*') => (0..=mailbox.len())
+      .filter(|index| mailbox.is_char_boundary(*index))
+      .any(|index| matches(&pattern[1..], &mailbox[index..])),
+    Some('%') => (0..=mailbox.len())
+      .filter(|index| mailbox.is_char_boundary(*index) && !mailbox[..*index].contains('/'))
+      .any(|index| matches(&pattern[1..], &mailbox[index..])),
+    Some(character) => mailbox
+      .strip_prefix(character)
+      .is_some_and(|mailbox| matches(&pattern[character.len_utf8()..], mailbox)),
+  }
+}
@@ -1,19 +1,28 @@
 use anyhow::Context as _;
-use std::{io, num, ops, panic, path, process, thread, time};
+use std::{fs, io, io::Write as _, net, num, ops, panic, path, process, thread, time};
 
 #[derive(Debug)]
-pub struct Child(process::Child);
+pub enum Child {
+  Process(process::Child),
+  // Stops when dropped.
+  #[allow(dead_code)] // Not every test uses the mock server.
+  Thread(mock::Server),
+}
 
 impl ops::Drop for Child {
   fn drop(&mut self) {
-    if let Err(error) = self.0.kill() {
-      log::warn!("couldn't kill {self:?} {error}")
+    if let Child::Process(child) = self {
+      if let Err(error) = child.kill() {
+        log::warn!("couldn't kill {child:?} {error}")
+      }
     }
   }
 }
 
 pub mod dovecot;
 mod maildir;
+#[allow(dead_code)] // Not every test uses the mock server.
+pub mod mock;
 mod notmuch;
 
 #[derive(Clone)]
@@ -24,7 +33,38 @@ pub struct Runner {
   user: String,
   password: String,
   purgeable: Vec<String>,
+  purge_all_removed: bool,
+  max_purge: Option<sin::MaxPurge>,
+  max_messages: Option<sin::MaxMessages>,
+  new_tags: bool,
+  notmuch_config: Option<String>,
+  mailbox_tag: Option<sin::MailboxTag>,
+  tunnel: Option<String>,
+  tls: bool,
+  auth_mechanism: Option<sin::Mechanism>,
+  subscribed_only: bool,
+  namespace_include: Vec<sin::NamespaceKind>,
+  priority: Vec<String>,
+  create_mailboxes: bool,
+  flag_merge: Option<sin::FlagMerge>,
+  expunge: bool,
+  move_rules: Vec<sin::Rule>,
+  rules: Vec<sin::Rule>,
+  local_only: Vec<path::PathBuf>,
+  mailbox: Option<String>,
+  message: Option<path::PathBuf>,
+  buffer_limit: usize,
+  post_pull_command: Option<String>,
   interruption: Option<sin::Interruption>,
+  faults: Vec<sin::Fault>,
+  trace_file: Option<String>,
+  replay_file: Option<String>,
+  threads: usize,
+  backend: Option<sin::Backend>,
+  fetch_batch: usize,
+  pipeline_depth: usize,
+  keepalive: time::Duration,
+  timeout: time::Duration,
 }
 
 impl Runner {
@@ -36,7 +76,38 @@ impl Runner {
       user: "user".to_string(),
       password: "password".to_string(),
       purgeable: Vec::new(),
+      purge_all_removed: false,
+      max_purge: None,
+      max_messages: None,
+      new_tags: false,
+      notmuch_config: None,
+      mailbox_tag: None,
+      tunnel: None,
+      tls: false,
+      auth_mechanism: None,
+      subscribed_only: false,
+      namespace_include: Vec::new(),
+      priority: Vec::new(),
+      create_mailboxes: false,
+      flag_merge: None,
+      expunge: false,
+      move_rules: Vec::new(),
+      rules: Vec::new(),
+      local_only: Vec::new(),
+      mailbox: None,
+      message: None,
+      buffer_limit: 1024 * 1024 * 1024,
+      post_pull_command: None,
       interruption: None,
+      faults: Vec::new(),
+      trace_file: None,
+      replay_file: None,
+      threads: 8,
+      backend: None,
+      fetch_batch: 100,
+      pipeline_depth: 16,
+      keepalive: time::Duration::from_secs(60),
+      timeout: time::Duration::from_secs(10),
     }
   }
 
@@ -61,6 +132,176 @@ impl Runner {
     }
   }
 
+  pub fn with_purge_all_removed(&self) -> Self {
+    Self {
+      purge_all_removed: true,
+      ..self.clone()
+    }
+  }
+
+  pub fn with_max_purge(&self, max_purge: sin::MaxPurge) -> Self {
+    Self {
+      max_purge: Some(max_purge),
+      ..self.clone()
+    }
+  }
+  pub fn with_max_messages(&self, max_messages: sin::MaxMessages) -> Self {
+    Self {
+      max_messages: Some(max_messages),
+      ..self.clone()
+    }
+  }
+
+  pub fn with_new_tags(&self) -> Self {
+    Self {
+      new_tags: true,
+      ..self.clone()
+    }
+  }
+
+  pub fn with_notmuch_config(&self, config: &path::Path) -> Self {
+    Self {
+      notmuch_config: config.to_str().map(str::to_string),
+      ..self.clone()
+    }
+  }
+
+  pub fn with_mailbox_tag(&self, prefix: &str, suffix: &str) -> Self {
+    Self {
+      mailbox_tag: Some(sin::MailboxTag {
+        prefix: prefix.to_string(),
+        suffix: suffix.to_string(),
+      }),
+      ..self.clone()
+    }
+  }
+
+  pub fn with_tunnel(&self) -> anyhow::Result<Self> {
+    let configuration = self.directory.join("test.conf");
+    Ok(Self {
+      tunnel: Some(format!(
+        "USER={} dovecot -c {} --exec-mail imap",
+        self.user,
+        configuration.to_str().with_context(|| "invalid file")?
+      )),
+      ..self.clone()
+    })
+  }
+
+  // The server's certificate is self-signed.
+  pub fn with_tls(&self) -> Self {
+    Self {
+      tls: true,
+      ..self.clone()
+    }
+  }
+
+  pub fn with_auth_mechanism(&self, mechanism: sin::Mechanism) -> Self {
+    Self {
+      auth_mechanism: Some(mechanism),
+      ..self.clone()
+    }
+  }
+
+  pub fn with_subscribed_only(&self) -> Self {
+    Self {
+      subscribed_only: true,
+      ..self.clone()
+    }
+  }
+
+  pub fn with_namespace_include(&self, kind: sin::NamespaceKind) -> Self {
+    let mut namespace_include = self.namespace_include.clone();
+    namespace_include.push(kind);
+    Self {
+      namespace_include,
+      ..self.clone()
+    }
+  }
+
+  pub fn with_priority(&self, mailbox: &str) -> Self {
+    let mut priority = self.priority.clone();
+    priority.push(mailbox.to_string());
+    Self {
+      priority,
+      ..self.clone()
+    }
+  }
+
+  pub fn with_create_mailboxes(&self) -> Self {
+    Self {
+      create_mailboxes: true,
+      ..self.clone()
+    }
+  }
+
+  pub fn with_flag_merge(&self, flag_merge: sin::FlagMerge) -> Self {
+    Self {
+      flag_merge: Some(flag_merge),
+      ..self.clone()
+    }
+  }
+
+  pub fn with_expunge(&self) -> Self {
+    Self {
+      expunge: true,
+      ..self.clone()
+    }
+  }
+
+  pub fn with_move_rule(&self, tag: &str, mailbox: &str) -> Self {
+    let mut move_rules = self.move_rules.clone();
+    move_rules.push(sin::Rule {
+      tag: tag.to_string(),
+      action: sin::Action::Move(mailbox.to_string()),
+    });
+    Self {
+      move_rules,
+      ..self.clone()
+    }
+  }
+
+  pub fn with_rule(&self, tag: &str, action: sin::Action) -> Self {
+    let mut rules = self.rules.clone();
+    rules.push(sin::Rule {
+      tag: tag.to_string(),
+      action,
+    });
+    Self {
+      rules,
+      ..self.clone()
+    }
+  }
+
+  pub fn with_local_only(&self, folder: &str) -> Self {
+    Self {
+      local_only: vec![path::PathBuf::from(folder)],
+      ..self.clone()
+    }
+  }
+
+  pub fn with_append(&self, mailbox: &str, message: &path::Path) -> Self {
+    Self {
+      mailbox: Some(mailbox.to_string()),
+      message: Some(message.to_path_buf()),
+      ..self.clone()
+    }
+  }
+
+  pub fn with_buffer_limit(&self, limit: usize) -> Self {
+    Self {
+      buffer_limit: limit,
+      ..self.clone()
+    }
+  }
+
+  pub fn with_post_pull_command(&self, command: &str) -> Self {
+    Self {
+      post_pull_command: Some(command.to_string()),
+      ..self.clone()
+    }
+  }
+
   pub fn with_interruption(&self, interruption: sin::Interruption) -> Self {
     Self {
       interruption: Some(interruption),
@@ -68,6 +309,68 @@ impl Runner {
     }
   }
 
+  // kind:site[@occurrence], see sin::Fault::parse.
+  pub fn with_fault(&self, fault: &str) -> anyhow::Result<Self> {
+    let mut faults = self.faults.clone();
+    faults.push(sin::Fault::parse(fault)?);
+    Ok(Self {
+      faults,
+      ..self.clone()
+    })
+  }
+
+  // Relative to the test's directory.
+  pub fn with_trace_file(&self, name: &str) -> Self {
+    Self {
+      trace_file: self.directory.join(name).to_str().map(str::to_string),
+      ..self.clone()
+    }
+  }
+
+  pub fn with_threads(&self, threads: usize) -> Self {
+    Self {
+      threads,
+      ..self.clone()
+    }
+  }
+
+  pub fn with_backend(&self, backend: sin::Backend) -> Self {
+    Self {
+      backend: Some(backend),
+      ..self.clone()
+    }
+  }
+
+  pub fn with_batching(&self, fetch_batch: usize, pipeline_depth: usize) -> Self {
+    Self {
+      fetch_batch,
+      pipeline_depth,
+      ..self.clone()
+    }
+  }
+
+  pub fn with_keepalive(&self, keepalive: time::Duration) -> Self {
+    Self {
+      keepalive,
+      ..self.clone()
+    }
+  }
+
+  pub fn with_timeout(&self, timeout: time::Duration) -> Self {
+    Self {
+      timeout,
+      ..self.clone()
+    }
+  }
+
+  // Replay a trace instead of talking to the server.
+  pub fn with_replay_file(&self, name: &str) -> Self {
+    Self {
+      replay_file: self.directory.join(name).to_str().map(str::to_string),
+      ..self.clone()
+    }
+  }
+
   fn server_maildir_builder(&self) -> io::Result<sin::maildir::Builder> {
     sin::maildir::Builder::new(&self.directory.join(&self.user).join("maildir"))
   }
@@ -80,29 +383,37 @@ impl Runner {
     self.server_maildir_builder()?.maildir(mailbox, separator)
   }
 
+  // A message in new, the mock lists its mailbox as \Marked.
+  pub fn server_new(&self, mailbox: &str, buffer: &[u8]) -> io::Result<path::PathBuf> {
+    let path = self.server_maildir(mailbox, &None)?.cur(buffer)?;
+    let new = path
+      .parent()
+      .unwrap()
+      .with_file_name("new")
+      .join(path.file_name().unwrap());
+    fs::rename(&path, &new)?;
+    Ok(new)
+  }
+
+  // Dovecot still reads the original subscriptions file format: one mailbox per line.
+  pub fn server_subscribe(&self, mailbox: &str) -> io::Result<()> {
+    let mut file = fs::OpenOptions::new().create(true).append(true).open(
+      self
+        .directory
+        .join(&self.user)
+        .join("maildir")
+        .join("subscriptions"),
+    )?;
+    writeln!(file, "{mailbox}")
+  }
+
   pub fn run(&self, mode: sin::Mode) -> anyhow::Result<()> {
-    let arguments = sin::Arguments {
-      mode,
-      address: "localhost".to_string(),
-      port: self.port,
-      threads: num::NonZeroUsize::new(8).unwrap(),
-      tls: false,
-      timeout: Some(time::Duration::new(10, 0)),
-      user: self.user.clone(),
-      password_command: vec!["echo".to_string(), self.password.clone()],
-      notmuch: Some(
-        self
-          .output
-          .to_str()
-          .with_context(|| "invalid directory")?
-          .to_string(),
-      ),
-      maildir: self.user.to_string(),
-      create: true,
-      purgeable: self.purgeable.clone(),
-      namespace: "sin".to_string(),
-      interruption: self.interruption,
-    };
+    self.run_all(&[mode])
+  }
+
+  // Over the same connection.
+  pub fn run_all(&self, modes: &[sin::Mode]) -> anyhow::Result<()> {
+    let arguments = self.arguments(modes)?;
     match &self.interruption {
       Some(interruption) => {
         let error = sin::run(&arguments).unwrap_err();
@@ -118,6 +429,118 @@ impl Runner {
     }
   }
 
+  pub fn arguments(&self, modes: &[sin::Mode]) -> anyhow::Result<sin::Arguments> {
+    Ok(sin::Arguments {
+      modes: modes.to_vec(),
+      address: Some("localhost".to_string()).filter(|_| self.tunnel.is_none()),
+      port: Some(self.port).filter(|_| self.tunnel.is_none()),
+      sieve_port: 4190,
+      tunnel: self.tunnel.clone(),
+      auto: false,
+      threads: num::NonZeroUsize::new(self.threads).unwrap(),
+      fetch_batch: num::NonZeroUsize::new(self.fetch_batch).unwrap(),
+      pipeline_depth: num::NonZeroUsize::new(self.pipeline_depth).unwrap(),
+      keepalive: self.keepalive,
+      tls: self.tls,
+      tls_client_cert: None,
+      tls_client_key: None,
+      tls_ca: None,
+      tls_pin: Vec::new(),
+      tls_insecure: self.tls,
+      timeout: Some(self.timeout),
+      user: self.user.clone(),
+      auth_mechanism: self.auth_mechanism,
+      password_keyring: None,
+      password_env: None,
+      password_prompt: false,
+      password_retries: 0,
+      ask_password: false,
+      password_command: match self.tunnel {
+        Some(_) => Vec::new(),
+        None => vec!["echo".to_string(), self.password.clone()],
+      },
+      id_name: "sin".to_string(),
+      id_version: "test".to_string(),
+      server_quirks: None,
+      buffer_limit: self.buffer_limit,
+      backend: self.backend,
+      notmuch: Some(
+        self
+          .output
+          .to_str()
+          .with_context(|| "invalid directory")?
+          .to_string(),
+      ),
+      // Only read by the maildir backend.
+      maildir_root: Some(
+        self
+          .output
+          .to_str()
+          .with_context(|| "invalid directory")?
+          .to_string(),
+      ),
+      notmuch_config: self.notmuch_config.clone(),
+      notmuch_profile: None,
+      maildir: self.user.to_string(),
+      create: true,
+      upgrade: false,
+      #[cfg(feature = "notmuch")]
+      decrypt: None,
+      new_tags: self.new_tags,
+      mailbox_tag: self.mailbox_tag.clone(),
+      purgeable: self.purgeable.clone(),
+      purge_all_removed: self.purge_all_removed,
+      max_purge: self.max_purge,
+      ignore_max_purge: false,
+      max_messages: self.max_messages,
+      namespace_include: self.namespace_include.clone(),
+      subscribed_only: self.subscribed_only,
+      priority: self.priority.clone(),
+      namespace: "sin".to_string(),
+      debounce: time::Duration::new(1, 0),
+      post_pull_hook: false,
+      post_pull_command: self.post_pull_command.clone(),
+      lock_timeout: None,
+      durability: false,
+      maildir_naming: None,
+      layout: None,
+      create_mailboxes: self.create_mailboxes,
+      flag_merge: self.flag_merge,
+      expunge: self.expunge,
+      move_rules: self.move_rules.clone(),
+      rules: self.rules.clone(),
+      local_only: self.local_only.clone(),
+      query: None,
+      json: false,
+      property: None,
+      value: None,
+      mailbox: self.mailbox.clone(),
+      message: self.message.clone(),
+      namespace_id: None,
+      migrate_from: None,
+      import_from: None,
+      import_state: None,
+      import_source: None,
+      trace_file: self.trace_file.clone(),
+      interruption: self.interruption,
+      faults: self.faults.clone(),
+      replay_file: self.replay_file.clone(),
+    })
+  }
+
+  pub fn port(&self) -> u16 {
+    self.port
+  }
+
+  pub fn user(&self) -> &str {
+    &self.user
+  }
+
+  // The files written next to the maildirs (e.g.: --trace-file).
+  pub fn read(&self, name: &str) -> io::Result<String> {
+    fs::read_to_string(self.directory.join(name))
+  }
+
   pub fn client_maildir_builder(&self) -> io::Result<sin::maildir::Builder> {
     sin::maildir::Builder::new(&self.output.join(&self.user))
   }
@@ -141,6 +564,10 @@ impl Runner {
     notmuch::dump(&self.output)
   }
 
+  pub fn notmuch_config(&self, key: &str) -> anyhow::Result<String> {
+    notmuch::config(&self.output, key)
+  }
+
   pub fn notmuch_new(&self) -> anyhow::Result<()> {
     notmuch::run(&self.output, &["new", "--no-hooks"])
   }
@@ -158,7 +585,8 @@ where
   let (directory, _child /* killed at the end of the function */, port) = server().unwrap();
   let runner = Runner::new(directory.path(), port);
   log::debug!("waiting for the server to be ready...");
-  while let Err(error) = runner.run(sin::Mode::ConnectOnly) {
+  // Agnostic to the transport (a plain connection to a TLS server would never be ready).
+  while let Err(error) = net::TcpStream::connect(("localhost", port)) {
     log::trace!("error while waiting for the server to be ready: {error:?}");
     thread::sleep(time::Duration::from_millis(100));
   }
@@ -173,6 +601,14 @@ where
   }
 }
 
+// The line of the first command naming the mailbox in a trace (see Runner::with_trace_file), its
+// SELECT.
+pub fn trace_position(trace: &str, mailbox: &str) -> Option<usize> {
+  trace
+    .lines()
+    .position(|line| line.contains(" C ") && line.contains(mailbox))
+}
+
 pub fn email(id: &str) -> String {
   format!(
     "From: {id}
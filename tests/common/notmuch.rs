@@ -10,13 +10,29 @@ pub fn dump(database: &path::Path) -> anyhow::Result<String> {
     .args(&["--config", "", "dump"])
     .output()?
     .stdout;
+  // The configuration is checked separately (see config), sin sets search.exclude_tags.
+  let stdout = str::from_utf8(&stdout)
+    .unwrap()
+    .lines()
+    .filter(|line| !line.starts_with("#@ "))
+    .map(|line| format!("{line}\n"))
+    .collect::<String>();
   Ok(
     regex
-      .replace_all(str::from_utf8(&stdout).unwrap(), ".uidvalidity=<omitted>")
+      .replace_all(&stdout, ".uidvalidity=<omitted>")
       .to_string(),
   )
 }
 
+pub fn config(database: &path::Path, key: &str) -> anyhow::Result<String> {
+  let output = process::Command::new("notmuch")
+    .env("NOTMUCH_DATABASE", database.as_os_str())
+    .args(["--config", "", "config", "get", key])
+    .output()?;
+  assert!(output.status.success());
+  Ok(String::from_utf8(output.stdout)?)
+}
+
 pub fn run(database: &path::Path, arguments: &[&str]) -> anyhow::Result<()> {
   let mut arguments_ = vec!["--config", ""];
   arguments_.extend(arguments);
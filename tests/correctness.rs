@@ -17,9 +17,9 @@ fn lastmod() {
 
     pretty_assertions::assert_eq!("#notmuch-dump batch-tag:3 config,properties,tags
 +sin.internal -- id:0@sin
-#= 0@sin sin.INBOX.highestmodseq=2 sin.INBOX.separator=%2f sin.INBOX.uidvalidity=<omitted> sin.lastmod=4 sin.mailbox=INBOX sin.marker=root
+#= 0@sin sin.INBOX.highestmodseq=2 sin.INBOX.separator=%2f sin.INBOX.uidnext=2 sin.INBOX.uidvalidity=<omitted> sin.account=user@localhost sin.lastmod=4 sin.mailbox=INBOX sin.marker=root sin.schema=1
 +unread -- id:test
-#= test sin.0.INBOX.modseq=2 sin.0.INBOX.tag=unread sin.0.INBOX.uid=1 sin.0.INBOX.uidvalidity=<omitted> sin.0.mailbox=INBOX sin.0.marker=message
+#= test sin.0.INBOX.modseq.1=2 sin.0.INBOX.tag=unread sin.0.INBOX.uid=1 sin.0.INBOX.uidvalidity=<omitted> sin.0.mailbox=INBOX sin.0.marker=message
 ", runner.notmuch_dump()?);
 
     Ok(())
@@ -39,9 +39,9 @@ fn quoting() {
 
     pretty_assertions::assert_eq!(format!("#notmuch-dump batch-tag:3 config,properties,tags
 +sin.internal -- id:0@sin
-#= 0@sin sin.INBOX.highestmodseq=1 sin.INBOX.separator=%2f sin.INBOX.uidvalidity=<omitted> sin.{urlencoded_folder}.highestmodseq=2 sin.{urlencoded_folder}.separator=%2f sin.{urlencoded_folder}.uidvalidity=<omitted> sin.mailbox=INBOX sin.mailbox={urlencoded_folder} sin.marker=root
+#= 0@sin sin.INBOX.highestmodseq=1 sin.INBOX.separator=%2f sin.INBOX.uidnext=1 sin.INBOX.uidvalidity=<omitted> sin.account=user@localhost sin.{urlencoded_folder}.highestmodseq=2 sin.{urlencoded_folder}.separator=%2f sin.{urlencoded_folder}.uidnext=2 sin.{urlencoded_folder}.uidvalidity=<omitted> sin.mailbox=INBOX sin.mailbox={urlencoded_folder} sin.marker=root sin.schema=1
 +unread -- id:test1
-#= test1 sin.0.{urlencoded_folder}.modseq=2 sin.0.{urlencoded_folder}.tag=unread sin.0.{urlencoded_folder}.uid=1 sin.0.{urlencoded_folder}.uidvalidity=<omitted> sin.0.mailbox={urlencoded_folder} sin.0.marker=message
+#= test1 sin.0.{urlencoded_folder}.modseq.1=2 sin.0.{urlencoded_folder}.tag=unread sin.0.{urlencoded_folder}.uid=1 sin.0.{urlencoded_folder}.uidvalidity=<omitted> sin.0.mailbox={urlencoded_folder} sin.0.marker=message
 "), runner.notmuch_dump()?);
 
     fs::rename(
@@ -63,11 +63,11 @@ fn quoting() {
 
     pretty_assertions::assert_eq!(format!("#notmuch-dump batch-tag:3 config,properties,tags
 +sin.internal -- id:0@sin
-#= 0@sin sin.INBOX.highestmodseq=1 sin.INBOX.separator=%2f sin.INBOX.uidvalidity=<omitted> sin.{urlencoded_folder}.highestmodseq=3 sin.{urlencoded_folder}.separator=%2f sin.{urlencoded_folder}.uidvalidity=<omitted> sin.lastmod=7 sin.mailbox=INBOX sin.mailbox={urlencoded_folder} sin.marker=root
+#= 0@sin sin.INBOX.highestmodseq=1 sin.INBOX.separator=%2f sin.INBOX.uidnext=1 sin.INBOX.uidvalidity=<omitted> sin.account=user@localhost sin.{urlencoded_folder}.highestmodseq=6 sin.{urlencoded_folder}.separator=%2f sin.{urlencoded_folder}.uidnext=2 sin.{urlencoded_folder}.uidvalidity=<omitted> sin.lastmod=7 sin.mailbox=INBOX sin.mailbox={urlencoded_folder} sin.marker=root sin.schema=1
 +test1 +unknown-0 +unread -- id:test1
-#= test1 sin.0.{urlencoded_folder}.modseq=6 sin.0.{urlencoded_folder}.tag=test1 sin.0.{urlencoded_folder}.tag=unknown-0 sin.0.{urlencoded_folder}.tag=unread sin.0.{urlencoded_folder}.uid=1 sin.0.{urlencoded_folder}.uidvalidity=<omitted> sin.0.mailbox={urlencoded_folder} sin.0.marker=message
+#= test1 sin.0.{urlencoded_folder}.modseq.1=6 sin.0.{urlencoded_folder}.tag=test1 sin.0.{urlencoded_folder}.tag=unknown-0 sin.0.{urlencoded_folder}.tag=unread sin.0.{urlencoded_folder}.uid=1 sin.0.{urlencoded_folder}.uidvalidity=<omitted> sin.0.mailbox={urlencoded_folder} sin.0.marker=message
 +inbox +unread -- id:test2
-#= test2 sin.0.{urlencoded_folder}.modseq=5 sin.0.{urlencoded_folder}.tag=inbox sin.0.{urlencoded_folder}.tag=unread sin.0.{urlencoded_folder}.uid=2 sin.0.{urlencoded_folder}.uidvalidity=<omitted> sin.0.mailbox={urlencoded_folder} sin.0.marker=message
+#= test2 sin.0.{urlencoded_folder}.modseq.2=5 sin.0.{urlencoded_folder}.tag=inbox sin.0.{urlencoded_folder}.tag=unread sin.0.{urlencoded_folder}.uid=2 sin.0.{urlencoded_folder}.uidvalidity=<omitted> sin.0.mailbox={urlencoded_folder} sin.0.marker=message
 "), runner.notmuch_dump()?);
 
     Ok(())
@@ -0,0 +1,73 @@
+// cargo test --features faults
+// The failures the synchronization recovers from, injected at named sites (see sin::Fault).
+
+use test_log::test;
+
+mod common;
+
+#[test]
+fn command() {
+  common::setup(common::mock::server, |runner| -> _ {
+    let server_inbox = runner.server_maildir("INBOX", &None)?;
+    server_inbox.cur(common::email("test").as_bytes())?;
+
+    // As if the connection was reset: the SELECT is repeated on a new connection.
+    runner.with_fault("command:SELECT")?.run(sin::Mode::Pull)?;
+
+    let client_inbox = runner.client_maildir("INBOX", &None)?;
+    assert_eq!((0, 1, 0), runner.maildir_count(&client_inbox)?);
+
+    Ok(())
+  })
+}
+
+#[test]
+fn short_read() {
+  common::setup(common::mock::server, |runner| -> _ {
+    let server_inbox = runner.server_maildir("INBOX", &None)?;
+    server_inbox.cur(common::email("test").as_bytes())?;
+
+    let error = runner
+      .with_fault("short-read:FETCH")?
+      .run(sin::Mode::Pull)
+      .unwrap_err();
+    assert_eq!("injected fault: short read", error.root_cause().to_string());
+
+    // The next pull picks up where it stopped.
+    runner.run(sin::Mode::Pull)?;
+    let client_inbox = runner.client_maildir("INBOX", &None)?;
+    assert_eq!((0, 1, 0), runner.maildir_count(&client_inbox)?);
+
+    Ok(())
+  })
+}
+
+#[test]
+fn notmuch() {
+  common::setup(common::mock::server, |runner| -> _ {
+    let server_inbox = runner.server_maildir("INBOX", &None)?;
+    server_inbox.cur(common::email("test").as_bytes())?;
+
+    let error = runner
+      .with_fault("notmuch:add")?
+      .run(sin::Mode::Pull)
+      .unwrap_err();
+    assert_eq!(
+      "injected fault: notmuch add failed",
+      error.root_cause().to_string()
+    );
+
+    // Nothing was recorded, the message isn't downloaded twice.
+    runner.run(sin::Mode::Pull)?;
+    let client_inbox = runner.client_maildir("INBOX", &None)?;
+    assert_eq!((0, 1, 0), runner.maildir_count(&client_inbox)?);
+    pretty_assertions::assert_eq!("#notmuch-dump batch-tag:3 config,properties,tags
++sin.internal -- id:0@sin
+#= 0@sin sin.INBOX.highestmodseq=2 sin.INBOX.separator=%2f sin.INBOX.uidnext=2 sin.INBOX.uidvalidity=<omitted> sin.account=user@localhost sin.mailbox=INBOX sin.marker=root sin.schema=1
++unread -- id:test
+#= test sin.0.INBOX.modseq.1=2 sin.0.INBOX.tag=unread sin.0.INBOX.uid=1 sin.0.INBOX.uidvalidity=<omitted> sin.0.mailbox=INBOX sin.0.marker=message
+", runner.notmuch_dump()?);
+
+    Ok(())
+  })
+}
@@ -38,9 +38,9 @@ fn successful_move_pre_commit_begin(runner: &common::Runner) -> anyhow::Result<(
   // But the current state doesn't agree.
   pretty_assertions::assert_eq!("#notmuch-dump batch-tag:3 config,properties,tags
 +sin.internal -- id:0@sin
-#= 0@sin sin.INBOX.highestmodseq=1 sin.INBOX.separator=%2f sin.INBOX.uidvalidity=<omitted> sin.folder.highestmodseq=1 sin.folder.separator=%2f sin.folder.uidvalidity=<omitted> sin.lastmod=4 sin.mailbox=INBOX sin.mailbox=folder sin.marker=root
+#= 0@sin sin.INBOX.highestmodseq=3 sin.INBOX.separator=%2f sin.INBOX.uidnext=1 sin.INBOX.uidvalidity=<omitted> sin.account=user@localhost sin.folder.highestmodseq=1 sin.folder.separator=%2f sin.folder.uidnext=1 sin.folder.uidvalidity=<omitted> sin.lastmod=4 sin.mailbox=INBOX sin.mailbox=folder sin.marker=root sin.schema=1
 +inbox +unread -- id:test
-#= test sin.0.INBOX.modseq=3 sin.0.INBOX.tag=inbox sin.0.INBOX.tag=unread sin.0.INBOX.uid=1 sin.0.INBOX.uidvalidity=<omitted> sin.0.mailbox=INBOX sin.0.marker=message
+#= test sin.0.INBOX.modseq.1=3 sin.0.INBOX.tag=inbox sin.0.INBOX.tag=unread sin.0.INBOX.uid=1 sin.0.INBOX.uidvalidity=<omitted> sin.0.mailbox=INBOX sin.0.marker=message
 ", runner.notmuch_dump()?);
 
   Ok(())
@@ -52,9 +52,9 @@ fn successful_move_pre_commit_end(runner: &common::Runner) -> anyhow::Result<()>
   // No more inbox.
   pretty_assertions::assert_eq!("#notmuch-dump batch-tag:3 config,properties,tags
 +sin.internal -- id:0@sin
-#= 0@sin sin.INBOX.highestmodseq=4 sin.INBOX.separator=%2f sin.INBOX.uidvalidity=<omitted> sin.folder.highestmodseq=3 sin.folder.separator=%2f sin.folder.uidvalidity=<omitted> sin.lastmod=4 sin.mailbox=INBOX sin.mailbox=folder sin.marker=root
+#= 0@sin sin.INBOX.highestmodseq=4 sin.INBOX.separator=%2f sin.INBOX.uidnext=2 sin.INBOX.uidvalidity=<omitted> sin.account=user@localhost sin.folder.highestmodseq=3 sin.folder.separator=%2f sin.folder.uidnext=2 sin.folder.uidvalidity=<omitted> sin.lastmod=4 sin.mailbox=INBOX sin.mailbox=folder sin.marker=root sin.schema=1
 +inbox +unread -- id:test
-#= test sin.0.folder.modseq=3 sin.0.folder.tag=inbox sin.0.folder.tag=unread sin.0.folder.uid=1 sin.0.folder.uidvalidity=<omitted> sin.0.mailbox=folder sin.0.marker=message
+#= test sin.0.folder.modseq.1=3 sin.0.folder.tag=inbox sin.0.folder.tag=unread sin.0.folder.uid=1 sin.0.folder.uidvalidity=<omitted> sin.0.mailbox=folder sin.0.marker=message
 ", runner.notmuch_dump()?);
 
   Ok(())
@@ -104,9 +104,9 @@ fn move_out_of_tmp_post_rename() {
 
     pretty_assertions::assert_eq!("#notmuch-dump batch-tag:3 config,properties,tags
 +sin.internal -- id:0@sin
-#= 0@sin sin.INBOX.highestmodseq=2 sin.INBOX.separator=%2f sin.INBOX.uidvalidity=<omitted> sin.mailbox=INBOX sin.marker=root
+#= 0@sin sin.INBOX.highestmodseq=2 sin.INBOX.separator=%2f sin.INBOX.uidnext=2 sin.INBOX.uidvalidity=<omitted> sin.account=user@localhost sin.mailbox=INBOX sin.marker=root sin.schema=1
 +unread -- id:test
-#= test sin.0.INBOX.modseq=2 sin.0.INBOX.tag=unread sin.0.INBOX.uid=1 sin.0.INBOX.uidvalidity=<omitted> sin.0.mailbox=INBOX sin.0.marker=message
+#= test sin.0.INBOX.modseq.1=2 sin.0.INBOX.tag=unread sin.0.INBOX.uid=1 sin.0.INBOX.uidvalidity=<omitted> sin.0.mailbox=INBOX sin.0.marker=message
 ", runner.notmuch_dump()?);
 
     Ok(())
@@ -141,9 +141,9 @@ fn append_is_not_transactional_push() {
     runner.run(sin::Mode::Pull)?;
 
     pretty_assertions::assert_eq!("#notmuch-dump batch-tag:3 config,properties,tags
-+sin.internal -- id:0@sin\n#= 0@sin sin.INBOX.highestmodseq=5 sin.INBOX.separator=%2f sin.INBOX.uidvalidity=<omitted> sin.lastmod=4 sin.mailbox=INBOX sin.marker=root
++sin.internal -- id:0@sin\n#= 0@sin sin.INBOX.highestmodseq=5 sin.INBOX.separator=%2f sin.INBOX.uidnext=3 sin.INBOX.uidvalidity=<omitted> sin.account=user@localhost sin.lastmod=4 sin.mailbox=INBOX sin.marker=root sin.schema=1
 +inbox +unread -- id:test
-#= test sin.0.INBOX.modseq=3 sin.0.INBOX.tag=inbox sin.0.INBOX.tag=unread sin.0.INBOX.uid=1 sin.0.INBOX.uidvalidity=<omitted> sin.0.mailbox=INBOX sin.0.marker=message
+#= test sin.0.INBOX.modseq.1=3 sin.0.INBOX.tag=inbox sin.0.INBOX.tag=unread sin.0.INBOX.uid=1 sin.0.INBOX.uidvalidity=<omitted> sin.0.mailbox=INBOX sin.0.marker=message
 ", runner.notmuch_dump()?);
 
     Ok(())
@@ -161,17 +161,17 @@ fn append_is_not_transactional_pull() {
     assert_eq!((1, 0, 0), runner.maildir_count(&server_inbox)?);
 
     pretty_assertions::assert_eq!("#notmuch-dump batch-tag:3 config,properties,tags
-+sin.internal -- id:0@sin\n#= 0@sin sin.INBOX.highestmodseq=3 sin.INBOX.separator=%2f sin.INBOX.uidvalidity=<omitted> sin.mailbox=INBOX sin.marker=root
++sin.internal -- id:0@sin\n#= 0@sin sin.INBOX.highestmodseq=3 sin.INBOX.separator=%2f sin.INBOX.uidnext=2 sin.INBOX.uidvalidity=<omitted> sin.account=user@localhost sin.mailbox=INBOX sin.marker=root sin.schema=1
 +inbox +unread -- id:test
-#= test sin.0.INBOX.modseq=3 sin.0.INBOX.tag=inbox sin.0.INBOX.tag=unread sin.0.INBOX.uid=1 sin.0.INBOX.uidvalidity=<omitted> sin.0.mailbox=INBOX sin.0.marker=message
+#= test sin.0.INBOX.modseq.1=3 sin.0.INBOX.tag=inbox sin.0.INBOX.tag=unread sin.0.INBOX.uid=1 sin.0.INBOX.uidvalidity=<omitted> sin.0.mailbox=INBOX sin.0.marker=message
 ", runner.notmuch_dump()?);
 
     runner.run(sin::Mode::Push)?;
 
     pretty_assertions::assert_eq!("#notmuch-dump batch-tag:3 config,properties,tags
-+sin.internal -- id:0@sin\n#= 0@sin sin.INBOX.highestmodseq=3 sin.INBOX.separator=%2f sin.INBOX.uidvalidity=<omitted> sin.lastmod=6 sin.mailbox=INBOX sin.marker=root
++sin.internal -- id:0@sin\n#= 0@sin sin.INBOX.highestmodseq=3 sin.INBOX.separator=%2f sin.INBOX.uidnext=2 sin.INBOX.uidvalidity=<omitted> sin.account=user@localhost sin.lastmod=6 sin.mailbox=INBOX sin.marker=root sin.schema=1
 +inbox +unread -- id:test
-#= test sin.0.INBOX.modseq=3 sin.0.INBOX.tag=inbox sin.0.INBOX.tag=unread sin.0.INBOX.uid=1 sin.0.INBOX.uidvalidity=<omitted> sin.0.mailbox=INBOX sin.0.marker=message
+#= test sin.0.INBOX.modseq.1=3 sin.0.INBOX.tag=inbox sin.0.INBOX.tag=unread sin.0.INBOX.uid=1 sin.0.INBOX.uidvalidity=<omitted> sin.0.mailbox=INBOX sin.0.marker=message
 ", runner.notmuch_dump()?);
 
     Ok(())
@@ -198,9 +198,9 @@ fn stored_flags() {
     // But not the local cache.
     pretty_assertions::assert_eq!("#notmuch-dump batch-tag:3 config,properties,tags
 +sin.internal -- id:0@sin
-#= 0@sin sin.INBOX.highestmodseq=2 sin.INBOX.separator=%2f sin.INBOX.uidvalidity=<omitted> sin.mailbox=INBOX sin.marker=root
+#= 0@sin sin.INBOX.highestmodseq=2 sin.INBOX.separator=%2f sin.INBOX.uidnext=2 sin.INBOX.uidvalidity=<omitted> sin.account=user@localhost sin.mailbox=INBOX sin.marker=root sin.schema=1
  -- id:test
-#= test sin.0.INBOX.modseq=2 sin.0.INBOX.tag=unread sin.0.INBOX.uid=1 sin.0.INBOX.uidvalidity=<omitted> sin.0.mailbox=INBOX sin.0.marker=message
+#= test sin.0.INBOX.modseq.1=2 sin.0.INBOX.tag=unread sin.0.INBOX.uid=1 sin.0.INBOX.uidvalidity=<omitted> sin.0.mailbox=INBOX sin.0.marker=message
 ", runner.notmuch_dump()?);
 
     // Repushing won't do anything since the modseq is specified.
@@ -214,9 +214,9 @@ fn stored_flags() {
 
     pretty_assertions::assert_eq!("#notmuch-dump batch-tag:3 config,properties,tags
 +sin.internal -- id:0@sin
-#= 0@sin sin.INBOX.highestmodseq=3 sin.INBOX.separator=%2f sin.INBOX.uidvalidity=<omitted> sin.mailbox=INBOX sin.marker=root
+#= 0@sin sin.INBOX.highestmodseq=3 sin.INBOX.separator=%2f sin.INBOX.uidnext=2 sin.INBOX.uidvalidity=<omitted> sin.account=user@localhost sin.mailbox=INBOX sin.marker=root sin.schema=1
  -- id:test
-#= test sin.0.INBOX.modseq=3 sin.0.INBOX.uid=1 sin.0.INBOX.uidvalidity=<omitted> sin.0.mailbox=INBOX sin.0.marker=message
+#= test sin.0.INBOX.modseq.1=3 sin.0.INBOX.uid=1 sin.0.INBOX.uidvalidity=<omitted> sin.0.mailbox=INBOX sin.0.marker=message
 ", runner.notmuch_dump()?);
 
     Ok(())
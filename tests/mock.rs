@@ -0,0 +1,956 @@
+use std::{fs, net, path, sync::atomic, thread, time};
+use test_log::test;
+
+mod common;
+
+// The same scenarios as with Dovecot, against the mock server.
+
+#[test]
+fn remote_new() {
+  common::setup(common::mock::server, |runner| -> _ {
+    let server_inbox = runner.server_maildir("INBOX", &None)?;
+    server_inbox.cur(common::email("test").as_bytes())?;
+
+    runner.run(sin::Mode::Pull)?;
+
+    let client_inbox = runner.client_maildir("INBOX", &None)?;
+    assert_eq!((0, 1, 0), runner.maildir_count(&client_inbox)?);
+    pretty_assertions::assert_eq!("#notmuch-dump batch-tag:3 config,properties,tags
++sin.internal -- id:0@sin
+#= 0@sin sin.INBOX.highestmodseq=2 sin.INBOX.separator=%2f sin.INBOX.uidnext=2 sin.INBOX.uidvalidity=<omitted> sin.account=user@localhost sin.mailbox=INBOX sin.marker=root sin.schema=1
++unread -- id:test
+#= test sin.0.INBOX.modseq.1=2 sin.0.INBOX.tag=unread sin.0.INBOX.uid=1 sin.0.INBOX.uidvalidity=<omitted> sin.0.mailbox=INBOX sin.0.marker=message
+", runner.notmuch_dump()?);
+
+    Ok(())
+  })
+}
+
+#[test]
+fn remote_change_and_removal() {
+  common::setup(common::mock::server, |runner| -> _ {
+    let server_inbox = runner.server_maildir("INBOX", &None)?;
+    let path = server_inbox.cur(common::email("test").as_bytes())?;
+
+    runner.run(sin::Mode::Pull)?;
+
+    let seen = path::PathBuf::from(format!("{}:2,S", path.to_str().unwrap()));
+    fs::rename(&path, &seen)?;
+    runner.run(sin::Mode::Pull)?;
+
+    let client_inbox = runner.client_maildir("INBOX", &None)?;
+    assert_eq!((1, 0, 0), runner.maildir_count(&client_inbox)?);
+    pretty_assertions::assert_eq!("#notmuch-dump batch-tag:3 config,properties,tags
++sin.internal -- id:0@sin
+#= 0@sin sin.INBOX.highestmodseq=3 sin.INBOX.separator=%2f sin.INBOX.uidnext=2 sin.INBOX.uidvalidity=<omitted> sin.account=user@localhost sin.mailbox=INBOX sin.marker=root sin.schema=1
+ -- id:test
+#= test sin.0.INBOX.modseq.1=3 sin.0.INBOX.uid=1 sin.0.INBOX.uidvalidity=<omitted> sin.0.mailbox=INBOX sin.0.marker=message
+", runner.notmuch_dump()?);
+
+    // Reported with VANISHED (EARLIER).
+    fs::remove_file(&seen)?;
+    runner.run(sin::Mode::Pull)?;
+
+    assert_eq!((0, 0, 0), runner.maildir_count(&client_inbox)?);
+    pretty_assertions::assert_eq!("#notmuch-dump batch-tag:3 config,properties,tags
++sin.internal -- id:0@sin
+#= 0@sin sin.INBOX.highestmodseq=4 sin.INBOX.separator=%2f sin.INBOX.uidnext=2 sin.INBOX.uidvalidity=<omitted> sin.account=user@localhost sin.mailbox=INBOX sin.marker=root sin.schema=1
+",
+      runner.notmuch_dump()?
+    );
+
+    Ok(())
+  })
+}
+
+#[test]
+fn duplicates() {
+  common::setup(common::mock::server, |runner| -> _ {
+    let server_inbox = runner.server_maildir("INBOX", &None)?;
+    let first = server_inbox.cur(common::email("test").as_bytes())?;
+    let second = server_inbox.cur(common::email("test").as_bytes())?;
+
+    runner.run(sin::Mode::Pull)?;
+
+    let client_inbox = runner.client_maildir("INBOX", &None)?;
+    assert_eq!((0, 2, 0), runner.maildir_count(&client_inbox)?);
+    let dump = runner.notmuch_dump()?;
+    assert!(dump.contains(" sin.0.INBOX.uid=1 sin.0.INBOX.uid=2 "));
+
+    // Stored to both UIDs.
+    runner.notmuch_tag("-unread", "mid:test")?;
+    runner.run(sin::Mode::Push)?;
+    for path in [&first, &second] {
+      assert!(path::Path::new(&format!("{}:2,S", path.to_str().unwrap())).exists());
+    }
+
+    // Nothing changes anymore once both are known.
+    runner.run(sin::Mode::Pull)?;
+    let dump = runner.notmuch_dump()?;
+    runner.run(sin::Mode::Pull)?;
+    assert_eq!(dump, runner.notmuch_dump()?);
+
+    // Only one of them is removed.
+    fs::remove_file(format!("{}:2,S", first.to_str().unwrap()))?;
+    runner.run(sin::Mode::Pull)?;
+    assert_eq!((1, 0, 0), runner.maildir_count(&client_inbox)?);
+    let dump = runner.notmuch_dump()?;
+    assert!(dump.contains(" sin.0.INBOX.uid=2 sin.0.INBOX.uidvalidity="));
+    assert!(!dump.contains("sin.0.INBOX.modseq.1="));
+    runner.run(sin::Mode::Check)?;
+
+    Ok(())
+  })
+}
+
+#[test]
+fn uidvalidity() {
+  common::setup(common::mock::server, |runner| -> _ {
+    let server_inbox = runner.server_maildir("INBOX", &None)?;
+    server_inbox.cur(common::email("test").as_bytes())?;
+
+    runner.run(sin::Mode::Pull)?;
+
+    // The UIDVALIDITY is the number of seconds since the epoch.
+    thread::sleep(time::Duration::from_secs(1));
+    fs::remove_file(server_inbox.path().join("mock-uidlist"))?;
+
+    assert_eq!(
+      runner
+        .run(sin::Mode::Pull)
+        .unwrap_err()
+        .chain()
+        .next()
+        .unwrap()
+        .to_string(),
+      "mailboxes changed on the server: INBOX (new validity); allow to purge them locally (all \
+       messages will be removed) by passing --purgeable INBOX (or a pattern, or \
+       --purge-all-removed for the removed ones)"
+    );
+    runner.with_purgeable("INBOX").run(sin::Mode::Pull)?;
+
+    let client_inbox = runner.client_maildir("INBOX", &None)?;
+    assert_eq!((0, 1, 0), runner.maildir_count(&client_inbox)?);
+
+    Ok(())
+  })
+}
+
+// The server starts over without changing the UIDVALIDITY.
+#[test]
+fn uids_reassigned() {
+  common::setup(common::mock::server, |runner| -> _ {
+    let server_inbox = runner.server_maildir("INBOX", &None)?;
+    server_inbox.cur(common::email("test0").as_bytes())?;
+    server_inbox.cur(common::email("test1").as_bytes())?;
+
+    runner.run(sin::Mode::Pull)?;
+
+    let uidlist = server_inbox.path().join("mock-uidlist");
+    let header: Vec<String> = fs::read_to_string(&uidlist)?
+      .lines()
+      .next()
+      .unwrap()
+      .split(' ')
+      .map(str::to_string)
+      .collect();
+    fs::write(&uidlist, format!("{} 1 {}\n", header[0], header[2]))?;
+    for entry in fs::read_dir(server_inbox.path().join("cur"))? {
+      fs::remove_file(entry?.path())?;
+    }
+    server_inbox.cur(common::email("other").as_bytes())?;
+
+    assert_eq!(
+      runner
+        .run(sin::Mode::Pull)
+        .unwrap_err()
+        .chain()
+        .next()
+        .unwrap()
+        .to_string(),
+      "mailboxes changed on the server: INBOX (UIDs reassigned); allow to purge them locally (all \
+       messages will be removed) by passing --purgeable INBOX (or a pattern, or \
+       --purge-all-removed for the removed ones)"
+    );
+    runner.with_purgeable("INBOX").run(sin::Mode::Pull)?;
+
+    let client_inbox = runner.client_maildir("INBOX", &None)?;
+    assert_eq!((0, 1, 0), runner.maildir_count(&client_inbox)?);
+    let dump = runner.notmuch_dump()?;
+    assert!(dump.contains("id:other"));
+    assert!(!dump.contains("id:test0"));
+
+    Ok(())
+  })
+}
+
+#[test]
+fn local_new() {
+  common::setup(common::mock::server, |runner| -> _ {
+    runner.run(sin::Mode::Pull)?;
+
+    let client_inbox = runner.client_maildir("INBOX", &None)?;
+    client_inbox.cur(common::email("test").as_bytes())?;
+    runner.notmuch_new()?;
+
+    runner.run(sin::Mode::Push)?;
+
+    let server_inbox = runner.server_maildir("INBOX", &None)?;
+    assert_eq!((1, 0, 0), runner.maildir_count(&server_inbox)?);
+    pretty_assertions::assert_eq!("#notmuch-dump batch-tag:3 config,properties,tags
++sin.internal -- id:0@sin
+#= 0@sin sin.INBOX.highestmodseq=2 sin.INBOX.separator=%2f sin.INBOX.uidnext=1 sin.INBOX.uidvalidity=<omitted> sin.account=user@localhost sin.lastmod=4 sin.mailbox=INBOX sin.marker=root sin.schema=1
++inbox +unread -- id:test
+#= test sin.0.INBOX.modseq.1=2 sin.0.INBOX.tag=inbox sin.0.INBOX.tag=unread sin.0.INBOX.uid=1 sin.0.INBOX.uidvalidity=<omitted> sin.0.mailbox=INBOX sin.0.marker=message
+", runner.notmuch_dump()?);
+
+    Ok(())
+  })
+}
+
+#[test]
+fn local_unchanged() {
+  common::setup(common::mock::server, |runner| -> _ {
+    let server_inbox = runner.server_maildir("INBOX", &None)?;
+    server_inbox.cur(common::email("test1").as_bytes())?;
+    let server_folder = runner.server_maildir("folder", &None)?;
+    server_folder.cur(common::email("test2").as_bytes())?;
+
+    runner.run_all(&[sin::Mode::Pull, sin::Mode::Push])?;
+
+    // A push would notice the new validity but it has nothing to do with the mailbox.
+    thread::sleep(time::Duration::from_secs(1));
+    fs::remove_file(server_inbox.path().join("mock-uidlist"))?;
+    runner.run(sin::Mode::Push)?;
+
+    // Only the mailbox with local changes is selected.
+    runner.notmuch_tag("+flagged", "id:test2")?;
+    runner.run(sin::Mode::Push)?;
+    assert!(runner.notmuch_dump()?.contains("sin.0.folder.tag=flagged"));
+
+    Ok(())
+  })
+}
+
+#[test]
+fn local_bulk_change() {
+  common::setup(common::mock::server, |runner| -> _ {
+    let server_inbox = runner.server_maildir("INBOX", &None)?;
+    let mut paths = Vec::new();
+    for index in 0..3 {
+      paths.push(server_inbox.cur(common::email(&format!("test{index}")).as_bytes())?);
+    }
+
+    runner.run(sin::Mode::Pull)?;
+
+    runner.notmuch_tag("-unread", "mid:test0 or mid:test1 or mid:test2")?;
+    runner.with_trace_file("trace").run(sin::Mode::Push)?;
+
+    // A single command for the three of them (the transcript has a line per written buffer).
+    let trace = runner.read("trace")?;
+    let lines: Vec<&str> = trace.lines().collect();
+    let stores: Vec<usize> = (0..lines.len())
+      .filter(|index| lines[*index].ends_with(" C store UID STORE "))
+      .collect();
+    assert_eq!(1, stores.len());
+    assert!(lines[stores[0] + 1].ends_with(" C 1:3"));
+    for path in paths {
+      assert!(path::Path::new(&format!("{}:2,S", path.to_str().unwrap())).exists());
+    }
+    let dump = runner.notmuch_dump()?;
+    assert!(!dump.contains("sin.0.INBOX.tag=unread"));
+
+    Ok(())
+  })
+}
+
+#[test]
+fn local_change_and_move() {
+  common::setup(common::mock::server, |runner| -> _ {
+    let server_inbox = runner.server_maildir("INBOX", &None)?;
+    let server_folder = runner.server_maildir("folder", &None)?;
+    let path = server_inbox.cur(common::email("test").as_bytes())?;
+
+    runner.run(sin::Mode::Pull)?;
+
+    runner.notmuch_tag("-unread", "mid:test")?;
+    runner.run(sin::Mode::Push)?;
+
+    // Stored with UNCHANGEDSINCE.
+    assert!(path::Path::new(&format!("{}:2,S", path.to_str().unwrap())).exists());
+
+    let client_inbox = runner.client_maildir("INBOX", &None)?;
+    let client_folder = runner.client_maildir("folder", &None)?;
+    for entry in fs::read_dir(client_inbox.path().join("cur"))? {
+      let path = entry?.path();
+      fs::rename(
+        &path,
+        client_folder
+          .path()
+          .join("cur")
+          .join(path.file_name().unwrap()),
+      )?;
+    }
+    runner.notmuch_new()?;
+    runner.run(sin::Mode::Push)?;
+    runner.run(sin::Mode::Pull)?;
+
+    assert_eq!((0, 0, 0), runner.maildir_count(&server_inbox)?);
+    assert_eq!((1, 0, 0), runner.maildir_count(&server_folder)?);
+    assert_eq!((0, 0, 0), runner.maildir_count(&client_inbox)?);
+    assert_eq!((1, 0, 0), runner.maildir_count(&client_folder)?);
+
+    Ok(())
+  })
+}
+
+#[test]
+fn many_mailboxes() {
+  common::setup(common::mock::server, |runner| -> _ {
+    // More mailboxes than workers and SELECTs pipelined by each of them.
+    let mailboxes: Vec<String> = (0..40).map(|index| format!("folder{index}")).collect();
+    for mailbox in &mailboxes {
+      let server_maildir = runner.server_maildir(mailbox, &None)?;
+      server_maildir.cur(common::email(mailbox).as_bytes())?;
+    }
+    let server_inbox = runner.server_maildir("INBOX", &None)?;
+    for index in 0..20 {
+      server_inbox.cur(common::email(&format!("inbox{index}")).as_bytes())?;
+    }
+
+    runner.run(sin::Mode::Pull)?;
+
+    for mailbox in &mailboxes {
+      let client_maildir = runner.client_maildir(mailbox, &None)?;
+      assert_eq!((0, 1, 0), runner.maildir_count(&client_maildir)?);
+    }
+    let client_inbox = runner.client_maildir("INBOX", &None)?;
+    assert_eq!((0, 20, 0), runner.maildir_count(&client_inbox)?);
+
+    Ok(())
+  })
+}
+
+#[test]
+fn small_batches() {
+  common::setup(common::mock::server, |runner| -> _ {
+    let mailboxes: Vec<String> = (0..5).map(|index| format!("folder{index}")).collect();
+    for mailbox in &mailboxes {
+      let server_maildir = runner.server_maildir(mailbox, &None)?;
+      server_maildir.cur(common::email(mailbox).as_bytes())?;
+    }
+    let server_inbox = runner.server_maildir("INBOX", &None)?;
+    for index in 0..20 {
+      server_inbox.cur(common::email(&format!("inbox{index}")).as_bytes())?;
+    }
+
+    // A SELECT at a time and several FETCH commands per worker.
+    runner.with_batching(3, 1).run(sin::Mode::Pull)?;
+
+    for mailbox in &mailboxes {
+      let client_maildir = runner.client_maildir(mailbox, &None)?;
+      assert_eq!((0, 1, 0), runner.maildir_count(&client_maildir)?);
+    }
+    let client_inbox = runner.client_maildir("INBOX", &None)?;
+    assert_eq!((0, 20, 0), runner.maildir_count(&client_inbox)?);
+
+    Ok(())
+  })
+}
+
+#[test]
+fn keepalive() {
+  common::setup(common::mock::server, |runner| -> _ {
+    let server_inbox = runner.server_maildir("INBOX", &None)?;
+    for index in 0..10 {
+      server_inbox.cur(common::email(&format!("test{index}")).as_bytes())?;
+    }
+
+    // Every connection left waiting sends a NOOP, the session is still usable by the push.
+    let runner = runner
+      .with_keepalive(time::Duration::ZERO)
+      .with_trace_file("trace");
+    runner.run_all(&[sin::Mode::Pull, sin::Mode::Push])?;
+
+    let trace = runner.read("trace")?;
+    assert!(
+      trace
+        .lines()
+        .any(|line| line.ends_with(" C keepalive NOOP\\r\\n"))
+    );
+    let client_inbox = runner.client_maildir("INBOX", &None)?;
+    assert_eq!((0, 10, 0), runner.maildir_count(&client_inbox)?);
+
+    Ok(())
+  })
+}
+
+#[test]
+fn retry_timeout() {
+  common::setup(common::mock::stalling_server, |runner| -> _ {
+    let server_inbox = runner.server_maildir("INBOX", &None)?;
+    for index in 0..3 {
+      server_inbox.cur(common::email(&format!("test{index}")).as_bytes())?;
+    }
+
+    // The first download times out, it's repeated on a new connection.
+    runner
+      .with_timeout(time::Duration::from_secs(1))
+      .run(sin::Mode::Pull)?;
+
+    let client_inbox = runner.client_maildir("INBOX", &None)?;
+    assert_eq!((0, 3, 0), runner.maildir_count(&client_inbox)?);
+
+    Ok(())
+  })
+}
+
+#[test]
+fn pull_then_push() {
+  common::setup(common::mock::server, |runner| -> _ {
+    let server_inbox = runner.server_maildir("INBOX", &None)?;
+    let path = server_inbox.cur(common::email("test").as_bytes())?;
+
+    runner.run(sin::Mode::Pull)?;
+    runner.notmuch_tag("-unread", "mid:test")?;
+
+    // The push reuses the session of the pull.
+    server_inbox.cur(common::email("other").as_bytes())?;
+    runner.run_all(&[sin::Mode::Pull, sin::Mode::Push])?;
+
+    assert!(path::Path::new(&format!("{}:2,S", path.to_str().unwrap())).exists());
+    let client_inbox = runner.client_maildir("INBOX", &None)?;
+    assert_eq!((1, 1, 0), runner.maildir_count(&client_inbox)?);
+
+    Ok(())
+  })
+}
+
+// Counts the connections.
+struct Transport {
+  port: u16,
+  connections: atomic::AtomicUsize,
+}
+
+impl sin::Open for Transport {
+  type RW = net::TcpStream;
+
+  fn open(&self) -> anyhow::Result<Self::RW> {
+    self.connections.fetch_add(1, atomic::Ordering::Relaxed);
+    Ok(net::TcpStream::connect(("127.0.0.1", self.port))?)
+  }
+}
+
+#[test]
+fn custom_transport() {
+  common::setup(common::mock::server, |runner| -> _ {
+    let server_inbox = runner.server_maildir("INBOX", &None)?;
+    server_inbox.cur(common::email("test").as_bytes())?;
+
+    let transport = Transport {
+      port: runner.port(),
+      connections: atomic::AtomicUsize::new(0),
+    };
+    let credentials = sin::Credentials::new(
+      runner.user(),
+      sin::Secret::Command(vec!["echo".to_string(), "password".to_string()]),
+      None,
+    );
+    sin::run_with(
+      &runner.arguments(&[sin::Mode::Pull])?,
+      &transport,
+      &credentials,
+    )?;
+
+    // One for the session, one for the worker pulling the INBOX.
+    assert_eq!(2, transport.connections.load(atomic::Ordering::Relaxed));
+    let client_inbox = runner.client_maildir("INBOX", &None)?;
+    assert_eq!((0, 1, 0), runner.maildir_count(&client_inbox)?);
+
+    Ok(())
+  })
+}
+
+#[test]
+fn post_pull_command() {
+  common::setup(common::mock::server, |runner| -> _ {
+    // Run from the top of the mail store.
+    let runner =
+      runner.with_post_pull_command(r#"echo "$SIN_MESSAGES $SIN_MAILBOXES" >> post-pull"#);
+    let output = runner
+      .client_maildir_builder()?
+      .path()
+      .parent()
+      .unwrap()
+      .join("post-pull");
+    let server_inbox = runner.server_maildir("INBOX", &None)?;
+    server_inbox.cur(common::email("test").as_bytes())?;
+
+    runner.run(sin::Mode::Pull)?;
+    assert_eq!("1 INBOX\n", fs::read_to_string(&output)?);
+
+    // Nothing changed.
+    runner.run(sin::Mode::Pull)?;
+    assert_eq!("1 INBOX\n", fs::read_to_string(&output)?);
+
+    Ok(())
+  })
+}
+
+#[test]
+fn mailbox_tag() {
+  common::setup(common::mock::server, |runner| -> _ {
+    let server_inbox = runner.server_maildir("INBOX", &None)?;
+    let server_folder = runner.server_maildir("folder", &None)?;
+    let path = server_inbox.cur(common::email("test").as_bytes())?;
+
+    // Existing messages are tagged once the option is given.
+    runner.run(sin::Mode::Pull)?;
+    let tagged = runner.with_mailbox_tag("folder::", "");
+    tagged.run(sin::Mode::Pull)?;
+
+    assert!(
+      runner
+        .notmuch_dump()?
+        .contains("+folder::INBOX +unread -- id:test")
+    );
+
+    // Updated on moves.
+    fs::rename(
+      &path,
+      server_folder
+        .path()
+        .join("cur")
+        .join(path.file_name().unwrap()),
+    )?;
+    tagged.run(sin::Mode::Pull)?;
+
+    assert!(
+      runner
+        .notmuch_dump()?
+        .contains("+folder::folder +unread -- id:test")
+    );
+
+    // But never pushed.
+    runner.notmuch_tag("-unread", "mid:test")?;
+    tagged.run(sin::Mode::Push)?;
+
+    assert!(!fs::read_to_string(server_folder.path().join("mock-uidlist"))?.contains("folder::"));
+
+    // And removed without the option.
+    runner.run(sin::Mode::Pull)?;
+
+    assert!(runner.notmuch_dump()?.contains(" -- id:test"));
+    assert!(!runner.notmuch_dump()?.contains("folder::"));
+
+    Ok(())
+  })
+}
+
+#[test]
+fn exclude_tags() {
+  common::setup(common::mock::server, |runner| -> _ {
+    runner.run(sin::Mode::Pull)?;
+
+    // Root messages don't show up in searches.
+    assert_eq!(
+      "sin.internal\n",
+      runner.notmuch_config("search.exclude_tags")?
+    );
+
+    Ok(())
+  })
+}
+
+#[test]
+fn check() {
+  common::setup(common::mock::server, |runner| -> _ {
+    let server_inbox = runner.server_maildir("INBOX", &None)?;
+    let path = server_inbox.cur(common::email("test").as_bytes())?;
+
+    runner.run(sin::Mode::Pull)?;
+    runner.run(sin::Mode::Check)?;
+
+    // Not pulled yet but not a discrepancy either.
+    let seen = path::PathBuf::from(format!("{}:2,S", path.to_str().unwrap()));
+    fs::rename(&path, &seen)?;
+    runner.run(sin::Mode::Check)?;
+
+    let dump = runner.notmuch_dump()?;
+    fs::remove_file(&seen)?;
+    assert_eq!(
+      runner
+        .run(sin::Mode::Check)
+        .unwrap_err()
+        .chain()
+        .next()
+        .unwrap()
+        .to_string(),
+      "found 1 discrepancies"
+    );
+    // Nothing changed.
+    pretty_assertions::assert_eq!(dump, runner.notmuch_dump()?);
+
+    Ok(())
+  })
+}
+
+#[test]
+fn repair() {
+  common::setup(common::mock::server, |runner| -> _ {
+    let server_inbox = runner.server_maildir("INBOX", &None)?;
+    server_inbox.cur(common::email("test").as_bytes())?;
+
+    runner.run(sin::Mode::Pull)?;
+
+    // Lost the file and left a partial download behind.
+    let client_inbox = runner.client_maildir("INBOX", &None)?;
+    for entry in fs::read_dir(client_inbox.path().join("new"))? {
+      fs::remove_file(entry?.path())?;
+    }
+    client_inbox.tmp_named_with_digest("test_1_1", b"partial")?;
+    assert!(runner.run(sin::Mode::Check).is_err());
+
+    // Another program's file and another namespace's run are left alone.
+    let foreign = client_inbox.tmp(b"foreign")?;
+    let builder = runner.client_maildir_builder()?;
+    let lock = builder.lock("other", None)?;
+    runner.run(sin::Mode::Repair)?;
+    assert_eq!((0, 0, 1), runner.maildir_count(&client_inbox)?);
+    assert!(builder.lock("other", None).is_err());
+    drop(lock);
+
+    fs::remove_file(foreign)?;
+    runner.run(sin::Mode::Pull)?;
+    assert_eq!((0, 1, 0), runner.maildir_count(&client_inbox)?);
+    runner.run(sin::Mode::Check)?;
+
+    Ok(())
+  })
+}
+
+#[test]
+fn state() {
+  common::setup(common::mock::server, |runner| -> _ {
+    let server_inbox = runner.server_maildir("INBOX", &None)?;
+    server_inbox.cur(common::email("test").as_bytes())?;
+
+    runner.run(sin::Mode::Pull)?;
+
+    let mut arguments = runner.arguments(&[sin::Mode::State])?;
+    arguments.query = Some("id:test".to_string());
+    let mut output = Vec::new();
+    sin::state(&arguments, &mut output)?;
+    let output = String::from_utf8(output)?;
+    assert!(output.contains("mailbox INBOX uidvalidity "));
+    assert!(output.contains(" uidnext 2 highestmodseq 2 separator /\nmessage test\n"));
+    assert!(output.contains(" uid 1 modseq 2 tags unread\n"));
+
+    arguments.json = true;
+    let mut output = Vec::new();
+    sin::state(&arguments, &mut output)?;
+    let output = String::from_utf8(output)?;
+    assert!(output.contains(r#"{"name":"INBOX","separator":"/","uidvalidity":"#));
+    assert!(output.contains(r#""uid":1,"modseq":2,"tags":["unread"]}]}]}"#));
+
+    Ok(())
+  })
+}
+
+#[test]
+fn state_set_and_clear() {
+  common::setup(common::mock::server, |runner| -> _ {
+    let server_inbox = runner.server_maildir("INBOX", &None)?;
+    server_inbox.cur(common::email("test").as_bytes())?;
+
+    runner.run(sin::Mode::Pull)?;
+
+    let mut arguments = runner.arguments(&[sin::Mode::StateSet])?;
+    arguments.property = Some("INBOX.highestmodseq".to_string());
+    arguments.value = Some("-1".to_string());
+    assert_eq!(
+      sin::run(&arguments).unwrap_err().to_string(),
+      "\"-1\" isn't a number"
+    );
+    arguments.value = Some("1".to_string());
+    sin::run(&arguments)?;
+    assert!(
+      runner
+        .notmuch_dump()?
+        .contains(" sin.INBOX.highestmodseq=1 ")
+    );
+    // Catches up.
+    runner.run(sin::Mode::Pull)?;
+    assert!(
+      runner
+        .notmuch_dump()?
+        .contains(" sin.INBOX.highestmodseq=2 ")
+    );
+
+    let mut arguments = runner.arguments(&[sin::Mode::StateClear])?;
+    arguments.mailbox = Some("INBOX".to_string());
+    sin::run(&arguments)?;
+    pretty_assertions::assert_eq!(
+      "#notmuch-dump batch-tag:3 config,properties,tags
++sin.internal -- id:0@sin
+#= 0@sin sin.account=user@localhost sin.marker=root sin.schema=1
++unread -- id:test
+",
+      runner.notmuch_dump()?
+    );
+
+    // In use.
+    let mut arguments = runner.arguments(&[sin::Mode::StateClear])?;
+    arguments.namespace_id = Some(0);
+    assert_eq!(
+      sin::run(&arguments).unwrap_err().to_string(),
+      "sin.0 is still in use"
+    );
+
+    Ok(())
+  })
+}
+
+#[test]
+fn bootstrap() {
+  common::setup(common::mock::server, |runner| -> _ {
+    let server_inbox = runner.server_maildir("INBOX", &None)?;
+    for index in 0..3 {
+      server_inbox.cur(common::email(&format!("test{index}")).as_bytes())?;
+    }
+
+    // Nothing is known yet: the flags are fetched rather than sent along the SELECT (QRESYNC).
+    runner.with_trace_file("first").run(sin::Mode::Pull)?;
+    let trace = runner.read("first")?;
+    assert!(!trace.contains("(QRESYNC ("));
+    let lines: Vec<&str> = trace.lines().collect();
+    assert!(lines.windows(3).any(|lines| {
+      lines[0].ends_with(" C fetch UID FETCH ")
+        && lines[1].ends_with(" C 1:3")
+        && lines[2].ends_with(" C  (FLAGS MODSEQ)\\r\\n")
+    }));
+    let client_inbox = runner.client_maildir("INBOX", &None)?;
+    assert_eq!((0, 3, 0), runner.maildir_count(&client_inbox)?);
+
+    // Only the changes afterwards.
+    server_inbox.cur(common::email("test3").as_bytes())?;
+    runner.with_trace_file("second").run(sin::Mode::Pull)?;
+    assert!(runner.read("second")?.contains("(QRESYNC ("));
+    assert_eq!((0, 4, 0), runner.maildir_count(&client_inbox)?);
+
+    Ok(())
+  })
+}
+
+#[test]
+fn marked_first() {
+  common::setup(common::mock::server, |runner| -> _ {
+    let server_archives = runner.server_maildir("Archives", &None)?;
+    server_archives.cur(common::email("archived").as_bytes())?;
+    runner.server_new("Lists", common::email("new").as_bytes())?;
+
+    // A single worker, so the mailboxes are selected in order: the marked one first.
+    runner
+      .with_threads(1)
+      .with_trace_file("trace")
+      .run(sin::Mode::Pull)?;
+    let trace = runner.read("trace")?;
+    let position = |mailbox| common::trace_position(&trace, mailbox).unwrap();
+    assert!(position("Lists") < position("Archives"));
+    let client_lists = runner.client_maildir("Lists", &None)?;
+    assert_eq!((0, 1, 0), runner.maildir_count(&client_lists)?);
+
+    Ok(())
+  })
+}
+
+#[test]
+fn priority() {
+  common::setup(common::mock::server, |runner| -> _ {
+    for mailbox in ["Archives", "Lists", "Sent"] {
+      runner.server_new(mailbox, common::email(mailbox).as_bytes())?;
+    }
+
+    // The named mailboxes first, in order, then the others (all marked) by name.
+    runner
+      .with_threads(1)
+      .with_priority("Sent")
+      .with_priority("Lists")
+      .with_trace_file("trace")
+      .run(sin::Mode::Pull)?;
+    let trace = runner.read("trace")?;
+    let position = |mailbox| common::trace_position(&trace, mailbox).unwrap();
+    assert!(position("Sent") < position("Lists"));
+    assert!(position("Lists") < position("Archives"));
+
+    Ok(())
+  })
+}
+
+#[test]
+fn migrate_namespace() {
+  common::setup(common::mock::server, |runner| -> _ {
+    let server_inbox = runner.server_maildir("INBOX", &None)?;
+    server_inbox.cur(common::email("test").as_bytes())?;
+
+    runner.run(sin::Mode::Pull)?;
+
+    let mut arguments = runner.arguments(&[sin::Mode::MigrateNamespace])?;
+    arguments.namespace = "other".to_string();
+    arguments.migrate_from = Some("sin".to_string());
+    sin::run(&arguments)?;
+    pretty_assertions::assert_eq!("#notmuch-dump batch-tag:3 config,properties,tags
++other.internal -- id:0@other
+#= 0@other other.INBOX.highestmodseq=2 other.INBOX.separator=%2f other.INBOX.uidnext=2 other.INBOX.uidvalidity=<omitted> other.account=user@localhost other.mailbox=INBOX other.marker=root other.schema=1
++unread -- id:test
+#= test other.0.INBOX.modseq.1=2 other.0.INBOX.tag=unread other.0.INBOX.uid=1 other.0.INBOX.uidvalidity=<omitted> other.0.mailbox=INBOX other.0.marker=message
+", runner.notmuch_dump()?);
+    assert!(!runner.client_maildir_builder()?.path().join("sin").exists());
+
+    assert_eq!(
+      sin::run(&arguments).unwrap_err().to_string(),
+      "other is already in use"
+    );
+
+    Ok(())
+  })
+}
+
+// What another client changes while a push is going on is applied right away, and still pulled.
+#[test]
+fn concurrent_change() {
+  common::setup(common::mock::concurrent_server, |runner| -> _ {
+    let server_inbox = runner.server_maildir("INBOX", &None)?;
+    server_inbox.cur(common::email("test0").as_bytes())?;
+    server_inbox.cur(common::email("test1").as_bytes())?;
+
+    runner.run(sin::Mode::Pull)?;
+    runner.notmuch_tag("-unread", "mid:test0")?;
+    for mode in [sin::Mode::Push, sin::Mode::Pull] {
+      runner.run(mode)?;
+      let dump = runner.notmuch_dump()?;
+      assert!(dump.contains("\n+flagged -- id:test0\n"));
+      assert!(dump.contains("\n+flagged +unread -- id:test1\n"));
+    }
+
+    Ok(())
+  })
+}
+
+// lieer's files are linked from their Gmail message IDs, the next pull doesn't download them again.
+#[test]
+fn import_lieer() {
+  common::setup(common::mock::server, |runner| -> _ {
+    let server_inbox = runner.server_maildir("INBOX", &None)?;
+    server_inbox.cur(common::email("test").as_bytes())?;
+
+    // The mock's Gmail message IDs are the UIDs.
+    let lieer = tempfile::tempdir()?;
+    fs::write(lieer.path().join(".gmailieer.json"), "{}")?;
+    for subdirectory in ["cur", "new", "tmp"] {
+      fs::create_dir_all(lieer.path().join("mail").join(subdirectory))?;
+    }
+    fs::write(lieer.path().join("mail/cur/1:2,S"), common::email("test"))?;
+    // Not on the server.
+    fs::write(lieer.path().join("mail/cur/2:2,S"), common::email("other"))?;
+
+    let mut arguments = runner.arguments(&[sin::Mode::Import])?;
+    arguments.import_from = Some(sin::Tool::Lieer);
+    arguments.import_state = lieer.path().to_str().map(str::to_string);
+    sin::run(&arguments)?;
+
+    let client_inbox = runner.client_maildir("INBOX", &None)?;
+    assert_eq!((1, 0, 0), runner.maildir_count(&client_inbox)?);
+    let dump = runner.notmuch_dump()?;
+    assert!(dump.contains(" sin.0.INBOX.uid=1 "));
+    assert!(!dump.contains("id:other"));
+
+    runner.run(sin::Mode::Pull)?;
+    assert_eq!((1, 0, 0), runner.maildir_count(&client_inbox)?);
+
+    Ok(())
+  })
+}
+
+// The Status header field becomes flags and is dropped, importing the same mbox again doesn't
+// duplicate its messages.
+#[test]
+fn import_mbox() {
+  common::setup(common::mock::server, |runner| -> _ {
+    runner.server_maildir("INBOX", &None)?;
+
+    let mbox = tempfile::NamedTempFile::new()?;
+    fs::write(
+      mbox.path(),
+      format!(
+        "From test Thu Jan  1 00:00:00 1970\nStatus: RO\n{}\n",
+        common::email("test")
+      ),
+    )?;
+
+    let mut arguments = runner.arguments(&[sin::Mode::Import])?;
+    arguments.import_from = Some(sin::Tool::Mbox);
+    arguments.import_source = mbox.path().to_str().map(str::to_string);
+    arguments.mailbox = Some("INBOX".to_string());
+    sin::run(&arguments)?;
+    sin::run(&arguments)?;
+
+    let client_inbox = runner.client_maildir("INBOX", &None)?;
+    assert_eq!((1, 0, 0), runner.maildir_count(&client_inbox)?);
+    let dump = runner.notmuch_dump()?;
+    assert!(dump.contains("-- id:test\n"));
+    assert!(!dump.contains("unread"));
+    for entry in fs::read_dir(client_inbox.path().join("cur"))? {
+      assert!(!fs::read_to_string(entry?.path())?.contains("Status:"));
+    }
+
+    Ok(())
+  })
+}
+
+// The mailbox disappeared between the SELECT and the APPEND: it's created again and the APPEND is
+// retried.
+#[test]
+fn trycreate() {
+  common::setup(common::mock::removing_server, |runner| -> _ {
+    runner.server_maildir("folder", &None)?;
+    runner.run(sin::Mode::Pull)?;
+
+    let client_folder = runner.client_maildir("folder", &None)?;
+    client_folder.cur(common::email("test").as_bytes())?;
+    runner.notmuch_new()?;
+    runner.run(sin::Mode::Push)?;
+
+    let server_folder = runner.server_maildir("folder", &None)?;
+    assert_eq!((1, 0, 0), runner.maildir_count(&server_folder)?);
+    assert!(runner.notmuch_dump()?.contains(" sin.0.folder.uid=1 "));
+
+    Ok(())
+  })
+}
+
+// The mailboxes of another namespace are only synchronized once it's included, under its prefix.
+#[test]
+fn namespace_include() {
+  common::setup(common::mock::server, |runner| -> _ {
+    let server_shared = runner.server_maildir("Shared/team", &Some('/'))?;
+    server_shared.cur(common::email("test").as_bytes())?;
+
+    runner.run(sin::Mode::Pull)?;
+    assert!(!runner.notmuch_dump()?.contains("id:test"));
+
+    let runner = runner.with_namespace_include(sin::NamespaceKind::Shared);
+    runner.run(sin::Mode::Pull)?;
+    let client_shared = runner.client_maildir("Shared/team", &Some('/'))?;
+    assert_eq!((0, 1, 0), runner.maildir_count(&client_shared)?);
+
+    runner.notmuch_tag("+flagged", "mid:test")?;
+    runner.run(sin::Mode::Push)?;
+    let entry = fs::read_dir(server_shared.path().join("cur"))?
+      .next()
+      .unwrap()?;
+    assert!(entry.file_name().to_str().unwrap().ends_with(":2,F"));
+
+    Ok(())
+  })
+}
@@ -0,0 +1,188 @@
+use std::{fs, path, thread, time};
+use test_log::test;
+
+mod common;
+
+// The maildir backend (without Notmuch), against the mock server.
+
+#[test]
+fn remote_new() {
+  common::setup(common::mock::server, |runner| -> _ {
+    let runner = runner.with_backend(sin::Backend::Maildir);
+    let server_inbox = runner.server_maildir("INBOX", &None)?;
+    server_inbox.cur(common::email("test").as_bytes())?;
+
+    runner.run(sin::Mode::Pull)?;
+
+    let client_inbox = runner.client_maildir("INBOX", &None)?;
+    assert_eq!((1, 0, 0), runner.maildir_count(&client_inbox)?);
+    let sidecar = fs::read_to_string(client_inbox.path().join("sin-uidlist"))?;
+    assert_eq!(2, sidecar.lines().count());
+    assert!(sidecar.lines().nth(1).unwrap().starts_with("1 "));
+
+    // Nothing new.
+    runner.run(sin::Mode::Pull)?;
+    assert_eq!((1, 0, 0), runner.maildir_count(&client_inbox)?);
+
+    Ok(())
+  })
+}
+
+#[test]
+fn remote_change_and_removal() {
+  common::setup(common::mock::server, |runner| -> _ {
+    let runner = runner.with_backend(sin::Backend::Maildir);
+    let server_inbox = runner.server_maildir("INBOX", &None)?;
+    let first = server_inbox.cur(common::email("test0").as_bytes())?;
+    let second = server_inbox.cur(common::email("test1").as_bytes())?;
+
+    runner.run(sin::Mode::Pull)?;
+
+    fs::rename(&first, format!("{}:2,FS", first.to_str().unwrap()))?;
+    fs::remove_file(&second)?;
+    runner.run(sin::Mode::Pull)?;
+
+    let client_inbox = runner.client_maildir("INBOX", &None)?;
+    assert_eq!((1, 0, 0), runner.maildir_count(&client_inbox)?);
+    let entry = fs::read_dir(client_inbox.path().join("cur"))?
+      .next()
+      .unwrap()?;
+    assert!(entry.file_name().to_str().unwrap().ends_with(":2,FS"));
+
+    Ok(())
+  })
+}
+
+#[test]
+fn local_change_and_removal() {
+  common::setup(common::mock::server, |runner| -> _ {
+    let runner = runner.with_backend(sin::Backend::Maildir);
+    let server_inbox = runner.server_maildir("INBOX", &None)?;
+    let first = server_inbox.cur(common::email("test0").as_bytes())?;
+    let second = server_inbox.cur(common::email("test1").as_bytes())?;
+    let third = server_inbox.cur(common::email("test2").as_bytes())?;
+
+    runner.run(sin::Mode::Pull)?;
+
+    let client_inbox = runner.client_maildir("INBOX", &None)?;
+    for entry in fs::read_dir(client_inbox.path().join("cur"))? {
+      let path = entry?.path();
+      let contents = fs::read_to_string(&path)?;
+      if contents.contains("test0") {
+        fs::rename(&path, format!("{}S", path.to_str().unwrap()))?;
+      } else if contents.contains("test1") {
+        fs::remove_file(&path)?;
+      } else {
+        fs::rename(&path, format!("{}T", path.to_str().unwrap()))?;
+      }
+    }
+    runner.run(sin::Mode::Push)?;
+
+    // Neither a removed file nor the T flag (without --expunge) touch the server.
+    assert!(path::Path::new(&format!("{}:2,S", first.to_str().unwrap())).exists());
+    assert!(second.exists());
+    assert!(third.exists());
+
+    Ok(())
+  })
+}
+
+#[test]
+fn local_new() {
+  common::setup(common::mock::server, |runner| -> _ {
+    let runner = runner.with_backend(sin::Backend::Maildir);
+    runner.server_maildir("INBOX", &None)?;
+
+    runner.run(sin::Mode::Pull)?;
+
+    let client_inbox = runner.client_maildir("INBOX", &None)?;
+    // As delivered by a mail client, without info.
+    fs::write(
+      client_inbox.path().join("new").join("local"),
+      common::email("test"),
+    )?;
+    runner.run(sin::Mode::Push)?;
+
+    let server_inbox = runner.server_maildir("INBOX", &None)?;
+    assert_eq!((1, 0, 0), runner.maildir_count(&server_inbox)?);
+
+    // Already known, it isn't downloaded back (only moved to cur).
+    runner.run_all(&[sin::Mode::Pull, sin::Mode::Push])?;
+    assert_eq!((1, 0, 0), runner.maildir_count(&client_inbox)?);
+    assert_eq!((1, 0, 0), runner.maildir_count(&server_inbox)?);
+
+    Ok(())
+  })
+}
+
+#[test]
+fn notmuch_only() {
+  common::setup(common::mock::server, |runner| -> _ {
+    let error = runner
+      .with_backend(sin::Backend::Maildir)
+      .run(sin::Mode::Check)
+      .unwrap_err();
+    assert!(error.to_string().contains("requires the notmuch backend"));
+
+    Ok(())
+  })
+}
+
+#[test]
+fn maildir_root() {
+  common::setup(common::mock::server, |runner| -> _ {
+    let mut arguments = runner
+      .with_backend(sin::Backend::Maildir)
+      .arguments(&[sin::Mode::Pull])?;
+    // Never the current directory.
+    arguments.maildir_root = None;
+    assert_eq!(
+      "the maildir backend requires --maildir-root",
+      sin::run(&arguments).unwrap_err().to_string()
+    );
+
+    Ok(())
+  })
+}
+
+#[test]
+fn uidvalidity() {
+  common::setup(common::mock::server, |runner| -> _ {
+    let runner = runner.with_backend(sin::Backend::Maildir);
+    let server_inbox = runner.server_maildir("INBOX", &None)?;
+    server_inbox.cur(common::email("test").as_bytes())?;
+
+    runner.run(sin::Mode::Pull)?;
+
+    // The UIDVALIDITY is the number of seconds since the epoch.
+    thread::sleep(time::Duration::from_secs(1));
+    fs::remove_file(server_inbox.path().join("mock-uidlist"))?;
+
+    // Like with the notmuch backend, nothing is purged without --purgeable.
+    assert_eq!(
+      runner.run(sin::Mode::Pull).unwrap_err().to_string(),
+      "mailboxes changed on the server: INBOX (new validity); allow to purge them locally (all \
+       messages will be removed) by passing --purgeable INBOX (or a pattern)"
+    );
+    let client_inbox = runner.client_maildir("INBOX", &None)?;
+    assert_eq!((1, 0, 0), runner.maildir_count(&client_inbox)?);
+    runner.with_purgeable("INBOX").run(sin::Mode::Pull)?;
+    assert_eq!((1, 0, 0), runner.maildir_count(&client_inbox)?);
+
+    Ok(())
+  })
+}
+
+#[test]
+fn notmuch_only_options() {
+  common::setup(common::mock::server, |runner| -> _ {
+    let error = runner
+      .with_backend(sin::Backend::Maildir)
+      .with_threads(1)
+      .run(sin::Mode::Pull)
+      .unwrap_err();
+    assert_eq!("--threads requires the notmuch backend", error.to_string());
+
+    Ok(())
+  })
+}
@@ -0,0 +1,77 @@
+// cargo test --features protocol
+// The IMAP layer used on its own, as another tool would.
+
+use sin::protocol::{Greeting, Stream, parser};
+use std::{cmp, io, mem};
+
+// Answers each command with the response and its completion, the NOOPs sent along (see
+// Stream::input) with their completion only.
+struct Server {
+  response: Vec<u8>,
+  input: Vec<u8>,
+  position: usize,
+  output: Vec<u8>,
+}
+
+impl sin::protocol::ReadWrite for Server {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    let length = cmp::min(buf.len(), self.input.len() - self.position);
+    buf[..length].copy_from_slice(&self.input[self.position..self.position + length]);
+    self.position += length;
+    Ok(length)
+  }
+
+  fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+    self.output.extend_from_slice(buf);
+    if !self.output.ends_with(b"\r\n") {
+      return Ok(());
+    }
+    let command = mem::take(&mut self.output);
+    let tag = command.split(|byte| *byte == b' ').next().unwrap();
+    if !command.ends_with(b" NOOP\r\n") {
+      self.input.extend_from_slice(&self.response);
+    }
+    self
+      .input
+      .extend_from_slice(&[tag, b" OK completed.\r\n"].concat());
+    Ok(())
+  }
+}
+
+#[test]
+fn session() -> anyhow::Result<()> {
+  let mut stream = Stream::new(Server {
+    response: b"* 1 FETCH (UID 4 FLAGS (\\Seen) MODSEQ (7))\r\n".to_vec(),
+    input: b"* OK [CAPABILITY IMAP4rev1 LITERAL+] ready\r\n".to_vec(),
+    position: 0,
+    output: Vec::new(),
+  });
+
+  stream.read(1024)?;
+  assert_eq!(b"*", stream.expect(parser::start)?);
+  assert_eq!(
+    Greeting {
+      preauthenticated: false,
+      capabilities: Some(vec![&b"IMAP4rev1"[..], b"LITERAL+"]),
+      text: b"ready",
+    },
+    stream.expect(parser::greeting)?
+  );
+  stream.set_capabilities(&[b"IMAP4rev1".to_vec(), b"LITERAL+".to_vec()]);
+
+  let command: &[&[u8]] = &[b"fetch UID FETCH 4 (FLAGS MODSEQ)\r\n"];
+  stream.input(command, command.len())?;
+  assert_eq!(b"*", stream.expect(parser::start)?);
+  assert_eq!(
+    (4, (vec![&b"\\Seen"[..]], 7)),
+    stream.expect(parser::fetch_flags_data)?
+  );
+  assert_eq!(b"fetch", stream.expect(parser::start)?);
+  stream.expect(parser::ok)?;
+
+  assert_eq!(
+    Some("INBOX/Entwürfe".to_string()),
+    sin::protocol::utf7_to_utf8(b"INBOX/Entw&APw-rfe")
+  );
+  Ok(())
+}
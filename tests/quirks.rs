@@ -0,0 +1,83 @@
+use std::{fs, path};
+use test_log::test;
+
+mod common;
+
+// Other servers' deviations from the RFCs, reproduced by the mock server.
+
+// Detected from the ID response, only the system flags are synchronized.
+#[test]
+fn cyrus_permanentflags() {
+  common::setup(common::mock::cyrus_server, |runner| -> _ {
+    let server_inbox = runner.server_maildir("INBOX", &None)?;
+    let path = server_inbox.cur(common::email("test").as_bytes())?;
+
+    runner.run(sin::Mode::Pull)?;
+    runner.notmuch_tag("+keyword -unread", "mid:test")?;
+    runner.run(sin::Mode::Push)?;
+
+    let seen = path::PathBuf::from(format!("{}:2,S", path.to_str().unwrap()));
+    assert!(seen.exists());
+    assert!(!fs::read_to_string(server_inbox.path().join("mock-uidlist"))?.contains("keyword"));
+
+    // The keyword isn't cached so it isn't removed by a pull.
+    fs::rename(&seen, format!("{}:2,FS", path.to_str().unwrap()))?;
+    runner.run(sin::Mode::Pull)?;
+
+    assert!(
+      runner
+        .notmuch_dump()?
+        .contains("+flagged +keyword -- id:test")
+    );
+
+    Ok(())
+  })
+}
+
+// Without QRESYNC, the changes and removals are searched for.
+#[test]
+fn condstore_search() {
+  common::setup(common::mock::condstore_server, |runner| -> _ {
+    let server_inbox = runner.server_maildir("INBOX", &None)?;
+    let path = server_inbox.cur(common::email("test").as_bytes())?;
+    let removed = server_inbox.cur(common::email("removed").as_bytes())?;
+
+    runner.run(sin::Mode::Pull)?;
+
+    let client_inbox = runner.client_maildir("INBOX", &None)?;
+    assert_eq!((2, 0, 0), runner.maildir_count(&client_inbox)?);
+
+    fs::rename(&path, format!("{}:2,S", path.to_str().unwrap()))?;
+    fs::remove_file(&removed)?;
+    runner.run(sin::Mode::Pull)?;
+
+    assert_eq!((1, 0, 0), runner.maildir_count(&client_inbox)?);
+    assert!(!runner.notmuch_dump()?.contains("+unread -- id:test"));
+
+    runner.notmuch_tag("+flagged", "mid:test")?;
+    runner.run(sin::Mode::Push)?;
+    assert!(path::PathBuf::from(format!("{}:2,FS", path.to_str().unwrap())).exists());
+
+    Ok(())
+  })
+}
+
+// Without UIDNEXT, nothing left in tmp is trusted but new messages are still pulled.
+#[test]
+fn missing_uidnext() {
+  common::setup(common::mock::no_uidnext_server, |runner| -> _ {
+    let server_inbox = runner.server_maildir("INBOX", &None)?;
+    server_inbox.cur(common::email("test0").as_bytes())?;
+
+    runner.run(sin::Mode::Pull)?;
+
+    let client_inbox = runner.client_maildir("INBOX", &None)?;
+    assert_eq!((0, 1, 0), runner.maildir_count(&client_inbox)?);
+
+    server_inbox.cur(common::email("test1").as_bytes())?;
+    runner.run(sin::Mode::Pull)?;
+    assert_eq!((0, 2, 0), runner.maildir_count(&client_inbox)?);
+
+    Ok(())
+  })
+}
@@ -35,15 +35,65 @@ fn remote_new() {
     assert_eq!((0, 1, 0), runner.maildir_count(&client_inbox)?);
     pretty_assertions::assert_eq!("#notmuch-dump batch-tag:3 config,properties,tags
 +sin.internal -- id:0@sin
-#= 0@sin sin.INBOX.highestmodseq=2 sin.INBOX.separator=%2f sin.INBOX.uidvalidity=<omitted> sin.mailbox=INBOX sin.marker=root
+#= 0@sin sin.INBOX.highestmodseq=2 sin.INBOX.separator=%2f sin.INBOX.uidnext=2 sin.INBOX.uidvalidity=<omitted> sin.account=user@localhost sin.mailbox=INBOX sin.marker=root sin.schema=1
 +unread -- id:test
-#= test sin.0.INBOX.modseq=2 sin.0.INBOX.tag=unread sin.0.INBOX.uid=1 sin.0.INBOX.uidvalidity=<omitted> sin.0.mailbox=INBOX sin.0.marker=message
+#= test sin.0.INBOX.modseq.1=2 sin.0.INBOX.tag=unread sin.0.INBOX.uid=1 sin.0.INBOX.uidvalidity=<omitted> sin.0.mailbox=INBOX sin.0.marker=message
 ", runner.notmuch_dump()?);
 
     Ok(())
   })
 }
 
+#[test]
+fn buffer_limit() {
+  common::setup(common::dovecot::server, |runner| -> _ {
+    let server_inbox = runner.server_maildir("INBOX", &None)?;
+    let large = common::email("large") + &"a".repeat(1024 * 1024);
+    server_inbox.cur(large.as_bytes())?;
+
+    let error = runner
+      .with_buffer_limit(512 * 1024)
+      .run(sin::Mode::Pull)
+      .unwrap_err();
+    assert!(
+      error
+        .root_cause()
+        .to_string()
+        .ends_with("exceeds the buffer limit of 524288 bytes")
+    );
+    runner.run(sin::Mode::Pull)?;
+
+    let client_inbox = runner.client_maildir("INBOX", &None)?;
+    assert_eq!((0, 1, 0), runner.maildir_count(&client_inbox)?);
+
+    Ok(())
+  })
+}
+
+#[test]
+fn notmuch_config() {
+  common::setup(common::dovecot::server, |runner| -> _ {
+    let server_inbox = runner.server_maildir("INBOX", &None)?;
+    server_inbox.cur(common::email("test").as_bytes())?;
+    let config = tempfile::NamedTempFile::new()?;
+    fs::write(config.path(), "[new]\ntags=custom\n")?;
+
+    runner
+      .with_new_tags()
+      .with_notmuch_config(config.path())
+      .run(sin::Mode::Pull)?;
+
+    assert!(
+      runner
+        .notmuch_dump()?
+        .lines()
+        .any(|line| line.starts_with("+custom ") && line.ends_with(" -- id:test"))
+    );
+
+    Ok(())
+  })
+}
+
 #[test]
 fn remote_subfolder() {
   common::setup(common::dovecot::server, |runner| -> _ {
@@ -56,9 +106,9 @@ fn remote_subfolder() {
     assert_eq!((0, 1, 0), runner.maildir_count(&client_subfolder)?);
     pretty_assertions::assert_eq!("#notmuch-dump batch-tag:3 config,properties,tags
 +sin.internal -- id:0@sin
-#= 0@sin sin.INBOX.highestmodseq=1 sin.INBOX.separator=%2f sin.INBOX.uidvalidity=<omitted> sin.folder%2fsub.highestmodseq=2 sin.folder%2fsub.separator=%2f sin.folder%2fsub.uidvalidity=<omitted> sin.mailbox=INBOX sin.mailbox=folder%2fsub sin.marker=root
+#= 0@sin sin.INBOX.highestmodseq=1 sin.INBOX.separator=%2f sin.INBOX.uidnext=1 sin.INBOX.uidvalidity=<omitted> sin.account=user@localhost sin.folder%2fsub.highestmodseq=2 sin.folder%2fsub.separator=%2f sin.folder%2fsub.uidnext=2 sin.folder%2fsub.uidvalidity=<omitted> sin.mailbox=INBOX sin.mailbox=folder%2fsub sin.marker=root sin.schema=1
 +unread -- id:test
-#= test sin.0.folder%2fsub.modseq=2 sin.0.folder%2fsub.tag=unread sin.0.folder%2fsub.uid=1 sin.0.folder%2fsub.uidvalidity=<omitted> sin.0.mailbox=folder%2fsub sin.0.marker=message
+#= test sin.0.folder%2fsub.modseq.1=2 sin.0.folder%2fsub.tag=unread sin.0.folder%2fsub.uid=1 sin.0.folder%2fsub.uidvalidity=<omitted> sin.0.mailbox=folder%2fsub sin.0.marker=message
 ", runner.notmuch_dump()?);
 
     Ok(())
@@ -78,9 +128,60 @@ fn remote_subfolder_separator() {
     assert_eq!((0, 1, 0), runner.maildir_count(&client_subfolder)?);
     pretty_assertions::assert_eq!("#notmuch-dump batch-tag:3 config,properties,tags
 +sin.internal -- id:0@sin
-#= 0@sin sin.INBOX.highestmodseq=1 sin.INBOX.separator=. sin.INBOX.uidvalidity=<omitted> sin.folder.sub.highestmodseq=2 sin.folder.sub.separator=. sin.folder.sub.uidvalidity=<omitted> sin.mailbox=INBOX sin.mailbox=folder.sub sin.marker=root
+#= 0@sin sin.INBOX.highestmodseq=1 sin.INBOX.separator=. sin.INBOX.uidnext=1 sin.INBOX.uidvalidity=<omitted> sin.account=separator@localhost sin.folder.sub.highestmodseq=2 sin.folder.sub.separator=. sin.folder.sub.uidnext=2 sin.folder.sub.uidvalidity=<omitted> sin.mailbox=INBOX sin.mailbox=folder.sub sin.marker=root sin.schema=1
++unread -- id:test
+#= test sin.0.folder.sub.modseq.1=2 sin.0.folder.sub.tag=unread sin.0.folder.sub.uid=1 sin.0.folder.sub.uidvalidity=<omitted> sin.0.mailbox=folder.sub sin.0.marker=message
+", runner.notmuch_dump()?);
+
+    Ok(())
+  })
+}
+
+#[test]
+fn remote_subfolder_namespace() {
+  common::setup(common::dovecot::server, |runner| -> _ {
+    // The server lists INBOX.folder, the prefix is stripped locally.
+    let runner = runner.with_user("prefix");
+    let server_subfolder = runner.server_maildir("folder", &Some('.'))?;
+    server_subfolder.cur(common::email("test").as_bytes())?;
+
+    runner.run(sin::Mode::Pull)?;
+
+    let client_subfolder = runner.client_maildir("folder", &Some('.'))?;
+    assert_eq!((0, 1, 0), runner.maildir_count(&client_subfolder)?);
+    pretty_assertions::assert_eq!("#notmuch-dump batch-tag:3 config,properties,tags
++sin.internal -- id:0@sin
+#= 0@sin sin.INBOX.highestmodseq=1 sin.INBOX.separator=. sin.INBOX.uidnext=1 sin.INBOX.uidvalidity=<omitted> sin.account=prefix@localhost sin.folder.highestmodseq=2 sin.folder.separator=. sin.folder.uidnext=2 sin.folder.uidvalidity=<omitted> sin.mailbox=INBOX sin.mailbox=folder sin.marker=root sin.schema=1
++unread -- id:test
+#= test sin.0.folder.modseq.1=2 sin.0.folder.tag=unread sin.0.folder.uid=1 sin.0.folder.uidvalidity=<omitted> sin.0.mailbox=folder sin.0.marker=message
+", runner.notmuch_dump()?);
+
+    Ok(())
+  })
+}
+
+#[test]
+fn subscribed_only() {
+  common::setup(common::dovecot::server, |runner| -> _ {
+    let runner = runner.with_subscribed_only();
+    let server_folder = runner.server_maildir("folder", &Some('/'))?;
+    server_folder.cur(common::email("test").as_bytes())?;
+
+    // Nothing is subscribed yet, not even INBOX.
+    runner.run(sin::Mode::Pull)?;
+
+    let client_folder = runner.client_maildir("folder", &Some('/'))?;
+    assert_eq!((0, 0, 0), runner.maildir_count(&client_folder)?);
+
+    runner.server_subscribe("folder")?;
+    runner.run(sin::Mode::Pull)?;
+
+    assert_eq!((0, 1, 0), runner.maildir_count(&client_folder)?);
+    pretty_assertions::assert_eq!("#notmuch-dump batch-tag:3 config,properties,tags
++sin.internal -- id:0@sin
+#= 0@sin sin.account=user@localhost sin.folder.highestmodseq=2 sin.folder.separator=%2f sin.folder.uidnext=2 sin.folder.uidvalidity=<omitted> sin.mailbox=folder sin.marker=root sin.schema=1
 +unread -- id:test
-#= test sin.0.folder.sub.modseq=2 sin.0.folder.sub.tag=unread sin.0.folder.sub.uid=1 sin.0.folder.sub.uidvalidity=<omitted> sin.0.mailbox=folder.sub sin.0.marker=message
+#= test sin.0.folder.modseq.1=2 sin.0.folder.tag=unread sin.0.folder.uid=1 sin.0.folder.uidvalidity=<omitted> sin.0.mailbox=folder sin.0.marker=message
 ", runner.notmuch_dump()?);
 
     Ok(())
@@ -99,9 +200,9 @@ fn remote_change() {
     assert_eq!((0, 1, 0), runner.maildir_count(&client_inbox)?);
     pretty_assertions::assert_eq!("#notmuch-dump batch-tag:3 config,properties,tags
 +sin.internal -- id:0@sin
-#= 0@sin sin.INBOX.highestmodseq=2 sin.INBOX.separator=%2f sin.INBOX.uidvalidity=<omitted> sin.mailbox=INBOX sin.marker=root
+#= 0@sin sin.INBOX.highestmodseq=2 sin.INBOX.separator=%2f sin.INBOX.uidnext=2 sin.INBOX.uidvalidity=<omitted> sin.account=user@localhost sin.mailbox=INBOX sin.marker=root sin.schema=1
 +unread -- id:test
-#= test sin.0.INBOX.modseq=2 sin.0.INBOX.tag=unread sin.0.INBOX.uid=1 sin.0.INBOX.uidvalidity=<omitted> sin.0.mailbox=INBOX sin.0.marker=message
+#= test sin.0.INBOX.modseq.1=2 sin.0.INBOX.tag=unread sin.0.INBOX.uid=1 sin.0.INBOX.uidvalidity=<omitted> sin.0.mailbox=INBOX sin.0.marker=message
 ", runner.notmuch_dump()?);
 
     fs::rename(
@@ -113,9 +214,9 @@ fn remote_change() {
     assert_eq!((0, 1, 0), runner.maildir_count(&client_inbox)?);
     pretty_assertions::assert_eq!("#notmuch-dump batch-tag:3 config,properties,tags
 +sin.internal -- id:0@sin
-#= 0@sin sin.INBOX.highestmodseq=3 sin.INBOX.separator=%2f sin.INBOX.uidvalidity=<omitted> sin.mailbox=INBOX sin.marker=root
+#= 0@sin sin.INBOX.highestmodseq=3 sin.INBOX.separator=%2f sin.INBOX.uidnext=2 sin.INBOX.uidvalidity=<omitted> sin.account=user@localhost sin.mailbox=INBOX sin.marker=root sin.schema=1
 +unknown-0 +unread -- id:test
-#= test sin.0.INBOX.modseq=3 sin.0.INBOX.tag=unknown-0 sin.0.INBOX.tag=unread sin.0.INBOX.uid=1 sin.0.INBOX.uidvalidity=<omitted> sin.0.mailbox=INBOX sin.0.marker=message
+#= test sin.0.INBOX.modseq.1=3 sin.0.INBOX.tag=unknown-0 sin.0.INBOX.tag=unread sin.0.INBOX.uid=1 sin.0.INBOX.uidvalidity=<omitted> sin.0.mailbox=INBOX sin.0.marker=message
 ", runner.notmuch_dump()?);
 
     Ok(())
@@ -139,7 +240,7 @@ fn remote_removal() {
     assert_eq!((0, 0, 0), runner.maildir_count(&client_inbox)?);
     pretty_assertions::assert_eq!("#notmuch-dump batch-tag:3 config,properties,tags
 +sin.internal -- id:0@sin
-#= 0@sin sin.INBOX.highestmodseq=3 sin.INBOX.separator=%2f sin.INBOX.uidvalidity=<omitted> sin.mailbox=INBOX sin.marker=root
+#= 0@sin sin.INBOX.highestmodseq=3 sin.INBOX.separator=%2f sin.INBOX.uidnext=2 sin.INBOX.uidvalidity=<omitted> sin.account=user@localhost sin.mailbox=INBOX sin.marker=root sin.schema=1
 ",
       runner.notmuch_dump()?
     );
@@ -148,6 +249,88 @@ fn remote_removal() {
   })
 }
 
+#[test]
+fn max_purge() {
+  common::setup(common::dovecot::server, |runner| -> _ {
+    let server_inbox = runner.server_maildir("INBOX", &None)?;
+    let paths = [
+      server_inbox.cur(common::email("test1").as_bytes())?,
+      server_inbox.cur(common::email("test2").as_bytes())?,
+    ];
+
+    runner.run(sin::Mode::Pull)?;
+
+    let client_inbox = runner.client_maildir("INBOX", &None)?;
+    assert_eq!((0, 2, 0), runner.maildir_count(&client_inbox)?);
+
+    for path in paths {
+      fs::remove_file(&path)?;
+    }
+    for max_purge in [sin::MaxPurge::Count(1), sin::MaxPurge::Percent(50)] {
+      assert_eq!(
+        runner
+          .with_max_purge(max_purge)
+          .run(sin::Mode::Pull)
+          .unwrap_err()
+          .chain()
+          .next()
+          .unwrap()
+          .to_string(),
+        format!(
+          "the pull would remove 2 local file(s), more than --max-purge {max_purge} allows, pass \
+           --ignore-max-purge if that's expected"
+        )
+      );
+      // Nothing was removed before the limit was checked.
+      assert_eq!((0, 2, 0), runner.maildir_count(&client_inbox)?);
+    }
+    runner
+      .with_max_purge(sin::MaxPurge::Count(2))
+      .run(sin::Mode::Pull)?;
+
+    assert_eq!((0, 0, 0), runner.maildir_count(&client_inbox)?);
+
+    Ok(())
+  })
+}
+
+#[test]
+fn max_messages() {
+  common::setup(common::dovecot::server, |runner| -> _ {
+    let server_inbox = runner.server_maildir("INBOX", &None)?;
+    for index in 0..3 {
+      server_inbox.cur(common::email(&format!("test{index}")).as_bytes())?;
+    }
+    let server_folder = runner.server_maildir("folder", &Some('/'))?;
+    for index in 3..5 {
+      server_folder.cur(common::email(&format!("test{index}")).as_bytes())?;
+    }
+
+    runner
+      .with_max_messages(sin::MaxMessages::PerMailbox(2))
+      .run(sin::Mode::Pull)?;
+    let client_inbox = runner.client_maildir("INBOX", &None)?;
+    let client_folder = runner.client_maildir("folder", &Some('/'))?;
+    assert_eq!((0, 2, 0), runner.maildir_count(&client_inbox)?);
+    assert_eq!((0, 2, 0), runner.maildir_count(&client_folder)?);
+
+    // The message left behind is still new to the next pull, whatever changed in the meantime.
+    server_inbox.cur(common::email("test5").as_bytes())?;
+    runner
+      .with_max_messages(sin::MaxMessages::Total(1))
+      .run(sin::Mode::Pull)?;
+    assert_eq!((0, 3, 0), runner.maildir_count(&client_inbox)?);
+
+    runner.run(sin::Mode::Pull)?;
+    assert_eq!((0, 4, 0), runner.maildir_count(&client_inbox)?);
+    assert_eq!((0, 2, 0), runner.maildir_count(&client_folder)?);
+    runner.run(sin::Mode::Pull)?;
+    assert_eq!((0, 4, 0), runner.maildir_count(&client_inbox)?);
+
+    Ok(())
+  })
+}
+
 #[test]
 fn remote_mailbox_removal() {
   common::setup(common::dovecot::server, |runner| -> _ {
@@ -158,9 +341,9 @@ fn remote_mailbox_removal() {
 
     pretty_assertions::assert_eq!("#notmuch-dump batch-tag:3 config,properties,tags
 +sin.internal -- id:0@sin
-#= 0@sin sin.INBOX.highestmodseq=1 sin.INBOX.separator=%2f sin.INBOX.uidvalidity=<omitted> sin.folder.highestmodseq=2 sin.folder.separator=%2f sin.folder.uidvalidity=<omitted> sin.mailbox=INBOX sin.mailbox=folder sin.marker=root
+#= 0@sin sin.INBOX.highestmodseq=1 sin.INBOX.separator=%2f sin.INBOX.uidnext=1 sin.INBOX.uidvalidity=<omitted> sin.account=user@localhost sin.folder.highestmodseq=2 sin.folder.separator=%2f sin.folder.uidnext=2 sin.folder.uidvalidity=<omitted> sin.mailbox=INBOX sin.mailbox=folder sin.marker=root sin.schema=1
 +unread -- id:test
-#= test sin.0.folder.modseq=2 sin.0.folder.tag=unread sin.0.folder.uid=1 sin.0.folder.uidvalidity=<omitted> sin.0.mailbox=folder sin.0.marker=message
+#= test sin.0.folder.modseq.1=2 sin.0.folder.tag=unread sin.0.folder.uid=1 sin.0.folder.uidvalidity=<omitted> sin.0.mailbox=folder sin.0.marker=message
 ", runner.notmuch_dump()?);
 
     server_folder.remove()?;
@@ -173,14 +356,67 @@ fn remote_mailbox_removal() {
         .next()
         .unwrap()
         .to_string(),
-      "folder has been removed on the server, allow to purge it locally (all messages will be \
-       removed) by passing --purgeable folder"
+      "mailboxes changed on the server: folder (removed); allow to purge them locally (all \
+       messages will be removed) by passing --purgeable folder (or a pattern, or \
+       --purge-all-removed for the removed ones)"
     );
     runner.with_purgeable("folder").run(sin::Mode::Pull)?;
 
     pretty_assertions::assert_eq!("#notmuch-dump batch-tag:3 config,properties,tags
 +sin.internal -- id:0@sin
-#= 0@sin sin.INBOX.highestmodseq=1 sin.INBOX.separator=%2f sin.INBOX.uidvalidity=<omitted> sin.mailbox=INBOX sin.marker=root
+#= 0@sin sin.INBOX.highestmodseq=1 sin.INBOX.separator=%2f sin.INBOX.uidnext=1 sin.INBOX.uidvalidity=<omitted> sin.account=user@localhost sin.mailbox=INBOX sin.marker=root sin.schema=1
+",
+      runner.notmuch_dump()?
+    );
+
+    Ok(())
+  })
+}
+
+#[test]
+fn remote_mailboxes_removal() {
+  common::setup(common::dovecot::server, |runner| -> _ {
+    let mut server_folders = Vec::new();
+    for mailbox in ["Archive/2019/a", "Archive/2019/b", "Archive/2020"] {
+      let server_folder = runner.server_maildir(mailbox, &Some('/'))?;
+      server_folder.cur(common::email(mailbox).as_bytes())?;
+      server_folders.push(server_folder);
+    }
+
+    runner.run(sin::Mode::Pull)?;
+
+    for server_folder in server_folders {
+      server_folder.remove()?;
+    }
+
+    // Every mailbox is reported at once.
+    let error = |runner: &common::Runner| -> String {
+      runner
+        .run(sin::Mode::Pull)
+        .unwrap_err()
+        .chain()
+        .next()
+        .unwrap()
+        .to_string()
+    };
+    assert_eq!(
+      error(runner),
+      "mailboxes changed on the server: Archive/2019/a (removed), Archive/2019/b (removed), \
+       Archive/2020 (removed); allow to purge them locally (all messages will be removed) by \
+       passing --purgeable Archive/2019/a --purgeable Archive/2019/b --purgeable Archive/2020 (or \
+       a pattern, or --purge-all-removed for the removed ones)"
+    );
+    assert_eq!(
+      error(&runner.with_purgeable("Archive/2019/*")),
+      "mailboxes changed on the server: Archive/2020 (removed); allow to purge them locally (all \
+       messages will be removed) by passing --purgeable Archive/2020 (or a pattern, or \
+       --purge-all-removed for the removed ones)"
+    );
+    runner.with_purge_all_removed().run(sin::Mode::Pull)?;
+
+    pretty_assertions::assert_eq!("#notmuch-dump batch-tag:3 config,properties,tags
++sin.internal -- id:0@sin
+#= 0@sin sin.INBOX.highestmodseq=1 sin.INBOX.separator=%2f sin.INBOX.uidnext=1 sin.INBOX.uidvalidity=<omitted> sin.account=user@localhost sin.mailbox=INBOX sin.marker=root sin.schema=1
 ",
       runner.notmuch_dump()?
     );
@@ -201,9 +437,9 @@ fn uidvalidity() {
     assert_eq!((0, 1, 0), runner.maildir_count(&client_inbox)?);
     pretty_assertions::assert_eq!("#notmuch-dump batch-tag:3 config,properties,tags
 +sin.internal -- id:0@sin
-#= 0@sin sin.INBOX.highestmodseq=2 sin.INBOX.separator=%2f sin.INBOX.uidvalidity=<omitted> sin.mailbox=INBOX sin.marker=root
+#= 0@sin sin.INBOX.highestmodseq=2 sin.INBOX.separator=%2f sin.INBOX.uidnext=2 sin.INBOX.uidvalidity=<omitted> sin.account=user@localhost sin.mailbox=INBOX sin.marker=root sin.schema=1
 +unread -- id:test1
-#= test1 sin.0.INBOX.modseq=2 sin.0.INBOX.tag=unread sin.0.INBOX.uid=1 sin.0.INBOX.uidvalidity=<omitted> sin.0.mailbox=INBOX sin.0.marker=message
+#= test1 sin.0.INBOX.modseq.1=2 sin.0.INBOX.tag=unread sin.0.INBOX.uid=1 sin.0.INBOX.uidvalidity=<omitted> sin.0.mailbox=INBOX sin.0.marker=message
 ", runner.notmuch_dump()?);
 
     // Dovecot would repopulate the maildir with the same uidvalidity (seconds since epoch).
@@ -221,8 +457,9 @@ fn uidvalidity() {
         .next()
         .unwrap()
         .to_string(),
-      "INBOX's validity has changed on the server, allow to purge it locally (all messages will be \
-       removed) by passing --purgeable INBOX"
+      "mailboxes changed on the server: INBOX (new validity); allow to purge them locally (all \
+       messages will be removed) by passing --purgeable INBOX (or a pattern, or \
+       --purge-all-removed for the removed ones)"
     );
 
     runner.with_purgeable("INBOX").run(sin::Mode::Pull)?;
@@ -230,9 +467,9 @@ fn uidvalidity() {
     assert_eq!((0, 1, 0), runner.maildir_count(&client_inbox)?);
     pretty_assertions::assert_eq!("#notmuch-dump batch-tag:3 config,properties,tags
 +sin.internal -- id:0@sin
-#= 0@sin sin.INBOX.highestmodseq=2 sin.INBOX.separator=%2f sin.INBOX.uidvalidity=<omitted> sin.mailbox=INBOX sin.marker=root
+#= 0@sin sin.INBOX.highestmodseq=2 sin.INBOX.separator=%2f sin.INBOX.uidnext=2 sin.INBOX.uidvalidity=<omitted> sin.account=user@localhost sin.mailbox=INBOX sin.marker=root sin.schema=1
 +unread -- id:test2
-#= test2 sin.0.INBOX.modseq=2 sin.0.INBOX.tag=unread sin.0.INBOX.uid=1 sin.0.INBOX.uidvalidity=<omitted> sin.0.mailbox=INBOX sin.0.marker=message
+#= test2 sin.0.INBOX.modseq.1=2 sin.0.INBOX.tag=unread sin.0.INBOX.uid=1 sin.0.INBOX.uidvalidity=<omitted> sin.0.mailbox=INBOX sin.0.marker=message
 ", runner.notmuch_dump()?);
 
     Ok(())
@@ -259,11 +496,11 @@ fn multi_user() {
 
     pretty_assertions::assert_eq!("#notmuch-dump batch-tag:3 config,properties,tags
 +sin.internal -- id:0@sin
-#= 0@sin sin.INBOX.highestmodseq=2 sin.INBOX.separator=%2f sin.INBOX.uidvalidity=<omitted> sin.mailbox=INBOX sin.marker=root
+#= 0@sin sin.INBOX.highestmodseq=2 sin.INBOX.separator=%2f sin.INBOX.uidnext=2 sin.INBOX.uidvalidity=<omitted> sin.account=user1@localhost sin.mailbox=INBOX sin.marker=root sin.schema=1
 +unread -- id:test
-#= test sin.0.INBOX.modseq=2 sin.0.INBOX.tag=unread sin.0.INBOX.uid=1 sin.0.INBOX.uidvalidity=<omitted> sin.0.mailbox=INBOX sin.0.marker=message sin.1.INBOX.modseq=2 sin.1.INBOX.tag=unread sin.1.INBOX.uid=1 sin.1.INBOX.uidvalidity=<omitted> sin.1.mailbox=INBOX sin.1.marker=message
+#= test sin.0.INBOX.modseq.1=2 sin.0.INBOX.tag=unread sin.0.INBOX.uid=1 sin.0.INBOX.uidvalidity=<omitted> sin.0.mailbox=INBOX sin.0.marker=message sin.1.INBOX.modseq.1=2 sin.1.INBOX.tag=unread sin.1.INBOX.uid=1 sin.1.INBOX.uidvalidity=<omitted> sin.1.mailbox=INBOX sin.1.marker=message
 +sin.internal -- id:1@sin
-#= 1@sin sin.INBOX.highestmodseq=2 sin.INBOX.separator=%2f sin.INBOX.uidvalidity=<omitted> sin.mailbox=INBOX sin.marker=root
+#= 1@sin sin.INBOX.highestmodseq=2 sin.INBOX.separator=%2f sin.INBOX.uidnext=2 sin.INBOX.uidvalidity=<omitted> sin.account=user2@localhost sin.mailbox=INBOX sin.marker=root sin.schema=1
 ", runner.notmuch_dump()?);
 
     fs::remove_dir_all(runner2.client_maildir_builder()?.path())?;
@@ -277,13 +514,13 @@ fn multi_user() {
     // 1@sin has been repurposed and so sin.1.* has been removed from test.
     pretty_assertions::assert_eq!("#notmuch-dump batch-tag:3 config,properties,tags
 +sin.internal -- id:0@sin
-#= 0@sin sin.INBOX.highestmodseq=2 sin.INBOX.separator=%2f sin.INBOX.uidvalidity=<omitted> sin.mailbox=INBOX sin.marker=root
+#= 0@sin sin.INBOX.highestmodseq=2 sin.INBOX.separator=%2f sin.INBOX.uidnext=2 sin.INBOX.uidvalidity=<omitted> sin.account=user1@localhost sin.mailbox=INBOX sin.marker=root sin.schema=1
 +unread -- id:test
-#= test sin.0.INBOX.modseq=2 sin.0.INBOX.tag=unread sin.0.INBOX.uid=1 sin.0.INBOX.uidvalidity=<omitted> sin.0.mailbox=INBOX sin.0.marker=message
+#= test sin.0.INBOX.modseq.1=2 sin.0.INBOX.tag=unread sin.0.INBOX.uid=1 sin.0.INBOX.uidvalidity=<omitted> sin.0.mailbox=INBOX sin.0.marker=message
 +sin.internal -- id:1@sin
-#= 1@sin sin.INBOX.highestmodseq=2 sin.INBOX.separator=%2f sin.INBOX.uidvalidity=<omitted> sin.mailbox=INBOX sin.marker=root
+#= 1@sin sin.INBOX.highestmodseq=2 sin.INBOX.separator=%2f sin.INBOX.uidnext=2 sin.INBOX.uidvalidity=<omitted> sin.account=user3@localhost sin.mailbox=INBOX sin.marker=root sin.schema=1
 +unread -- id:test3
-#= test3 sin.1.INBOX.modseq=2 sin.1.INBOX.tag=unread sin.1.INBOX.uid=1 sin.1.INBOX.uidvalidity=<omitted> sin.1.mailbox=INBOX sin.1.marker=message
+#= test3 sin.1.INBOX.modseq.1=2 sin.1.INBOX.tag=unread sin.1.INBOX.uid=1 sin.1.INBOX.uidvalidity=<omitted> sin.1.mailbox=INBOX sin.1.marker=message
 ", runner.notmuch_dump()?);
 
     Ok(())
@@ -308,15 +545,146 @@ fn local_new() {
     assert_eq!((1, 0, 0), runner.maildir_count(&server_inbox)?);
 
     pretty_assertions::assert_eq!("#notmuch-dump batch-tag:3 config,properties,tags
-+sin.internal -- id:0@sin\n#= 0@sin sin.INBOX.highestmodseq=1 sin.INBOX.separator=%2f sin.INBOX.uidvalidity=<omitted> sin.lastmod=4 sin.mailbox=INBOX sin.marker=root
++sin.internal -- id:0@sin\n#= 0@sin sin.INBOX.highestmodseq=3 sin.INBOX.separator=%2f sin.INBOX.uidnext=1 sin.INBOX.uidvalidity=<omitted> sin.account=user@localhost sin.lastmod=4 sin.mailbox=INBOX sin.marker=root sin.schema=1
 +inbox +unread -- id:test
-#= test sin.0.INBOX.modseq=3 sin.0.INBOX.tag=inbox sin.0.INBOX.tag=unread sin.0.INBOX.uid=1 sin.0.INBOX.uidvalidity=<omitted> sin.0.mailbox=INBOX sin.0.marker=message
+#= test sin.0.INBOX.modseq.1=3 sin.0.INBOX.tag=inbox sin.0.INBOX.tag=unread sin.0.INBOX.uid=1 sin.0.INBOX.uidvalidity=<omitted> sin.0.mailbox=INBOX sin.0.marker=message
 ", runner.notmuch_dump()?);
 
     Ok(())
   })
 }
 
+#[test]
+fn local_mailbox() {
+  common::setup(common::dovecot::server, |runner| -> _ {
+    runner.run(sin::Mode::Pull)?;
+
+    // Created by hand, it doesn't exist on the server.
+    let client_folder = runner.client_maildir("folder", &Some('/'))?;
+    client_folder.cur(common::email("test").as_bytes())?;
+    runner.notmuch_new()?;
+
+    runner.run(sin::Mode::Push)?;
+    let runner = runner.with_create_mailboxes();
+    runner.run(sin::Mode::Push)?;
+    // Its messages are uploaded once it's been pulled.
+    runner.run_all(&[sin::Mode::Pull, sin::Mode::Push])?;
+
+    let server_folder = runner.server_maildir("folder", &Some('/'))?;
+    assert_eq!((1, 0, 0), runner.maildir_count(&server_folder)?);
+
+    Ok(())
+  })
+}
+
+#[test]
+fn local_only() {
+  common::setup(common::dovecot::server, |runner| -> _ {
+    let server_folder = runner.server_maildir("folder", &None)?;
+
+    runner.run(sin::Mode::Pull)?;
+
+    let client_folder = runner.client_maildir("folder", &None)?;
+    client_folder.cur(common::email("test").as_bytes())?;
+    runner.notmuch_new()?;
+
+    runner.with_local_only(".folder").run(sin::Mode::Push)?;
+    assert_eq!((0, 0, 0), runner.maildir_count(&server_folder)?);
+
+    runner.run(sin::Mode::Push)?;
+    assert_eq!((1, 0, 0), runner.maildir_count(&server_folder)?);
+
+    Ok(())
+  })
+}
+
+#[test]
+fn draft() {
+  common::setup(common::dovecot::server, |runner| -> _ {
+    let server_drafts = runner.server_maildir("Drafts", &None)?;
+    server_drafts.cur(common::email("test").as_bytes())?;
+
+    runner.run(sin::Mode::Pull)?;
+
+    // Edited locally: a new file for the same message.
+    let client_drafts = runner.client_maildir("Drafts", &None)?;
+    let edited = format!("{}\nedited", common::email("test"));
+    for entry in fs::read_dir(client_drafts.path().join("new"))? {
+      fs::remove_file(entry?.path())?;
+    }
+    client_drafts.cur(edited.as_bytes())?;
+    runner.notmuch_new()?;
+
+    runner.run(sin::Mode::Push)?;
+    assert_eq!((1, 0, 0), runner.maildir_count(&server_drafts)?);
+    for entry in fs::read_dir(server_drafts.path().join("cur"))? {
+      assert_eq!(edited, fs::read_to_string(entry?.path())?);
+    }
+
+    // Nothing left to do.
+    runner.run_all(&[sin::Mode::Pull, sin::Mode::Push])?;
+    assert_eq!((1, 0, 0), runner.maildir_count(&server_drafts)?);
+    assert_eq!((1, 0, 0), runner.maildir_count(&client_drafts)?);
+
+    Ok(())
+  })
+}
+
+#[test]
+fn draft_header() {
+  common::setup(common::dovecot::server, |runner| -> _ {
+    let server_drafts = runner.server_maildir("Drafts", &None)?;
+    server_drafts.cur(common::email("test").as_bytes())?;
+
+    runner.run(sin::Mode::Pull)?;
+
+    // Only the header was edited: the text is referenced from the previous version (CATENATE).
+    let client_drafts = runner.client_maildir("Drafts", &None)?;
+    let edited = format!("Cc: test\n{}", common::email("test"));
+    for entry in fs::read_dir(client_drafts.path().join("new"))? {
+      fs::remove_file(entry?.path())?;
+    }
+    client_drafts.cur(edited.as_bytes())?;
+    runner.notmuch_new()?;
+
+    runner.run(sin::Mode::Push)?;
+    assert_eq!((1, 0, 0), runner.maildir_count(&server_drafts)?);
+    for entry in fs::read_dir(server_drafts.path().join("cur"))? {
+      assert_eq!(edited, fs::read_to_string(entry?.path())?);
+    }
+
+    runner.run_all(&[sin::Mode::Pull, sin::Mode::Push])?;
+    assert_eq!((1, 0, 0), runner.maildir_count(&client_drafts)?);
+
+    Ok(())
+  })
+}
+
+#[test]
+fn append() {
+  common::setup(common::dovecot::server, |runner| -> _ {
+    let server_sent = runner.server_maildir("Sent", &None)?;
+
+    runner.run(sin::Mode::Pull)?;
+
+    let message = tempfile::NamedTempFile::new()?;
+    fs::write(message.path(), common::email("test"))?;
+    runner
+      .with_append("Sent", message.path())
+      .run(sin::Mode::Append)?;
+    assert_eq!((1, 0, 0), runner.maildir_count(&server_sent)?);
+    let client_sent = runner.client_maildir("Sent", &None)?;
+    assert_eq!((1, 0, 0), runner.maildir_count(&client_sent)?);
+    assert!(runner.notmuch_dump()?.contains(" sin.0.Sent.uid=1 "));
+
+    // Not downloaded again.
+    runner.run(sin::Mode::Pull)?;
+    assert_eq!((1, 0, 0), runner.maildir_count(&client_sent)?);
+
+    Ok(())
+  })
+}
+
 #[test]
 fn local_change() {
   common::setup(common::dovecot::server, |runner| -> _ {
@@ -330,18 +698,18 @@ fn local_change() {
 
     pretty_assertions::assert_eq!("#notmuch-dump batch-tag:3 config,properties,tags
 +sin.internal -- id:0@sin
-#= 0@sin sin.INBOX.highestmodseq=2 sin.INBOX.separator=%2f sin.INBOX.uidvalidity=<omitted> sin.mailbox=INBOX sin.marker=root
+#= 0@sin sin.INBOX.highestmodseq=2 sin.INBOX.separator=%2f sin.INBOX.uidnext=2 sin.INBOX.uidvalidity=<omitted> sin.account=user@localhost sin.mailbox=INBOX sin.marker=root sin.schema=1
 +unread -- id:test
-#= test sin.0.INBOX.modseq=2 sin.0.INBOX.tag=unread sin.0.INBOX.uid=1 sin.0.INBOX.uidvalidity=<omitted> sin.0.mailbox=INBOX sin.0.marker=message
+#= test sin.0.INBOX.modseq.1=2 sin.0.INBOX.tag=unread sin.0.INBOX.uid=1 sin.0.INBOX.uidvalidity=<omitted> sin.0.mailbox=INBOX sin.0.marker=message
 ", runner.notmuch_dump()?);
 
     runner.notmuch_tag("-unread", "mid:test")?;
 
     pretty_assertions::assert_eq!("#notmuch-dump batch-tag:3 config,properties,tags
 +sin.internal -- id:0@sin
-#= 0@sin sin.INBOX.highestmodseq=2 sin.INBOX.separator=%2f sin.INBOX.uidvalidity=<omitted> sin.mailbox=INBOX sin.marker=root
+#= 0@sin sin.INBOX.highestmodseq=2 sin.INBOX.separator=%2f sin.INBOX.uidnext=2 sin.INBOX.uidvalidity=<omitted> sin.account=user@localhost sin.mailbox=INBOX sin.marker=root sin.schema=1
  -- id:test
-#= test sin.0.INBOX.modseq=2 sin.0.INBOX.tag=unread sin.0.INBOX.uid=1 sin.0.INBOX.uidvalidity=<omitted> sin.0.mailbox=INBOX sin.0.marker=message
+#= test sin.0.INBOX.modseq.1=2 sin.0.INBOX.tag=unread sin.0.INBOX.uid=1 sin.0.INBOX.uidvalidity=<omitted> sin.0.mailbox=INBOX sin.0.marker=message
 ", runner.notmuch_dump()?);
 
     runner.run(sin::Mode::Push)?;
@@ -349,27 +717,83 @@ fn local_change() {
     assert!(path::Path::new(&format!("{}:2,S", path.to_str().unwrap())).exists());
     assert_eq!((1, 0, 0), runner.maildir_count(&client_inbox)?);
 
-    // Notice how highestmodseq < modseq.
+    // Only the push changed the mailbox, the highestmodseq follows.
     pretty_assertions::assert_eq!("#notmuch-dump batch-tag:3 config,properties,tags
 +sin.internal -- id:0@sin
-#= 0@sin sin.INBOX.highestmodseq=2 sin.INBOX.separator=%2f sin.INBOX.uidvalidity=<omitted> sin.lastmod=6 sin.mailbox=INBOX sin.marker=root
+#= 0@sin sin.INBOX.highestmodseq=3 sin.INBOX.separator=%2f sin.INBOX.uidnext=2 sin.INBOX.uidvalidity=<omitted> sin.account=user@localhost sin.lastmod=6 sin.mailbox=INBOX sin.marker=root sin.schema=1
  -- id:test
-#= test sin.0.INBOX.modseq=3 sin.0.INBOX.uid=1 sin.0.INBOX.uidvalidity=<omitted> sin.0.mailbox=INBOX sin.0.marker=message
+#= test sin.0.INBOX.modseq.1=3 sin.0.INBOX.uid=1 sin.0.INBOX.uidvalidity=<omitted> sin.0.mailbox=INBOX sin.0.marker=message
 ", runner.notmuch_dump()?);
 
     runner.run(sin::Mode::Pull)?;
 
     pretty_assertions::assert_eq!("#notmuch-dump batch-tag:3 config,properties,tags
 +sin.internal -- id:0@sin
-#= 0@sin sin.INBOX.highestmodseq=3 sin.INBOX.separator=%2f sin.INBOX.uidvalidity=<omitted> sin.lastmod=6 sin.mailbox=INBOX sin.marker=root
+#= 0@sin sin.INBOX.highestmodseq=3 sin.INBOX.separator=%2f sin.INBOX.uidnext=2 sin.INBOX.uidvalidity=<omitted> sin.account=user@localhost sin.lastmod=6 sin.mailbox=INBOX sin.marker=root sin.schema=1
  -- id:test
-#= test sin.0.INBOX.modseq=3 sin.0.INBOX.uid=1 sin.0.INBOX.uidvalidity=<omitted> sin.0.mailbox=INBOX sin.0.marker=message
+#= test sin.0.INBOX.modseq.1=3 sin.0.INBOX.uid=1 sin.0.INBOX.uidvalidity=<omitted> sin.0.mailbox=INBOX sin.0.marker=message
 ", runner.notmuch_dump()?);
 
     Ok(())
   })
 }
 
+#[test]
+fn flag_merge() {
+  common::setup(common::dovecot::server, |runner| -> _ {
+    let server_inbox = runner.server_maildir("INBOX", &None)?;
+    let path = server_inbox.cur(common::email("test").as_bytes())?;
+
+    runner.run(sin::Mode::Pull)?;
+
+    // Both sides changed since the last pull.
+    let path = {
+      let flagged = path::PathBuf::from(format!("{}:2,F", path.to_str().unwrap()));
+      fs::rename(&path, &flagged)?;
+      path.to_str().unwrap().to_string()
+    };
+    runner.notmuch_tag("-unread", "mid:test")?;
+
+    assert!(runner.run(sin::Mode::Push).is_err());
+    runner
+      .with_flag_merge(sin::FlagMerge::ThreeWay)
+      .run(sin::Mode::Push)?;
+
+    assert!(path::Path::new(&format!("{path}:2,FS")).exists());
+    assert!(runner.notmuch_dump()?.contains("\n+flagged -- id:test\n"));
+
+    Ok(())
+  })
+}
+
+#[test]
+fn expunge() {
+  common::setup(common::dovecot::server, |runner| -> _ {
+    let server_inbox = runner.server_maildir("INBOX", &None)?;
+    server_inbox.cur(common::email("test").as_bytes())?;
+    server_inbox.cur(common::email("kept").as_bytes())?;
+
+    runner.run(sin::Mode::Pull)?;
+    let client_inbox = runner.client_maildir("INBOX", &None)?;
+
+    runner.notmuch_tag("+deleted", "mid:test")?;
+
+    // Opt-in.
+    runner.run(sin::Mode::Push)?;
+    assert_eq!((2, 0, 0), runner.maildir_count(&server_inbox)?);
+
+    runner.with_expunge().run(sin::Mode::Push)?;
+    assert_eq!((1, 0, 0), runner.maildir_count(&server_inbox)?);
+    assert_eq!((0, 1, 0), runner.maildir_count(&client_inbox)?);
+    assert!(!runner.notmuch_dump()?.contains("id:test"));
+
+    runner.run(sin::Mode::Pull)?;
+    assert_eq!((0, 1, 0), runner.maildir_count(&client_inbox)?);
+
+    Ok(())
+  })
+}
+
 #[test]
 fn local_move() {
   common::setup(common::dovecot::server, |runner| -> _ {
@@ -403,15 +827,112 @@ fn local_move() {
 
     pretty_assertions::assert_eq!("#notmuch-dump batch-tag:3 config,properties,tags
 +sin.internal -- id:0@sin
-#= 0@sin sin.INBOX.highestmodseq=1 sin.INBOX.separator=%2f sin.INBOX.uidvalidity=<omitted> sin.folder.highestmodseq=1 sin.folder.separator=%2f sin.folder.uidvalidity=<omitted> sin.lastmod=7 sin.mailbox=INBOX sin.mailbox=folder sin.marker=root
+#= 0@sin sin.INBOX.highestmodseq=3 sin.INBOX.separator=%2f sin.INBOX.uidnext=1 sin.INBOX.uidvalidity=<omitted> sin.account=user@localhost sin.folder.highestmodseq=1 sin.folder.separator=%2f sin.folder.uidnext=1 sin.folder.uidvalidity=<omitted> sin.lastmod=7 sin.mailbox=INBOX sin.mailbox=folder sin.marker=root sin.schema=1
 +inbox +unread -- id:test
-#= test sin.0.folder.modseq=3 sin.0.folder.tag=inbox sin.0.folder.tag=unread sin.0.folder.uid=1 sin.0.folder.uidvalidity=<omitted> sin.0.mailbox=folder sin.0.marker=message
+#= test sin.0.folder.modseq.1=3 sin.0.folder.tag=inbox sin.0.folder.tag=unread sin.0.folder.uid=1 sin.0.folder.uidvalidity=<omitted> sin.0.mailbox=folder sin.0.marker=message
 ", runner.notmuch_dump()?);
 
     Ok(())
   })
 }
 
+#[test]
+fn local_copy() {
+  common::setup(common::dovecot::server, |runner| -> _ {
+    let server_inbox = runner.server_maildir("INBOX", &None)?;
+    let server_folder = runner.server_maildir("folder", &None)?;
+
+    runner.run(sin::Mode::Pull)?;
+
+    let client_inbox = runner.client_maildir("INBOX", &None)?;
+    let path = client_inbox.cur(common::email("test").as_bytes())?;
+    let client_folder = runner.client_maildir("folder", &None)?;
+
+    runner.notmuch_new()?;
+
+    runner.run(sin::Mode::Push)?;
+
+    fs::copy(
+      &path,
+      client_folder
+        .path()
+        .join("cur")
+        .join(path.file_name().unwrap()),
+    )?;
+
+    runner.notmuch_new()?;
+
+    runner.run(sin::Mode::Push)?;
+
+    assert_eq!((1, 0, 0), runner.maildir_count(&server_inbox)?);
+    assert_eq!((1, 0, 0), runner.maildir_count(&server_folder)?);
+
+    // The copy is known, it isn't downloaded again.
+    runner.run(sin::Mode::Pull)?;
+    assert_eq!((1, 0, 0), runner.maildir_count(&client_inbox)?);
+    assert_eq!((1, 0, 0), runner.maildir_count(&client_folder)?);
+
+    Ok(())
+  })
+}
+
+#[test]
+fn move_rule() {
+  common::setup(common::dovecot::server, |runner| -> _ {
+    let server_inbox = runner.server_maildir("INBOX", &None)?;
+    let server_junk = runner.server_maildir("Junk", &None)?;
+    server_inbox.cur(common::email("test").as_bytes())?;
+
+    runner.run(sin::Mode::Pull)?;
+    let client_inbox = runner.client_maildir("INBOX", &None)?;
+    let client_junk = runner.client_maildir("Junk", &None)?;
+    let runner = runner.with_move_rule("spam", "Junk");
+
+    runner.notmuch_tag("+spam", "mid:test")?;
+    runner.run(sin::Mode::Push)?;
+    assert_eq!((0, 0, 0), runner.maildir_count(&server_inbox)?);
+    assert_eq!((1, 0, 0), runner.maildir_count(&server_junk)?);
+    assert_eq!((0, 0, 0), runner.maildir_count(&client_inbox)?);
+    assert_eq!((0, 1, 0), runner.maildir_count(&client_junk)?);
+
+    // Nothing left to do.
+    runner.run(sin::Mode::Pull)?;
+    runner.run(sin::Mode::Push)?;
+    assert_eq!((0, 1, 0), runner.maildir_count(&client_junk)?);
+
+    runner.notmuch_tag("-spam", "mid:test")?;
+    runner.run(sin::Mode::Push)?;
+    assert_eq!((1, 0, 0), runner.maildir_count(&server_inbox)?);
+    assert_eq!((0, 0, 0), runner.maildir_count(&server_junk)?);
+    assert_eq!((0, 1, 0), runner.maildir_count(&client_inbox)?);
+    assert_eq!((0, 0, 0), runner.maildir_count(&client_junk)?);
+
+    Ok(())
+  })
+}
+
+#[test]
+fn keyword_rule() {
+  common::setup(common::dovecot::server, |runner| -> _ {
+    let server_inbox = runner.server_maildir("INBOX", &None)?;
+    server_inbox.cur(common::email("test").as_bytes())?;
+
+    runner.run(sin::Mode::Pull)?;
+    let runner = runner.with_rule("important", sin::Action::Keyword("$Important".to_string()));
+
+    runner.notmuch_tag("+important", "mid:test")?;
+    runner.run(sin::Mode::Push)?;
+    runner.run(sin::Mode::Pull)?;
+    assert!(
+      runner
+        .notmuch_dump()?
+        .contains("\n+$Important +important +inbox +unread -- id:test\n")
+    );
+
+    Ok(())
+  })
+}
+
 #[test]
 fn remote_move_with_local_change() {
   common::setup(common::dovecot::server, |runner| -> _ {
@@ -438,11 +959,101 @@ fn remote_move_with_local_change() {
     assert_eq!((0, 1, 0), runner.maildir_count(&client_inbox)?);
     pretty_assertions::assert_eq!("#notmuch-dump batch-tag:3 config,properties,tags
 +sin.internal -- id:0@sin
-#= 0@sin sin.INBOX.highestmodseq=2 sin.INBOX.separator=%2f sin.INBOX.uidvalidity=<omitted> sin.folder.highestmodseq=3 sin.folder.separator=%2f sin.folder.uidvalidity=<omitted> sin.mailbox=INBOX sin.mailbox=folder sin.marker=root
+#= 0@sin sin.INBOX.highestmodseq=2 sin.INBOX.separator=%2f sin.INBOX.uidnext=2 sin.INBOX.uidvalidity=<omitted> sin.account=user@localhost sin.folder.highestmodseq=3 sin.folder.separator=%2f sin.folder.uidnext=2 sin.folder.uidvalidity=<omitted> sin.mailbox=INBOX sin.mailbox=folder sin.marker=root sin.schema=1
 +tag +unread -- id:test
-#= test sin.0.INBOX.modseq=2 sin.0.INBOX.tag=unread sin.0.INBOX.uid=1 sin.0.INBOX.uidvalidity=<omitted> sin.0.mailbox=INBOX sin.0.marker=message
+#= test sin.0.INBOX.modseq.1=2 sin.0.INBOX.tag=unread sin.0.INBOX.uid=1 sin.0.INBOX.uidvalidity=<omitted> sin.0.mailbox=INBOX sin.0.marker=message
+", runner.notmuch_dump()?);
+
+    Ok(())
+  })
+}
+
+#[test]
+fn tunnel() {
+  common::setup(common::dovecot::server, |runner| -> _ {
+    let server_inbox = runner.server_maildir("INBOX", &None)?;
+    server_inbox.cur(common::email("test").as_bytes())?;
+
+    // Dovecot's --exec-mail greets with PREAUTH, no password is involved.
+    runner.with_tunnel()?.run(sin::Mode::Pull)?;
+
+    let client_inbox = runner.client_maildir("INBOX", &None)?;
+    assert_eq!((0, 1, 0), runner.maildir_count(&client_inbox)?);
+    pretty_assertions::assert_eq!("#notmuch-dump batch-tag:3 config,properties,tags
++sin.internal -- id:0@sin
+#= 0@sin sin.INBOX.highestmodseq=2 sin.INBOX.separator=%2f sin.INBOX.uidnext=2 sin.INBOX.uidvalidity=<omitted> sin.mailbox=INBOX sin.marker=root sin.schema=1
++unread -- id:test
+#= test sin.0.INBOX.modseq.1=2 sin.0.INBOX.tag=unread sin.0.INBOX.uid=1 sin.0.INBOX.uidvalidity=<omitted> sin.0.mailbox=INBOX sin.0.marker=message
 ", runner.notmuch_dump()?);
 
     Ok(())
   })
 }
+
+#[test]
+fn tls() {
+  common::setup(common::dovecot::tls_server, |runner| -> _ {
+    let runner = runner.with_tls();
+    runner.run(sin::Mode::ConnectOnly)?;
+
+    let server_inbox = runner.server_maildir("INBOX", &None)?;
+    server_inbox.cur(common::email("test").as_bytes())?;
+
+    runner.run(sin::Mode::Pull)?;
+
+    let client_inbox = runner.client_maildir("INBOX", &None)?;
+    assert_eq!((0, 1, 0), runner.maildir_count(&client_inbox)?);
+
+    Ok(())
+  })
+}
+
+#[test]
+fn auth_mechanisms() {
+  common::setup(common::dovecot::server, |runner| -> _ {
+    let server_inbox = runner.server_maildir("INBOX", &None)?;
+    server_inbox.cur(common::email("test").as_bytes())?;
+
+    for mechanism in [
+      sin::Mechanism::Login,
+      sin::Mechanism::Plain,
+      sin::Mechanism::CramMd5,
+      sin::Mechanism::ScramSha256,
+    ] {
+      runner.with_auth_mechanism(mechanism).run(sin::Mode::Pull)?;
+    }
+    assert!(
+      runner
+        .with_auth_mechanism(sin::Mechanism::ScramSha256)
+        .with_password("wrong")
+        .run(sin::Mode::Pull)
+        .is_err()
+    );
+
+    let client_inbox = runner.client_maildir("INBOX", &None)?;
+    assert_eq!((0, 1, 0), runner.maildir_count(&client_inbox)?);
+
+    Ok(())
+  })
+}
+
+#[test]
+fn trace() {
+  common::setup(common::dovecot::server, |runner| -> _ {
+    let server_inbox = runner.server_maildir("INBOX", &None)?;
+    server_inbox.cur(common::email("test").as_bytes())?;
+
+    // Challenge-response mechanisms can't be replayed.
+    let runner = runner.with_auth_mechanism(sin::Mechanism::Plain);
+    runner.with_trace_file("trace").run(sin::Mode::Pull)?;
+
+    // Another account, so the pull happens again but against the transcript.
+    let replay = runner.with_user("replay").with_replay_file("trace");
+    replay.run(sin::Mode::Pull)?;
+
+    let client_inbox = replay.client_maildir("INBOX", &None)?;
+    assert_eq!((0, 1, 0), replay.maildir_count(&client_inbox)?);
+
+    Ok(())
+  })
+}